@@ -1,12 +1,12 @@
-use std::hash::{Hash, Hasher, SipHasher};
+use std::hash::{DefaultHasher, Hash, Hasher};
 
 fn hashy(str: &str) -> u64 {
-    let mut hasher = SipHasher::new_with_keys(0, 0);
+    let mut hasher = DefaultHasher::new();
     str.hash(&mut hasher);
     let res = hasher.finish();
     println!("Hash result: {str} -> {res}");
 
-    return res;
+    res
 }
 
 fn main() {
@@ -1,5 +1,7 @@
 use std::error::Error;
 use std::sync::Arc;
+use redis_server::log::{log, log_to_file, Level};
+use redis_server::config::Config;
 use redis_server::multi_server::Server;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
@@ -7,22 +9,59 @@ use redis_server::protocol::stream_parser_tokio::handle_connection;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let server = Arc::from(Server::new());
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+    let config = Config::from_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+    if let Some(path) = config.logfile() {
+        log_to_file(&path).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            std::process::exit(1);
+        });
+    }
+
+    let mut listeners = Vec::new();
+    for address in config.listen_addresses() {
+        listeners.push(TcpListener::bind(&address).await?);
+        log(Level::Notice, format_args!("Listening on {address}"));
+    }
+    if listeners.is_empty() {
+        return Err("Not listening on any address, check bind".into());
+    }
+
+    let server = Arc::from(Server::with_config(config));
+    let accepting = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(accept_connections(server.clone(), listener)))
+        .collect::<Vec<_>>();
+    let serve = async {
+        for handle in accepting {
+            handle.await??;
+        }
+        Ok::<(), Box<dyn Error>>(())
+    };
+    tokio::select! {
+        res = serve => res?,
+        // Leaving main shuts the runtime down, which drops the server and stops the engine
+        _ = tokio::signal::ctrl_c() => log(Level::Notice, "Shutting down"),
+    }
+    Ok(())
+}
 
+async fn accept_connections(server: Arc<Server>, listener: TcpListener) -> Result<(), std::io::Error> {
     loop {
         let (mut stream, _) = listener.accept().await?;
 
         let server_clone = server.clone();
         tokio::spawn(async move {
-            println!("Handling connection!");
+            log(Level::Verbose, "Accepted a connection");
             let result = handle_connection(server_clone, &mut stream).await;
             match result {
                 Err(x) => {
-                    println!("Failed to handle connection: {x}");
+                    log(Level::Verbose, format_args!("Closing a connection after an error: {x}"));
                 }
                 _ => {
-                    println!("Closing connection!");
+                    log(Level::Verbose, "Client closed the connection");
                 }
             }
             stream.shutdown().await.unwrap_or(());
@@ -1,11 +1,35 @@
 use std::error::Error;
 use std::net::TcpListener;
+use redis_server::log::{log, log_to_file, Level};
+use redis_server::config::{Config, ConfigValue};
 use redis_server::single_server::Server;
 use redis_server::protocol::stream_parser_std::handle_connection;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut server = Server::new();
-    let listener = TcpListener::bind("127.0.0.1:6379")?;
+    let config = Config::from_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+    if let Some(path) = config.logfile() {
+        log_to_file(&path).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            std::process::exit(1);
+        });
+    }
+
+    if config.get("engine") != Some(ConfigValue::Enum("memory")) {
+        eprintln!("The single threaded server keeps everything in one keyspace, it can't run on another engine");
+        std::process::exit(1);
+    }
+
+    // Connections are served one at a time, so there's no waiting on several listeners at once
+    let address = match config.listen_addresses().as_slice() {
+        [address] => address.clone(),
+        _ => return Err("The single threaded server listens on exactly one address, check bind".into()),
+    };
+    let listener = TcpListener::bind(&address)?;
+    log(Level::Notice, format_args!("Listening on {address}"));
+    let mut server = Server::with_config(config);
 
     loop {
         let (mut stream, _) = listener.accept()?;
@@ -14,10 +38,10 @@ fn main() -> Result<(), Box<dyn Error>> {
 
         match result {
             Err(x) => {
-                println!("Failed to handle connection: {x}");
+                log(Level::Verbose, format_args!("Closing a connection after an error: {x}"));
             }
             _ => {
-                println!("Closing connection!");
+                log(Level::Verbose, "Client closed the connection");
             }
         }
     }
@@ -1,3 +1,7 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
 #[derive(Debug, PartialEq)]
 pub enum SetExistingOptions {
     OnlySetIfNotExists,
@@ -6,22 +10,945 @@ pub enum SetExistingOptions {
 
 #[derive(Debug, PartialEq, Default)]
 pub struct SetCommand {
-    pub key: String,
-    pub value: String,
+    pub key: Bytes,
+    pub value: Bytes,
     pub expiration: Option<u128>,
     pub set_existing: Option<SetExistingOptions>,
     pub keep_previous_ttl: bool,
     pub get_previous_value: bool,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum IncrBy {
+    Integer(i64),
+    Float(f64),
+}
+
+/// INCR, DECR, INCRBY, DECRBY and INCRBYFLOAT, the decrements are parsed into negative increments.
+#[derive(Debug, PartialEq)]
+pub struct IncrCommand {
+    pub key: Bytes,
+    pub by: IncrBy,
+}
+
+/// The NX, XX, GT and LT flags of the EXPIRE family.
+#[derive(Debug, PartialEq)]
+pub enum ExpireCondition {
+    NoExpiry,
+    HasExpiry,
+    GreaterThan,
+    LessThan,
+}
+
+/// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT, relative times are already resolved to a unix timestamp in milliseconds.
+#[derive(Debug, PartialEq)]
+pub struct ExpireCommand {
+    pub key: Bytes,
+    pub expiration: u128,
+    pub conditions: Vec<ExpireCondition>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TtlKind {
+    Ttl,
+    Pttl,
+    ExpireTime,
+    PexpireTime,
+}
+
+/// RENAME, or RENAMENX when `only_if_new` is set.
+#[derive(Debug, PartialEq)]
+pub struct RenameCommand {
+    pub source: Bytes,
+    pub destination: Bytes,
+    pub only_if_new: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CopyCommand {
+    pub source: Bytes,
+    pub destination: Bytes,
+    pub replace: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// The list commands that only touch a single key. Indexes are passed through as given,
+/// negative ones count from the end of the list.
+#[derive(Debug, PartialEq)]
+pub enum ListCommand {
+    /// LPUSH and RPUSH, or LPUSHX and RPUSHX when `only_if_exists` is set.
+    Push {
+        key: Bytes,
+        end: ListEnd,
+        values: Vec<Bytes>,
+        only_if_exists: bool,
+    },
+    /// LPOP and RPOP, with a count the reply is an array even for a single element.
+    Pop {
+        key: Bytes,
+        end: ListEnd,
+        count: Option<usize>,
+    },
+    Range {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    Len {
+        key: Bytes,
+    },
+    Index {
+        key: Bytes,
+        index: i64,
+    },
+    Set {
+        key: Bytes,
+        index: i64,
+        value: Bytes,
+    },
+    Rem {
+        key: Bytes,
+        count: i64,
+        value: Bytes,
+    },
+    Trim {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    Insert {
+        key: Bytes,
+        before: bool,
+        pivot: Bytes,
+        value: Bytes,
+    },
+}
+
+impl ListCommand {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            ListCommand::Push { key, .. }
+            | ListCommand::Pop { key, .. }
+            | ListCommand::Range { key, .. }
+            | ListCommand::Len { key }
+            | ListCommand::Index { key, .. }
+            | ListCommand::Set { key, .. }
+            | ListCommand::Rem { key, .. }
+            | ListCommand::Trim { key, .. }
+            | ListCommand::Insert { key, .. } => key,
+        }
+    }
+}
+
+/// LMOVE, RPOPLPUSH is parsed into a move from the right to the left.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LMoveCommand {
+    pub source: Bytes,
+    pub destination: Bytes,
+    pub from: ListEnd,
+    pub to: ListEnd,
+}
+
+/// The cursor and filters of the SCAN family.
+#[derive(Debug, PartialEq)]
+pub struct ScanOptions {
+    pub cursor: u64,
+    pub pattern: Option<Bytes>,
+    pub count: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HashCommand {
+    /// HSET, or HSETNX when `only_if_new` is set.
+    Set {
+        key: Bytes,
+        fields: Vec<(Bytes, Bytes)>,
+        only_if_new: bool,
+    },
+    Get {
+        key: Bytes,
+        field: Bytes,
+    },
+    MGet {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    Del {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    GetAll {
+        key: Bytes,
+    },
+    Keys {
+        key: Bytes,
+    },
+    Vals {
+        key: Bytes,
+    },
+    Len {
+        key: Bytes,
+    },
+    Exists {
+        key: Bytes,
+        field: Bytes,
+    },
+    /// HINCRBY and HINCRBYFLOAT.
+    IncrBy {
+        key: Bytes,
+        field: Bytes,
+        by: IncrBy,
+    },
+    Scan {
+        key: Bytes,
+        options: ScanOptions,
+        no_values: bool,
+    },
+    /// A positive count picks distinct fields, a negative one may pick the same field more than once.
+    RandField {
+        key: Bytes,
+        count: Option<i64>,
+        with_values: bool,
+    },
+    /// HEXPIRE, HPEXPIRE, HEXPIREAT and HPEXPIREAT, resolved to a unix timestamp in milliseconds like `ExpireCommand`.
+    Expire {
+        key: Bytes,
+        expiration: u128,
+        conditions: Vec<ExpireCondition>,
+        fields: Vec<Bytes>,
+    },
+    /// HTTL, HPTTL, HEXPIRETIME and HPEXPIRETIME.
+    Ttl {
+        key: Bytes,
+        kind: TtlKind,
+        fields: Vec<Bytes>,
+    },
+    Persist {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+}
+
+impl HashCommand {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            HashCommand::Set { key, .. }
+            | HashCommand::Get { key, .. }
+            | HashCommand::MGet { key, .. }
+            | HashCommand::Del { key, .. }
+            | HashCommand::GetAll { key }
+            | HashCommand::Keys { key }
+            | HashCommand::Vals { key }
+            | HashCommand::Len { key }
+            | HashCommand::Exists { key, .. }
+            | HashCommand::IncrBy { key, .. }
+            | HashCommand::Scan { key, .. }
+            | HashCommand::RandField { key, .. }
+            | HashCommand::Expire { key, .. }
+            | HashCommand::Ttl { key, .. }
+            | HashCommand::Persist { key, .. } => key,
+        }
+    }
+}
+
+/// The commands for the set type that only touch a single key, named apart from `SetCommand` which is SET.
+#[derive(Debug, PartialEq)]
+pub enum SetTypeCommand {
+    Add {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Rem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Members {
+        key: Bytes,
+    },
+    IsMember {
+        key: Bytes,
+        member: Bytes,
+    },
+    MIsMember {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Card {
+        key: Bytes,
+    },
+    Pop {
+        key: Bytes,
+        count: Option<usize>,
+    },
+    /// Same as `HashCommand::RandField`, a negative count may pick the same member more than once.
+    RandMember {
+        key: Bytes,
+        count: Option<i64>,
+    },
+    Scan {
+        key: Bytes,
+        options: ScanOptions,
+    },
+}
+
+impl SetTypeCommand {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            SetTypeCommand::Add { key, .. }
+            | SetTypeCommand::Rem { key, .. }
+            | SetTypeCommand::Members { key }
+            | SetTypeCommand::IsMember { key, .. }
+            | SetTypeCommand::MIsMember { key, .. }
+            | SetTypeCommand::Card { key }
+            | SetTypeCommand::Pop { key, .. }
+            | SetTypeCommand::RandMember { key, .. }
+            | SetTypeCommand::Scan { key, .. } => key,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SetOperation {
+    Inter,
+    Union,
+    Diff,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SetAlgebraOutput {
+    /// SINTER, SUNION and SDIFF reply with the members.
+    Members,
+    /// The *STORE forms save the result to the destination and reply with its size.
+    Store(Bytes),
+    /// SINTERCARD, a limit of 0 means no limit.
+    Cardinality {
+        limit: usize,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SetAlgebraCommand {
+    pub operation: SetOperation,
+    pub keys: Vec<Bytes>,
+    pub output: SetAlgebraOutput,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SMoveCommand {
+    pub source: Bytes,
+    pub destination: Bytes,
+    pub member: Bytes,
+}
+
+/// GT and LT for ZADD, an existing member's score only changes if the new one is greater or less.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ScoreComparison {
+    GreaterThan,
+    LessThan,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct ZAddOptions {
+    pub set_existing: Option<SetExistingOptions>,
+    pub comparison: Option<ScoreComparison>,
+    /// CH, the reply counts the members whose score changed as well as the new ones.
+    pub changed: bool,
+    /// INCR, which is also how ZINCRBY gets parsed. The reply is the new score.
+    pub increment: bool,
+}
+
+/// One end of a BYSCORE range, `(` in front of the score makes it exclusive.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+/// One end of a BYLEX range, `-` and `+` are `Min` and `Max`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+/// The bounds are always stored lowest first, also for the REV forms that take them the other way around.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ZRangeBy {
+    Rank {
+        start: i64,
+        stop: i64,
+    },
+    Score {
+        min: ScoreBound,
+        max: ScoreBound,
+    },
+    Lex {
+        min: LexBound,
+        max: LexBound,
+    },
+}
+
+/// ZRANGE, the older ZRANGEBYSCORE, ZRANGEBYLEX and ZREV* forms are parsed into it too.
+#[derive(Debug, PartialEq)]
+pub struct ZRangeOptions {
+    pub by: ZRangeBy,
+    pub rev: bool,
+    /// The offset and count of LIMIT, a negative count means everything after the offset.
+    pub limit: Option<(i64, i64)>,
+    pub with_scores: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ZSetCommand {
+    Add {
+        key: Bytes,
+        options: ZAddOptions,
+        members: Vec<(f64, Bytes)>,
+    },
+    Rem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Score {
+        key: Bytes,
+        member: Bytes,
+    },
+    MScore {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    Card {
+        key: Bytes,
+    },
+    /// ZCOUNT and ZLEXCOUNT, `by` is never a rank range.
+    Count {
+        key: Bytes,
+        by: ZRangeBy,
+    },
+    Rank {
+        key: Bytes,
+        member: Bytes,
+        rev: bool,
+        with_score: bool,
+    },
+    Range {
+        key: Bytes,
+        options: ZRangeOptions,
+    },
+    RemRange {
+        key: Bytes,
+        by: ZRangeBy,
+    },
+    /// ZPOPMIN, or ZPOPMAX when `max` is set.
+    Pop {
+        key: Bytes,
+        max: bool,
+        count: Option<usize>,
+    },
+}
+
+impl ZSetCommand {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            ZSetCommand::Add { key, .. }
+            | ZSetCommand::Rem { key, .. }
+            | ZSetCommand::Score { key, .. }
+            | ZSetCommand::MScore { key, .. }
+            | ZSetCommand::Card { key }
+            | ZSetCommand::Count { key, .. }
+            | ZSetCommand::Rank { key, .. }
+            | ZSetCommand::Range { key, .. }
+            | ZSetCommand::RemRange { key, .. }
+            | ZSetCommand::Pop { key, .. } => key,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+/// ZUNIONSTORE, ZINTERSTORE and ZDIFFSTORE. There's a weight for every key, ZDIFFSTORE doesn't
+/// take any so they're all 1.
+#[derive(Debug, PartialEq)]
+pub struct ZStoreCommand {
+    pub destination: Bytes,
+    pub operation: SetOperation,
+    pub keys: Vec<Bytes>,
+    pub weights: Vec<f64>,
+    pub aggregate: Aggregate,
+}
+
+/// A stream entry ID, `ms-seq`. Ordered by the milliseconds first and then the sequence number.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn next(&self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId { ms: self.ms.checked_add(1)?, seq: 0 }),
+        }
+    }
+
+    pub fn prev(&self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId { ms: self.ms.checked_sub(1)?, seq: u64::MAX }),
+        }
+    }
+}
+
+impl std::fmt::Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID argument of XADD. `*` is resolved against the clock when the command is parsed, the
+/// engine only makes sure the ID ends up above the last one in the stream.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum XAddId {
+    Auto {
+        now: u64,
+    },
+    /// `ms-*`, the sequence number is picked by the engine.
+    AutoSequence {
+        ms: u64,
+    },
+    Explicit(StreamId),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TrimStrategy {
+    MaxLen(u64),
+    MinId(StreamId),
+}
+
+/// The MAXLEN and MINID options of XADD and XTRIM. `~` is accepted but the trimming is always
+/// exact, redis only promises an approximate trim won't remove more than an exact one.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StreamTrim {
+    pub strategy: TrimStrategy,
+    /// At most this many entries get removed, only allowed together with `~`.
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StreamCommand {
+    Add {
+        key: Bytes,
+        id: XAddId,
+        fields: Vec<(Bytes, Bytes)>,
+        no_mkstream: bool,
+        trim: Option<StreamTrim>,
+    },
+    /// XRANGE, or XREVRANGE when `rev` is set. Exclusive bounds are already resolved to inclusive ones.
+    Range {
+        key: Bytes,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    },
+    Len {
+        key: Bytes,
+    },
+    Trim {
+        key: Bytes,
+        trim: StreamTrim,
+    },
+    Del {
+        key: Bytes,
+        ids: Vec<StreamId>,
+    },
+    /// XGROUP CREATE. The group starts out having delivered everything up to `id`.
+    GroupCreate {
+        key: Bytes,
+        group: Bytes,
+        id: XReadId,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    },
+    GroupSetId {
+        key: Bytes,
+        group: Bytes,
+        id: XReadId,
+        entries_read: Option<u64>,
+    },
+    GroupDestroy {
+        key: Bytes,
+        group: Bytes,
+    },
+    GroupCreateConsumer {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+    },
+    GroupDelConsumer {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+    },
+    Ack {
+        key: Bytes,
+        group: Bytes,
+        ids: Vec<StreamId>,
+    },
+    /// XPENDING, the summary form when there's no range.
+    Pending {
+        key: Bytes,
+        group: Bytes,
+        range: Option<PendingRange>,
+    },
+    Claim {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        min_idle: u128,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    AutoClaim {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        min_idle: u128,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    },
+    InfoStream {
+        key: Bytes,
+    },
+    InfoGroups {
+        key: Bytes,
+    },
+    InfoConsumers {
+        key: Bytes,
+        group: Bytes,
+    },
+}
+
+impl StreamCommand {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            StreamCommand::Add { key, .. }
+            | StreamCommand::Range { key, .. }
+            | StreamCommand::Len { key }
+            | StreamCommand::Trim { key, .. }
+            | StreamCommand::Del { key, .. }
+            | StreamCommand::GroupCreate { key, .. }
+            | StreamCommand::GroupSetId { key, .. }
+            | StreamCommand::GroupDestroy { key, .. }
+            | StreamCommand::GroupCreateConsumer { key, .. }
+            | StreamCommand::GroupDelConsumer { key, .. }
+            | StreamCommand::Ack { key, .. }
+            | StreamCommand::Pending { key, .. }
+            | StreamCommand::Claim { key, .. }
+            | StreamCommand::AutoClaim { key, .. }
+            | StreamCommand::InfoStream { key }
+            | StreamCommand::InfoGroups { key }
+            | StreamCommand::InfoConsumers { key, .. } => key,
+        }
+    }
+}
+
+/// The extended form of XPENDING. Exclusive bounds are already resolved to inclusive ones.
+#[derive(Debug, PartialEq)]
+pub struct PendingRange {
+    pub min_idle: u128,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<Bytes>,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct ClaimOptions {
+    /// When the claimed entries count as delivered, from IDLE or TIME. Now if not given.
+    pub delivery_time: Option<u128>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub just_id: bool,
+    pub last_id: Option<StreamId>,
+}
+
+/// Where XREAD starts reading, or where XGROUP CREATE/SETID puts the group.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum XReadId {
+    /// Only entries with a greater ID.
+    After(StreamId),
+    /// `$`, only entries added after the command.
+    Last,
+    /// `>`, the entries never delivered to anyone in the group. XREADGROUP only.
+    New,
+}
+
+/// The GROUP option of XREADGROUP.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReadGroup {
+    pub group: Bytes,
+    pub consumer: Bytes,
+    pub no_ack: bool,
+}
+
+/// XREAD, or XREADGROUP when there's a group.
+#[derive(Debug, PartialEq, Clone)]
+pub struct XReadCommand {
+    pub streams: Vec<(Bytes, XReadId)>,
+    pub count: Option<usize>,
+    pub group: Option<ReadGroup>,
+}
+
+/// What a blocking command does once one of its keys has something for it.
+#[derive(Debug, PartialEq)]
+pub enum BlockingOperation {
+    /// BLPOP and BRPOP.
+    Pop {
+        keys: Vec<Bytes>,
+        end: ListEnd,
+    },
+    /// BLMOVE, BRPOPLPUSH is parsed into a move from the right to the left.
+    Move(LMoveCommand),
+    /// BZPOPMIN and BZPOPMAX.
+    ZPop {
+        keys: Vec<Bytes>,
+        max: bool,
+    },
+    /// XREAD and XREADGROUP with the BLOCK option.
+    XRead(XReadCommand),
+}
+
+impl BlockingOperation {
+    pub fn keys(&self) -> Vec<Bytes> {
+        match self {
+            BlockingOperation::Pop { keys, .. } | BlockingOperation::ZPop { keys, .. } => keys.clone(),
+            BlockingOperation::Move(cmd) => vec![cmd.source.clone()],
+            BlockingOperation::XRead(cmd) => cmd.streams.iter().map(|(key, _)| key.clone()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BlockingCommand {
+    pub operation: BlockingOperation,
+    /// None waits for as long as it takes.
+    pub timeout: Option<Duration>,
+}
+
+/// What a client subscribes to, a channel by name or every channel matching a glob pattern.
+/// Shard channels are kept apart from the others, hashed like keys so each lives in one shard.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum SubscriptionKind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PubSubCommand {
+    /// SUBSCRIBE, PSUBSCRIBE and SSUBSCRIBE.
+    Subscribe {
+        kind: SubscriptionKind,
+        names: Vec<Bytes>,
+    },
+    /// UNSUBSCRIBE, PUNSUBSCRIBE and SUNSUBSCRIBE, no names drops every subscription of the kind.
+    Unsubscribe {
+        kind: SubscriptionKind,
+        names: Vec<Bytes>,
+    },
+    Publish {
+        channel: Bytes,
+        message: Bytes,
+    },
+    ShardPublish {
+        channel: Bytes,
+        message: Bytes,
+    },
+    Channels {
+        pattern: Option<Bytes>,
+    },
+    ShardChannels {
+        pattern: Option<Bytes>,
+    },
+    NumSub {
+        channels: Vec<Bytes>,
+    },
+    ShardNumSub {
+        channels: Vec<Bytes>,
+    },
+    NumPat,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigCommand {
+    Get {
+        patterns: Vec<String>,
+    },
+    Set {
+        parameters: Vec<(String, String)>,
+    },
+    ResetStat,
+    Rewrite,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct HelloCommand {
+    pub protocol: Option<i64>,
+    pub auth: Option<(Bytes, Bytes)>,
+    pub client_name: Option<Bytes>,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Command {
     Set(SetCommand),
     Get {
-        key: String,
-    },
-    ConfigGet {
-        key: Option<String>,
+        key: Bytes,
     },
+    Config(ConfigCommand),
     Dump,
+    Ping {
+        message: Option<Bytes>,
+    },
+    Hello(HelloCommand),
+    Auth {
+        username: Option<Bytes>,
+        password: Bytes,
+    },
+    Incr(IncrCommand),
+    Expire(ExpireCommand),
+    Persist {
+        key: Bytes,
+    },
+    Ttl {
+        key: Bytes,
+        kind: TtlKind,
+    },
+    /// DEL, or UNLINK when `unlink` is set.
+    Del {
+        keys: Vec<Bytes>,
+        unlink: bool,
+    },
+    Exists {
+        keys: Vec<Bytes>,
+    },
+    Touch {
+        keys: Vec<Bytes>,
+    },
+    Type {
+        key: Bytes,
+    },
+    Rename(RenameCommand),
+    Copy(CopyCommand),
+    List(ListCommand),
+    LMove(LMoveCommand),
+    Hash(HashCommand),
+    SetType(SetTypeCommand),
+    SetAlgebra(SetAlgebraCommand),
+    SMove(SMoveCommand),
+    ZSet(ZSetCommand),
+    ZStore(ZStoreCommand),
+    Stream(StreamCommand),
+    XRead(XReadCommand),
+    Blocking(BlockingCommand),
+    PubSub(PubSubCommand),
+    Multi,
+    Exec,
+    Discard,
+    Watch {
+        keys: Vec<Bytes>,
+    },
+    Unwatch,
+}
+
+impl Command {
+    /// Whether a RESP2 client can send the command while it's subscribed to something, those
+    /// connections are only there to receive messages.
+    pub fn allowed_while_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Ping { .. } | Command::PubSub(PubSubCommand::Subscribe { .. } | PubSubCommand::Unsubscribe { .. })
+        )
+    }
+
+    /// Every key the command reads or writes.
+    pub fn keys(&self) -> Vec<&Bytes> {
+        match self {
+            Command::Set(SetCommand { key, .. })
+            | Command::Get { key }
+            | Command::Incr(IncrCommand { key, .. })
+            | Command::Expire(ExpireCommand { key, .. })
+            | Command::Persist { key }
+            | Command::Ttl { key, .. }
+            | Command::Type { key } => vec![key],
+            Command::Del { keys, .. } | Command::Exists { keys } | Command::Touch { keys } => keys.iter().collect(),
+            Command::Rename(RenameCommand { source, destination, .. })
+            | Command::Copy(CopyCommand { source, destination, .. })
+            | Command::LMove(LMoveCommand { source, destination, .. })
+            | Command::SMove(SMoveCommand { source, destination, .. }) => vec![source, destination],
+            Command::List(cmd) => vec![cmd.key()],
+            Command::Hash(cmd) => vec![cmd.key()],
+            Command::SetType(cmd) => vec![cmd.key()],
+            Command::ZSet(cmd) => vec![cmd.key()],
+            Command::Stream(cmd) => vec![cmd.key()],
+            Command::SetAlgebra(cmd) => match &cmd.output {
+                SetAlgebraOutput::Store(destination) => cmd.keys.iter().chain([destination]).collect(),
+                _ => cmd.keys.iter().collect(),
+            },
+            Command::ZStore(cmd) => cmd.keys.iter().chain([&cmd.destination]).collect(),
+            Command::XRead(cmd) => cmd.streams.iter().map(|(key, _)| key).collect(),
+            Command::Blocking(cmd) => match &cmd.operation {
+                BlockingOperation::Pop { keys, .. } | BlockingOperation::ZPop { keys, .. } => keys.iter().collect(),
+                BlockingOperation::Move(cmd) => vec![&cmd.source, &cmd.destination],
+                BlockingOperation::XRead(cmd) => cmd.streams.iter().map(|(key, _)| key).collect(),
+            },
+            Command::Config(_)
+            | Command::Dump
+            | Command::Ping { .. }
+            | Command::Hello(_)
+            | Command::Auth { .. }
+            | Command::PubSub(_)
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch => vec![],
+        }
+    }
+
+    /// Whether the command works on keys. These are the ones a transaction has the engine run
+    /// together, the rest belong to the server or the connection.
+    pub fn works_on_keys(&self) -> bool {
+        !matches!(
+            self,
+            Command::Config(_)
+                | Command::Dump
+                | Command::Ping { .. }
+                | Command::Hello(_)
+                | Command::Auth { .. }
+                | Command::PubSub(_)
+                | Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch { .. }
+                | Command::Unwatch
+        )
+    }
 }
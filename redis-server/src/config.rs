@@ -0,0 +1,517 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::commands::ConfigCommand;
+use crate::data::notify::{notify_flags_to_string, parse_notify_flags};
+use crate::data::keyspace::set_hz;
+use crate::data::shared::glob_match;
+use crate::datatypes::DataType;
+use crate::log;
+use crate::protocol::inline_parser::split_args;
+
+/// How the value of a parameter is checked and shown.
+#[derive(Debug, Clone, Copy)]
+enum ConfigType {
+    Bool,
+    Integer { min: i64, max: i64 },
+    /// A number of bytes, written with an optional unit like `100mb`.
+    Memory,
+    String,
+    /// Space separated words, like the addresses of `bind`.
+    List,
+    Enum(&'static [&'static str]),
+    /// `save`, pairs of seconds and changes.
+    Save,
+    /// `notify-keyspace-events`.
+    NotifyFlags,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    Bool(bool),
+    Integer(i64),
+    Memory(u64),
+    String(String),
+    List(Vec<String>),
+    Enum(&'static str),
+    Save(Vec<(u64, u64)>),
+    NotifyFlags(u16),
+}
+
+struct Parameter {
+    name: &'static str,
+    kind: ConfigType,
+    default: &'static str,
+    /// Whether CONFIG SET can change it, the others only come from the config file or the
+    /// command line. Parameters nothing acts on yet, like persistence and eviction, are kept
+    /// immutable so redis.conf files still load but CONFIG SET doesn't pretend to apply them.
+    mutable: bool,
+}
+
+const LOG_LEVELS: &[&str] = &["debug", "verbose", "notice", "warning", "nothing"];
+const MAXMEMORY_POLICIES: &[&str] = &[
+    "volatile-lru",
+    "volatile-lfu",
+    "volatile-random",
+    "volatile-ttl",
+    "allkeys-lru",
+    "allkeys-lfu",
+    "allkeys-random",
+    "noeviction",
+];
+
+/// The storage engines the multi threaded server can run on, see `multi_server::Server`.
+const ENGINES: &[&str] = &["memory", "threaded"];
+
+const PARAMETERS: &[Parameter] = &[
+    Parameter { name: "bind", kind: ConfigType::List, default: "127.0.0.1", mutable: false },
+    Parameter { name: "port", kind: ConfigType::Integer { min: 0, max: 65535 }, default: "6379", mutable: false },
+    Parameter { name: "requirepass", kind: ConfigType::String, default: "", mutable: true },
+    Parameter { name: "timeout", kind: ConfigType::Integer { min: 0, max: i32::MAX as i64 }, default: "0", mutable: true },
+    Parameter { name: "tcp-keepalive", kind: ConfigType::Integer { min: 0, max: i32::MAX as i64 }, default: "300", mutable: false },
+    Parameter { name: "databases", kind: ConfigType::Integer { min: 1, max: i32::MAX as i64 }, default: "16", mutable: false },
+    Parameter { name: "save", kind: ConfigType::Save, default: "3600 1 300 100 60 10000", mutable: false },
+    Parameter { name: "dbfilename", kind: ConfigType::String, default: "dump.rdb", mutable: false },
+    Parameter { name: "dir", kind: ConfigType::String, default: ".", mutable: false },
+    Parameter { name: "appendonly", kind: ConfigType::Bool, default: "no", mutable: false },
+    Parameter { name: "daemonize", kind: ConfigType::Bool, default: "no", mutable: false },
+    Parameter { name: "loglevel", kind: ConfigType::Enum(LOG_LEVELS), default: "notice", mutable: true },
+    Parameter { name: "logfile", kind: ConfigType::String, default: "", mutable: false },
+    Parameter { name: "maxmemory", kind: ConfigType::Memory, default: "0", mutable: false },
+    Parameter { name: "maxmemory-policy", kind: ConfigType::Enum(MAXMEMORY_POLICIES), default: "noeviction", mutable: false },
+    Parameter { name: "maxclients", kind: ConfigType::Integer { min: 1, max: i32::MAX as i64 }, default: "10000", mutable: true },
+    Parameter { name: "hz", kind: ConfigType::Integer { min: 1, max: 500 }, default: "10", mutable: true },
+    Parameter { name: "engine", kind: ConfigType::Enum(ENGINES), default: "memory", mutable: false },
+    Parameter { name: "notify-keyspace-events", kind: ConfigType::NotifyFlags, default: "", mutable: true },
+];
+
+/// Parses `1gb`, `100m` or `512` the way redis' `memtoull` does, the `b` suffixes are powers of 1024.
+fn parse_memory(value: &str) -> Option<u64> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|x: char| x.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn parse_save(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers = value.split_whitespace().map(|x| x.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+impl ConfigType {
+    fn parse(&self, value: &str) -> Result<ConfigValue, String> {
+        match self {
+            ConfigType::Bool => match value.to_ascii_lowercase().as_str() {
+                "yes" => Ok(ConfigValue::Bool(true)),
+                "no" => Ok(ConfigValue::Bool(false)),
+                _ => Err("argument must be 'yes' or 'no'".into()),
+            },
+            ConfigType::Integer { min, max } => {
+                let value = value.parse::<i64>().map_err(|_| "argument couldn't be parsed into an integer".to_string())?;
+                if value < *min || value > *max {
+                    return Err(format!("argument must be between {} and {} inclusive", min, max));
+                }
+                Ok(ConfigValue::Integer(value))
+            }
+            ConfigType::Memory => parse_memory(value).map(ConfigValue::Memory).ok_or_else(|| "argument must be a memory value".into()),
+            ConfigType::String => Ok(ConfigValue::String(value.to_string())),
+            ConfigType::List => Ok(ConfigValue::List(value.split_whitespace().map(String::from).collect())),
+            ConfigType::Enum(options) => options
+                .iter()
+                .find(|option| option.eq_ignore_ascii_case(value))
+                .map(|option| ConfigValue::Enum(option))
+                .ok_or_else(|| format!("argument(s) must be one of the following: {}", options.join(", "))),
+            ConfigType::Save => parse_save(value).map(ConfigValue::Save).ok_or_else(|| "Invalid save parameters".into()),
+            ConfigType::NotifyFlags => parse_notify_flags(value).map(ConfigValue::NotifyFlags),
+        }
+    }
+}
+
+impl ConfigValue {
+    /// The value the way CONFIG GET shows it.
+    pub fn to_config_string(&self) -> String {
+        match self {
+            ConfigValue::Bool(true) => "yes".into(),
+            ConfigValue::Bool(false) => "no".into(),
+            ConfigValue::Integer(value) => value.to_string(),
+            ConfigValue::Memory(value) => value.to_string(),
+            ConfigValue::String(value) => value.clone(),
+            ConfigValue::List(words) => words.join(" "),
+            ConfigValue::Enum(value) => value.to_string(),
+            ConfigValue::Save(points) => points.iter().map(|(seconds, changes)| format!("{} {}", seconds, changes)).collect::<Vec<_>>().join(" "),
+            ConfigValue::NotifyFlags(flags) => notify_flags_to_string(*flags),
+        }
+    }
+
+    /// The value the way it's written to the config file, quoted when it has to be.
+    fn to_file_string(&self) -> String {
+        match self {
+            // Every pair is an argument of its own
+            ConfigValue::Save(points) if !points.is_empty() => self.to_config_string(),
+            ConfigValue::List(words) if !words.is_empty() => words.iter().map(|word| quote(word)).collect::<Vec<_>>().join(" "),
+            value => quote(&value.to_config_string()),
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    if !value.is_empty() && !value.chars().any(|x| x.is_whitespace() || x == '"' || x == '\'' || x == '\\') {
+        return value.to_string();
+    }
+    let mut quoted = String::from('"');
+    for x in value.chars() {
+        match x {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(x);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            x => quoted.push(x),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn find_parameter(name: &str) -> Option<usize> {
+    PARAMETERS.iter().position(|parameter| parameter.name.eq_ignore_ascii_case(name))
+}
+
+/// Splits a config file line into its arguments, quoted the same way as an inline command.
+fn split_directive(line: &str) -> Result<Vec<String>, String> {
+    let args = split_args(line.as_bytes()).map_err(|_| "Unbalanced quotes in configuration line".to_string())?;
+    Ok(args.iter().map(|arg| String::from_utf8_lossy(arg).into_owned()).collect())
+}
+
+/// Applies a line of the config file or a `--name value` option. Unlike CONFIG SET these can
+/// set the immutable parameters, and `save` lines add up rather than replace each other.
+fn apply_directive(values: &mut [ConfigValue], loaded: &mut [bool], directive: &[String]) -> Result<(), String> {
+    let bad_directive = || "Bad directive or wrong number of arguments".to_string();
+    let (name, arguments) = directive.split_first().ok_or_else(bad_directive)?;
+    let idx = find_parameter(name).ok_or_else(bad_directive)?;
+    let kind = PARAMETERS[idx].kind;
+    if arguments.is_empty() || (arguments.len() > 1 && !matches!(kind, ConfigType::Save | ConfigType::List)) {
+        return Err(bad_directive());
+    }
+
+    let value = kind.parse(&arguments.join(" "))?;
+    values[idx] = match (value, &values[idx]) {
+        (ConfigValue::Save(points), ConfigValue::Save(previous)) if loaded[idx] && !points.is_empty() => {
+            ConfigValue::Save(previous.iter().copied().chain(points).collect())
+        }
+        (value, _) => value,
+    };
+    loaded[idx] = true;
+    Ok(())
+}
+
+fn fatal_error(location: &str, line: &str, err: &str) -> String {
+    format!("*** FATAL CONFIG FILE ERROR ***\nReading the configuration {}\n>>> '{}'\n{}", location, line.trim(), err)
+}
+
+/// The server's settings, each kept as the type its parameter has. Shared between connections,
+/// CONFIG SET changes it while the server is running.
+#[derive(Debug)]
+pub struct Config {
+    values: RwLock<Vec<ConfigValue>>,
+    /// The config file the server was started with, where CONFIG REWRITE writes to.
+    file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        let values = PARAMETERS
+            .iter()
+            .map(|parameter| parameter.kind.parse(parameter.default).expect("Expected the default to be valid"))
+            .collect();
+        Config {
+            values: RwLock::new(values),
+            file: None,
+        }
+    }
+
+    /// Reads the config the way redis-server takes it, an optional config file followed by
+    /// `--name value` options that override what's in the file.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::new();
+        let values = config.values.get_mut().unwrap();
+        let mut loaded = vec![false; PARAMETERS.len()];
+        let mut args = args.into_iter().peekable();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            let contents = fs::read_to_string(&path).map_err(|err| format!("Fatal error, can't open config file '{}': {}", path, err))?;
+            for (number, line) in contents.lines().enumerate() {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    continue;
+                }
+                split_directive(line)
+                    .and_then(|directive| apply_directive(values, &mut loaded, &directive))
+                    .map_err(|err| fatal_error(&format!("file, at line {}", number + 1), line, &err))?;
+            }
+            // CONFIG REWRITE has to find it even if the working directory changes
+            config.file = Some(fs::canonicalize(&path).map_err(|err| format!("Fatal error, can't open config file '{}': {}", path, err))?);
+        }
+
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(fatal_error("from the command line", &arg, "Expected an option starting with '--'"));
+            };
+            let mut directive = vec![name.to_string()];
+            while let Some(value) = args.next_if(|arg| !arg.starts_with("--")) {
+                directive.push(value);
+            }
+            // `--save` on its own turns saving off, same as `--save ""`
+            if directive.len() == 1 {
+                directive.push(String::new());
+            }
+            apply_directive(values, &mut loaded, &directive).map_err(|err| fatal_error("from the command line", &format!("--{}", directive.join(" ")), &err))?;
+        }
+        Ok(config)
+    }
+
+    pub fn get(&self, name: &str) -> Option<ConfigValue> {
+        let idx = find_parameter(name)?;
+        Some(self.values.read().unwrap()[idx].clone())
+    }
+
+    /// The password clients have to AUTH with, if there's one.
+    pub fn requirepass(&self) -> Option<String> {
+        match self.get("requirepass") {
+            Some(ConfigValue::String(password)) if !password.is_empty() => Some(password),
+            _ => None,
+        }
+    }
+
+    /// Where the log goes, `None` for stderr.
+    pub fn logfile(&self) -> Option<String> {
+        match self.get("logfile") {
+            Some(ConfigValue::String(path)) if !path.is_empty() => Some(path),
+            _ => None,
+        }
+    }
+
+    pub fn maxclients(&self) -> usize {
+        match self.get("maxclients") {
+            Some(ConfigValue::Integer(clients)) => clients as usize,
+            _ => usize::MAX,
+        }
+    }
+
+    /// Hands the settings kept for the whole process, the log level and the expiry cycle's `hz`,
+    /// to what uses them. Servers call it when they start and after every CONFIG command.
+    pub fn apply_process_settings(&self) {
+        if let Some(ConfigValue::Enum(level)) = self.get("loglevel") {
+            log::set_level(level);
+        }
+        if let Some(ConfigValue::Integer(hz)) = self.get("hz") {
+            set_hz(hz as u64);
+        }
+    }
+
+    /// Every `address:port` the server listens on.
+    pub fn listen_addresses(&self) -> Vec<String> {
+        let (Some(ConfigValue::List(addresses)), Some(ConfigValue::Integer(port))) = (self.get("bind"), self.get("port")) else {
+            return vec![];
+        };
+        addresses
+            .iter()
+            .map(|address| match address.contains(':') {
+                true => format!("[{}]:{}", address, port),
+                false => format!("{}:{}", address, port),
+            })
+            .collect()
+    }
+
+    /// CONFIG GET, every parameter matching any of the glob patterns with its value.
+    pub fn matching(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        let values = self.values.read().unwrap();
+        PARAMETERS
+            .iter()
+            .zip(values.iter())
+            .filter(|(parameter, _)| patterns.iter().any(|pattern| glob_match(pattern.to_ascii_lowercase().as_bytes(), parameter.name.as_bytes())))
+            .map(|(parameter, value)| (parameter.name, value.to_config_string()))
+            .collect()
+    }
+
+    /// CONFIG SET. Every parameter is checked before any of them changes, so either all of them
+    /// are applied or none are. The error is the reply to send back.
+    pub fn set(&self, parameters: &[(String, String)]) -> Result<(), String> {
+        let mut parsed = Vec::with_capacity(parameters.len());
+        for (name, value) in parameters {
+            let Some(idx) = find_parameter(name) else {
+                return Err(format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", name));
+            };
+            let failed = |reason: String| format!("ERR CONFIG SET failed (possibly related to argument '{}') - {}", name, reason);
+            if parsed.iter().any(|(other, _)| *other == idx) {
+                return Err(failed("duplicate parameter".into()));
+            }
+            if !PARAMETERS[idx].mutable {
+                return Err(failed("can't set immutable config".into()));
+            }
+            parsed.push((idx, PARAMETERS[idx].kind.parse(value).map_err(failed)?));
+        }
+
+        let mut values = self.values.write().unwrap();
+        for (idx, value) in parsed {
+            values[idx] = value;
+        }
+        Ok(())
+    }
+
+    pub fn process(&self, command: ConfigCommand) -> DataType {
+        let res = match command {
+            ConfigCommand::Get { patterns } => {
+                let reply = self
+                    .matching(&patterns)
+                    .into_iter()
+                    .flat_map(|(name, value)| [DataType::BulkString(name.into()), DataType::BulkString(value.into())])
+                    .collect();
+                return DataType::Array(reply);
+            }
+            ConfigCommand::Set { parameters } => self.set(&parameters),
+            ConfigCommand::ResetStat => Err("ERR CONFIG RESETSTAT is not supported, the server keeps no statistics".into()),
+            ConfigCommand::Rewrite => self.rewrite(),
+        };
+        match res {
+            Ok(()) => DataType::SimpleString("OK".into()),
+            Err(err) => DataType::Error(err),
+        }
+    }
+
+    /// CONFIG REWRITE. Lines for known parameters get the current value, everything else in the
+    /// file is left as it was. Parameters that aren't in the file yet are added at the end if
+    /// they've been changed from their default.
+    pub fn rewrite(&self) -> Result<(), String> {
+        let Some(path) = &self.file else {
+            return Err("ERR The server is running without a config file".into());
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(format!("ERR Rewriting config file: {}", err)),
+        };
+
+        let values = self.values.read().unwrap();
+        let mut written = vec![false; PARAMETERS.len()];
+        let mut lines = Vec::new();
+        for line in contents.lines() {
+            let name = line.split_whitespace().next().filter(|name| !name.starts_with('#'));
+            match name.and_then(find_parameter) {
+                // Repeated directives collapse into the first one
+                Some(idx) if written[idx] => {}
+                Some(idx) => {
+                    lines.push(format!("{} {}", PARAMETERS[idx].name, values[idx].to_file_string()));
+                    written[idx] = true;
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+
+        let mut generated = PARAMETERS
+            .iter()
+            .zip(values.iter())
+            .enumerate()
+            .filter(|(idx, (parameter, value))| !written[*idx] && parameter.kind.parse(parameter.default).ok().as_ref() != Some(*value))
+            .map(|(_, (parameter, value))| format!("{} {}", parameter.name, value.to_file_string()))
+            .peekable();
+        if generated.peek().is_some() {
+            lines.push("# Generated by CONFIG REWRITE".into());
+            lines.extend(generated);
+        }
+
+        // Written next to the file and renamed over it, so a failure never leaves half a config behind
+        let temporary = path.with_extension("rewrite.tmp");
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        fs::write(&temporary, contents)
+            .and_then(|_| fs::rename(&temporary, path))
+            .map_err(|err| format!("ERR Rewriting config file: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_get_and_set() {
+        let config = Config::new();
+        assert_eq!(config.matching(&["maxmemory*".into()]), vec![("maxmemory", "0".into()), ("maxmemory-policy", "noeviction".into())]);
+
+        config.set(&[("hz".into(), "20".into()), ("loglevel".into(), "WARNING".into())]).unwrap();
+        assert_eq!(config.get("hz"), Some(ConfigValue::Integer(20)));
+        assert_eq!(config.get("loglevel"), Some(ConfigValue::Enum("warning")));
+
+        // Nothing changes when any of the parameters is bad
+        assert_eq!(
+            config.set(&[("timeout".into(), "5".into()), ("maxclients".into(), "0".into())]),
+            Err("ERR CONFIG SET failed (possibly related to argument 'maxclients') - argument must be between 1 and 2147483647 inclusive".into())
+        );
+        assert_eq!(config.get("timeout"), Some(ConfigValue::Integer(0)));
+        assert!(config.set(&[("port".into(), "7000".into())]).is_err());
+        assert_eq!(
+            config.set(&[("maxmemory".into(), "1mb".into())]),
+            Err("ERR CONFIG SET failed (possibly related to argument 'maxmemory') - can't set immutable config".into())
+        );
+        assert!(config.set(&[("nope".into(), "1".into())]).is_err());
+    }
+
+    #[test]
+    pub fn test_file_and_arguments() {
+        let path = std::env::temp_dir().join(format!("redis-server-load-{}.conf", std::process::id()));
+        fs::write(&path, "# Comment\n  port 7000\nbind 0.0.0.0 ::1\nsave 900 1\nsave 60 100\nrequirepass \"a \\\"b\\\"\"\n").unwrap();
+        let args = [path.to_str().unwrap(), "--port", "7001", "--maxmemory", "1kb"].map(String::from);
+        let config = Config::from_args(args);
+        fs::write(&path, "port 7000\nnot-a-directive yes\n").unwrap();
+        let bad = Config::from_args([path.to_str().unwrap().to_string()]);
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.listen_addresses(), vec!["0.0.0.0:7001".to_string(), "[::1]:7001".to_string()]);
+        assert_eq!(config.get("save"), Some(ConfigValue::Save(vec![(900, 1), (60, 100)])));
+        assert_eq!(config.get("maxmemory"), Some(ConfigValue::Memory(1024)));
+        assert_eq!(config.requirepass(), Some("a \"b\"".into()));
+
+        assert!(bad.unwrap_err().contains("at line 2\n>>> 'not-a-directive yes'\nBad directive"));
+        assert!(Config::from_args(["--port", "many"].map(String::from)).is_err());
+        assert_eq!(Config::from_args(["--save"].map(String::from)).unwrap().get("save"), Some(ConfigValue::Save(vec![])));
+    }
+
+    #[test]
+    pub fn test_rewrite() {
+        let path = std::env::temp_dir().join(format!("redis-server-rewrite-{}.conf", std::process::id()));
+        fs::write(&path, "# Comment\nport 7000\nsave 900 1\nsave 60 100\nunknown-thing yes\n").unwrap();
+        let config = Config { file: Some(path.clone()), ..Config::new() };
+        config.set(&[("timeout".into(), "5".into()), ("requirepass".into(), "a b".into())]).unwrap();
+
+        config.rewrite().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            contents,
+            "# Comment\nport 6379\nsave 3600 1 300 100 60 10000\nunknown-thing yes\n# Generated by CONFIG REWRITE\nrequirepass \"a b\"\ntimeout 5\n"
+        );
+    }
+}
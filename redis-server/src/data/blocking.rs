@@ -0,0 +1,292 @@
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex}};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::{commands::{BlockingOperation, Command, CopyCommand, LMoveCommand, ListCommand, RenameCommand, StreamCommand, StreamId, XReadId, ZSetCommand, ZStoreCommand}, datatypes::DataType};
+
+struct Waiter {
+    id: u64,
+    keys: Vec<Bytes>,
+    notify: Notify,
+    /// Set when a write picked this waiter, so the wake up can be passed on if it's never used.
+    woken: AtomicBool,
+}
+
+/// The clients parked by a blocking command, queued per key in the order they started waiting.
+/// A write to a key wakes the first client in its queue, which then tries its command again.
+#[derive(Default)]
+pub(crate) struct WaitRegistry {
+    queues: Mutex<HashMap<Bytes, VecDeque<Arc<Waiter>>>>,
+    /// How many waits are registered, so writes can skip the lock when nobody is blocked.
+    registered: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+impl WaitRegistry {
+    /// Queues a wait on every key. A client that was woken but found nothing goes back to the
+    /// front, it has been waiting the longest.
+    pub fn register(&self, keys: &[Bytes], front: bool) -> Wait<'_> {
+        let waiter = Arc::new(Waiter {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            keys: keys.to_vec(),
+            notify: Notify::new(),
+            woken: AtomicBool::new(false),
+        });
+        let mut queues = self.queues.lock().unwrap();
+        for key in keys {
+            let queue = queues.entry(key.clone()).or_default();
+            match front {
+                true => queue.push_front(waiter.clone()),
+                false => queue.push_back(waiter.clone()),
+            }
+        }
+        self.registered.fetch_add(1, Ordering::Relaxed);
+        Wait { registry: self, waiter }
+    }
+
+    /// Signals the key of a `Wakes` command once it has run, unless it didn't write anything.
+    pub fn signal_write(&self, key: Option<Bytes>, reply: &Result<DataType, String>) {
+        match (key, reply) {
+            (_, Err(_) | Ok(DataType::Nil | DataType::Error(_))) | (None, _) => {}
+            (Some(key), Ok(_)) => self.signal(&key),
+        }
+    }
+
+    /// `signal_write` for each command of a transaction, `keys` are their woken keys in order.
+    pub fn signal_transaction(&self, keys: Vec<Option<Bytes>>, reply: &Result<DataType, String>) {
+        let Ok(DataType::Array(replies)) = reply else {
+            return;
+        };
+        for (key, reply) in keys.into_iter().zip(replies) {
+            match (key, reply) {
+                (_, DataType::Nil | DataType::Error(_)) | (None, _) => {}
+                (Some(key), _) => self.signal(&key),
+            }
+        }
+    }
+
+    /// Wakes the client that has been waiting on `key` the longest.
+    pub fn signal(&self, key: &[u8]) {
+        if self.registered.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let Some(waiter) = queues.get_mut(key).and_then(|queue| queue.pop_front()) else {
+            return;
+        };
+        // It's done waiting on its other keys too, they can wake someone else
+        remove_waiter(&mut queues, &waiter);
+        waiter.woken.store(true, Ordering::Relaxed);
+        waiter.notify.notify_one();
+    }
+}
+
+fn remove_waiter(queues: &mut HashMap<Bytes, VecDeque<Arc<Waiter>>>, waiter: &Waiter) {
+    for key in &waiter.keys {
+        let Some(queue) = queues.get_mut(key) else {
+            continue;
+        };
+        queue.retain(|other| other.id != waiter.id);
+        if queue.is_empty() {
+            queues.remove(key);
+        }
+    }
+}
+
+/// A queued wait. Dropping it takes it out of the queues, so a timeout or a disconnect doesn't
+/// leave anything behind.
+pub(crate) struct Wait<'a> {
+    registry: &'a WaitRegistry,
+    waiter: Arc<Waiter>,
+}
+
+impl Wait<'_> {
+    pub async fn notified(&self) {
+        self.waiter.notify.notified().await;
+        self.waiter.woken.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        remove_waiter(&mut self.registry.queues.lock().unwrap(), &self.waiter);
+        self.registry.registered.fetch_sub(1, Ordering::Relaxed);
+        // A write picked this client but it went away before looking, the next one gets a go
+        if self.waiter.woken.load(Ordering::Relaxed) {
+            self.waiter.keys.iter().for_each(|key| self.registry.signal(key));
+        }
+    }
+}
+
+/// Commands that can give a blocked client something, and the key they give it on.
+pub(crate) trait Wakes {
+    fn woken_key(&self) -> Option<&Bytes>;
+}
+
+impl Wakes for ListCommand {
+    fn woken_key(&self) -> Option<&Bytes> {
+        matches!(self, ListCommand::Push { .. } | ListCommand::Insert { .. }).then(|| self.key())
+    }
+}
+
+impl Wakes for LMoveCommand {
+    fn woken_key(&self) -> Option<&Bytes> {
+        Some(&self.destination)
+    }
+}
+
+impl Wakes for ZSetCommand {
+    fn woken_key(&self) -> Option<&Bytes> {
+        matches!(self, ZSetCommand::Add { .. }).then(|| self.key())
+    }
+}
+
+impl Wakes for ZStoreCommand {
+    fn woken_key(&self) -> Option<&Bytes> {
+        Some(&self.destination)
+    }
+}
+
+impl Wakes for StreamCommand {
+    fn woken_key(&self) -> Option<&Bytes> {
+        // Moving a group back makes entries new to it again
+        matches!(self, StreamCommand::Add { .. } | StreamCommand::GroupSetId { .. }).then(|| self.key())
+    }
+}
+
+impl Wakes for RenameCommand {
+    fn woken_key(&self) -> Option<&Bytes> {
+        Some(&self.destination)
+    }
+}
+
+impl Wakes for CopyCommand {
+    fn woken_key(&self) -> Option<&Bytes> {
+        Some(&self.destination)
+    }
+}
+
+impl Wakes for Command {
+    fn woken_key(&self) -> Option<&Bytes> {
+        match self {
+            Command::List(cmd) => cmd.woken_key(),
+            Command::LMove(cmd) => cmd.woken_key(),
+            Command::ZSet(cmd) => cmd.woken_key(),
+            Command::ZStore(cmd) => cmd.woken_key(),
+            Command::Stream(cmd) => cmd.woken_key(),
+            Command::Rename(cmd) => cmd.woken_key(),
+            Command::Copy(cmd) => cmd.woken_key(),
+            Command::Blocking(cmd) => match &cmd.operation {
+                BlockingOperation::Move(cmd) => cmd.woken_key(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The non blocking commands a blocking one tries, in order, until one of them finds something.
+pub(crate) fn attempts(operation: &BlockingOperation) -> Vec<Command> {
+    match operation {
+        BlockingOperation::Pop { keys, end } => keys
+            .iter()
+            .map(|key| Command::List(ListCommand::Pop { key: key.clone(), end: *end, count: None }))
+            .collect(),
+        BlockingOperation::Move(cmd) => vec![Command::LMove(cmd.clone())],
+        BlockingOperation::ZPop { keys, max } => keys
+            .iter()
+            .map(|key| Command::ZSet(ZSetCommand::Pop { key: key.clone(), max: *max, count: None }))
+            .collect(),
+        BlockingOperation::XRead(cmd) => vec![Command::XRead(cmd.clone())],
+    }
+}
+
+/// Turns the reply of an attempt into the reply of the blocking command, None if it found nothing.
+pub(crate) fn served(operation: &BlockingOperation, attempt: usize, reply: DataType) -> Option<DataType> {
+    match (operation, reply) {
+        (_, DataType::Nil | DataType::NullArray) => None,
+        (_, error @ DataType::Error(_)) => Some(error),
+        (BlockingOperation::Pop { keys, .. }, value) => Some(DataType::Array(vec![DataType::BulkString(keys[attempt].clone()), value])),
+        (BlockingOperation::ZPop { .. }, DataType::Array(popped)) if popped.is_empty() => None,
+        (BlockingOperation::ZPop { keys, .. }, DataType::Array(mut popped)) => {
+            popped.insert(0, DataType::BulkString(keys[attempt].clone()));
+            Some(DataType::Array(popped))
+        }
+        (_, reply) => Some(reply),
+    }
+}
+
+pub(crate) fn timeout_reply(operation: &BlockingOperation) -> DataType {
+    match operation {
+        BlockingOperation::Move(_) => DataType::Nil,
+        _ => DataType::NullArray,
+    }
+}
+
+/// `$` in a blocking XREAD means the entries added while waiting, so it has to be pinned to
+/// the last entry before the first attempt. The command that finds it for `key`.
+pub(crate) fn last_entry_command(key: &Bytes) -> Command {
+    Command::Stream(StreamCommand::Range { key: key.clone(), start: StreamId::MIN, end: StreamId::MAX, count: Some(1), rev: true })
+}
+
+/// Pins `$` to the ID in the reply of `last_entry_command`. Anything newer than the last entry
+/// is also newer than any deleted one, so it doesn't matter that the stream may have had more.
+pub(crate) fn pin_last_id(id: &mut XReadId, reply: &DataType) {
+    if *id != XReadId::Last {
+        return;
+    }
+    let last = match reply {
+        DataType::Array(entries) => match entries.first() {
+            Some(DataType::Array(entry)) => match entry.first() {
+                Some(DataType::BulkString(last)) => parse_id(last),
+                _ => None,
+            },
+            _ => Some(StreamId::MIN),
+        },
+        // Leave WRONGTYPE for the attempt to report
+        _ => None,
+    };
+    if let Some(last) = last {
+        *id = XReadId::After(last);
+    }
+}
+
+fn parse_id(id: &[u8]) -> Option<StreamId> {
+    let (ms, seq) = std::str::from_utf8(id).ok()?.split_once('-')?;
+    Some(StreamId { ms: ms.parse().ok()?, seq: seq.parse().ok()? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_signal_wakes_in_order() {
+        let registry = WaitRegistry::default();
+        let keys = vec![Bytes::from("a"), Bytes::from("b")];
+        let first = registry.register(&keys, false);
+        let second = registry.register(&keys[..1], false);
+
+        registry.signal(b"b");
+        assert!(first.waiter.woken.load(Ordering::Relaxed));
+        // Woken through b, so it's no longer in line for a
+        registry.signal(b"a");
+        assert!(second.waiter.woken.load(Ordering::Relaxed));
+        assert!(registry.queues.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_dropping_a_woken_wait_passes_it_on() {
+        let registry = WaitRegistry::default();
+        let keys = vec![Bytes::from("a")];
+        let first = registry.register(&keys, false);
+        let second = registry.register(&keys, false);
+
+        registry.signal(b"a");
+        drop(first);
+        assert!(second.waiter.woken.load(Ordering::Relaxed));
+        drop(second);
+        assert_eq!(registry.registered.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,42 @@
+use crate::{commands::Command, datatypes::DataType};
+
+use super::{blocking::{attempts, served, timeout_reply}, hash::process_hash, keyspace::Keyspaces, list::{process_list, process_lmove}, set::{process_set_algebra, process_set_type, process_smove}, shared::{process_copy, process_del, process_exists, process_expire, process_get, process_incr, process_persist, process_rename, process_set, process_ttl, process_type}, stream::{process_stream, process_xread}, zset::{process_zset, process_zstore}};
+
+/// Runs any command that works on keys against keyspaces the caller already holds. Engines only
+/// decide which keyspaces those are and how they're locked, so several commands can also run
+/// without anything in between. A blocking command acts like its non blocking form, same as redis.
+pub(crate) fn execute(map: &mut impl Keyspaces, command: Command, now: u128) -> Result<DataType, String> {
+    match command {
+        Command::Set(cmd) => process_set(map.keyspace_for(&cmd.key), cmd, now),
+        Command::Get { key } => process_get(map.keyspace_for(&key), key, now),
+        Command::Incr(cmd) => process_incr(map.keyspace_for(&cmd.key), cmd, now),
+        Command::Expire(cmd) => process_expire(map.keyspace_for(&cmd.key), cmd, now),
+        Command::Persist { key } => process_persist(map.keyspace_for(&key), key, now),
+        Command::Ttl { key, kind } => process_ttl(map.keyspace_for(&key), key, kind, now),
+        Command::Del { keys, unlink } => process_del(map, keys, unlink, now),
+        Command::Exists { keys } | Command::Touch { keys } => process_exists(map, keys, now),
+        Command::Type { key } => process_type(map.keyspace_for(&key), key, now),
+        Command::Rename(cmd) => process_rename(map, cmd, now),
+        Command::Copy(cmd) => process_copy(map, cmd, now),
+        Command::List(cmd) => process_list(map.keyspace_for(cmd.key()), cmd, now),
+        Command::LMove(cmd) => process_lmove(map, cmd, now),
+        Command::Hash(cmd) => process_hash(map.keyspace_for(cmd.key()), cmd, now),
+        Command::SetType(cmd) => process_set_type(map.keyspace_for(cmd.key()), cmd, now),
+        Command::SetAlgebra(cmd) => process_set_algebra(map, cmd, now),
+        Command::SMove(cmd) => process_smove(map, cmd, now),
+        Command::ZSet(cmd) => process_zset(map.keyspace_for(cmd.key()), cmd, now),
+        Command::ZStore(cmd) => process_zstore(map, cmd, now),
+        Command::Stream(cmd) => process_stream(map.keyspace_for(cmd.key()), cmd, now),
+        Command::XRead(cmd) => process_xread(map, cmd, now),
+        Command::Blocking(cmd) => {
+            for (attempt, attempt_command) in attempts(&cmd.operation).into_iter().enumerate() {
+                let reply = execute(map, attempt_command, now)?;
+                if let Some(reply) = served(&cmd.operation, attempt, reply) {
+                    return Ok(reply);
+                }
+            }
+            Ok(timeout_reply(&cmd.operation))
+        }
+        _ => Err("Expected a command that works on keys".into()),
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::{commands::{HashCommand, IncrBy, ScanOptions}, protocol::serializer::format_double, datatypes::{DataType, StorageRecord, StorageValue}};
+
+use super::{keyspace::Keyspace, notify::EventClass, shared::{expire_conditions_met, parse_stored_float, parse_stored_integer, random_index, random_picks, remove_if_empty, scan_page, scan_reply, ttl_reply, WRONGTYPE}};
+
+/// The fields of a hash. Expiry times are kept on the side for the fields that have one, so
+/// hashes that never use field TTLs don't pay for them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HashValue {
+    fields: HashMap<Bytes, Bytes>,
+    field_ttls: HashMap<Bytes, u128>,
+}
+
+impl HashValue {
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Field TTLs are only enforced lazily, every command goes through this before looking at the
+    /// fields. Returns whether any field expired.
+    fn expire_fields(&mut self, now: u128) -> bool {
+        if self.field_ttls.is_empty() {
+            return false;
+        }
+        let expired = self
+            .field_ttls
+            .iter()
+            .filter(|(_, ttl)| now > **ttl)
+            .map(|(field, _)| field.clone())
+            .collect::<Vec<_>>();
+        for field in &expired {
+            self.remove(field);
+        }
+        !expired.is_empty()
+    }
+
+    fn remove(&mut self, field: &[u8]) -> bool {
+        self.field_ttls.remove(field);
+        self.fields.remove(field).is_some()
+    }
+}
+
+/// The error side of these results is the reply to send back as is, which is always WRONGTYPE.
+type HashResult = Result<DataType, DataType>;
+
+/// Returns the hash stored at `key` with its expired fields removed, None if there's nothing there.
+fn get_hash<'a>(map: &'a mut Keyspace, key: &[u8], now: u128) -> Result<Option<&'a mut HashValue>, DataType> {
+    let expired = match map.get_mut(key, now) {
+        None => return Ok(None),
+        Some(StorageValue::Hash(hash)) => hash.expire_fields(now),
+        Some(_) => return Err(DataType::Error(WRONGTYPE.into())),
+    };
+    if expired {
+        map.notify(EventClass::Hash, "hexpired", key);
+    }
+    // Expiring fields can leave the hash empty, in which case the key goes too
+    remove_if_empty(map, key, now);
+    match map.get_mut(key, now) {
+        Some(StorageValue::Hash(hash)) => Ok(Some(hash)),
+        _ => Ok(None),
+    }
+}
+
+/// The new hash starts out empty, the caller has to add a field to it before it's done.
+fn get_or_insert_hash<'a>(map: &'a mut Keyspace, key: &Bytes, now: u128) -> Result<&'a mut HashValue, DataType> {
+    if get_hash(map, key, now)?.is_none() {
+        map.insert(key.clone(), StorageRecord {
+            value: StorageValue::Hash(HashValue::default()),
+            ttl: None,
+        });
+    }
+    match map.get_mut(key, now) {
+        Some(StorageValue::Hash(hash)) => Ok(hash),
+        _ => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+fn bulk_strings<'a>(values: impl Iterator<Item = &'a Bytes>) -> DataType {
+    DataType::Array(values.cloned().map(DataType::BulkString).collect())
+}
+
+fn increment(map: &mut Keyspace, key: Bytes, field: Bytes, by: IncrBy, now: u128) -> HashResult {
+    let current = get_hash(map, &key, now)?.and_then(|hash| hash.fields.get(&field));
+    let (stored, reply, event) = match by {
+        IncrBy::Integer(by) => {
+            let Some(current) = current.map_or(Some(0), |x| parse_stored_integer(x)) else {
+                return Ok(DataType::Error("ERR hash value is not an integer".into()));
+            };
+            let Some(result) = current.checked_add(by) else {
+                return Ok(DataType::Error("ERR increment or decrement would overflow".into()));
+            };
+            (Bytes::from(result.to_string()), DataType::Integer(result), "hincrby")
+        }
+        IncrBy::Float(by) => {
+            let Some(current) = current.map_or(Some(0.0), |x| parse_stored_float(x)) else {
+                return Ok(DataType::Error("ERR hash value is not a float".into()));
+            };
+            let result = current + by;
+            if !result.is_finite() {
+                return Ok(DataType::Error("ERR increment would produce NaN or Infinity".into()));
+            }
+            let stored = Bytes::from(format_double(result));
+            (stored.clone(), DataType::BulkString(stored), "hincrbyfloat")
+        }
+    };
+
+    // Same as the string counters, the field keeps its TTL
+    get_or_insert_hash(map, &key, now)?.fields.insert(field, stored);
+    map.notify(EventClass::Hash, event, &key);
+    Ok(reply)
+}
+
+fn scan(hash: &HashValue, options: ScanOptions, no_values: bool) -> DataType {
+    let (cursor, page) = scan_page(hash.fields.iter(), &options);
+    let mut items = Vec::new();
+    for (field, value) in page {
+        items.push(DataType::BulkString(field.clone()));
+        if !no_values {
+            items.push(DataType::BulkString(value.clone()));
+        }
+    }
+    scan_reply(cursor, items)
+}
+
+fn random_fields(hash: &HashValue, count: i64, with_values: bool) -> DataType {
+    let fields = hash.fields.iter().collect::<Vec<_>>();
+    let mut items = Vec::new();
+    for idx in random_picks(fields.len(), count) {
+        let (field, value) = fields[idx];
+        items.push(DataType::BulkString(field.clone()));
+        if with_values {
+            items.push(DataType::BulkString(value.clone()));
+        }
+    }
+    DataType::Array(items)
+}
+
+fn run_hash_command(map: &mut Keyspace, cmd: HashCommand, now: u128) -> HashResult {
+    match cmd {
+        HashCommand::Set { key, fields, only_if_new } => {
+            let hash = get_or_insert_hash(map, &key, now)?;
+            let mut added = 0;
+            let mut written = false;
+            for (field, value) in fields {
+                if only_if_new && hash.fields.contains_key(&field) {
+                    continue;
+                }
+                // Overwriting a field clears its TTL, the same as SET does for keys
+                hash.field_ttls.remove(&field);
+                written = true;
+                if hash.fields.insert(field, value).is_none() {
+                    added += 1;
+                }
+            }
+            if written {
+                map.notify(EventClass::Hash, "hset", &key);
+            }
+            Ok(DataType::Integer(added))
+        }
+        HashCommand::Get { key, field } => {
+            let value = get_hash(map, &key, now)?.and_then(|hash| hash.fields.get(&field));
+            Ok(value.map_or(DataType::Nil, |value| DataType::BulkString(value.clone())))
+        }
+        HashCommand::MGet { key, fields } => {
+            let hash = get_hash(map, &key, now)?;
+            let values = fields
+                .iter()
+                .map(|field| match hash.as_ref().and_then(|hash| hash.fields.get(field)) {
+                    Some(value) => DataType::BulkString(value.clone()),
+                    None => DataType::Nil,
+                })
+                .collect();
+            Ok(DataType::Array(values))
+        }
+        HashCommand::Del { key, fields } => {
+            let Some(hash) = get_hash(map, &key, now)? else {
+                return Ok(DataType::Integer(0));
+            };
+            let removed = fields.iter().filter(|field| hash.remove(field)).count();
+            if removed > 0 {
+                map.notify(EventClass::Hash, "hdel", &key);
+            }
+            remove_if_empty(map, &key, now);
+            Ok(DataType::Integer(removed as i64))
+        }
+        HashCommand::GetAll { key } => {
+            let Some(hash) = get_hash(map, &key, now)? else {
+                return Ok(DataType::Map(vec![]));
+            };
+            let pairs = hash
+                .fields
+                .iter()
+                .map(|(field, value)| (DataType::BulkString(field.clone()), DataType::BulkString(value.clone())))
+                .collect();
+            Ok(DataType::Map(pairs))
+        }
+        HashCommand::Keys { key } => {
+            let hash = get_hash(map, &key, now)?.map(|hash| &*hash);
+            Ok(bulk_strings(hash.into_iter().flat_map(|hash| hash.fields.keys())))
+        }
+        HashCommand::Vals { key } => {
+            let hash = get_hash(map, &key, now)?.map(|hash| &*hash);
+            Ok(bulk_strings(hash.into_iter().flat_map(|hash| hash.fields.values())))
+        }
+        HashCommand::Len { key } => {
+            let len = get_hash(map, &key, now)?.map_or(0, |hash| hash.len());
+            Ok(DataType::Integer(len as i64))
+        }
+        HashCommand::Exists { key, field } => {
+            let exists = get_hash(map, &key, now)?.is_some_and(|hash| hash.fields.contains_key(&field));
+            Ok(DataType::Integer(exists as i64))
+        }
+        HashCommand::IncrBy { key, field, by } => increment(map, key, field, by, now),
+        HashCommand::Scan { key, options, no_values } => match get_hash(map, &key, now)? {
+            Some(hash) => Ok(scan(hash, options, no_values)),
+            None => Ok(scan_reply(0, vec![])),
+        },
+        HashCommand::RandField { key, count, with_values } => match (get_hash(map, &key, now)?, count) {
+            (Some(hash), Some(count)) => Ok(random_fields(hash, count, with_values)),
+            (Some(hash), None) => {
+                let field = hash.fields.keys().nth(random_index(hash.len()));
+                Ok(field.map_or(DataType::Nil, |field| DataType::BulkString(field.clone())))
+            }
+            (None, Some(_)) => Ok(DataType::Array(vec![])),
+            (None, None) => Ok(DataType::Nil),
+        },
+        HashCommand::Expire { key, expiration, conditions, fields } => {
+            let Some(hash) = get_hash(map, &key, now)? else {
+                return Ok(DataType::Array(fields.iter().map(|_| DataType::Integer(-2)).collect()));
+            };
+            let replies = fields
+                .iter()
+                .map(|field| {
+                    if !hash.fields.contains_key(field) {
+                        return DataType::Integer(-2);
+                    }
+                    if !expire_conditions_met(&conditions, hash.field_ttls.get(field).copied(), expiration) {
+                        return DataType::Integer(0);
+                    }
+                    if expiration <= now {
+                        hash.remove(field);
+                        return DataType::Integer(2);
+                    }
+                    hash.field_ttls.insert(field.clone(), expiration);
+                    DataType::Integer(1)
+                })
+                .collect::<Vec<_>>();
+            if replies.contains(&DataType::Integer(1)) {
+                map.notify(EventClass::Hash, "hexpire", &key);
+            }
+            if replies.contains(&DataType::Integer(2)) {
+                map.notify(EventClass::Hash, "hdel", &key);
+            }
+            remove_if_empty(map, &key, now);
+            Ok(DataType::Array(replies))
+        }
+        HashCommand::Ttl { key, kind, fields } => {
+            let hash = get_hash(map, &key, now)?;
+            let replies = fields
+                .iter()
+                .map(|field| match hash.as_ref().filter(|hash| hash.fields.contains_key(field)) {
+                    None => DataType::Integer(-2),
+                    Some(hash) => match hash.field_ttls.get(field) {
+                        None => DataType::Integer(-1),
+                        Some(ttl) => DataType::Integer(ttl_reply(*ttl, kind, now)),
+                    },
+                })
+                .collect();
+            Ok(DataType::Array(replies))
+        }
+        HashCommand::Persist { key, fields } => {
+            let mut hash = get_hash(map, &key, now)?;
+            let replies = fields
+                .iter()
+                .map(|field| match hash.as_mut().filter(|hash| hash.fields.contains_key(field)) {
+                    None => DataType::Integer(-2),
+                    Some(hash) => match hash.field_ttls.remove(field) {
+                        None => DataType::Integer(-1),
+                        Some(_) => DataType::Integer(1),
+                    },
+                })
+                .collect::<Vec<_>>();
+            if replies.contains(&DataType::Integer(1)) {
+                map.notify(EventClass::Hash, "hpersist", &key);
+            }
+            Ok(DataType::Array(replies))
+        }
+    }
+}
+
+pub(crate) fn process_hash(map: &mut Keyspace, cmd: HashCommand, now: u128) -> Result<DataType, String> {
+    Ok(run_hash_command(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::ExpireCondition;
+
+    fn hset(map: &mut Keyspace, key: &'static str, fields: &[(&'static str, &'static str)]) -> Result<DataType, String> {
+        let fields = fields.iter().map(|(field, value)| (Bytes::from(*field), Bytes::from(*value))).collect();
+        process_hash(map, HashCommand::Set { key: key.into(), fields, only_if_new: false }, 0)
+    }
+
+    #[test]
+    pub fn test_field_expiry() {
+        let mut map = Keyspace::new();
+        hset(&mut map, "h", &[("a", "1"), ("b", "2")]).unwrap();
+
+        let cmd = HashCommand::Expire {
+            key: "h".into(),
+            expiration: 100,
+            conditions: vec![ExpireCondition::NoExpiry],
+            fields: vec!["a".into(), "c".into()],
+        };
+        assert_eq!(process_hash(&mut map, cmd, 0), Ok(DataType::Array(vec![DataType::Integer(1), DataType::Integer(-2)])));
+
+        let len = |map: &mut Keyspace, now| process_hash(map, HashCommand::Len { key: "h".into() }, now);
+        assert_eq!(len(&mut map, 100), Ok(DataType::Integer(2)));
+        assert_eq!(len(&mut map, 101), Ok(DataType::Integer(1)));
+    }
+
+    #[test]
+    pub fn test_expiring_last_field_deletes_key() {
+        let mut map = Keyspace::new();
+        hset(&mut map, "h", &[("a", "1")]).unwrap();
+
+        let cmd = HashCommand::Expire { key: "h".into(), expiration: 100, conditions: vec![], fields: vec!["a".into()] };
+        process_hash(&mut map, cmd, 0).unwrap();
+        let cmd = HashCommand::Get { key: "h".into(), field: "a".into() };
+        assert_eq!(process_hash(&mut map, cmd, 200), Ok(DataType::Nil));
+        assert!(map.get(b"h", 200).is_none());
+    }
+
+    #[test]
+    pub fn test_scan_visits_every_field() {
+        let mut map = Keyspace::new();
+        let fields = (0..50).map(|idx| (Bytes::from(format!("f{}", idx)), Bytes::from("v"))).collect();
+        process_hash(&mut map, HashCommand::Set { key: "h".into(), fields, only_if_new: false }, 0).unwrap();
+
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let options = ScanOptions { cursor, pattern: None, count: 7 };
+            let reply = process_hash(&mut map, HashCommand::Scan { key: "h".into(), options, no_values: true }, 0).unwrap();
+            let DataType::Array(mut reply) = reply else {
+                panic!("Expected an array reply");
+            };
+            let (Some(DataType::Array(items)), Some(DataType::BulkString(next))) = (reply.pop(), reply.pop()) else {
+                panic!("Expected a cursor and a list of fields");
+            };
+            seen.extend(items);
+            cursor = std::str::from_utf8(&next).unwrap().parse().unwrap();
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 50);
+    }
+
+    #[test]
+    pub fn test_incr_on_non_integer() {
+        let mut map = Keyspace::new();
+        hset(&mut map, "h", &[("a", "x")]).unwrap();
+
+        let cmd = HashCommand::IncrBy { key: "h".into(), field: "a".into(), by: IncrBy::Integer(1) };
+        assert_eq!(process_hash(&mut map, cmd, 0), Ok(DataType::Error("ERR hash value is not an integer".into())));
+    }
+}
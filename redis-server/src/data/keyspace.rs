@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use crate::datatypes::{StorageRecord, StorageValue};
+
+use super::notify::{EventClass, Notifier};
+use super::shared::random_index;
+
+/// How often the engines run `active_expire_cycle` on each of their keyspaces, in milliseconds.
+/// It's `1000 / hz`, redis' default of `hz 10` until the config says otherwise.
+static ACTIVE_EXPIRE_INTERVAL_MS: AtomicU64 = AtomicU64::new(100);
+
+pub(crate) fn active_expire_interval() -> Duration {
+    Duration::from_millis(ACTIVE_EXPIRE_INTERVAL_MS.load(Ordering::Relaxed))
+}
+
+pub(crate) fn set_hz(hz: u64) {
+    ACTIVE_EXPIRE_INTERVAL_MS.store(1000 / hz.max(1), Ordering::Relaxed);
+}
+
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(2);
+
+/// A map of keys to records that hides expired keys.
+///
+/// Keys with a TTL are also tracked in `expiring` so the active expiry cycle can sample them at
+/// random without walking the whole map. Everything that changes a TTL has to go through
+/// `insert`, `remove` or `set_ttl` to keep that index in sync.
+///
+/// Keys a client WATCHes get a version in `watched`. Those same three methods bump it, a value
+/// changed in place through `get_mut` gets bumped by the keyspace event the write reports through
+/// `notify`, or by calling `touch` for the few writes that have no event.
+///
+/// Expired keys are reported here.
+#[derive(Debug, Default)]
+pub(crate) struct Keyspace {
+    entries: HashMap<Bytes, StorageRecord>,
+    expiring: Vec<Bytes>,
+    expiring_index: HashMap<Bytes, usize>,
+    watched: HashMap<Bytes, WatchedVersion>,
+    notifier: Option<Arc<Notifier>>,
+}
+
+#[derive(Debug)]
+struct WatchedVersion {
+    version: u64,
+    watchers: usize,
+}
+
+/// Gives multi-key commands the keyspace each key lives in, whether that's the one map of the
+/// single threaded server or whichever shard the key hashes to.
+pub(crate) trait Keyspaces {
+    fn keyspace_for(&mut self, key: &[u8]) -> &mut Keyspace;
+}
+
+impl Keyspaces for Keyspace {
+    fn keyspace_for(&mut self, _: &[u8]) -> &mut Keyspace {
+        self
+    }
+}
+
+fn is_expired(record: &StorageRecord, now: u128) -> bool {
+    record.ttl.is_some_and(|ttl| now > ttl)
+}
+
+impl Keyspace {
+    pub fn new() -> Keyspace {
+        Keyspace::default()
+    }
+
+    pub fn with_notifier(notifier: Arc<Notifier>) -> Keyspace {
+        Keyspace {
+            notifier: Some(notifier),
+            ..Default::default()
+        }
+    }
+
+    /// Marks the write to `key` and publishes its keyspace event, if anyone asked for that kind of
+    /// event. Called once the write happened, so a command that ends up changing nothing leaves
+    /// the clients watching the key alone.
+    pub fn notify(&mut self, class: EventClass, event: &'static str, key: &[u8]) {
+        self.touch(key);
+        self.publish(class, event, key);
+    }
+
+    fn publish(&self, class: EventClass, event: &'static str, key: &[u8]) {
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(class, event, key);
+        }
+    }
+
+    /// Returns the record for `key`, deleting it first if its TTL has passed.
+    pub fn get(&mut self, key: &[u8], now: u128) -> Option<&StorageRecord> {
+        self.expire_if_needed(key, now);
+        self.entries.get(key)
+    }
+
+    /// Returns the value for `key` to modify in place. The TTL isn't exposed here, use `set_ttl`.
+    pub fn get_mut(&mut self, key: &[u8], now: u128) -> Option<&mut StorageValue> {
+        self.expire_if_needed(key, now);
+        self.entries.get_mut(key).map(|record| &mut record.value)
+    }
+
+    pub fn insert(&mut self, key: Bytes, record: StorageRecord) -> Option<StorageRecord> {
+        self.track_ttl(&key, record.ttl);
+        self.touch(&key);
+        self.entries.insert(key, record)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<StorageRecord> {
+        self.track_ttl(key, None);
+        self.touch(key);
+        self.entries.remove(key)
+    }
+
+    /// Updates the TTL of an existing key, returning false if the key doesn't exist.
+    pub fn set_ttl(&mut self, key: &[u8], ttl: Option<u128>) -> bool {
+        let Some(record) = self.entries.get_mut(key) else {
+            return false;
+        };
+        record.ttl = ttl;
+        self.track_ttl(key, ttl);
+        self.touch(key);
+        true
+    }
+
+    /// Starts watching `key` and returns its version. Every call has to be paired with `unwatch`.
+    pub fn watch(&mut self, key: &Bytes, now: u128) -> u64 {
+        // An expired key is gone already, it expiring later isn't a change
+        self.expire_if_needed(key, now);
+        let watched = self.watched.entry(key.clone()).or_insert(WatchedVersion { version: 0, watchers: 0 });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        let Some(watched) = self.watched.get_mut(key) else {
+            return;
+        };
+        watched.watchers -= 1;
+        if watched.watchers == 0 {
+            self.watched.remove(key);
+        }
+    }
+
+    /// The version of a watched key, which changes with every write to it. Expires the key
+    /// first, so a key whose TTL ran out since it was watched counts as changed.
+    pub fn version(&mut self, key: &[u8], now: u128) -> Option<u64> {
+        self.expire_if_needed(key, now);
+        self.watched.get(key).map(|watched| watched.version)
+    }
+
+    /// Marks a write to `key`, which fails the transactions of the clients watching it.
+    pub fn touch(&mut self, key: &[u8]) {
+        if self.watched.is_empty() {
+            return;
+        }
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &StorageRecord)> {
+        self.entries.iter()
+    }
+
+    fn expire_if_needed(&mut self, key: &[u8], now: u128) {
+        if self.entries.get(key).is_some_and(|record| is_expired(record, now)) {
+            self.expire(key);
+        }
+    }
+
+    fn expire(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.publish(EventClass::Expired, "expired", key);
+    }
+
+    fn track_ttl(&mut self, key: &[u8], ttl: Option<u128>) {
+        match (ttl, self.expiring_index.contains_key(key)) {
+            (Some(_), false) => {
+                let key = Bytes::copy_from_slice(key);
+                self.expiring_index.insert(key.clone(), self.expiring.len());
+                self.expiring.push(key);
+            }
+            (None, true) => {
+                let idx = self.expiring_index.remove(key).expect("Expected the key to be in the index");
+                self.expiring.swap_remove(idx);
+                if let Some(moved) = self.expiring.get(idx) {
+                    self.expiring_index.insert(moved.clone(), idx);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Deletes expired keys that haven't been accessed since they expired, the same way redis'
+    /// `activeExpireCycle` does. Samples 20 keys with a TTL at a time and keeps going while more
+    /// than a quarter of each sample turns out to be expired, up to a small time budget.
+    /// Returns the number of keys that were deleted.
+    pub fn active_expire_cycle(&mut self, now: u128) -> usize {
+        let started = Instant::now();
+        let mut deleted = 0;
+
+        loop {
+            let sample_size = self.expiring.len().min(ACTIVE_EXPIRE_KEYS_PER_LOOP);
+            let mut expired_in_sample = 0;
+            for _ in 0..sample_size {
+                let key = self.expiring[random_index(self.expiring.len())].clone();
+                if self.entries.get(&key).is_some_and(|record| is_expired(record, now)) {
+                    self.expire(&key);
+                    expired_in_sample += 1;
+                }
+            }
+            deleted += expired_in_sample;
+
+            if expired_in_sample * 4 <= ACTIVE_EXPIRE_KEYS_PER_LOOP || started.elapsed() > ACTIVE_EXPIRE_TIME_LIMIT {
+                return deleted;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(value: &'static str, ttl: Option<u128>) -> StorageRecord {
+        StorageRecord {
+            value: StorageValue::String(value.into()),
+            ttl,
+        }
+    }
+
+    #[test]
+    pub fn test_lazy_expiry() {
+        let mut keyspace = Keyspace::new();
+        keyspace.insert("a".into(), record("1", Some(100)));
+
+        assert!(keyspace.get(b"a", 100).is_some());
+        assert!(keyspace.get(b"a", 101).is_none());
+        assert!(keyspace.entries.is_empty());
+        assert!(keyspace.expiring.is_empty());
+    }
+
+    #[test]
+    pub fn test_overwrite_clears_ttl() {
+        let mut keyspace = Keyspace::new();
+        keyspace.insert("a".into(), record("1", Some(100)));
+        keyspace.insert("a".into(), record("2", None));
+
+        assert!(keyspace.get(b"a", 200).is_some());
+        assert!(keyspace.expiring.is_empty());
+    }
+
+    #[test]
+    pub fn test_watched_versions() {
+        let mut keyspace = Keyspace::new();
+        let key = Bytes::from("a");
+        assert_eq!(keyspace.watch(&key, 0), 0);
+        keyspace.insert(key.clone(), record("1", Some(100)));
+        assert_eq!(keyspace.version(&key, 0), Some(1));
+
+        // Running out of time is a change too
+        assert_eq!(keyspace.version(&key, 101), Some(2));
+        keyspace.unwatch(&key);
+        assert!(keyspace.watched.is_empty());
+    }
+
+    #[test]
+    pub fn test_active_expire_cycle() {
+        let mut keyspace = Keyspace::new();
+        for idx in 0..1000 {
+            keyspace.insert(format!("expired{}", idx).into(), record("1", Some(10)));
+        }
+        for idx in 0..10 {
+            keyspace.insert(format!("live{}", idx).into(), record("1", Some(1000)));
+            keyspace.insert(format!("persistent{}", idx).into(), record("1", None));
+        }
+
+        while keyspace.active_expire_cycle(100) > 0 {}
+
+        // Sampling stops once most of a sample is alive, so a handful of expired keys can survive
+        assert!(keyspace.entries.len() < 20 + 20);
+        assert!((0..10).all(|idx| keyspace.get(format!("live{}", idx).as_bytes(), 100).is_some()));
+        assert!((0..10).all(|idx| keyspace.get(format!("persistent{}", idx).as_bytes(), 100).is_some()));
+        assert_eq!(keyspace.expiring.len(), keyspace.expiring_index.len());
+    }
+}
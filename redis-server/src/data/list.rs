@@ -0,0 +1,315 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+use crate::{commands::{LMoveCommand, ListCommand, ListEnd}, datatypes::{DataType, StorageRecord, StorageValue}};
+
+use super::{keyspace::{Keyspace, Keyspaces}, notify::EventClass, shared::{remove_if_empty, WRONGTYPE}};
+
+/// The error side of these results is the reply to send back as is, which is always WRONGTYPE.
+type ListResult = Result<DataType, DataType>;
+
+/// Returns the list stored at `key`, None if there's nothing there.
+fn get_list<'a>(map: &'a mut Keyspace, key: &[u8], now: u128) -> Result<Option<&'a mut VecDeque<Bytes>>, DataType> {
+    match map.get_mut(key, now) {
+        None => Ok(None),
+        Some(StorageValue::List(list)) => Ok(Some(list)),
+        Some(_) => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+fn push_event(end: ListEnd) -> &'static str {
+    match end {
+        ListEnd::Left => "lpush",
+        ListEnd::Right => "rpush",
+    }
+}
+
+fn pop_event(end: ListEnd) -> &'static str {
+    match end {
+        ListEnd::Left => "lpop",
+        ListEnd::Right => "rpop",
+    }
+}
+
+fn pop_end(list: &mut VecDeque<Bytes>, end: ListEnd) -> Option<Bytes> {
+    match end {
+        ListEnd::Left => list.pop_front(),
+        ListEnd::Right => list.pop_back(),
+    }
+}
+
+/// Resolves negative indexes from the end of the list, None if the index is out of range.
+fn normalize_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+/// Clamps a start and stop index pair to the list the way LRANGE and LTRIM do, the returned
+/// range is inclusive and None if it doesn't cover any elements.
+pub(crate) fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+fn push(map: &mut Keyspace, key: Bytes, end: ListEnd, values: Vec<Bytes>, only_if_exists: bool, now: u128) -> ListResult {
+    let list = match get_list(map, &key, now)? {
+        Some(list) => list,
+        None if only_if_exists => return Ok(DataType::Integer(0)),
+        None => {
+            map.insert(key.clone(), StorageRecord {
+                value: StorageValue::List(VecDeque::new()),
+                ttl: None,
+            });
+            get_list(map, &key, now)?.expect("Expected the list that was just inserted")
+        }
+    };
+
+    for value in values {
+        match end {
+            ListEnd::Left => list.push_front(value),
+            ListEnd::Right => list.push_back(value),
+        }
+    }
+    let len = list.len();
+    map.notify(EventClass::List, push_event(end), &key);
+    Ok(DataType::Integer(len as i64))
+}
+
+fn pop(map: &mut Keyspace, key: Bytes, end: ListEnd, count: Option<usize>, now: u128) -> ListResult {
+    let Some(list) = get_list(map, &key, now)? else {
+        // With a count the reply is an array, so a missing key is a null array
+        return Ok(match count {
+            Some(_) => DataType::NullArray,
+            None => DataType::Nil,
+        });
+    };
+
+    let popped = (0..count.unwrap_or(1))
+        .map_while(|_| pop_end(list, end))
+        .map(DataType::BulkString)
+        .collect::<Vec<_>>();
+    if !popped.is_empty() {
+        map.notify(EventClass::List, pop_event(end), &key);
+    }
+    remove_if_empty(map, &key, now);
+
+    match count {
+        Some(_) => Ok(DataType::Array(popped)),
+        None => Ok(popped.into_iter().next().unwrap_or(DataType::Nil)),
+    }
+}
+
+fn remove(map: &mut Keyspace, key: Bytes, count: i64, value: Bytes, now: u128) -> ListResult {
+    let Some(list) = get_list(map, &key, now)? else {
+        return Ok(DataType::Integer(0));
+    };
+
+    // A positive count removes from the head, a negative one from the tail and zero removes everything
+    let limit = match count {
+        0 => usize::MAX,
+        count => count.unsigned_abs() as usize,
+    };
+    let matches = list.iter().enumerate().filter(|(_, x)| **x == value).map(|(idx, _)| idx);
+    let mut indexes = match count < 0 {
+        true => matches.rev().take(limit).collect::<Vec<_>>(),
+        false => matches.take(limit).collect::<Vec<_>>(),
+    };
+    indexes.sort_unstable();
+
+    let mut idx = 0;
+    list.retain(|_| {
+        idx += 1;
+        indexes.binary_search(&(idx - 1)).is_err()
+    });
+    if !indexes.is_empty() {
+        map.notify(EventClass::List, "lrem", &key);
+    }
+    remove_if_empty(map, &key, now);
+    Ok(DataType::Integer(indexes.len() as i64))
+}
+
+fn trim(map: &mut Keyspace, key: Bytes, start: i64, stop: i64, now: u128) -> ListResult {
+    let Some(list) = get_list(map, &key, now)? else {
+        return Ok(DataType::SimpleString("OK".into()));
+    };
+
+    match normalize_range(start, stop, list.len()) {
+        Some((start, stop)) => {
+            list.truncate(stop + 1);
+            list.drain(..start);
+        }
+        None => list.clear(),
+    }
+    map.notify(EventClass::List, "ltrim", &key);
+    remove_if_empty(map, &key, now);
+    Ok(DataType::SimpleString("OK".into()))
+}
+
+fn run_list_command(map: &mut Keyspace, cmd: ListCommand, now: u128) -> ListResult {
+    match cmd {
+        ListCommand::Push { key, end, values, only_if_exists } => push(map, key, end, values, only_if_exists, now),
+        ListCommand::Pop { key, end, count } => pop(map, key, end, count, now),
+        ListCommand::Rem { key, count, value } => remove(map, key, count, value, now),
+        ListCommand::Trim { key, start, stop } => trim(map, key, start, stop, now),
+        ListCommand::Range { key, start, stop } => {
+            let Some(list) = get_list(map, &key, now)? else {
+                return Ok(DataType::Array(vec![]));
+            };
+            let items = match normalize_range(start, stop, list.len()) {
+                Some((start, stop)) => list.range(start..=stop).cloned().map(DataType::BulkString).collect(),
+                None => vec![],
+            };
+            Ok(DataType::Array(items))
+        }
+        ListCommand::Len { key } => {
+            let len = get_list(map, &key, now)?.map_or(0, |list| list.len());
+            Ok(DataType::Integer(len as i64))
+        }
+        ListCommand::Index { key, index } => {
+            let Some(list) = get_list(map, &key, now)? else {
+                return Ok(DataType::Nil);
+            };
+            match normalize_index(index, list.len()) {
+                Some(index) => Ok(DataType::BulkString(list[index].clone())),
+                None => Ok(DataType::Nil),
+            }
+        }
+        ListCommand::Set { key, index, value } => {
+            let Some(list) = get_list(map, &key, now)? else {
+                return Ok(DataType::Error("ERR no such key".into()));
+            };
+            let Some(index) = normalize_index(index, list.len()) else {
+                return Ok(DataType::Error("ERR index out of range".into()));
+            };
+            list[index] = value;
+            map.notify(EventClass::List, "lset", &key);
+            Ok(DataType::SimpleString("OK".into()))
+        }
+        ListCommand::Insert { key, before, pivot, value } => {
+            let Some(list) = get_list(map, &key, now)? else {
+                return Ok(DataType::Integer(0));
+            };
+            let Some(position) = list.iter().position(|x| *x == pivot) else {
+                return Ok(DataType::Integer(-1));
+            };
+            list.insert(if before { position } else { position + 1 }, value);
+            let len = list.len();
+            map.notify(EventClass::List, "linsert", &key);
+            Ok(DataType::Integer(len as i64))
+        }
+    }
+}
+
+pub(crate) fn process_list(map: &mut Keyspace, cmd: ListCommand, now: u128) -> Result<DataType, String> {
+    Ok(run_list_command(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+fn lmove(map: &mut impl Keyspaces, cmd: LMoveCommand, now: u128) -> ListResult {
+    if get_list(map.keyspace_for(&cmd.source), &cmd.source, now)?.is_none() {
+        return Ok(DataType::Nil);
+    }
+    // Check the destination before popping so a WRONGTYPE destination doesn't lose the element
+    get_list(map.keyspace_for(&cmd.destination), &cmd.destination, now)?;
+
+    let source = get_list(map.keyspace_for(&cmd.source), &cmd.source, now)?.expect("Expected the source list to exist");
+    let value = pop_end(source, cmd.from).expect("Expected lists to never be empty");
+    push(map.keyspace_for(&cmd.destination), cmd.destination, cmd.to, vec![value.clone()], false, now)?;
+    map.keyspace_for(&cmd.source).notify(EventClass::List, pop_event(cmd.from), &cmd.source);
+    // Only after the push, so rotating a single element list onto itself keeps the key and its TTL
+    remove_if_empty(map.keyspace_for(&cmd.source), &cmd.source, now);
+
+    Ok(DataType::BulkString(value))
+}
+
+pub(crate) fn process_lmove(map: &mut impl Keyspaces, cmd: LMoveCommand, now: u128) -> Result<DataType, String> {
+    Ok(lmove(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_right(map: &mut Keyspace, key: &'static str, values: &[&'static str]) {
+        let cmd = ListCommand::Push {
+            key: key.into(),
+            end: ListEnd::Right,
+            values: values.iter().map(|x| Bytes::from(*x)).collect(),
+            only_if_exists: false,
+        };
+        process_list(map, cmd, 0).expect("Expected the push to succeed");
+    }
+
+    fn range(map: &mut Keyspace, key: &'static str) -> DataType {
+        process_list(map, ListCommand::Range { key: key.into(), start: 0, stop: -1 }, 0).unwrap()
+    }
+
+    fn array(values: &[&'static str]) -> DataType {
+        DataType::Array(values.iter().map(|x| DataType::BulkString((*x).into())).collect())
+    }
+
+    #[test]
+    pub fn test_normalize_range() {
+        assert_eq!(normalize_range(0, -1, 3), Some((0, 2)));
+        assert_eq!(normalize_range(-100, 100, 3), Some((0, 2)));
+        assert_eq!(normalize_range(1, 0, 3), None);
+        assert_eq!(normalize_range(5, 10, 3), None);
+        assert_eq!(normalize_range(0, -1, 0), None);
+    }
+
+    #[test]
+    pub fn test_rem_from_either_end() {
+        let mut map = Keyspace::new();
+        push_right(&mut map, "a", &["x", "y", "x", "y", "x"]);
+
+        let cmd = ListCommand::Rem { key: "a".into(), count: -2, value: "x".into() };
+        assert_eq!(process_list(&mut map, cmd, 0), Ok(DataType::Integer(2)));
+        assert_eq!(range(&mut map, "a"), array(&["x", "y", "y"]));
+
+        let cmd = ListCommand::Rem { key: "a".into(), count: 0, value: "y".into() };
+        assert_eq!(process_list(&mut map, cmd, 0), Ok(DataType::Integer(2)));
+        assert_eq!(range(&mut map, "a"), array(&["x"]));
+    }
+
+    #[test]
+    pub fn test_empty_list_is_deleted() {
+        let mut map = Keyspace::new();
+        push_right(&mut map, "a", &["x", "y"]);
+
+        let cmd = ListCommand::Pop { key: "a".into(), end: ListEnd::Left, count: Some(5) };
+        assert_eq!(process_list(&mut map, cmd, 0), Ok(array(&["x", "y"])));
+        assert!(map.get(b"a", 0).is_none());
+    }
+
+    #[test]
+    pub fn test_pop_missing_key() {
+        let mut map = Keyspace::new();
+        let pop = |count| ListCommand::Pop { key: "a".into(), end: ListEnd::Right, count };
+        assert_eq!(process_list(&mut map, pop(None), 0), Ok(DataType::Nil));
+        assert_eq!(process_list(&mut map, pop(Some(2)), 0), Ok(DataType::NullArray));
+    }
+
+    #[test]
+    pub fn test_wrong_type() {
+        let mut map = Keyspace::new();
+        map.insert("a".into(), StorageRecord { value: StorageValue::String("1".into()), ttl: None });
+
+        let cmd = ListCommand::Len { key: "a".into() };
+        assert_eq!(process_list(&mut map, cmd, 0), Ok(DataType::Error(WRONGTYPE.into())));
+    }
+
+    #[test]
+    pub fn test_lmove_rotates_single_element() {
+        let mut map = Keyspace::new();
+        push_right(&mut map, "a", &["x"]);
+
+        let cmd = LMoveCommand { source: "a".into(), destination: "a".into(), from: ListEnd::Left, to: ListEnd::Right };
+        assert_eq!(process_lmove(&mut map, cmd, 0), Ok(DataType::BulkString("x".into())));
+        assert_eq!(range(&mut map, "a"), array(&["x"]));
+    }
+}
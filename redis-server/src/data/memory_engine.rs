@@ -1,122 +1,210 @@
-use crate::{commands::{SetCommand, SetExistingOptions}, datatypes::{DataType,StorageRecord, StorageValue}};
-use std::{cell::RefCell, collections::{hash_map::DefaultHasher, HashMap}, hash::{Hash, Hasher}, sync::Mutex};
+use bytes::Bytes;
+use crate::{commands::Command, datatypes::{DataType, StorageRecord}};
+use std::{collections::BTreeMap, sync::{Arc, Mutex, MutexGuard, Weak}, thread};
 
-use super::{shared::hashy, typesd::StorageEngine};
+use super::{blocking::{WaitRegistry, Wakes}, dispatch::execute, keyspace::{active_expire_interval, Keyspace, Keyspaces}, notify::Notifier, shared::{current_unix_timestamp_millis, dump_reply, key_slot}, transaction::{execute_transaction, unwatch_keys, watch_keys, WatchedKey}, typesd::{EngineFuture, StorageEngine, Unkeyed}};
+
+pub(crate) const SHARD_COUNT: usize = 8;
+
+type Shards = [Mutex<Keyspace>; SHARD_COUNT];
+
+pub(crate) fn shard_index(key: &[u8]) -> usize {
+    key_slot(key, SHARD_COUNT)
+}
+
+/// The shard locks held by a multi-key command.
+struct LockedShards<'a> {
+    shards: Vec<(usize, MutexGuard<'a, Keyspace>)>,
+}
+
+impl Keyspaces for LockedShards<'_> {
+    fn keyspace_for(&mut self, key: &[u8]) -> &mut Keyspace {
+        let index = shard_index(key);
+        let (_, shard) = self
+            .shards
+            .iter_mut()
+            .find(|(idx, _)| *idx == index)
+            .expect("Expected the shard for the key to be locked");
+        shard
+    }
+}
 
 pub struct InMemoryEngine {
-    keymap: [Mutex<HashMap<String, StorageRecord>>;8],
+    keymap: Arc<Shards>,
+    waiters: WaitRegistry,
 }
 
 // thread_local! {
 //     static HASHER: RefCell<DefaultHasher> = RefCell::from(DefaultHasher::new());
 // }
 
+impl Default for InMemoryEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InMemoryEngine {
     pub fn new() -> InMemoryEngine {
+        Self::with_notifier(Arc::default())
+    }
+
+    /// Keyspace events from every shard go out through `notifier`.
+    pub fn with_notifier(notifier: Arc<Notifier>) -> InMemoryEngine {
+        let keymap = Arc::new(std::array::from_fn(|_| Mutex::from(Keyspace::with_notifier(notifier.clone()))));
+
+        let weak_keymap = Arc::downgrade(&keymap);
+        thread::spawn(move || Self::run_active_expiry(weak_keymap));
+
         InMemoryEngine {
-            keymap: [
-                Mutex::from(HashMap::new()),
-                Mutex::from(HashMap::new()),
-                Mutex::from(HashMap::new()),
-                Mutex::from(HashMap::new()),
-                Mutex::from(HashMap::new()),
-                Mutex::from(HashMap::new()),
-                Mutex::from(HashMap::new()),
-                Mutex::from(HashMap::new()),
-            ],
+            keymap,
+            waiters: WaitRegistry::default(),
         }
     }
 
-    fn get_map_for_key(&self, str: &str) -> &Mutex<HashMap<String, StorageRecord>> {
-        let hash = hashy(str);
-        let index = (hash % 8) as usize;
-        // println!("hash: {str}: {index}({hash}) (");
-        &self.keymap[index]
-    }
-
-    pub fn process_set_int(&self, cmd: SetCommand) -> Result<DataType, String> {
-        let mut map = self.get_map_for_key(&cmd.key).lock().map_err(|err| err.to_string())?;
-        let previous_obj: Option<&StorageRecord> = map.get(&cmd.key);
-        let previous_value = match (cmd.get_previous_value, previous_obj) {
-            (true, Some(stored_value)) => {
-                let StorageValue::String(x) = &stored_value.value else {
-                    // TODO: Update the error message to match redis message
-                    return Ok(DataType::Error(format!(
-                        "Error: Expected key {} to be of type String",
-                        cmd.key
-                    )));
-                };
-                Some(x.to_string())
+    /// Background expiry, runs until the engine is dropped. Each shard is locked on its own so a
+    /// cycle never blocks more than one eighth of the keyspace at a time.
+    fn run_active_expiry(keymap: Weak<Shards>) {
+        loop {
+            thread::sleep(active_expire_interval());
+            let Some(keymap) = keymap.upgrade() else {
+                return;
+            };
+            for shard in keymap.iter() {
+                if let Ok(mut map) = shard.lock() {
+                    map.active_expire_cycle(current_unix_timestamp_millis());
+                }
             }
-            _ => None,
-        };
-
-        let previous_ttl = match (cmd.keep_previous_ttl, previous_obj) {
-            (true, Some(stored_value)) => stored_value.ttl,
-            _ => None,
-        };
+        }
+    }
 
-        let ttl = previous_ttl.or(cmd.expiration);
-        let storage_record = StorageRecord {
-            ttl,
-            value: StorageValue::String(cmd.value),
-        };
+    /// Locks every shard the keys live in. Locks are always taken in shard order so two
+    /// multi-key commands touching the same shards can't deadlock on each other.
+    fn lock_shards_for_keys<'a>(&self, keys: impl IntoIterator<Item = &'a Bytes>) -> Result<LockedShards<'_>, String> {
+        let mut indexes = keys.into_iter().map(|key| shard_index(key)).collect::<Vec<usize>>();
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        let shards = indexes
+            .into_iter()
+            .map(|idx| Ok((idx, self.keymap[idx].lock().map_err(|err| err.to_string())?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(LockedShards { shards })
+    }
 
-        let should_insert = match (cmd.set_existing, previous_obj) {
-            (None, _) => true,
-            (Some(SetExistingOptions::OnlySetIfExists), Some(_)) => true,
-            (Some(SetExistingOptions::OnlySetIfNotExists), None) => true,
-            _ => false,
-        };
+    /// Locks every shard, in order, for commands that can touch any key.
+    fn lock_all_shards(&self) -> Result<LockedShards<'_>, String> {
+        let shards = self
+            .keymap
+            .iter()
+            .enumerate()
+            .map(|(idx, shard)| Ok((idx, shard.lock().map_err(|err| err.to_string())?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(LockedShards { shards })
+    }
 
-        if should_insert {
-            map.insert(cmd.key, storage_record);
+    /// Locks the shards the command's keys live in and runs it, every shard involved stays
+    /// locked for the whole command so multi-key commands like SINTERSTORE are atomic.
+    fn process_int(&self, command: Command) -> Result<DataType, String> {
+        if let Command::Dump = command {
+            return self.process_dump_int();
         }
 
-        return match previous_value {
-            Some(value) => Ok(DataType::BulkString(value)),
-            None => Ok(DataType::SimpleString("OK".into())),
+        let now = current_unix_timestamp_millis();
+        // Most commands have a single key, which doesn't need the bookkeeping of several shards
+        let single_shard = match command.keys().as_slice() {
+            [key] => Some(shard_index(key)),
+            _ => None,
         };
+        match single_shard {
+            Some(idx) => {
+                let mut map = self.keymap[idx].lock().map_err(|err| err.to_string())?;
+                execute(&mut *map, command, now)
+            }
+            None => {
+                let mut shards = self.lock_shards_for_keys(command.keys())?;
+                execute(&mut shards, command, now)
+            }
+        }
     }
 
-    pub fn process_get_int(&self, key: String) -> Result<DataType, String> {
-        let map = self.get_map_for_key(&key).lock().map_err(|err| err.to_string())?;
-        let val = map.get(&key);
-        match val {
-            Some(StorageRecord{
-                value: StorageValue::String(x),
-                ..
-            }) => Ok(DataType::BulkString(x.into())),
-            None => Ok(DataType::Nil),
-        }
+    pub fn process_watch_int(&self, keys: Vec<Bytes>) -> Result<Vec<WatchedKey>, String> {
+        let now = current_unix_timestamp_millis();
+        let mut shards = self.lock_shards_for_keys(&keys)?;
+        Ok(watch_keys(&mut shards, keys, now))
     }
 
-    pub fn process_dump_int(&self) -> Result<DataType, String> {        
-        let mut overall_map = HashMap::<String, StorageRecord>::new();
-        self.keymap.iter().for_each(|x| {
-            let map = x.lock().unwrap();
-            overall_map.extend(map.iter().map(|(k, v)| (k.to_string(), v.clone())));
-        });
+    /// Holds every shard for the whole transaction, it's rare enough that working out which
+    /// ones it needs isn't worth it.
+    fn process_transaction_int(&self, commands: Vec<Command>, watched: &[WatchedKey], unkeyed: Unkeyed<'_>) -> Result<DataType, String> {
+        let now = current_unix_timestamp_millis();
+        let mut shards = self.lock_all_shards()?;
+        execute_transaction(&mut shards, commands, watched, now, unkeyed)
+    }
 
-        println!("{:#?}", overall_map);
+    pub fn process_dump_int(&self) -> Result<DataType, String> {
+        let mut overall_map = BTreeMap::<Bytes, StorageRecord>::new();
+        for x in self.keymap.iter() {
+            let map = x.lock().map_err(|err| err.to_string())?;
+            overall_map.extend(map.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
 
-        Ok(DataType::Nil)
+        Ok(dump_reply(&overall_map))
     }
 }
 
 impl StorageEngine for InMemoryEngine {
-    async fn process_set(&self, cmd: SetCommand) -> Result<DataType, String> {
-        return self.process_set_int(cmd);
+    fn process(&self, command: Command) -> EngineFuture<'_, DataType> {
+        Box::pin(async move {
+            let key = command.woken_key().cloned();
+            let res = self.process_int(command);
+            self.waiters.signal_write(key, &res);
+            res
+        })
     }
 
-    async fn process_get(&self, key: String) -> Result<DataType, String> {
-        println!("Get get {}", key);
-        let val = self.process_get_int(key)?;
-        println!("Get get {:#?}", val);
-        return Ok(val);
+    fn process_watch(&self, keys: Vec<Bytes>) -> EngineFuture<'_, Vec<WatchedKey>> {
+        Box::pin(async move {
+            self.process_watch_int(keys)
+        })
     }
 
-    async fn process_dump(&self) -> Result<DataType, String> {
-        return self.process_dump_int();
+    fn process_transaction<'a>(&'a self, commands: Vec<Command>, watched: &'a [WatchedKey], unkeyed: Unkeyed<'a>) -> EngineFuture<'a, DataType> {
+        Box::pin(async move {
+            let keys = commands.iter().map(|cmd| cmd.woken_key().cloned()).collect();
+            let res = self.process_transaction_int(commands, watched, unkeyed);
+            self.waiters.signal_transaction(keys, &res);
+            res
+        })
+    }
+
+    fn unwatch(&self, watched: &[WatchedKey]) {
+        if let Ok(mut shards) = self.lock_shards_for_keys(watched.iter().map(|watched| &watched.key)) {
+            unwatch_keys(&mut shards, watched);
+        }
+    }
+
+    fn waiters(&self) -> &WaitRegistry {
+        &self.waiters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::SetCommand;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_commands_span_shards() {
+        let engine = InMemoryEngine::new();
+        let keys = (0..16).map(|x| Bytes::from(format!("key{}", x))).collect::<Vec<_>>();
+        for key in &keys {
+            let set = SetCommand { key: key.clone(), value: "1".into(), ..Default::default() };
+            assert_eq!(engine.process(Command::Set(set)).await, Ok(DataType::SimpleString("OK".into())));
+        }
+        assert_eq!(engine.process(Command::Exists { keys: keys.clone() }).await, Ok(DataType::Integer(16)));
+        assert_eq!(engine.process(Command::Del { keys, unlink: false }).await, Ok(DataType::Integer(16)));
+        assert!(engine.process(Command::Ping { message: None }).await.is_err());
     }
 }
@@ -1,4 +1,14 @@
 pub mod memory_engine;
 pub mod thread_engine;
 pub mod typesd;
-pub mod shared;
\ No newline at end of file
+pub mod shared;
+pub mod keyspace;
+pub mod list;
+pub mod hash;
+pub mod set;
+pub mod zset;
+pub mod stream;
+pub mod blocking;
+pub mod transaction;
+pub mod dispatch;
+pub mod notify;
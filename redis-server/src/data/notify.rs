@@ -0,0 +1,140 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::pubsub::Broker;
+
+const KEYSPACE: u16 = 1 << 0;
+const KEYEVENT: u16 = 1 << 1;
+
+/// The kinds of keyspace events, each turned on by its own letter in `notify-keyspace-events`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EventClass {
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    ZSet,
+    Expired,
+    Evicted,
+    Stream,
+}
+
+impl EventClass {
+    /// In the order redis lists them when it turns the flags back into a string.
+    const ALL: [(EventClass, char); 9] = [
+        (EventClass::Generic, 'g'),
+        (EventClass::String, '$'),
+        (EventClass::List, 'l'),
+        (EventClass::Set, 's'),
+        (EventClass::Hash, 'h'),
+        (EventClass::ZSet, 'z'),
+        (EventClass::Expired, 'x'),
+        (EventClass::Evicted, 'e'),
+        (EventClass::Stream, 't'),
+    ];
+
+    fn flag(self) -> u16 {
+        1 << (self as u16 + 2)
+    }
+}
+
+/// Every class, what `A` stands for.
+const ALL_CLASSES: u16 = ((1 << EventClass::ALL.len()) - 1) << 2;
+
+/// Parses the `notify-keyspace-events` letters. Without K or E nothing gets published no matter
+/// which classes are on, same as redis.
+pub(crate) fn parse_notify_flags(value: &str) -> Result<u16, String> {
+    value.chars().try_fold(0, |flags, letter| {
+        let flag = match letter {
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'A' => ALL_CLASSES,
+            letter => EventClass::ALL
+                .iter()
+                .find(|(_, x)| *x == letter)
+                .map(|(class, _)| class.flag())
+                .ok_or_else(|| "Invalid event class character. Use 'Ag$lshzxeKEt'.".to_string())?,
+        };
+        Ok(flags | flag)
+    })
+}
+
+pub(crate) fn notify_flags_to_string(flags: u16) -> String {
+    let mut value = match flags & ALL_CLASSES == ALL_CLASSES {
+        true => "A".to_string(),
+        false => EventClass::ALL.iter().filter(|(class, _)| flags & class.flag() != 0).map(|(_, letter)| *letter).collect(),
+    };
+    if flags & KEYSPACE != 0 {
+        value.push('K');
+    }
+    if flags & KEYEVENT != 0 {
+        value.push('E');
+    }
+    value
+}
+
+fn channel(prefix: &[u8], name: &[u8]) -> Bytes {
+    let mut channel = BytesMut::with_capacity(prefix.len() + name.len());
+    channel.put_slice(prefix);
+    channel.put_slice(name);
+    channel.freeze()
+}
+
+/// Publishes keyspace events to the pub/sub broker. Shared by every keyspace of an engine so a
+/// CONFIG SET reaches all of them at once.
+#[derive(Default)]
+pub struct Notifier {
+    flags: AtomicU16,
+    broker: Arc<Broker>,
+}
+
+impl fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = notify_flags_to_string(self.flags.load(Ordering::Relaxed));
+        f.debug_struct("Notifier").field("flags", &flags).finish()
+    }
+}
+
+impl Notifier {
+    pub fn new(broker: Arc<Broker>) -> Notifier {
+        Notifier { flags: AtomicU16::new(0), broker }
+    }
+
+    /// Takes the flags the way the config registry keeps them, see `parse_notify_flags`.
+    pub fn set_flags(&self, flags: u16) {
+        self.flags.store(flags, Ordering::Relaxed);
+    }
+
+    /// Sends `event` to `__keyspace@0__:<key>` and `key` to `__keyevent@0__:<event>`, whichever
+    /// of the two are turned on.
+    pub(crate) fn notify(&self, class: EventClass, event: &'static str, key: &[u8]) {
+        let flags = self.flags.load(Ordering::Relaxed);
+        if flags & class.flag() == 0 {
+            return;
+        }
+        if flags & KEYSPACE != 0 {
+            self.broker.publish(&channel(b"__keyspace@0__:", key), &Bytes::from_static(event.as_bytes()));
+        }
+        if flags & KEYEVENT != 0 {
+            self.broker.publish(&channel(b"__keyevent@0__:", event.as_bytes()), &Bytes::copy_from_slice(key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_notify_flags() {
+        assert_eq!(parse_notify_flags(""), Ok(0));
+        assert_eq!(notify_flags_to_string(parse_notify_flags("Ex").unwrap()), "xE");
+        assert_eq!(notify_flags_to_string(parse_notify_flags("KEA").unwrap()), "AKE");
+        assert_eq!(notify_flags_to_string(parse_notify_flags("tezxhsl$gK").unwrap()), "AK");
+        assert!(parse_notify_flags("Kq").is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use crate::{commands::{SMoveCommand, SetAlgebraCommand, SetAlgebraOutput, SetOperation, SetTypeCommand}, datatypes::{DataType, StorageRecord, StorageValue}};
+
+use super::{keyspace::{Keyspace, Keyspaces}, notify::EventClass, shared::{random_index, random_picks, remove_if_empty, scan_page, scan_reply, WRONGTYPE}};
+
+/// The error side of these results is the reply to send back as is, which is always WRONGTYPE.
+type SetResult<T> = Result<T, DataType>;
+
+/// Returns the set stored at `key`, None if there's nothing there.
+fn get_set<'a>(map: &'a mut Keyspace, key: &[u8], now: u128) -> SetResult<Option<&'a mut HashSet<Bytes>>> {
+    match map.get_mut(key, now) {
+        None => Ok(None),
+        Some(StorageValue::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+/// The new set starts out empty, the caller has to add a member to it before it's done.
+fn get_or_insert_set<'a>(map: &'a mut Keyspace, key: &Bytes, now: u128) -> SetResult<&'a mut HashSet<Bytes>> {
+    if get_set(map, key, now)?.is_none() {
+        map.insert(key.clone(), StorageRecord {
+            value: StorageValue::Set(HashSet::new()),
+            ttl: None,
+        });
+    }
+    match map.get_mut(key, now) {
+        Some(StorageValue::Set(set)) => Ok(set),
+        _ => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+fn members_reply<'a>(members: impl Iterator<Item = &'a Bytes>) -> DataType {
+    DataType::Set(members.cloned().map(DataType::BulkString).collect())
+}
+
+fn pop(map: &mut Keyspace, key: Bytes, count: Option<usize>, now: u128) -> SetResult<DataType> {
+    let Some(set) = get_set(map, &key, now)? else {
+        return Ok(match count {
+            Some(_) => DataType::Set(vec![]),
+            None => DataType::Nil,
+        });
+    };
+
+    let members = set.iter().collect::<Vec<_>>();
+    let picked = random_picks(members.len(), count.unwrap_or(1).min(i64::MAX as usize) as i64)
+        .into_iter()
+        .map(|idx| members[idx].clone())
+        .collect::<Vec<_>>();
+    for member in &picked {
+        set.remove(member);
+    }
+    if !picked.is_empty() {
+        map.notify(EventClass::Set, "spop", &key);
+    }
+    remove_if_empty(map, &key, now);
+
+    match count {
+        Some(_) => Ok(members_reply(picked.iter())),
+        None => Ok(picked.into_iter().next().map_or(DataType::Nil, DataType::BulkString)),
+    }
+}
+
+fn run_set_command(map: &mut Keyspace, cmd: SetTypeCommand, now: u128) -> SetResult<DataType> {
+    match cmd {
+        SetTypeCommand::Add { key, members } => {
+            let set = get_or_insert_set(map, &key, now)?;
+            let added = members.into_iter().filter(|member| set.insert(member.clone())).count();
+            if added > 0 {
+                map.notify(EventClass::Set, "sadd", &key);
+            }
+            Ok(DataType::Integer(added as i64))
+        }
+        SetTypeCommand::Rem { key, members } => {
+            let Some(set) = get_set(map, &key, now)? else {
+                return Ok(DataType::Integer(0));
+            };
+            let removed = members.iter().filter(|member| set.remove(*member)).count();
+            if removed > 0 {
+                map.notify(EventClass::Set, "srem", &key);
+            }
+            remove_if_empty(map, &key, now);
+            Ok(DataType::Integer(removed as i64))
+        }
+        SetTypeCommand::Members { key } => {
+            let set = get_set(map, &key, now)?.map(|set| &*set);
+            Ok(members_reply(set.into_iter().flatten()))
+        }
+        SetTypeCommand::IsMember { key, member } => {
+            let found = get_set(map, &key, now)?.is_some_and(|set| set.contains(&member));
+            Ok(DataType::Integer(found as i64))
+        }
+        SetTypeCommand::MIsMember { key, members } => {
+            let set = get_set(map, &key, now)?;
+            let found = members
+                .iter()
+                .map(|member| DataType::Integer(set.as_ref().is_some_and(|set| set.contains(member)) as i64))
+                .collect();
+            Ok(DataType::Array(found))
+        }
+        SetTypeCommand::Card { key } => {
+            let len = get_set(map, &key, now)?.map_or(0, |set| set.len());
+            Ok(DataType::Integer(len as i64))
+        }
+        SetTypeCommand::Pop { key, count } => pop(map, key, count, now),
+        SetTypeCommand::RandMember { key, count } => {
+            let Some(set) = get_set(map, &key, now)? else {
+                return Ok(match count {
+                    Some(_) => DataType::Array(vec![]),
+                    None => DataType::Nil,
+                });
+            };
+            let Some(count) = count else {
+                let member = set.iter().nth(random_index(set.len()));
+                return Ok(member.map_or(DataType::Nil, |member| DataType::BulkString(member.clone())));
+            };
+            let members = set.iter().collect::<Vec<_>>();
+            let picked = random_picks(members.len(), count)
+                .into_iter()
+                .map(|idx| DataType::BulkString(members[idx].clone()))
+                .collect();
+            Ok(DataType::Array(picked))
+        }
+        SetTypeCommand::Scan { key, options } => {
+            let Some(set) = get_set(map, &key, now)? else {
+                return Ok(scan_reply(0, vec![]));
+            };
+            let (cursor, page) = scan_page(set.iter().map(|member| (member, ())), &options);
+            let items = page.into_iter().map(|(member, _)| DataType::BulkString(member.clone())).collect();
+            Ok(scan_reply(cursor, items))
+        }
+    }
+}
+
+pub(crate) fn process_set_type(map: &mut Keyspace, cmd: SetTypeCommand, now: u128) -> Result<DataType, String> {
+    Ok(run_set_command(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+/// Combines the sets at `keys`, a missing key counts as an empty set. Only one set is borrowed
+/// at a time so this works across the shards of `Keyspaces`, the result is built up in a copy
+/// of the first (or for SINTER the smallest) set.
+fn combine_sets(map: &mut impl Keyspaces, operation: SetOperation, keys: &[Bytes], now: u128) -> SetResult<HashSet<Bytes>> {
+    // Every key gets type checked, even once it's clear the result is going to be empty
+    let mut sizes = Vec::with_capacity(keys.len());
+    for key in keys {
+        sizes.push(get_set(map.keyspace_for(key), key, now)?.map_or(0, |set| set.len()));
+    }
+
+    let start = match operation {
+        SetOperation::Inter => (0..keys.len()).min_by_key(|idx| sizes[*idx]).unwrap_or(0),
+        SetOperation::Union | SetOperation::Diff => 0,
+    };
+    let Some(start_key) = keys.get(start) else {
+        return Ok(HashSet::new());
+    };
+    let mut result = get_set(map.keyspace_for(start_key), start_key, now)?.cloned().unwrap_or_default();
+
+    for (idx, key) in keys.iter().enumerate() {
+        if idx == start {
+            continue;
+        }
+        let set = get_set(map.keyspace_for(key), key, now)?;
+        match (operation, set) {
+            (SetOperation::Inter, None) => result.clear(),
+            (SetOperation::Inter, Some(set)) => result.retain(|member| set.contains(member)),
+            (SetOperation::Union, Some(set)) => result.extend(set.iter().cloned()),
+            (SetOperation::Diff, Some(set)) => result.retain(|member| !set.contains(member)),
+            (_, None) => {}
+        }
+    }
+    Ok(result)
+}
+
+fn store_event(operation: SetOperation) -> &'static str {
+    match operation {
+        SetOperation::Inter => "sinterstore",
+        SetOperation::Union => "sunionstore",
+        SetOperation::Diff => "sdiffstore",
+    }
+}
+
+/// Replaces whatever is at `key` with the set, or deletes the key if the set is empty.
+pub(crate) fn store_set(map: &mut Keyspace, key: Bytes, set: HashSet<Bytes>, event: &'static str) {
+    if set.is_empty() {
+        if map.remove(&key).is_some() {
+            map.notify(EventClass::Generic, "del", &key);
+        }
+        return;
+    }
+    map.insert(key.clone(), StorageRecord {
+        value: StorageValue::Set(set),
+        ttl: None,
+    });
+    map.notify(EventClass::Set, event, &key);
+}
+
+fn set_algebra_reply(result: &HashSet<Bytes>, output: &SetAlgebraOutput) -> DataType {
+    match output {
+        SetAlgebraOutput::Members => members_reply(result.iter()),
+        SetAlgebraOutput::Store(_) => DataType::Integer(result.len() as i64),
+        SetAlgebraOutput::Cardinality { limit: 0 } => DataType::Integer(result.len() as i64),
+        SetAlgebraOutput::Cardinality { limit } => DataType::Integer(result.len().min(*limit) as i64),
+    }
+}
+
+pub(crate) fn process_set_algebra(map: &mut impl Keyspaces, cmd: SetAlgebraCommand, now: u128) -> Result<DataType, String> {
+    let result = match combine_sets(map, cmd.operation, &cmd.keys, now) {
+        Ok(result) => result,
+        Err(err) => return Ok(err),
+    };
+
+    let reply = set_algebra_reply(&result, &cmd.output);
+    if let SetAlgebraOutput::Store(destination) = cmd.output {
+        store_set(map.keyspace_for(&destination), destination, result, store_event(cmd.operation));
+    }
+    Ok(reply)
+}
+
+fn smove(map: &mut impl Keyspaces, cmd: SMoveCommand, now: u128) -> SetResult<DataType> {
+    let Some(source) = get_set(map.keyspace_for(&cmd.source), &cmd.source, now)? else {
+        return Ok(DataType::Integer(0));
+    };
+    let is_member = source.contains(&cmd.member);
+    get_set(map.keyspace_for(&cmd.destination), &cmd.destination, now)?;
+    if !is_member || cmd.source == cmd.destination {
+        return Ok(DataType::Integer(is_member as i64));
+    }
+
+    if let Some(source) = get_set(map.keyspace_for(&cmd.source), &cmd.source, now)? {
+        source.remove(&cmd.member);
+    }
+    map.keyspace_for(&cmd.source).notify(EventClass::Set, "srem", &cmd.source);
+    remove_if_empty(map.keyspace_for(&cmd.source), &cmd.source, now);
+    get_or_insert_set(map.keyspace_for(&cmd.destination), &cmd.destination, now)?.insert(cmd.member);
+    map.keyspace_for(&cmd.destination).notify(EventClass::Set, "sadd", &cmd.destination);
+    Ok(DataType::Integer(1))
+}
+
+pub(crate) fn process_smove(map: &mut impl Keyspaces, cmd: SMoveCommand, now: u128) -> Result<DataType, String> {
+    Ok(smove(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sadd(map: &mut Keyspace, key: &'static str, members: &[&'static str]) {
+        let members = members.iter().map(|x| Bytes::from(*x)).collect();
+        process_set_type(map, SetTypeCommand::Add { key: key.into(), members }, 0).expect("Expected the add to succeed");
+    }
+
+    fn combine(map: &mut Keyspace, operation: SetOperation, keys: &[&'static str]) -> Vec<Bytes> {
+        let keys = keys.iter().map(|x| Bytes::from(*x)).collect::<Vec<_>>();
+        let mut result = combine_sets(map, operation, &keys, 0).unwrap().into_iter().collect::<Vec<_>>();
+        result.sort();
+        result
+    }
+
+    #[test]
+    pub fn test_set_algebra() {
+        let mut map = Keyspace::new();
+        sadd(&mut map, "a", &["1", "2", "3"]);
+        sadd(&mut map, "b", &["2", "3", "4"]);
+
+        assert_eq!(combine(&mut map, SetOperation::Inter, &["a", "b"]), vec!["2", "3"]);
+        assert_eq!(combine(&mut map, SetOperation::Union, &["a", "b"]), vec!["1", "2", "3", "4"]);
+        assert_eq!(combine(&mut map, SetOperation::Diff, &["a", "b"]), vec!["1"]);
+        assert_eq!(combine(&mut map, SetOperation::Inter, &["a", "missing"]), Vec::<Bytes>::new());
+        assert_eq!(combine(&mut map, SetOperation::Diff, &["a", "missing"]), vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    pub fn test_store_empty_result_deletes_destination() {
+        let mut map = Keyspace::new();
+        sadd(&mut map, "a", &["1"]);
+        sadd(&mut map, "dest", &["x"]);
+
+        let cmd = SetAlgebraCommand {
+            operation: SetOperation::Inter,
+            keys: vec!["a".into(), "missing".into()],
+            output: SetAlgebraOutput::Store("dest".into()),
+        };
+        assert_eq!(process_set_algebra(&mut map, cmd, 0), Ok(DataType::Integer(0)));
+        assert!(map.get(b"dest", 0).is_none());
+    }
+
+    #[test]
+    pub fn test_algebra_wrong_type() {
+        let mut map = Keyspace::new();
+        map.insert("s".into(), StorageRecord { value: StorageValue::String("1".into()), ttl: None });
+
+        let cmd = SetAlgebraCommand {
+            operation: SetOperation::Union,
+            keys: vec!["missing".into(), "s".into()],
+            output: SetAlgebraOutput::Members,
+        };
+        assert_eq!(process_set_algebra(&mut map, cmd, 0), Ok(DataType::Error(WRONGTYPE.into())));
+    }
+
+    #[test]
+    pub fn test_spop_removes_members() {
+        let mut map = Keyspace::new();
+        sadd(&mut map, "a", &["1", "2", "3"]);
+
+        let DataType::Set(popped) = process_set_type(&mut map, SetTypeCommand::Pop { key: "a".into(), count: Some(2) }, 0).unwrap() else {
+            panic!("Expected a set reply");
+        };
+        assert_eq!(popped.len(), 2);
+        assert_eq!(process_set_type(&mut map, SetTypeCommand::Card { key: "a".into() }, 0), Ok(DataType::Integer(1)));
+    }
+}
@@ -1,27 +1,129 @@
-use std::{collections::HashMap, hash::{Hash, Hasher, SipHasher}};
+use std::{cell::Cell, collections::BTreeMap, hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState}, sync::{mpsc::{channel, Sender}, Mutex, OnceLock}, thread, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::{commands::{SetCommand, SetExistingOptions}, datatypes::{DataType, StorageRecord, StorageValue}};
+use bytes::Bytes;
 
-pub(crate) fn hashy(str: &str) -> u64 {
-    let mut hasher = SipHasher::new_with_keys(0, 0);
+use crate::{commands::{CopyCommand, ExpireCommand, ExpireCondition, IncrBy, IncrCommand, RenameCommand, ScanOptions, SetCommand, SetExistingOptions, TtlKind}, datatypes::{DataType, StorageRecord, StorageValue}, protocol::serializer::format_double};
+
+use super::keyspace::{Keyspace, Keyspaces};
+use super::notify::EventClass;
+
+pub(crate) const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+pub(crate) fn hashy(str: &[u8]) -> u64 {
+    // DefaultHasher::new() always uses the same keys, so a key maps to the same shard every time
+    let mut hasher = DefaultHasher::new();
     str.hash(&mut hasher);
-    let res = hasher.finish();
-    // println!("Hash result: {str} -> {res}");
-    return res;
+    hasher.finish()
+}
+
+/// The part of a key that decides where it lives. Same as redis cluster, when the key has a
+/// non empty `{tag}` only the tag counts, so related keys can be kept together.
+pub(crate) fn hash_tag(key: &[u8]) -> &[u8] {
+    let Some(open) = key.iter().position(|&b| b == b'{') else {
+        return key;
+    };
+    match key[open + 1..].iter().position(|&b| b == b'}') {
+        Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+        _ => key,
+    }
+}
+
+/// Which of `count` shards or threads a key lives in, the one rule every engine routes keys by.
+pub(crate) fn key_slot(key: &[u8], count: usize) -> usize {
+    (hashy(hash_tag(key)) % count as u64) as usize
 }
 
-pub(crate) fn process_set(map: &mut HashMap<String, StorageRecord>, cmd: SetCommand) -> Result<DataType, String> {
-    let previous_obj: Option<&StorageRecord> = map.get(&cmd.key);
+pub(crate) fn current_unix_timestamp_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("SystemTime before UNIX EPOCH!")
+        .as_millis()
+}
+
+thread_local! {
+    static RANDOM_STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+}
+
+/// Returns a random index below `len` from a thread local xorshift generator. Not suitable for
+/// anything security related, it's only used to pick keys and members.
+pub(crate) fn random_index(len: usize) -> usize {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x % len as u64) as usize
+    })
+}
+
+/// Picks `count` random indexes below `len` the way the SRANDMEMBER family does. A positive count
+/// picks distinct indexes and is capped at `len`, a negative one may pick the same index twice.
+pub(crate) fn random_picks(len: usize, count: i64) -> Vec<usize> {
+    if len == 0 {
+        return vec![];
+    }
+    if count < 0 {
+        return (0..count.unsigned_abs()).map(|_| random_index(len)).collect();
+    }
+
+    // Partial Fisher-Yates shuffle, the first `count` indexes end up distinct and random
+    let count = (count as usize).min(len);
+    let mut indexes = (0..len).collect::<Vec<_>>();
+    for idx in 0..count {
+        let swap = idx + random_index(len - idx);
+        indexes.swap(idx, swap);
+    }
+    indexes.truncate(count);
+    indexes
+}
+
+/// One page of a HSCAN style scan, returns the cursor to continue from (0 once done) and the
+/// items on this page that match the pattern.
+///
+/// Items are walked in the order of their hashes and the cursor is the hash to continue from,
+/// so an item that's there for the whole scan is returned no matter what else gets added or
+/// removed in between.
+pub(crate) fn scan_page<'a, T>(items: impl Iterator<Item = (&'a Bytes, T)>, options: &ScanOptions) -> (u64, Vec<(&'a Bytes, T)>) {
+    let mut candidates = items
+        .map(|(name, item)| (hashy(name), name, item))
+        .filter(|(position, _, _)| *position >= options.cursor)
+        .collect::<Vec<_>>();
+    candidates.sort_unstable_by_key(|(position, _, _)| *position);
+
+    // Items with the same hash have to come back in the same call, the cursor can't split them
+    let mut end = candidates.len().min(options.count.max(1));
+    while end < candidates.len() && candidates[end].0 == candidates[end - 1].0 {
+        end += 1;
+    }
+    let next_cursor = candidates.get(end).map_or(0, |(position, _, _)| *position);
+    candidates.truncate(end);
+
+    let page = candidates
+        .into_iter()
+        .filter(|(_, name, _)| options.pattern.as_ref().is_none_or(|pattern| glob_match(pattern, name)))
+        .map(|(_, name, item)| (name, item))
+        .collect();
+    (next_cursor, page)
+}
+
+pub(crate) fn scan_reply(cursor: u64, items: Vec<DataType>) -> DataType {
+    DataType::Array(vec![DataType::BulkString(cursor.to_string().into()), DataType::Array(items)])
+}
+
+/// DUMP's reply, every record sorted by key so the output reads the same every time.
+pub(crate) fn dump_reply(records: &BTreeMap<Bytes, StorageRecord>) -> DataType {
+    DataType::BulkString(format!("{:#?}", records).into())
+}
+
+pub(crate) fn process_set(map: &mut Keyspace, cmd: SetCommand, now: u128) -> Result<DataType, String> {
+    let previous_obj: Option<&StorageRecord> = map.get(&cmd.key, now);
     let previous_value = match (cmd.get_previous_value, previous_obj) {
         (true, Some(stored_value)) => {
             let StorageValue::String(x) = &stored_value.value else {
-                // TODO: Update the error message to match redis message
-                return Ok(DataType::Error(format!(
-                    "Error: Expected key {} to be of type String",
-                    cmd.key
-                )));
+                return Ok(DataType::Error(WRONGTYPE.into()));
             };
-            Some(x.to_string())
+            Some(x.clone())
         }
         _ => None,
     };
@@ -37,30 +139,379 @@ pub(crate) fn process_set(map: &mut HashMap<String, StorageRecord>, cmd: SetComm
         value: StorageValue::String(cmd.value),
     };
 
-    let should_insert = match (cmd.set_existing, previous_obj) {
-        (None, _) => true,
-        (Some(SetExistingOptions::OnlySetIfExists), Some(_)) => true,
-        (Some(SetExistingOptions::OnlySetIfNotExists), None) => true,
-        _ => false,
-    };
+    let should_insert = matches!(
+        (cmd.set_existing, previous_obj),
+        (None, _)
+            | (Some(SetExistingOptions::OnlySetIfExists), Some(_))
+            | (Some(SetExistingOptions::OnlySetIfNotExists), None)
+    );
 
     if should_insert {
-        map.insert(cmd.key, storage_record);
+        map.insert(cmd.key.clone(), storage_record);
+        map.notify(EventClass::String, "set", &cmd.key);
+        if cmd.expiration.is_some() {
+            map.notify(EventClass::Generic, "expire", &cmd.key);
+        }
     }
 
-    return match previous_value {
+    match previous_value {
         Some(value) => Ok(DataType::BulkString(value)),
         None => Ok(DataType::SimpleString("OK".into())),
-    };
+    }
 }
 
-pub(crate) fn process_get(map: &HashMap<String, StorageRecord>, key: String) -> Result<DataType, String> {
-    let val = map.get(&key);
+pub(crate) fn process_get(map: &mut Keyspace, key: Bytes, now: u128) -> Result<DataType, String> {
+    let val = map.get(&key, now);
     match val {
         Some(StorageRecord{
             value: StorageValue::String(x),
             ..
-        }) => Ok(DataType::BulkString(x.into())),
+        }) => Ok(DataType::BulkString(x.clone())),
+        Some(_) => Ok(DataType::Error(WRONGTYPE.into())),
         None => Ok(DataType::Nil),
     }
 }
+
+/// Parses a stored string the way redis' `string2ll` does, no whitespace, `+` signs or leading zeroes.
+pub(crate) fn parse_stored_integer(value: &[u8]) -> Option<i64> {
+    let digits = value.strip_prefix(b"-").unwrap_or(value);
+    if digits.is_empty() || (digits[0] == b'0' && value.len() > 1) || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(value).ok()?.parse::<i64>().ok()
+}
+
+pub(crate) fn parse_stored_float(value: &[u8]) -> Option<f64> {
+    let str = std::str::from_utf8(value).ok()?;
+    if str.starts_with(|x: char| x.is_ascii_whitespace()) || str.ends_with(|x: char| x.is_ascii_whitespace()) {
+        return None;
+    }
+    str.parse::<f64>().ok().filter(|x| !x.is_nan())
+}
+
+pub(crate) fn process_incr(map: &mut Keyspace, cmd: IncrCommand, now: u128) -> Result<DataType, String> {
+    let current = match map.get(&cmd.key, now).map(|record| &record.value) {
+        None => None,
+        Some(StorageValue::String(x)) => Some(x),
+        Some(_) => return Ok(DataType::Error(WRONGTYPE.into())),
+    };
+
+    let (stored, reply, event) = match cmd.by {
+        IncrBy::Integer(by) => {
+            let Some(current) = current.map_or(Some(0), |x| parse_stored_integer(x)) else {
+                return Ok(DataType::Error("ERR value is not an integer or out of range".into()));
+            };
+            let Some(result) = current.checked_add(by) else {
+                return Ok(DataType::Error("ERR increment or decrement would overflow".into()));
+            };
+            (Bytes::from(result.to_string()), DataType::Integer(result), "incrby")
+        }
+        IncrBy::Float(by) => {
+            let Some(current) = current.map_or(Some(0.0), |x| parse_stored_float(x)) else {
+                return Ok(DataType::Error("ERR value is not a valid float".into()));
+            };
+            let result = current + by;
+            if !result.is_finite() {
+                return Ok(DataType::Error("ERR increment would produce NaN or Infinity".into()));
+            }
+            let stored = Bytes::from(format_double(result));
+            (stored.clone(), DataType::BulkString(stored), "incrbyfloat")
+        }
+    };
+
+    // Counters keep whatever TTL the key already had
+    match map.get_mut(&cmd.key, now) {
+        Some(value) => *value = StorageValue::String(stored),
+        None => {
+            map.insert(cmd.key.clone(), StorageRecord {
+                value: StorageValue::String(stored),
+                ttl: None,
+            });
+        }
+    }
+    map.notify(EventClass::String, event, &cmd.key);
+
+    Ok(reply)
+}
+
+/// Whether an expiration can replace the current TTL under the NX, XX, GT and LT flags.
+pub(crate) fn expire_conditions_met(conditions: &[ExpireCondition], ttl: Option<u128>, expiration: u128) -> bool {
+    // A key without a TTL counts as expiring infinitely far in the future for GT and LT
+    conditions.iter().all(|condition| match (condition, ttl) {
+        (ExpireCondition::NoExpiry, ttl) => ttl.is_none(),
+        (ExpireCondition::HasExpiry, ttl) => ttl.is_some(),
+        (ExpireCondition::GreaterThan, Some(ttl)) => expiration > ttl,
+        (ExpireCondition::GreaterThan, None) => false,
+        (ExpireCondition::LessThan, Some(ttl)) => expiration < ttl,
+        (ExpireCondition::LessThan, None) => true,
+    })
+}
+
+pub(crate) fn process_expire(map: &mut Keyspace, cmd: ExpireCommand, now: u128) -> Result<DataType, String> {
+    let Some(record) = map.get(&cmd.key, now) else {
+        return Ok(DataType::Integer(0));
+    };
+
+    if !expire_conditions_met(&cmd.conditions, record.ttl, cmd.expiration) {
+        return Ok(DataType::Integer(0));
+    }
+
+    if cmd.expiration <= now {
+        map.remove(&cmd.key);
+        map.notify(EventClass::Generic, "del", &cmd.key);
+    } else {
+        map.set_ttl(&cmd.key, Some(cmd.expiration));
+        map.notify(EventClass::Generic, "expire", &cmd.key);
+    }
+    Ok(DataType::Integer(1))
+}
+
+pub(crate) fn process_persist(map: &mut Keyspace, key: Bytes, now: u128) -> Result<DataType, String> {
+    match map.get(&key, now) {
+        Some(StorageRecord { ttl: Some(_), .. }) => {
+            map.set_ttl(&key, None);
+            map.notify(EventClass::Generic, "persist", &key);
+            Ok(DataType::Integer(1))
+        }
+        _ => Ok(DataType::Integer(0)),
+    }
+}
+
+pub(crate) fn process_ttl(map: &mut Keyspace, key: Bytes, kind: TtlKind, now: u128) -> Result<DataType, String> {
+    let ttl = match map.get(&key, now) {
+        None => return Ok(DataType::Integer(-2)),
+        Some(StorageRecord { ttl: None, .. }) => return Ok(DataType::Integer(-1)),
+        Some(StorageRecord { ttl: Some(ttl), .. }) => *ttl,
+    };
+    Ok(DataType::Integer(ttl_reply(ttl, kind, now)))
+}
+
+/// Converts an absolute expiry time into what the TTL family replies with.
+pub(crate) fn ttl_reply(ttl: u128, kind: TtlKind, now: u128) -> i64 {
+    let remaining = ttl.saturating_sub(now);
+    let reply = match kind {
+        // Rounded to the closest second, same as redis
+        TtlKind::Ttl => (remaining + 500) / 1000,
+        TtlKind::Pttl => remaining,
+        TtlKind::ExpireTime => ttl / 1000,
+        TtlKind::PexpireTime => ttl,
+    };
+    reply as i64
+}
+
+/// Collections are never left empty, one that loses its last element is deleted like in redis.
+pub(crate) fn remove_if_empty(map: &mut Keyspace, key: &[u8], now: u128) {
+    let empty = match map.get_mut(key, now) {
+        Some(StorageValue::List(list)) => list.is_empty(),
+        Some(StorageValue::Hash(hash)) => hash.is_empty(),
+        Some(StorageValue::Set(set)) => set.is_empty(),
+        Some(StorageValue::ZSet(zset)) => zset.is_empty(),
+        _ => false,
+    };
+    if empty {
+        map.remove(key);
+        map.notify(EventClass::Generic, "del", key);
+    }
+}
+
+/// Glob style matching with the same rules as redis' `stringmatchlen`: `*`, `?`, `[abc]`,
+/// `[^abc]`, `[a-z]` and `\` to escape the next character.
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((b'*', rest)) => {
+            let rest = rest.iter().position(|x| *x != b'*').map_or(&[][..], |idx| &rest[idx..]);
+            rest.is_empty() || (0..=string.len()).any(|idx| glob_match(rest, &string[idx..]))
+        }
+        Some((b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..]),
+        Some((b'[', rest)) => {
+            let Some((&current, tail)) = string.split_first() else {
+                return false;
+            };
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+
+            let mut matched = false;
+            loop {
+                match class {
+                    // An unterminated class runs to the end of the pattern
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        class = tail;
+                        break;
+                    }
+                    [b'\\', escaped, tail @ ..] => {
+                        matched |= *escaped == current;
+                        class = tail;
+                    }
+                    [start, b'-', end, tail @ ..] if *end != b']' => {
+                        let (low, high) = if start <= end { (*start, *end) } else { (*end, *start) };
+                        matched |= (low..=high).contains(&current);
+                        class = tail;
+                    }
+                    [x, tail @ ..] => {
+                        matched |= *x == current;
+                        class = tail;
+                    }
+                }
+            }
+            matched != negate && glob_match(class, tail)
+        }
+        Some((b'\\', [escaped, rest @ ..])) => string.first() == Some(escaped) && glob_match(rest, &string[1..]),
+        Some((x, rest)) => string.first() == Some(x) && glob_match(rest, &string[1..]),
+    }
+}
+
+/// Values that take more than this many allocations to free are dropped on the lazy free thread
+/// by UNLINK, same threshold as redis' `LAZYFREE_THRESHOLD`.
+const LAZY_FREE_THRESHOLD: usize = 64;
+
+static LAZY_FREE: OnceLock<Mutex<Sender<Vec<StorageRecord>>>> = OnceLock::new();
+
+fn free_effort(value: &StorageValue) -> usize {
+    match value {
+        StorageValue::String(_) => 1,
+        StorageValue::List(list) => list.len(),
+        StorageValue::Hash(hash) => hash.len(),
+        StorageValue::Set(set) => set.len(),
+        StorageValue::ZSet(zset) => zset.len(),
+        StorageValue::Stream(stream) => stream.len(),
+    }
+}
+
+/// Drops the records on a background thread if they're expensive enough to free that doing it
+/// inline would hold up the command.
+fn free_lazily(records: Vec<StorageRecord>) {
+    if records.iter().map(|x| free_effort(&x.value)).sum::<usize>() <= LAZY_FREE_THRESHOLD {
+        return;
+    }
+
+    let sender = LAZY_FREE.get_or_init(|| {
+        let (sender, receiver) = channel::<Vec<StorageRecord>>();
+        thread::spawn(move || receiver.into_iter().for_each(drop));
+        Mutex::new(sender)
+    });
+    if let Ok(sender) = sender.lock() {
+        // If the thread is gone the records come back in the error and get dropped right here
+        let _ = sender.send(records);
+    }
+}
+
+pub(crate) fn process_del(map: &mut impl Keyspaces, keys: Vec<Bytes>, unlink: bool, now: u128) -> Result<DataType, String> {
+    let mut deleted = Vec::new();
+    for key in keys {
+        let keyspace = map.keyspace_for(&key);
+        // Go through get first so a key that's already expired doesn't count as deleted
+        if keyspace.get(&key, now).is_some() {
+            deleted.extend(keyspace.remove(&key));
+            keyspace.notify(EventClass::Generic, "del", &key);
+        }
+    }
+
+    let count = deleted.len() as i64;
+    if unlink {
+        free_lazily(deleted);
+    }
+    Ok(DataType::Integer(count))
+}
+
+/// Also used for TOUCH, nothing tracks access times so the two only differ in name.
+pub(crate) fn process_exists(map: &mut impl Keyspaces, keys: Vec<Bytes>, now: u128) -> Result<DataType, String> {
+    let count = keys
+        .iter()
+        .filter(|key| map.keyspace_for(key).get(key, now).is_some())
+        .count();
+    Ok(DataType::Integer(count as i64))
+}
+
+pub(crate) fn process_type(map: &mut Keyspace, key: Bytes, now: u128) -> Result<DataType, String> {
+    let name = match map.get(&key, now).map(|x| &x.value) {
+        None => "none",
+        Some(StorageValue::String(_)) => "string",
+        Some(StorageValue::List(_)) => "list",
+        Some(StorageValue::Hash(_)) => "hash",
+        Some(StorageValue::Set(_)) => "set",
+        Some(StorageValue::ZSet(_)) => "zset",
+        Some(StorageValue::Stream(_)) => "stream",
+    };
+    Ok(DataType::SimpleString(name.into()))
+}
+
+pub(crate) fn process_rename(map: &mut impl Keyspaces, cmd: RenameCommand, now: u128) -> Result<DataType, String> {
+    if map.keyspace_for(&cmd.source).get(&cmd.source, now).is_none() {
+        return Ok(DataType::Error("ERR no such key".into()));
+    }
+
+    let destination_taken = cmd.only_if_new && map.keyspace_for(&cmd.destination).get(&cmd.destination, now).is_some();
+    let renamed = if cmd.source == cmd.destination || destination_taken {
+        false
+    } else {
+        let source = map.keyspace_for(&cmd.source);
+        let record = source.remove(&cmd.source).expect("Expected the source key to exist");
+        source.notify(EventClass::Generic, "rename_from", &cmd.source);
+        let destination = map.keyspace_for(&cmd.destination);
+        destination.insert(cmd.destination.clone(), record);
+        destination.notify(EventClass::Generic, "rename_to", &cmd.destination);
+        true
+    };
+
+    match cmd.only_if_new {
+        true => Ok(DataType::Integer(renamed as i64)),
+        false => Ok(DataType::SimpleString("OK".into())),
+    }
+}
+
+pub(crate) fn process_copy(map: &mut impl Keyspaces, cmd: CopyCommand, now: u128) -> Result<DataType, String> {
+    if cmd.source == cmd.destination {
+        return Ok(DataType::Error("ERR source and destination objects are the same".into()));
+    }
+    let Some(record) = map.keyspace_for(&cmd.source).get(&cmd.source, now).cloned() else {
+        return Ok(DataType::Integer(0));
+    };
+
+    let destination = map.keyspace_for(&cmd.destination);
+    if !cmd.replace && destination.get(&cmd.destination, now).is_some() {
+        return Ok(DataType::Integer(0));
+    }
+    destination.insert(cmd.destination.clone(), record);
+    destination.notify(EventClass::Generic, "copy_to", &cmd.destination);
+    Ok(DataType::Integer(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_incrbyfloat_formatting() {
+        let mut map = Keyspace::new();
+        let incr = |by| IncrCommand { key: "f".into(), by: IncrBy::Float(by) };
+        assert_eq!(process_incr(&mut map, incr(1e300), 0), Ok(DataType::BulkString("1e+300".into())));
+        assert_eq!(process_incr(&mut map, incr(1e300), 0), Ok(DataType::BulkString("2e+300".into())));
+        assert_eq!(process_incr(&mut map, incr(-2e300), 0), Ok(DataType::BulkString("0".into())));
+        assert_eq!(process_incr(&mut map, incr(10.5), 0), Ok(DataType::BulkString("10.5".into())));
+    }
+
+    #[test]
+    pub fn test_hash_tag() {
+        assert_eq!(hash_tag(b"{user1000}.following"), b"user1000");
+        assert_eq!(hash_tag(b"foo{}{bar}"), b"foo{}{bar}");
+        assert_eq!(hash_tag(b"foo{{bar}}zap"), b"{bar");
+        assert_eq!(hash_tag(b"foo{bar"), b"foo{bar");
+    }
+
+    #[test]
+    pub fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(!glob_match(b"user:*", b"account:1"));
+    }
+}
@@ -0,0 +1,892 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use bytes::Bytes;
+
+use crate::{
+    commands::{ClaimOptions, PendingRange, ReadGroup, StreamCommand, StreamId, StreamTrim, TrimStrategy, XAddId, XReadCommand, XReadId},
+    datatypes::{DataType, StorageRecord, StorageValue},
+};
+
+use super::{keyspace::{Keyspace, Keyspaces}, notify::EventClass, shared::WRONGTYPE};
+
+/// The field value pairs of an entry, in the order they were given.
+type Fields = Vec<(Bytes, Bytes)>;
+
+/// An append only log of entries ordered by ID. Unlike the other collections a stream stays
+/// around when it's empty, so the last ID keeps new entries from reusing old IDs.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    /// Every entry ever added, the deleted ones included.
+    entries_added: u64,
+    max_deleted_id: StreamId,
+    groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+/// An entry delivered to a consumer that hasn't been acknowledged yet.
+#[derive(Debug, Clone)]
+struct PendingEntry {
+    consumer: Bytes,
+    delivery_time: u128,
+    delivery_count: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Consumer {
+    pending: BTreeSet<StreamId>,
+    /// The last time the consumer did anything, and the last time it got entries.
+    seen_time: u128,
+    active_time: Option<u128>,
+}
+
+#[derive(Debug, Clone)]
+struct ConsumerGroup {
+    last_delivered: StreamId,
+    /// How many entries the group has read, None when that can't be known because of deletions.
+    entries_read: Option<u64>,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<Bytes, Consumer>,
+}
+
+impl ConsumerGroup {
+    fn new(last_delivered: StreamId, entries_read: Option<u64>) -> Self {
+        Self { last_delivered, entries_read, pending: BTreeMap::new(), consumers: BTreeMap::new() }
+    }
+
+    /// Returns the consumer, creating it if needed, and marks it as seen.
+    fn consumer(&mut self, name: &Bytes, now: u128) -> &mut Consumer {
+        let consumer = self
+            .consumers
+            .entry(name.clone())
+            .or_insert_with(|| Consumer { pending: BTreeSet::new(), seen_time: now, active_time: None });
+        consumer.seen_time = now;
+        consumer
+    }
+
+    /// Hands a pending entry to `consumer`, taking it away from whoever had it before.
+    fn assign(&mut self, id: StreamId, consumer: &Bytes, delivery_time: u128, now: u128) -> &mut PendingEntry {
+        if let Some(previous) = self.pending.get(&id).map(|entry| entry.consumer.clone()) {
+            if let Some(previous) = self.consumers.get_mut(&previous) {
+                previous.pending.remove(&id);
+            }
+        }
+        let owner = self.consumer(consumer, now);
+        owner.pending.insert(id);
+        owner.active_time = Some(now);
+
+        let entry = self.pending.entry(id).or_insert_with(|| PendingEntry {
+            consumer: consumer.clone(),
+            delivery_time,
+            delivery_count: 0,
+        });
+        entry.consumer = consumer.clone();
+        entry.delivery_time = delivery_time;
+        entry
+    }
+
+    fn acknowledge(&mut self, id: &StreamId) -> bool {
+        let Some(entry) = self.pending.remove(id) else {
+            return false;
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(id);
+        }
+        true
+    }
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn remove_entry(&mut self, id: &StreamId) -> bool {
+        if self.entries.remove(id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(*id);
+        true
+    }
+
+    /// How many entries a group that has delivered everything up to `id` has read, if that can
+    /// still be worked out.
+    fn entries_read_at(&self, id: StreamId) -> Option<u64> {
+        if id >= self.last_id {
+            return Some(self.entries_added);
+        }
+        // Without deletions every entry ever added is still there to count
+        match self.max_deleted_id == StreamId::MIN {
+            true => Some(self.entries.range(..=id).count() as u64),
+            false => None,
+        }
+    }
+
+    /// The entries the group hasn't delivered yet.
+    fn lag(&self, group: &ConsumerGroup) -> usize {
+        match group.last_delivered.next() {
+            Some(start) => self.entries.range(start..).count(),
+            None => 0,
+        }
+    }
+
+    /// The ID for a new entry, an error if it wouldn't be greater than the last one.
+    fn next_id(&self, id: XAddId) -> Result<StreamId, String> {
+        let last = self.last_id;
+        let id = match id {
+            XAddId::Explicit(StreamId::MIN) => return Err("ERR The ID specified in XADD must be greater than 0-0".into()),
+            XAddId::Explicit(id) => Some(id),
+            XAddId::AutoSequence { ms } if ms == last.ms => last.seq.checked_add(1).map(|seq| StreamId { ms, seq }),
+            XAddId::AutoSequence { ms } => Some(StreamId { ms, seq: 0 }),
+            XAddId::Auto { now } if now > last.ms => Some(StreamId { ms: now, seq: 0 }),
+            // The clock went backwards or more than one entry gets added per millisecond
+            XAddId::Auto { .. } => {
+                return last.next().ok_or("ERR The stream has exhausted the last possible ID, unable to add more items".into());
+            }
+        };
+        id.filter(|id| *id > last)
+            .ok_or("ERR The ID specified in XADD is equal or smaller than the target stream top item".into())
+    }
+
+    /// Removes entries from the start of the stream, returns how many.
+    fn trim(&mut self, trim: StreamTrim) -> usize {
+        let mut removed = 0;
+        while removed < trim.limit.unwrap_or(usize::MAX) {
+            let len = self.entries.len();
+            let Some(entry) = self.entries.first_entry() else {
+                break;
+            };
+            let remove = match trim.strategy {
+                TrimStrategy::MaxLen(max) => len as u64 > max,
+                TrimStrategy::MinId(min) => *entry.key() < min,
+            };
+            if !remove {
+                break;
+            }
+            let (id, _) = entry.remove_entry();
+            self.max_deleted_id = self.max_deleted_id.max(id);
+            removed += 1;
+        }
+        removed
+    }
+
+    fn range(&self, start: StreamId, end: StreamId, rev: bool) -> Box<dyn Iterator<Item = (&StreamId, &Fields)> + '_> {
+        if start > end {
+            return Box::new(std::iter::empty());
+        }
+        let range = self.entries.range(start..=end);
+        if rev { Box::new(range.rev()) } else { Box::new(range) }
+    }
+}
+
+/// The error side of these results is the reply to send back as is, which is always WRONGTYPE.
+type StreamResult<T> = Result<T, DataType>;
+
+/// Returns the stream stored at `key`, None if there's nothing there.
+fn get_stream<'a>(map: &'a mut Keyspace, key: &[u8], now: u128) -> StreamResult<Option<&'a mut Stream>> {
+    match map.get_mut(key, now) {
+        None => Ok(None),
+        Some(StorageValue::Stream(stream)) => Ok(Some(stream)),
+        Some(_) => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+fn get_or_insert_stream<'a>(map: &'a mut Keyspace, key: &Bytes, now: u128) -> StreamResult<&'a mut Stream> {
+    if get_stream(map, key, now)?.is_none() {
+        map.insert(key.clone(), StorageRecord {
+            value: StorageValue::Stream(Stream::default()),
+            ttl: None,
+        });
+    }
+    match map.get_mut(key, now) {
+        Some(StorageValue::Stream(stream)) => Ok(stream),
+        _ => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+/// An entry is its ID followed by the field value pairs, flattened.
+fn entry_reply(id: &StreamId, fields: &[(Bytes, Bytes)]) -> DataType {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [DataType::BulkString(field.clone()), DataType::BulkString(value.clone())])
+        .collect();
+    DataType::Array(vec![DataType::BulkString(id.to_string().into()), DataType::Array(fields)])
+}
+
+fn add(map: &mut Keyspace, key: Bytes, id: XAddId, fields: Fields, no_mkstream: bool, trim: Option<StreamTrim>, now: u128) -> StreamResult<DataType> {
+    // The ID gets checked before the stream is created, a bad ID shouldn't leave an empty stream behind
+    let id = match get_stream(map, &key, now)? {
+        Some(stream) => stream.next_id(id),
+        None if no_mkstream => return Ok(DataType::Nil),
+        None => Stream::default().next_id(id),
+    };
+    let id = match id {
+        Ok(id) => id,
+        Err(err) => return Ok(DataType::Error(err)),
+    };
+
+    let stream = get_or_insert_stream(map, &key, now)?;
+    stream.entries.insert(id, fields);
+    stream.last_id = id;
+    stream.entries_added += 1;
+    let trimmed = trim.map_or(0, |trim| stream.trim(trim));
+    map.notify(EventClass::Stream, "xadd", &key);
+    if trimmed > 0 {
+        map.notify(EventClass::Stream, "xtrim", &key);
+    }
+    Ok(DataType::BulkString(id.to_string().into()))
+}
+
+fn run_stream_command(map: &mut Keyspace, cmd: StreamCommand, now: u128) -> StreamResult<DataType> {
+    match cmd {
+        StreamCommand::Add { key, id, fields, no_mkstream, trim } => add(map, key, id, fields, no_mkstream, trim, now),
+        StreamCommand::Range { count: Some(0), .. } => Ok(DataType::Nil),
+        StreamCommand::Range { key, start, end, count, rev } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Array(vec![]));
+            };
+            let entries = stream
+                .range(start, end, rev)
+                .take(count.unwrap_or(usize::MAX))
+                .map(|(id, fields)| entry_reply(id, fields))
+                .collect();
+            Ok(DataType::Array(entries))
+        }
+        StreamCommand::Len { key } => {
+            let len = get_stream(map, &key, now)?.map_or(0, |stream| stream.len());
+            Ok(DataType::Integer(len as i64))
+        }
+        StreamCommand::Trim { key, trim } => {
+            let removed = get_stream(map, &key, now)?.map_or(0, |stream| stream.trim(trim));
+            if removed > 0 {
+                map.notify(EventClass::Stream, "xtrim", &key);
+            }
+            Ok(DataType::Integer(removed as i64))
+        }
+        StreamCommand::Del { key, ids } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Integer(0));
+            };
+            let removed = ids.iter().filter(|id| stream.remove_entry(id)).count();
+            if removed > 0 {
+                map.notify(EventClass::Stream, "xdel", &key);
+            }
+            Ok(DataType::Integer(removed as i64))
+        }
+        StreamCommand::GroupCreate { key, group, id, mkstream, entries_read } => {
+            let stream = match get_stream(map, &key, now)? {
+                Some(stream) => stream,
+                None if mkstream => get_or_insert_stream(map, &key, now)?,
+                None => return Ok(DataType::Error(NO_KEY.into())),
+            };
+            if stream.groups.contains_key(&group) {
+                return Ok(DataType::Error("BUSYGROUP Consumer Group name already exists".into()));
+            }
+            let last_delivered = match id {
+                XReadId::After(id) => id,
+                _ => stream.last_id,
+            };
+            let entries_read = entries_read.or_else(|| stream.entries_read_at(last_delivered));
+            stream.groups.insert(group, ConsumerGroup::new(last_delivered, entries_read));
+            map.notify(EventClass::Stream, "xgroup-create", &key);
+            Ok(DataType::SimpleString("OK".into()))
+        }
+        StreamCommand::GroupSetId { key, group, id, entries_read } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Error(NO_KEY.into()));
+            };
+            let last_delivered = match id {
+                XReadId::After(id) => id,
+                _ => stream.last_id,
+            };
+            let entries_read = entries_read.or_else(|| stream.entries_read_at(last_delivered));
+            let Some(consumer_group) = stream.groups.get_mut(&group) else {
+                return Ok(no_group_for_key(&key, &group));
+            };
+            consumer_group.last_delivered = last_delivered;
+            consumer_group.entries_read = entries_read;
+            map.notify(EventClass::Stream, "xgroup-setid", &key);
+            Ok(DataType::SimpleString("OK".into()))
+        }
+        StreamCommand::GroupDestroy { key, group } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Error(NO_KEY.into()));
+            };
+            let destroyed = stream.groups.remove(&group).is_some();
+            if destroyed {
+                map.notify(EventClass::Stream, "xgroup-destroy", &key);
+            }
+            Ok(DataType::Integer(destroyed as i64))
+        }
+        StreamCommand::GroupCreateConsumer { key, group, consumer } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Error(NO_KEY.into()));
+            };
+            let Some(consumer_group) = stream.groups.get_mut(&group) else {
+                return Ok(no_group_for_key(&key, &group));
+            };
+            let created = !consumer_group.consumers.contains_key(&consumer);
+            consumer_group.consumer(&consumer, now);
+            if created {
+                map.notify(EventClass::Stream, "xgroup-createconsumer", &key);
+            }
+            Ok(DataType::Integer(created as i64))
+        }
+        StreamCommand::GroupDelConsumer { key, group, consumer } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Error(NO_KEY.into()));
+            };
+            let Some(consumer_group) = stream.groups.get_mut(&group) else {
+                return Ok(no_group_for_key(&key, &group));
+            };
+            let Some(removed) = consumer_group.consumers.remove(&consumer) else {
+                return Ok(DataType::Integer(0));
+            };
+            for id in &removed.pending {
+                consumer_group.pending.remove(id);
+            }
+            map.notify(EventClass::Stream, "xgroup-delconsumer", &key);
+            Ok(DataType::Integer(removed.pending.len() as i64))
+        }
+        StreamCommand::Ack { key, group, ids } => {
+            let group = get_stream(map, &key, now)?.and_then(|stream| stream.groups.get_mut(&group));
+            let acknowledged = group.map_or(0, |group| ids.iter().filter(|id| group.acknowledge(id)).count());
+            // Group changes have no keyspace event, WATCH still sees them as writes
+            if acknowledged > 0 {
+                map.touch(&key);
+            }
+            Ok(DataType::Integer(acknowledged as i64))
+        }
+        StreamCommand::Pending { key, group, range } => {
+            let Some(consumer_group) = get_stream(map, &key, now)?.and_then(|stream| stream.groups.get_mut(&group)) else {
+                return Ok(no_group(&key, &group));
+            };
+            Ok(match range {
+                Some(range) => pending_range(consumer_group, range, now),
+                None => pending_summary(consumer_group),
+            })
+        }
+        StreamCommand::Claim { key, group, consumer, min_idle, ids, options } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(no_group(&key, &group));
+            };
+            if !stream.groups.contains_key(&group) {
+                return Ok(no_group(&key, &group));
+            }
+            let reply = claim(stream, &group, &consumer, min_idle, ids, options, now);
+            map.touch(&key);
+            Ok(reply)
+        }
+        StreamCommand::AutoClaim { key, group, consumer, min_idle, start, count, just_id } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(no_group(&key, &group));
+            };
+            if !stream.groups.contains_key(&group) {
+                return Ok(no_group(&key, &group));
+            }
+            let reply = auto_claim(stream, &group, &consumer, min_idle, start, count, just_id, now);
+            map.touch(&key);
+            Ok(reply)
+        }
+        StreamCommand::InfoStream { key } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Error("ERR no such key".into()));
+            };
+            Ok(info_stream(stream))
+        }
+        StreamCommand::InfoGroups { key } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Error("ERR no such key".into()));
+            };
+            let groups = stream
+                .groups
+                .iter()
+                .map(|(name, group)| {
+                    DataType::Map(vec![
+                        (bulk("name"), DataType::BulkString(name.clone())),
+                        (bulk("consumers"), DataType::Integer(group.consumers.len() as i64)),
+                        (bulk("pending"), DataType::Integer(group.pending.len() as i64)),
+                        (bulk("last-delivered-id"), id_reply(&group.last_delivered)),
+                        (bulk("entries-read"), group.entries_read.map_or(DataType::Nil, |read| DataType::Integer(read as i64))),
+                        (bulk("lag"), DataType::Integer(stream.lag(group) as i64)),
+                    ])
+                })
+                .collect();
+            Ok(DataType::Array(groups))
+        }
+        StreamCommand::InfoConsumers { key, group } => {
+            let Some(stream) = get_stream(map, &key, now)? else {
+                return Ok(DataType::Error("ERR no such key".into()));
+            };
+            let Some(consumer_group) = stream.groups.get(&group) else {
+                return Ok(no_group_for_key(&key, &group));
+            };
+            let consumers = consumer_group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    let inactive = consumer.active_time.map_or(-1, |time| now.saturating_sub(time) as i64);
+                    DataType::Map(vec![
+                        (bulk("name"), DataType::BulkString(name.clone())),
+                        (bulk("pending"), DataType::Integer(consumer.pending.len() as i64)),
+                        (bulk("idle"), DataType::Integer(now.saturating_sub(consumer.seen_time) as i64)),
+                        (bulk("inactive"), DataType::Integer(inactive)),
+                    ])
+                })
+                .collect();
+            Ok(DataType::Array(consumers))
+        }
+    }
+}
+
+const NO_KEY: &str = "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+
+fn no_group(key: &[u8], group: &[u8]) -> DataType {
+    DataType::Error(format!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    ))
+}
+
+fn no_group_for_key(key: &[u8], group: &[u8]) -> DataType {
+    DataType::Error(format!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        String::from_utf8_lossy(group),
+        String::from_utf8_lossy(key)
+    ))
+}
+
+fn bulk(value: &'static str) -> DataType {
+    DataType::BulkString(Bytes::from_static(value.as_bytes()))
+}
+
+fn id_reply(id: &StreamId) -> DataType {
+    DataType::BulkString(id.to_string().into())
+}
+
+fn pending_summary(group: &ConsumerGroup) -> DataType {
+    let (Some(first), Some(last)) = (group.pending.keys().next(), group.pending.keys().next_back()) else {
+        return DataType::Array(vec![DataType::Integer(0), DataType::Nil, DataType::Nil, DataType::Nil]);
+    };
+    let consumers = group
+        .consumers
+        .iter()
+        .filter(|(_, consumer)| !consumer.pending.is_empty())
+        .map(|(name, consumer)| {
+            DataType::Array(vec![DataType::BulkString(name.clone()), DataType::BulkString(consumer.pending.len().to_string().into())])
+        })
+        .collect();
+    DataType::Array(vec![DataType::Integer(group.pending.len() as i64), id_reply(first), id_reply(last), DataType::Array(consumers)])
+}
+
+fn pending_range(group: &ConsumerGroup, range: PendingRange, now: u128) -> DataType {
+    if range.start > range.end {
+        return DataType::Array(vec![]);
+    }
+    let entries = group
+        .pending
+        .range(range.start..=range.end)
+        .filter(|(_, entry)| range.consumer.as_ref().is_none_or(|consumer| *consumer == entry.consumer))
+        .filter(|(_, entry)| now.saturating_sub(entry.delivery_time) >= range.min_idle)
+        .take(range.count)
+        .map(|(id, entry)| {
+            DataType::Array(vec![
+                id_reply(id),
+                DataType::BulkString(entry.consumer.clone()),
+                DataType::Integer(now.saturating_sub(entry.delivery_time) as i64),
+                DataType::Integer(entry.delivery_count as i64),
+            ])
+        })
+        .collect();
+    DataType::Array(entries)
+}
+
+fn claim(stream: &mut Stream, group: &Bytes, consumer: &Bytes, min_idle: u128, ids: Vec<StreamId>, options: ClaimOptions, now: u128) -> DataType {
+    let Stream { entries, groups, .. } = stream;
+    let Some(group) = groups.get_mut(group) else {
+        return DataType::Array(vec![]);
+    };
+    group.consumer(consumer, now);
+
+    let mut claimed = Vec::new();
+    for id in ids {
+        let Some(fields) = entries.get(&id) else {
+            // Entries deleted from the stream are dropped from the PEL instead of being claimed
+            group.acknowledge(&id);
+            continue;
+        };
+        let delivery_time = match group.pending.get(&id) {
+            Some(entry) => entry.delivery_time,
+            None if options.force => now,
+            None => continue,
+        };
+        if now.saturating_sub(delivery_time) < min_idle {
+            continue;
+        }
+
+        let entry = group.assign(id, consumer, options.delivery_time.unwrap_or(now), now);
+        match options.retry_count {
+            Some(count) => entry.delivery_count = count,
+            None if !options.just_id => entry.delivery_count += 1,
+            None => {}
+        }
+        claimed.push(match options.just_id {
+            true => id_reply(&id),
+            false => entry_reply(&id, fields),
+        });
+    }
+
+    if let Some(last_id) = options.last_id {
+        group.last_delivered = group.last_delivered.max(last_id);
+    }
+    DataType::Array(claimed)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn auto_claim(stream: &mut Stream, group: &Bytes, consumer: &Bytes, min_idle: u128, start: StreamId, count: usize, just_id: bool, now: u128) -> DataType {
+    let Stream { entries, groups, .. } = stream;
+    let Some(group) = groups.get_mut(group) else {
+        return DataType::Array(vec![]);
+    };
+    group.consumer(consumer, now);
+
+    // Same as redis, at most ten times the count gets looked at so a huge PEL can't stall the server
+    let mut attempts = count.saturating_mul(10);
+    let mut cursor = Some(start);
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    while attempts > 0 && claimed.len() < count {
+        let Some(id) = cursor.and_then(|start| group.pending.range(start..).next().map(|(id, _)| *id)) else {
+            cursor = None;
+            break;
+        };
+        cursor = id.next();
+        attempts -= 1;
+
+        let Some(fields) = entries.get(&id) else {
+            group.acknowledge(&id);
+            deleted.push(id_reply(&id));
+            continue;
+        };
+        if now.saturating_sub(group.pending[&id].delivery_time) < min_idle {
+            continue;
+        }
+        let entry = group.assign(id, consumer, now, now);
+        if !just_id {
+            entry.delivery_count += 1;
+        }
+        claimed.push(match just_id {
+            true => id_reply(&id),
+            false => entry_reply(&id, fields),
+        });
+    }
+
+    // The cursor is 0-0 once the whole PEL has been scanned
+    let next = cursor.and_then(|start| group.pending.range(start..).next().map(|(id, _)| *id)).unwrap_or(StreamId::MIN);
+    DataType::Array(vec![id_reply(&next), DataType::Array(claimed), DataType::Array(deleted)])
+}
+
+fn info_stream(stream: &Stream) -> DataType {
+    let first = stream.entries.first_key_value();
+    let last = stream.entries.last_key_value();
+    DataType::Map(vec![
+        (bulk("length"), DataType::Integer(stream.len() as i64)),
+        (bulk("last-generated-id"), id_reply(&stream.last_id)),
+        (bulk("max-deleted-entry-id"), id_reply(&stream.max_deleted_id)),
+        (bulk("entries-added"), DataType::Integer(stream.entries_added as i64)),
+        (bulk("recorded-first-entry-id"), id_reply(first.map_or(&StreamId::MIN, |(id, _)| id))),
+        (bulk("groups"), DataType::Integer(stream.groups.len() as i64)),
+        (bulk("first-entry"), first.map_or(DataType::Nil, |(id, fields)| entry_reply(id, fields))),
+        (bulk("last-entry"), last.map_or(DataType::Nil, |(id, fields)| entry_reply(id, fields))),
+    ])
+}
+
+pub(crate) fn process_stream(map: &mut Keyspace, cmd: StreamCommand, now: u128) -> Result<DataType, String> {
+    Ok(run_stream_command(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+/// Delivers the entries the group hasn't handed out yet to `read.consumer`.
+fn read_new(stream: &mut Stream, read: &ReadGroup, count: usize, now: u128) -> Vec<DataType> {
+    let Stream { entries, groups, last_id, entries_added, .. } = stream;
+    let Some(group) = groups.get_mut(&read.group) else {
+        return vec![];
+    };
+    group.consumer(&read.consumer, now);
+    let Some(start) = group.last_delivered.next() else {
+        return vec![];
+    };
+
+    let mut reply = Vec::new();
+    for (id, fields) in entries.range(start..).take(count) {
+        group.last_delivered = *id;
+        group.entries_read = group.entries_read.map(|read| read + 1);
+        if !read.no_ack {
+            group.assign(*id, &read.consumer, now, now).delivery_count = 1;
+        }
+        reply.push(entry_reply(id, fields));
+    }
+    if group.last_delivered == *last_id {
+        group.entries_read = Some(*entries_added);
+    }
+    reply
+}
+
+/// The consumer's own pending entries after `start`, without counting it as a new delivery.
+/// Entries deleted from the stream since are sent back with no fields.
+fn read_history(stream: &mut Stream, read: &ReadGroup, start: StreamId, count: usize, now: u128) -> Vec<DataType> {
+    let Stream { entries, groups, .. } = stream;
+    let Some(group) = groups.get_mut(&read.group) else {
+        return vec![];
+    };
+    let consumer = group.consumer(&read.consumer, now);
+    let Some(start) = start.next() else {
+        return vec![];
+    };
+    consumer
+        .pending
+        .range(start..)
+        .take(count)
+        .map(|id| match entries.get(id) {
+            Some(fields) => entry_reply(id, fields),
+            None => DataType::Array(vec![id_reply(id), DataType::Nil]),
+        })
+        .collect()
+}
+
+fn xread(map: &mut impl Keyspaces, cmd: XReadCommand, now: u128) -> StreamResult<DataType> {
+    // Every group gets checked first so a missing one doesn't leave the other streams half read
+    if let Some(read) = &cmd.group {
+        for (key, _) in &cmd.streams {
+            let stream = get_stream(map.keyspace_for(key), key, now)?;
+            if !stream.is_some_and(|stream| stream.groups.contains_key(&read.group)) {
+                return Ok(DataType::Error(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(&read.group)
+                )));
+            }
+        }
+    }
+
+    let count = cmd.count.unwrap_or(usize::MAX);
+    let mut reply = Vec::new();
+    for (key, id) in cmd.streams {
+        let Some(stream) = get_stream(map.keyspace_for(&key), &key, now)? else {
+            continue;
+        };
+        let entries = match (id, &cmd.group) {
+            (XReadId::New, Some(read)) => {
+                let entries = read_new(stream, read, count, now);
+                if !entries.is_empty() {
+                    map.keyspace_for(&key).touch(&key);
+                }
+                entries
+            }
+            // Reading the history always replies for the stream, even with nothing in it
+            (XReadId::After(start), Some(read)) => {
+                let entries = read_history(stream, read, start, count, now);
+                reply.push(DataType::Array(vec![DataType::BulkString(key), DataType::Array(entries)]));
+                continue;
+            }
+            (XReadId::After(start), None) => read_after(stream, start, count),
+            (XReadId::Last, None) => read_after(stream, stream.last_id, count),
+            // The parser doesn't let `>` into XREAD or `$` into XREADGROUP
+            (XReadId::New, None) | (XReadId::Last, Some(_)) => continue,
+        };
+        if !entries.is_empty() {
+            reply.push(DataType::Array(vec![DataType::BulkString(key), DataType::Array(entries)]));
+        }
+    }
+
+    match reply.is_empty() {
+        true => Ok(DataType::NullArray),
+        false => Ok(DataType::Array(reply)),
+    }
+}
+
+fn read_after(stream: &Stream, start: StreamId, count: usize) -> Vec<DataType> {
+    let Some(start) = start.next() else {
+        return vec![];
+    };
+    stream
+        .range(start, StreamId::MAX, false)
+        .take(count)
+        .map(|(id, fields)| entry_reply(id, fields))
+        .collect()
+}
+
+pub(crate) fn process_xread(map: &mut impl Keyspaces, cmd: XReadCommand, now: u128) -> Result<DataType, String> {
+    Ok(xread(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xadd(map: &mut Keyspace, key: &'static str, id: XAddId) -> DataType {
+        let cmd = StreamCommand::Add {
+            key: key.into(),
+            id,
+            fields: vec![("f".into(), "v".into())],
+            no_mkstream: false,
+            trim: None,
+        };
+        process_stream(map, cmd, 0).unwrap()
+    }
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    pub fn test_ids_always_increase() {
+        let mut map = Keyspace::new();
+        assert_eq!(xadd(&mut map, "s", XAddId::Auto { now: 5 }), DataType::BulkString("5-0".into()));
+        assert_eq!(xadd(&mut map, "s", XAddId::Auto { now: 5 }), DataType::BulkString("5-1".into()));
+        // A clock that went backwards doesn't take the IDs with it
+        assert_eq!(xadd(&mut map, "s", XAddId::Auto { now: 3 }), DataType::BulkString("5-2".into()));
+        assert_eq!(xadd(&mut map, "s", XAddId::AutoSequence { ms: 7 }), DataType::BulkString("7-0".into()));
+        assert_eq!(
+            xadd(&mut map, "s", XAddId::Explicit(id(7, 0))),
+            DataType::Error("ERR The ID specified in XADD is equal or smaller than the target stream top item".into())
+        );
+        assert_eq!(xadd(&mut map, "new", XAddId::AutoSequence { ms: 0 }), DataType::BulkString("0-1".into()));
+    }
+
+    #[test]
+    pub fn test_deleting_the_last_entry_keeps_its_id_used() {
+        let mut map = Keyspace::new();
+        xadd(&mut map, "s", XAddId::Explicit(id(1, 1)));
+        let cmd = StreamCommand::Del { key: "s".into(), ids: vec![id(1, 1), id(9, 9)] };
+        assert_eq!(process_stream(&mut map, cmd, 0), Ok(DataType::Integer(1)));
+
+        assert_eq!(process_stream(&mut map, StreamCommand::Len { key: "s".into() }, 0), Ok(DataType::Integer(0)));
+        assert!(matches!(xadd(&mut map, "s", XAddId::Explicit(id(1, 1))), DataType::Error(_)));
+    }
+
+    #[test]
+    pub fn test_trim() {
+        let mut map = Keyspace::new();
+        for ms in 1..=5 {
+            xadd(&mut map, "s", XAddId::Explicit(id(ms, 0)));
+        }
+
+        let trim = StreamTrim { strategy: TrimStrategy::MaxLen(2), limit: Some(2) };
+        assert_eq!(process_stream(&mut map, StreamCommand::Trim { key: "s".into(), trim }, 0), Ok(DataType::Integer(2)));
+        let trim = StreamTrim { strategy: TrimStrategy::MinId(id(5, 0)), limit: None };
+        assert_eq!(process_stream(&mut map, StreamCommand::Trim { key: "s".into(), trim }, 0), Ok(DataType::Integer(2)));
+
+        let cmd = StreamCommand::Range { key: "s".into(), start: StreamId::MIN, end: StreamId::MAX, count: None, rev: true };
+        let DataType::Array(entries) = process_stream(&mut map, cmd, 0).unwrap() else {
+            panic!("Expected an array reply");
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0], entry_reply(&id(5, 0), &[("f".into(), "v".into())]));
+    }
+
+    #[test]
+    pub fn test_xread_skips_streams_without_new_entries() {
+        let mut map = Keyspace::new();
+        xadd(&mut map, "a", XAddId::Explicit(id(1, 0)));
+        xadd(&mut map, "a", XAddId::Explicit(id(2, 0)));
+        xadd(&mut map, "b", XAddId::Explicit(id(1, 0)));
+
+        let cmd = XReadCommand {
+            streams: vec![("a".into(), XReadId::After(id(1, 0))), ("b".into(), XReadId::Last), ("c".into(), XReadId::After(StreamId::MIN))],
+            count: None,
+            group: None,
+        };
+        let expected = DataType::Array(vec![DataType::BulkString("a".into()), DataType::Array(vec![entry_reply(&id(2, 0), &[("f".into(), "v".into())])])]);
+        assert_eq!(process_xread(&mut map, cmd, 0), Ok(DataType::Array(vec![expected])));
+    }
+
+    fn read_group(map: &mut Keyspace, consumer: &'static str, id: XReadId, now: u128) -> DataType {
+        let cmd = XReadCommand {
+            streams: vec![("s".into(), id)],
+            count: None,
+            group: Some(ReadGroup { group: "g".into(), consumer: consumer.into(), no_ack: false }),
+        };
+        process_xread(map, cmd, now).unwrap()
+    }
+
+    fn create_group(map: &mut Keyspace) {
+        let cmd = StreamCommand::GroupCreate { key: "s".into(), group: "g".into(), id: XReadId::After(StreamId::MIN), mkstream: true, entries_read: None };
+        assert_eq!(process_stream(map, cmd, 0), Ok(DataType::SimpleString("OK".into())));
+    }
+
+    #[test]
+    pub fn test_group_delivers_each_entry_once_until_acknowledged() {
+        let mut map = Keyspace::new();
+        create_group(&mut map);
+        xadd(&mut map, "s", XAddId::Explicit(id(1, 0)));
+        xadd(&mut map, "s", XAddId::Explicit(id(2, 0)));
+
+        let DataType::Array(streams) = read_group(&mut map, "alice", XReadId::New, 10) else {
+            panic!("Expected an array reply");
+        };
+        assert_eq!(streams.len(), 1);
+        assert_eq!(read_group(&mut map, "bob", XReadId::New, 10), DataType::NullArray);
+
+        let ack = StreamCommand::Ack { key: "s".into(), group: "g".into(), ids: vec![id(1, 0), id(1, 0)] };
+        assert_eq!(process_stream(&mut map, ack, 20), Ok(DataType::Integer(1)));
+
+        let pending = StreamCommand::Pending { key: "s".into(), group: "g".into(), range: None };
+        assert_eq!(
+            process_stream(&mut map, pending, 20),
+            Ok(DataType::Array(vec![
+                DataType::Integer(1),
+                id_reply(&id(2, 0)),
+                id_reply(&id(2, 0)),
+                DataType::Array(vec![DataType::Array(vec![DataType::BulkString("alice".into()), DataType::BulkString("1".into())])]),
+            ]))
+        );
+
+        // Alice's history still has the unacknowledged entry
+        let history = DataType::Array(vec![DataType::Array(vec![
+            DataType::BulkString("s".into()),
+            DataType::Array(vec![entry_reply(&id(2, 0), &[("f".into(), "v".into())])]),
+        ])]);
+        assert_eq!(read_group(&mut map, "alice", XReadId::After(StreamId::MIN), 30), history);
+    }
+
+    #[test]
+    pub fn test_claim_moves_idle_entries() {
+        let mut map = Keyspace::new();
+        create_group(&mut map);
+        xadd(&mut map, "s", XAddId::Explicit(id(1, 0)));
+        xadd(&mut map, "s", XAddId::Explicit(id(2, 0)));
+        read_group(&mut map, "alice", XReadId::New, 0);
+        process_stream(&mut map, StreamCommand::Del { key: "s".into(), ids: vec![id(2, 0)] }, 0).unwrap();
+
+        let claim = |min_idle| StreamCommand::AutoClaim {
+            key: "s".into(),
+            group: "g".into(),
+            consumer: "bob".into(),
+            min_idle,
+            start: StreamId::MIN,
+            count: 10,
+            just_id: true,
+        };
+        assert_eq!(
+            process_stream(&mut map, claim(100), 50),
+            Ok(DataType::Array(vec![id_reply(&StreamId::MIN), DataType::Array(vec![]), DataType::Array(vec![id_reply(&id(2, 0))])]))
+        );
+        assert_eq!(
+            process_stream(&mut map, claim(100), 100),
+            Ok(DataType::Array(vec![id_reply(&StreamId::MIN), DataType::Array(vec![id_reply(&id(1, 0))]), DataType::Array(vec![])]))
+        );
+
+        let range = PendingRange { min_idle: 0, start: StreamId::MIN, end: StreamId::MAX, count: 10, consumer: None };
+        let pending = StreamCommand::Pending { key: "s".into(), group: "g".into(), range: Some(range) };
+        assert_eq!(
+            process_stream(&mut map, pending, 150),
+            Ok(DataType::Array(vec![DataType::Array(vec![
+                id_reply(&id(1, 0)),
+                DataType::BulkString("bob".into()),
+                DataType::Integer(50),
+                DataType::Integer(1),
+            ])]))
+        );
+    }
+}
@@ -1,88 +1,83 @@
-use crate::{commands::{Command, SetCommand, SetExistingOptions}, data::shared::hashy, datatypes::DataType};
-use std::{cell::RefCell, collections::{hash_map::DefaultHasher, HashMap}, hash::{Hash, Hasher}, sync::mpsc::{channel, Receiver}, thread::{self, JoinHandle}};
+use bytes::Bytes;
+use crate::{commands::Command, data::shared::{current_unix_timestamp_millis, dump_reply, key_slot}, log::{log, Level}, datatypes::{DataType, StorageRecord}};
+use std::{num::NonZeroUsize, panic::{self, AssertUnwindSafe}, sync::{mpsc::{channel, Receiver, RecvTimeoutError}, Arc}, thread::{self, JoinHandle}, time::Instant};
 use std::sync::mpsc::Sender;
 use std::thread::available_parallelism;
 use tokio::sync::oneshot;
 
-use super::typesd::StorageEngine;
-
-#[derive(Debug, Clone)]
-enum StorageValue {
-    String(String),
-}
-
-#[derive(Debug, Clone)]
-struct StorageRecord {
-    value: StorageValue,
-    ttl: Option<u128>,
-}
+use super::{blocking::{WaitRegistry, Wakes}, dispatch::execute, keyspace::{active_expire_interval, Keyspace, Keyspaces}, notify::Notifier, transaction::{execute_transaction, unwatch_keys, watch_keys, WatchedKey}, typesd::{EngineFuture, StorageEngine, Unkeyed}};
 
 struct ThreadEngineInternal {
-    map: HashMap<String, StorageRecord>,
+    map: Keyspace,
 }
 
 impl ThreadEngineInternal{
-    fn new() -> ThreadEngineInternal {
+    fn new(notifier: Arc<Notifier>) -> ThreadEngineInternal {
         ThreadEngineInternal {
-            map: HashMap::new()
+            map: Keyspace::with_notifier(notifier)
         }
     }
 
-    pub fn process_set(&mut self, cmd: SetCommand) -> Result<DataType, String> {
-        let map = &mut self.map;
-        let previous_obj: Option<&StorageRecord> = map.get(&cmd.key);
-        let previous_value = match (cmd.get_previous_value, previous_obj) {
-            (true, Some(stored_value)) => {
-                let StorageValue::String(x) = &stored_value.value else {
-                    // TODO: Update the error message to match redis message
-                    return Ok(DataType::Error(format!(
-                        "Error: Expected key {} to be of type String",
-                        cmd.key
-                    )));
-                };
-                Some(x.to_string())
-            }
-            _ => None,
-        };
-
-        let previous_ttl = match (cmd.keep_previous_ttl, previous_obj) {
-            (true, Some(stored_value)) => stored_value.ttl,
-            _ => None,
-        };
-
-        let ttl = previous_ttl.or(cmd.expiration);
-        let storage_record = StorageRecord {
-            ttl,
-            value: StorageValue::String(cmd.value),
-        };
-
-        let should_insert = match (cmd.set_existing, previous_obj) {
-            (None, _) => true,
-            (Some(SetExistingOptions::OnlySetIfExists), Some(_)) => true,
-            (Some(SetExistingOptions::OnlySetIfNotExists), None) => true,
-            _ => false,
-        };
-
-        if should_insert {
-            map.insert(cmd.key, storage_record);
+    fn handle(&mut self, msg: ThreadEngineMessage) {
+        match msg {
+            ThreadEngineMessage::Process(msg) => {
+                let res = execute(&mut self.map, msg.command, current_unix_timestamp_millis());
+                let _ = msg.response.send(res);
+            },
+            ThreadEngineMessage::Records { response } => {
+                let _ = response.send(self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect());
+            },
+            ThreadEngineMessage::Watch { keys, response } => {
+                let _ = response.send(watch_keys(&mut self.map, keys, current_unix_timestamp_millis()));
+            },
+            ThreadEngineMessage::Unwatch { watched } => {
+                unwatch_keys(&mut self.map, &watched);
+            },
+            ThreadEngineMessage::Park { response } => {
+                let (back, returned) = channel();
+                // If nobody is waiting for it any more the keyspace comes straight back on drop
+                let _ = response.send(Parked { map: std::mem::take(&mut self.map), back });
+                if let Ok(map) = returned.recv() {
+                    self.map = map;
+                }
+            },
+            #[cfg(test)]
+            ThreadEngineMessage::Panic { response } => {
+                let _response = response;
+                panic!("Expected to be caught by the thread");
+            },
         }
+    }
+}
 
-        return match previous_value {
-            Some(value) => Ok(DataType::BulkString(value)),
-            None => Ok(DataType::SimpleString("OK".into())),
-        };
+/// A thread's keyspace, lent out while the thread waits for it. Dropping it hands the keyspace
+/// back and lets the thread carry on, also when whoever parked the thread went away early.
+pub(crate) struct Parked {
+    map: Keyspace,
+    back: Sender<Keyspace>,
+}
+
+impl Drop for Parked {
+    fn drop(&mut self) {
+        let _ = self.back.send(std::mem::take(&mut self.map));
     }
+}
 
-    pub fn process_get(&self, key: String) -> Result<DataType, String> {
-        let map = &self.map;
-        let val = map.get(&key);
-        match val {
-            Some(StorageRecord{
-                value: StorageValue::String(x),
-                ..
-            }) => Ok(DataType::BulkString(x.into())),
-            None => Ok(DataType::Nil),
-        }
+/// The keyspaces of the threads parked for a command whose keys live on several threads.
+struct ParkedThreads {
+    thread_count: usize,
+    threads: Vec<(usize, Parked)>,
+}
+
+impl Keyspaces for ParkedThreads {
+    fn keyspace_for(&mut self, key: &[u8]) -> &mut Keyspace {
+        let index = key_slot(key, self.thread_count);
+        let (_, parked) = self
+            .threads
+            .iter_mut()
+            .find(|(thread, _)| *thread == index)
+            .expect("Expected the thread for the key to be parked");
+        &mut parked.map
     }
 }
 
@@ -91,22 +86,30 @@ pub struct ThreadEngine {
 }
 
 impl ThreadEngine {
-    pub fn new(receiver: Receiver<ThreadEngineProcessMessage>) -> ThreadEngine {
+    pub(crate) fn new(receiver: Receiver<ThreadEngineMessage>, notifier: Arc<Notifier>) -> ThreadEngine {
         let handle = thread::spawn(move || {
-            let mut interal_thread_engine = ThreadEngineInternal::new();
-            while let Ok(msg) = receiver.recv() {
-                match msg.command {
-                    Command::Get { key } => {
-                        let res = interal_thread_engine.process_get(key);
-                        msg.response.send(res).unwrap(); // TODO Fix this
-                    },
-                    Command::Set(set_cmd) => {
-                        let res = interal_thread_engine.process_set(set_cmd);
-                        msg.response.send(res).unwrap(); // TODO Fix this
-                    },
-                    _ => {
-                        todo!()
+            let mut interal_thread_engine = ThreadEngineInternal::new(notifier);
+            let mut next_expire_cycle = Instant::now() + active_expire_interval();
+            loop {
+                // Wake up for the expiry cycle even when no commands are coming in
+                let msg = match receiver.recv_timeout(next_expire_cycle.saturating_duration_since(Instant::now())) {
+                    Ok(msg) => Some(msg),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                // A bug in one command shouldn't take every key on the thread down with it. The
+                // reply channel is dropped while unwinding, so whoever sent it gets an error.
+                let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                    if Instant::now() >= next_expire_cycle {
+                        interal_thread_engine.map.active_expire_cycle(current_unix_timestamp_millis());
+                        next_expire_cycle = Instant::now() + active_expire_interval();
+                    }
+                    if let Some(msg) = msg {
+                        interal_thread_engine.handle(msg);
                     }
+                }));
+                if res.is_err() {
+                    log(Level::Warning, "A thread engine thread recovered from a panic");
                 }
             }
         });
@@ -115,6 +118,12 @@ impl ThreadEngine {
             handle,
         }
     }
+
+    fn join(self) {
+        if self.handle.join().is_err() {
+            log(Level::Warning, "A thread engine thread panicked while shutting down");
+        }
+    }
 }
 
 pub struct ThreadEngineProcessMessage{
@@ -122,70 +131,398 @@ pub struct ThreadEngineProcessMessage{
     response: oneshot::Sender<Result<DataType, String>>,
 }
 
+pub(crate) enum ThreadEngineMessage {
+    Process(Box<ThreadEngineProcessMessage>),
+    /// Hands back a copy of every record on the thread.
+    Records {
+        response: oneshot::Sender<Vec<(Bytes, StorageRecord)>>,
+    },
+    Watch {
+        keys: Vec<Bytes>,
+        response: oneshot::Sender<Vec<WatchedKey>>,
+    },
+    Unwatch {
+        watched: Vec<WatchedKey>,
+    },
+    /// Lends the thread's keyspace out and waits until it comes back.
+    Park {
+        response: oneshot::Sender<Parked>,
+    },
+    /// Panics while holding on to the reply channel, like a command with a bug in it would.
+    #[cfg(test)]
+    Panic {
+        response: oneshot::Sender<()>,
+    },
+}
+
 struct ThreadEngineRecord{
     engine: ThreadEngine,
-    sender: Sender<ThreadEngineProcessMessage>,
+    sender: Sender<ThreadEngineMessage>,
 }
 
 pub struct ThreadEngineManager {
-    parallelism_count: u64,
     keymap: Vec<ThreadEngineRecord>,
+    waiters: WaitRegistry,
+}
+
+impl Default for ThreadEngineManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ThreadEngineManager {
     pub fn new() -> ThreadEngineManager {
-        let default_parallelism_approx = available_parallelism().unwrap().get();
-        let mut v = Vec::with_capacity(default_parallelism_approx);
+        Self::with_notifier(Arc::default())
+    }
+
+    /// Keyspace events from every thread go out through `notifier`.
+    pub fn with_notifier(notifier: Arc<Notifier>) -> ThreadEngineManager {
+        let default_parallelism_approx = available_parallelism().map(NonZeroUsize::get).unwrap_or(1);
+        Self::spawn(default_parallelism_approx, notifier)
+    }
+
+    /// Spreads the keys over `threads` threads instead of one per core.
+    pub fn with_threads(threads: usize) -> ThreadEngineManager {
+        Self::spawn(threads.max(1), Arc::default())
+    }
+
+    fn spawn(threads: usize, notifier: Arc<Notifier>) -> ThreadEngineManager {
+        let mut v = Vec::with_capacity(threads);
 
-        for _ in 0..default_parallelism_approx {
-            let (sender, receiver) = channel::<ThreadEngineProcessMessage>();
-            let engine = ThreadEngine::new(receiver);
+        for _ in 0..threads {
+            let (sender, receiver) = channel::<ThreadEngineMessage>();
+            let engine = ThreadEngine::new(receiver, notifier.clone());
             v.push(ThreadEngineRecord { engine, sender });
         }
 
         ThreadEngineManager {
-            parallelism_count: default_parallelism_approx as u64,
             keymap: v,
+            waiters: WaitRegistry::default(),
         }
     }
 }
 
+/// Disconnecting every channel stops the threads once they're through what was already sent,
+/// then they're waited on so nothing is still running when the engine is gone.
+impl Drop for ThreadEngineManager {
+    fn drop(&mut self) {
+        let engines = self.keymap.drain(..).map(|record| record.engine).collect::<Vec<_>>();
+        engines.into_iter().for_each(ThreadEngine::join);
+    }
+}
+
 impl ThreadEngineManager {
-    fn get_engine_for_matching_thread(&self, str: &str) -> &ThreadEngineRecord {
-        let hash = hashy(str);
-        let index = (hash % self.parallelism_count) as usize;
-        // println!("hash: {str}: {index}({hash}) (");
-        &self.keymap[index]
+    fn get_thread_index(&self, str: &[u8]) -> usize {
+        key_slot(str, self.keymap.len())
+    }
+
+    fn start_message_on<T>(
+        &self,
+        thread: usize,
+        message: impl FnOnce(oneshot::Sender<T>) -> ThreadEngineMessage,
+    ) -> Result<oneshot::Receiver<T>, String> {
+        let (sender, receiver) = oneshot::channel::<T>();
+        self.keymap[thread].sender.send(message(sender)).map_err(|e| format!("An error occurred sending a message to the thread engine: {}", e))?;
+        Ok(receiver)
+    }
+
+    async fn wait_for_reply<T>(receiver: oneshot::Receiver<T>) -> Result<T, String> {
+        receiver.await.map_err(|e| format!("An error occurred waiting on a response from the thread engine: {}", e))
+    }
+
+    /// Sends the command to the thread that owns all of its keys and waits for its reply.
+    async fn send_command(&self, thread: usize, command: Command) -> Result<DataType, String> {
+        let woken = command.woken_key().cloned();
+        let reply = self.start_message_on(thread, |response| ThreadEngineMessage::Process(Box::new(ThreadEngineProcessMessage {
+            command,
+            response,
+        })))?;
+        let res = Self::wait_for_reply(reply).await?;
+        self.waiters.signal_write(woken, &res);
+        res
+    }
+
+    /// Sends a message to every thread and gathers their replies, for commands that can touch
+    /// any key.
+    async fn broadcast<T>(&self, message: impl Fn(oneshot::Sender<T>) -> ThreadEngineMessage) -> Result<Vec<T>, String> {
+        let pending = (0..self.keymap.len())
+            .map(|thread| self.start_message_on(thread, &message))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut replies = Vec::with_capacity(pending.len());
+        for reply in pending {
+            replies.push(Self::wait_for_reply(reply).await?);
+        }
+        Ok(replies)
+    }
+
+    /// Groups the keys by the thread that owns them, leaving out threads with none.
+    fn keys_by_thread<T>(&self, items: impl IntoIterator<Item = T>, key: impl Fn(&T) -> &Bytes) -> Vec<(usize, Vec<T>)> {
+        let mut by_thread: Vec<Vec<T>> = (0..self.keymap.len()).map(|_| Vec::new()).collect();
+        for item in items {
+            by_thread[self.get_thread_index(key(&item))].push(item);
+        }
+        by_thread.into_iter().enumerate().filter(|(_, items)| !items.is_empty()).collect()
+    }
+
+    /// The threads the keys live on, in index order.
+    fn threads_for<'a>(&self, keys: impl IntoIterator<Item = &'a Bytes>) -> Vec<usize> {
+        let mut threads = keys.into_iter().map(|key| self.get_thread_index(key)).collect::<Vec<usize>>();
+        threads.sort_unstable();
+        threads.dedup();
+        threads
+    }
+
+    /// Parks the threads one after the other in index order, so two commands parking some of the
+    /// same threads can't deadlock on each other. They stay parked until the result is dropped.
+    async fn park(&self, threads: Vec<usize>) -> Result<ParkedThreads, String> {
+        let mut parked = Vec::with_capacity(threads.len());
+        for thread in threads {
+            let reply = self.start_message_on(thread, |response| ThreadEngineMessage::Park { response })?;
+            parked.push((thread, Self::wait_for_reply(reply).await?));
+        }
+        Ok(ParkedThreads { thread_count: self.keymap.len(), threads: parked })
     }
 }
 
 impl StorageEngine for ThreadEngineManager {
-    async fn process_set(&self, cmd: SetCommand) -> Result<DataType, String> {
-        let engine = self.get_engine_for_matching_thread(&cmd.key);
-        let (sender, receiver) = oneshot::channel::<Result<DataType, String>>();
-        engine.sender.send(ThreadEngineProcessMessage {
-            command: Command::Set(cmd),
-            response: sender,
-        }).map_err(|e| format!("An error occurred sending a message to the thread engine: {}", e.to_string()))?;
+    /// Commands with every key on one thread are sent there to run. For the others the threads
+    /// their keys live on are parked and they run here, as atomic as they are on a single thread.
+    fn process(&self, command: Command) -> EngineFuture<'_, DataType> {
+        Box::pin(async move {
+            if let Command::Dump = command {
+                let records = self.broadcast(|response| ThreadEngineMessage::Records { response }).await?;
+                return Ok(dump_reply(&records.into_iter().flatten().collect()));
+            }
+
+            let threads = self.threads_for(command.keys());
+            match threads.as_slice() {
+                [] => Err("Expected a command that works on keys".into()),
+                [thread] => self.send_command(*thread, command).await,
+                _ => {
+                    let woken = command.woken_key().cloned();
+                    let mut parked = self.park(threads).await?;
+                    let res = execute(&mut parked, command, current_unix_timestamp_millis());
+                    drop(parked);
+                    self.waiters.signal_write(woken, &res);
+                    res
+                }
+            }
+        })
+    }
+
+    fn process_watch(&self, keys: Vec<Bytes>) -> EngineFuture<'_, Vec<WatchedKey>> {
+        Box::pin(async move {
+            let mut pending = Vec::new();
+            for (thread, keys) in self.keys_by_thread(keys, |key| key) {
+                pending.push(self.start_message_on(thread, |response| ThreadEngineMessage::Watch { keys, response })?);
+            }
 
-        let output = receiver.await.map_err(|e| format!("An error occurred waiting on a response from the thread engine: {}", e.to_string()))?;
-        return output;
+            let mut watched = Vec::new();
+            for reply in pending {
+                watched.extend(Self::wait_for_reply(reply).await?);
+            }
+            Ok(watched)
+        })
     }
 
-    async fn process_get(&self, key: String) -> Result<DataType, String> {
-        let engine = self.get_engine_for_matching_thread(&key);
-        let (sender, receiver) = oneshot::channel::<Result<DataType, String>>();
-        engine.sender.send(ThreadEngineProcessMessage {
-            command: Command::Get { key: key },
-            response: sender,
-        }).map_err(|e| format!("An error occurred sending a message to the thread engine: {}", e.to_string()))?;
+    /// The threads the transaction's keys live on, watched ones included, are parked and it runs
+    /// here, so the commands that don't work on keys can run in between and none of the threads
+    /// does anything else meanwhile.
+    fn process_transaction<'a>(&'a self, commands: Vec<Command>, watched: &'a [WatchedKey], unkeyed: Unkeyed<'a>) -> EngineFuture<'a, DataType> {
+        Box::pin(async move {
+            let woken = commands.iter().map(|cmd| cmd.woken_key().cloned()).collect();
+            let threads = self.threads_for(commands.iter().flat_map(|cmd| cmd.keys()).chain(watched.iter().map(|watched| &watched.key)));
+            let mut parked = self.park(threads).await?;
+            let res = execute_transaction(&mut parked, commands, watched, current_unix_timestamp_millis(), unkeyed);
+            drop(parked);
+            self.waiters.signal_transaction(woken, &res);
+            res
+        })
+    }
 
-        let output = receiver.await.map_err(|e| format!("An error occurred waiting on a response from the thread engine: {}", e.to_string()))?;
-        return output;
+    fn unwatch(&self, watched: &[WatchedKey]) {
+        for (thread, watched) in self.keys_by_thread(watched.iter().cloned(), |watched| &watched.key) {
+            // Nothing to wait for, if the thread is gone so are its watched keys
+            let _ = self.keymap[thread].sender.send(ThreadEngineMessage::Unwatch { watched });
+        }
     }
 
-    async fn process_dump(&self) -> Result<DataType, String> {
-        todo!()
+    fn waiters(&self) -> &WaitRegistry {
+        &self.waiters
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::commands::{Aggregate, CopyCommand, IncrBy, IncrCommand, LMoveCommand, ListCommand, ListEnd, RenameCommand, SMoveCommand, SetAlgebraCommand, SetAlgebraOutput, SetCommand, SetOperation, SetTypeCommand, StreamCommand, StreamId, XAddId, XReadCommand, XReadId, ZAddOptions, ZSetCommand, ZStoreCommand};
+    use crate::data::shared::WRONGTYPE;
+
+    use super::*;
+
+    /// One key on each thread of the engine.
+    fn key_per_thread(engine: &ThreadEngineManager) -> Vec<Bytes> {
+        let mut keys: Vec<Option<Bytes>> = vec![None; engine.keymap.len()];
+        for key in (0..).map(|x| Bytes::from(format!("key{}", x))) {
+            let thread = engine.get_thread_index(&key);
+            keys[thread].get_or_insert(key);
+            if keys.iter().all(Option::is_some) {
+                return keys.into_iter().flatten().collect();
+            }
+        }
+        unreachable!()
+    }
+
+    fn set(key: &Bytes, value: &'static str) -> Command {
+        Command::Set(SetCommand { key: key.clone(), value: value.into(), ..Default::default() })
+    }
+
+    #[tokio::test]
+    pub async fn test_transaction_across_threads() {
+        let engine = ThreadEngineManager::with_threads(4);
+        let keys = key_per_thread(&engine);
+        let watched = engine.process_watch(vec![keys[0].clone()]).await.unwrap();
+        let commands = keys.iter().map(|key| Command::Incr(IncrCommand { key: key.clone(), by: IncrBy::Integer(1) })).collect();
+        assert_eq!(engine.process_transaction(commands, &watched, &mut |_| DataType::Nil).await, Ok(DataType::Array(vec![DataType::Integer(1), DataType::Integer(1), DataType::Integer(1), DataType::Integer(1)])));
+
+        engine.process(set(&keys[0], "5")).await.unwrap();
+        let commands = keys.iter().map(|key| set(key, "x")).collect();
+        assert_eq!(engine.process_transaction(commands, &watched, &mut |_| DataType::Nil).await, Ok(DataType::NullArray));
+        assert_eq!(engine.process(Command::Get { key: keys[3].clone() }).await, Ok(DataType::BulkString("1".into())));
+    }
+
+    #[tokio::test]
+    pub async fn test_rename_across_threads() {
+        let engine = ThreadEngineManager::with_threads(2);
+        let keys = key_per_thread(&engine);
+        let (source, destination) = (&keys[0], &keys[1]);
+        let rename = |only_if_new| Command::Rename(RenameCommand { source: source.clone(), destination: destination.clone(), only_if_new });
+
+        engine.process(set(source, "1")).await.unwrap();
+        engine.process(set(destination, "2")).await.unwrap();
+        assert_eq!(engine.process(rename(true)).await, Ok(DataType::Integer(0)));
+        assert_eq!(engine.process(Command::Get { key: source.clone() }).await, Ok(DataType::BulkString("1".into())));
+
+        assert_eq!(engine.process(rename(false)).await, Ok(DataType::SimpleString("OK".into())));
+        assert_eq!(engine.process(Command::Get { key: source.clone() }).await, Ok(DataType::Nil));
+        assert_eq!(engine.process(Command::Get { key: destination.clone() }).await, Ok(DataType::BulkString("1".into())));
+        assert_eq!(engine.process(rename(false)).await, Ok(DataType::Error("ERR no such key".into())));
+    }
+
+    #[tokio::test]
+    pub async fn test_copy_across_threads() {
+        let engine = ThreadEngineManager::with_threads(2);
+        let keys = key_per_thread(&engine);
+        let copy = |replace| Command::Copy(CopyCommand { source: keys[0].clone(), destination: keys[1].clone(), replace });
+
+        assert_eq!(engine.process(copy(false)).await, Ok(DataType::Integer(0)));
+        engine.process(set(&keys[0], "1")).await.unwrap();
+        engine.process(set(&keys[1], "2")).await.unwrap();
+        assert_eq!(engine.process(copy(false)).await, Ok(DataType::Integer(0)));
+        assert_eq!(engine.process(copy(true)).await, Ok(DataType::Integer(1)));
+        assert_eq!(engine.process(Command::Get { key: keys[0].clone() }).await, Ok(DataType::BulkString("1".into())));
+        assert_eq!(engine.process(Command::Get { key: keys[1].clone() }).await, Ok(DataType::BulkString("1".into())));
+    }
+
+    #[tokio::test]
+    pub async fn test_lmove_across_threads() {
+        let engine = ThreadEngineManager::with_threads(2);
+        let keys = key_per_thread(&engine);
+        let push = Command::List(ListCommand::Push { key: keys[0].clone(), end: ListEnd::Right, values: vec!["a".into(), "b".into()], only_if_exists: false });
+        engine.process(push).await.unwrap();
+        let lmove = |source: &Bytes, destination: &Bytes| Command::LMove(LMoveCommand { source: source.clone(), destination: destination.clone(), from: ListEnd::Left, to: ListEnd::Right });
+
+        assert_eq!(engine.process(lmove(&keys[0], &keys[1])).await, Ok(DataType::BulkString("a".into())));
+        assert_eq!(engine.process(Command::List(ListCommand::Len { key: keys[1].clone() })).await, Ok(DataType::Integer(1)));
+
+        // A destination of the wrong type leaves the source alone
+        engine.process(set(&keys[1], "x")).await.unwrap();
+        assert_eq!(engine.process(lmove(&keys[0], &keys[1])).await, Ok(DataType::Error(WRONGTYPE.into())));
+        assert_eq!(engine.process(Command::List(ListCommand::Len { key: keys[0].clone() })).await, Ok(DataType::Integer(1)));
+    }
+
+    #[tokio::test]
+    pub async fn test_smove_and_set_algebra_across_threads() {
+        let engine = ThreadEngineManager::with_threads(3);
+        let keys = key_per_thread(&engine);
+        let add = |key: &Bytes, members: &[&'static str]| Command::SetType(SetTypeCommand::Add { key: key.clone(), members: members.iter().map(|member| Bytes::from(*member)).collect() });
+        engine.process(add(&keys[0], &["a", "b", "c"])).await.unwrap();
+        engine.process(add(&keys[1], &["b", "c", "d"])).await.unwrap();
+
+        let inter_card = SetAlgebraCommand { operation: SetOperation::Inter, keys: keys[..2].to_vec(), output: SetAlgebraOutput::Cardinality { limit: 0 } };
+        assert_eq!(engine.process(Command::SetAlgebra(inter_card)).await, Ok(DataType::Integer(2)));
+        let union_store = SetAlgebraCommand { operation: SetOperation::Union, keys: keys[..2].to_vec(), output: SetAlgebraOutput::Store(keys[2].clone()) };
+        assert_eq!(engine.process(Command::SetAlgebra(union_store)).await, Ok(DataType::Integer(4)));
+
+        let smove = Command::SMove(SMoveCommand { source: keys[0].clone(), destination: keys[1].clone(), member: "a".into() });
+        assert_eq!(engine.process(smove).await, Ok(DataType::Integer(1)));
+        assert_eq!(engine.process(Command::SetType(SetTypeCommand::Card { key: keys[0].clone() })).await, Ok(DataType::Integer(2)));
+        assert_eq!(engine.process(Command::SetType(SetTypeCommand::Card { key: keys[1].clone() })).await, Ok(DataType::Integer(4)));
+    }
+
+    #[tokio::test]
+    pub async fn test_zunionstore_across_threads() {
+        let engine = ThreadEngineManager::with_threads(3);
+        let keys = key_per_thread(&engine);
+        for (key, score) in keys[..2].iter().zip([1.0, 2.0]) {
+            let add = ZSetCommand::Add { key: key.clone(), options: ZAddOptions::default(), members: vec![(score, "m".into())] };
+            engine.process(Command::ZSet(add)).await.unwrap();
+        }
+
+        let store = ZStoreCommand { destination: keys[2].clone(), operation: SetOperation::Union, keys: keys[..2].to_vec(), weights: vec![], aggregate: Aggregate::Sum };
+        assert_eq!(engine.process(Command::ZStore(store)).await, Ok(DataType::Integer(1)));
+        assert_eq!(engine.process(Command::ZSet(ZSetCommand::Score { key: keys[2].clone(), member: "m".into() })).await, Ok(DataType::Double(3.0)));
+    }
+
+    #[tokio::test]
+    pub async fn test_xread_across_threads() {
+        let engine = ThreadEngineManager::with_threads(2);
+        let keys = key_per_thread(&engine);
+        for key in &keys {
+            let add = StreamCommand::Add { key: key.clone(), id: XAddId::Explicit(StreamId { ms: 1, seq: 0 }), fields: vec![("f".into(), "v".into())], no_mkstream: false, trim: None };
+            engine.process(Command::Stream(add)).await.unwrap();
+        }
+
+        let streams = keys.iter().map(|key| (key.clone(), XReadId::After(StreamId::MIN))).collect();
+        let entry = || DataType::Array(vec![DataType::BulkString("1-0".into()), DataType::Array(vec![DataType::BulkString("f".into()), DataType::BulkString("v".into())])]);
+        let expected = keys
+            .iter()
+            .map(|key| DataType::Array(vec![DataType::BulkString(key.clone()), DataType::Array(vec![entry()])]))
+            .collect();
+        assert_eq!(engine.process(Command::XRead(XReadCommand { streams, count: None, group: None })).await, Ok(DataType::Array(expected)));
+    }
+
+    #[tokio::test]
+    pub async fn test_thread_survives_a_panic() {
+        let engine = ThreadEngineManager::with_threads(2);
+        let keys = key_per_thread(&engine);
+        engine.process(set(&keys[0], "1")).await.unwrap();
+
+        let thread = engine.get_thread_index(&keys[0]);
+        let reply = engine.start_message_on(thread, |response| ThreadEngineMessage::Panic { response }).unwrap();
+        assert!(ThreadEngineManager::wait_for_reply(reply).await.is_err());
+
+        assert_eq!(engine.process(Command::Get { key: keys[0].clone() }).await, Ok(DataType::BulkString("1".into())));
+        let rename = RenameCommand { source: keys[0].clone(), destination: keys[1].clone(), only_if_new: false };
+        assert_eq!(engine.process(Command::Rename(rename)).await, Ok(DataType::SimpleString("OK".into())));
+        assert_eq!(engine.process(Command::Get { key: keys[1].clone() }).await, Ok(DataType::BulkString("1".into())));
+    }
+
+    #[tokio::test]
+    pub async fn test_dump_and_shutdown() {
+        let engine = ThreadEngineManager::new();
+        let keys = (0..16).map(|x| Bytes::from(format!("key{}", x))).collect::<Vec<_>>();
+        for key in &keys {
+            let set = SetCommand { key: key.clone(), value: "1".into(), ..Default::default() };
+            assert_eq!(engine.process(Command::Set(set)).await, Ok(DataType::SimpleString("OK".into())));
+        }
+        let Ok(DataType::BulkString(dump)) = engine.process(Command::Dump).await else {
+            panic!("Expected DUMP to reply with the records");
+        };
+        let dump = String::from_utf8_lossy(&dump);
+        assert!(keys.iter().all(|key| dump.contains(&format!("{:?}", key))));
+        assert_eq!(engine.process(Command::Del { keys, unlink: false }).await, Ok(DataType::Integer(16)));
+        drop(engine);
+    }
+}
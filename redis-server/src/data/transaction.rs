@@ -0,0 +1,97 @@
+use bytes::Bytes;
+
+use crate::{commands::Command, datatypes::DataType};
+
+use super::{dispatch::execute, keyspace::Keyspaces, typesd::Unkeyed};
+
+/// A key as it was when the client WATCHed it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchedKey {
+    pub(crate) key: Bytes,
+    pub(crate) version: u64,
+}
+
+pub(crate) fn watch_keys(map: &mut impl Keyspaces, keys: Vec<Bytes>, now: u128) -> Vec<WatchedKey> {
+    keys.into_iter()
+        .map(|key| {
+            let version = map.keyspace_for(&key).watch(&key, now);
+            WatchedKey { key, version }
+        })
+        .collect()
+}
+
+pub(crate) fn unwatch_keys(map: &mut impl Keyspaces, watched: &[WatchedKey]) {
+    for watched in watched {
+        map.keyspace_for(&watched.key).unwatch(&watched.key);
+    }
+}
+
+/// EXEC once the caller holds every keyspace involved. If a watched key changed nothing runs
+/// and the reply is a null array, otherwise it's the reply of each command in turn.
+pub(crate) fn execute_transaction(map: &mut impl Keyspaces, commands: Vec<Command>, watched: &[WatchedKey], now: u128, unkeyed: Unkeyed<'_>) -> Result<DataType, String> {
+    let changed = watched
+        .iter()
+        .any(|watched| map.keyspace_for(&watched.key).version(&watched.key, now) != Some(watched.version));
+    if changed {
+        return Ok(DataType::NullArray);
+    }
+
+    let replies = commands
+        .into_iter()
+        .map(|command| match command.works_on_keys() {
+            true => execute(map, command, now),
+            false => Ok(unkeyed(command)),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(DataType::Array(replies))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{commands::{IncrBy, IncrCommand, ListCommand, ListEnd, SetCommand, SetExistingOptions}, data::keyspace::Keyspace};
+
+    use super::*;
+
+    fn incr(key: &'static str) -> Command {
+        Command::Incr(IncrCommand { key: key.into(), by: IncrBy::Integer(1) })
+    }
+
+    #[test]
+    pub fn test_write_to_a_watched_key_fails_the_transaction() {
+        let mut map = Keyspace::new();
+        let watched = watch_keys(&mut map, vec!["stock".into()], 0);
+        assert_eq!(
+            execute_transaction(&mut map, vec![incr("stock")], &watched, 0, &mut |_| DataType::Nil),
+            Ok(DataType::Array(vec![DataType::Integer(1)]))
+        );
+
+        // The transaction's own write counts for the next one
+        assert_eq!(execute_transaction(&mut map, vec![incr("stock")], &watched, 0, &mut |_| DataType::Nil), Ok(DataType::NullArray));
+        unwatch_keys(&mut map, &watched);
+    }
+
+    #[test]
+    pub fn test_writes_that_change_nothing_leave_watched_keys_alone() {
+        let mut map = Keyspace::new();
+        execute(&mut map, incr("n"), 0).unwrap();
+        execute(&mut map, Command::List(ListCommand::Push { key: "l".into(), end: ListEnd::Left, values: vec!["a".into()], only_if_exists: false }), 0).unwrap();
+        let watched = watch_keys(&mut map, vec!["n".into(), "l".into(), "missing".into()], 0);
+
+        let set_nx = Command::Set(SetCommand { key: "n".into(), value: "2".into(), set_existing: Some(SetExistingOptions::OnlySetIfNotExists), ..Default::default() });
+        execute(&mut map, set_nx, 0).unwrap();
+        let wrongtype = execute(&mut map, Command::List(ListCommand::Push { key: "n".into(), end: ListEnd::Left, values: vec!["a".into()], only_if_exists: false }), 0);
+        assert!(matches!(wrongtype, Ok(DataType::Error(_))));
+        execute(&mut map, Command::List(ListCommand::Push { key: "missing".into(), end: ListEnd::Left, values: vec!["a".into()], only_if_exists: true }), 0).unwrap();
+        execute(&mut map, Command::List(ListCommand::Rem { key: "l".into(), count: 0, value: "b".into() }), 0).unwrap();
+        assert_eq!(execute_transaction(&mut map, vec![], &watched, 0, &mut |_| DataType::Nil), Ok(DataType::Array(vec![])));
+    }
+
+    #[test]
+    pub fn test_reads_leave_watched_keys_alone() {
+        let mut map = Keyspace::new();
+        execute(&mut map, Command::List(ListCommand::Push { key: "l".into(), end: ListEnd::Left, values: vec!["a".into()], only_if_exists: false }), 0).unwrap();
+        let watched = watch_keys(&mut map, vec!["l".into()], 0);
+        execute(&mut map, Command::List(ListCommand::Range { key: "l".into(), start: 0, stop: -1 }), 0).unwrap();
+        assert_eq!(execute_transaction(&mut map, vec![], &watched, 0, &mut |_| DataType::Nil), Ok(DataType::Array(vec![])));
+    }
+}
@@ -1,7 +1,32 @@
-use crate::{commands::SetCommand, datatypes::DataType};
+use std::future::Future;
+use std::pin::Pin;
 
-pub(crate) trait StorageEngine {
-    async fn process_set(&self, cmd: SetCommand) -> Result<DataType, String>;
-    async fn process_get(&self, key: String) -> Result<DataType, String>;
-    async fn process_dump(&self) -> Result<DataType, String>;
+use bytes::Bytes;
+use super::{blocking::WaitRegistry, transaction::WatchedKey};
+use crate::{commands::Command, datatypes::DataType};
+
+/// What the engine hands back for a command. Boxed so `StorageEngine` can be used as a trait
+/// object, which lets the server pick its engine at startup.
+pub(crate) type EngineFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, String>> + Send + 'a>>;
+
+/// Runs the commands of a transaction that don't work on keys, like CONFIG SET or PUBLISH, at
+/// their place in the queue. They belong to the server, the engine only calls back into it.
+pub(crate) type Unkeyed<'a> = &'a mut (dyn FnMut(Command) -> DataType + Send);
+
+/// Where the keys live. Running a command is the same for every engine, see `dispatch::execute`,
+/// an engine only decides which keyspaces a command goes to and how they're locked.
+pub(crate) trait StorageEngine: Send + Sync {
+    /// Runs a command that works on keys, or DUMP.
+    fn process(&self, command: Command) -> EngineFuture<'_, DataType>;
+    /// Starts watching the keys for the next EXEC and returns the version each one is at.
+    fn process_watch(&self, keys: Vec<Bytes>) -> EngineFuture<'_, Vec<WatchedKey>>;
+    /// Runs the commands in queue order with nothing in between, handing the ones that don't work
+    /// on keys to `unkeyed`. If a watched key changed since WATCH nothing runs and the reply is a
+    /// null array.
+    fn process_transaction<'a>(&'a self, commands: Vec<Command>, watched: &'a [WatchedKey], unkeyed: Unkeyed<'a>) -> EngineFuture<'a, DataType>;
+    /// Stops watching the keys, every `process_watch` is paired with one of these.
+    fn unwatch(&self, watched: &[WatchedKey]);
+    /// Where blocking commands wait. Writes that can hand something to a blocked client signal
+    /// the key they wrote.
+    fn waiters(&self) -> &WaitRegistry;
 }
@@ -0,0 +1,844 @@
+use std::collections::{HashMap, HashSet};
+
+use bytes::Bytes;
+
+use crate::{commands::{Aggregate, LexBound, ScoreComparison, SetExistingOptions, SetOperation, ZAddOptions, ZRangeBy, ZSetCommand, ZStoreCommand}, datatypes::{DataType, StorageRecord, StorageValue}};
+
+use super::{keyspace::{Keyspace, Keyspaces}, list::normalize_range, notify::EventClass, shared::{random_index, remove_if_empty, WRONGTYPE}};
+
+const MAX_LEVEL: usize = 32;
+const HEAD: usize = 0;
+/// Stands in for a null link between nodes.
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    forward: usize,
+    /// How many nodes the forward link moves past, summing these up on the way to a node gives its rank.
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    backward: usize,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    /// Members are ordered by score, and members with the same score lexicographically.
+    fn is_before(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || (self.score == score && self.member.as_ref() < member)
+    }
+}
+
+/// The skiplist from redis' t_zset.c, with the nodes kept in a Vec and linked by index instead
+/// of by pointer. The node at index 0 is the head and not a member, the slots of removed nodes
+/// get reused.
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: usize,
+    level: usize,
+    len: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            backward: NIL,
+            levels: vec![Level { forward: NIL, span: 0 }; MAX_LEVEL],
+        };
+        SkipList { nodes: vec![head], free: vec![], tail: NIL, level: 1, len: 0 }
+    }
+}
+
+/// Every level up has a quarter of the nodes of the one below it, same as redis.
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && random_index(4) == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    /// The last node on every level that comes before `score` and `member`, and the rank of each.
+    fn predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            loop {
+                let next = self.nodes[x].levels[i].forward;
+                if next == NIL || !self.nodes[next].is_before(score, member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    /// The member must not be in the list yet.
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            backward: if update[0] == HEAD { NIL } else { update[0] },
+            levels: vec![Level { forward: NIL, span: 0 }; level],
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let before = rank[0] - rank[i];
+            let Level { forward, span } = self.nodes[update[i]].levels[i];
+            self.nodes[idx].levels[i] = Level { forward, span: span - before };
+            self.nodes[update[i]].levels[i] = Level { forward: idx, span: before + 1 };
+        }
+        for (i, prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*prev].levels[i].span += 1;
+        }
+
+        match self.nodes[idx].levels[0].forward {
+            NIL => self.tail = idx,
+            next => self.nodes[next].backward = idx,
+        }
+        self.len += 1;
+    }
+
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.predecessors(score, member);
+        let idx = self.nodes[update[0]].levels[0].forward;
+        if idx == NIL || self.nodes[idx].score != score || self.nodes[idx].member != member {
+            return false;
+        }
+
+        for (i, prev) in update.iter().enumerate().take(self.level) {
+            if self.nodes[*prev].levels[i].forward == idx {
+                let Level { forward, span } = self.nodes[idx].levels[i];
+                let prev = &mut self.nodes[*prev].levels[i];
+                prev.span = prev.span + span - 1;
+                prev.forward = forward;
+            } else {
+                self.nodes[*prev].levels[i].span -= 1;
+            }
+        }
+
+        let backward = self.nodes[idx].backward;
+        match self.nodes[idx].levels[0].forward {
+            NIL => self.tail = backward,
+            next => self.nodes[next].backward = backward,
+        }
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward == NIL {
+            self.level -= 1;
+        }
+        self.len -= 1;
+
+        // Let go of the member now instead of whenever the slot gets reused
+        self.nodes[idx].member = Bytes::new();
+        self.nodes[idx].levels = vec![];
+        self.free.push(idx);
+        true
+    }
+
+    /// The 0 based rank of the member, which has to have the given score.
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.nodes[x].levels[i].forward;
+                if next == NIL || !(self.nodes[next].is_before(score, member) || self.nodes[next].member == member) {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if x != HEAD && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// The node at the 0 based rank, NIL if there's no such rank.
+    fn node_at(&self, rank: usize) -> usize {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let Level { forward, span } = self.nodes[x].levels[i];
+                if forward == NIL || traversed + span > target {
+                    break;
+                }
+                traversed += span;
+                x = forward;
+            }
+            if traversed == target {
+                return x;
+            }
+        }
+        NIL
+    }
+
+    /// The last node that matches, `matches` has to hold for some prefix of the list and for
+    /// nothing after it. Returns the head if there's no such node.
+    fn last_matching(&self, matches: impl Fn(&Node) -> bool) -> usize {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.nodes[x].levels[i].forward;
+                if next == NIL || !matches(&self.nodes[next]) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        x
+    }
+
+    fn first(&self) -> usize {
+        self.nodes[HEAD].levels[0].forward
+    }
+
+    fn iter_from(&self, idx: usize, rev: bool) -> Iter<'_> {
+        Iter { list: self, next: idx, rev }
+    }
+}
+
+struct Iter<'a> {
+    list: &'a SkipList,
+    next: usize,
+    rev: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Bytes, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == NIL {
+            return None;
+        }
+        let node = &self.list.nodes[self.next];
+        self.next = if self.rev { node.backward } else { node.levels[0].forward };
+        Some((&node.member, node.score))
+    }
+}
+
+fn below_min(by: &ZRangeBy, score: f64, member: &[u8]) -> bool {
+    match by {
+        // Rank ranges are resolved by position and never get here
+        ZRangeBy::Rank { .. } => false,
+        ZRangeBy::Score { min, .. } if min.exclusive => score <= min.value,
+        ZRangeBy::Score { min, .. } => score < min.value,
+        ZRangeBy::Lex { min, .. } => match min {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(x) => member < x.as_ref(),
+            LexBound::Exclusive(x) => member <= x.as_ref(),
+        },
+    }
+}
+
+fn above_max(by: &ZRangeBy, score: f64, member: &[u8]) -> bool {
+    match by {
+        ZRangeBy::Rank { .. } => false,
+        ZRangeBy::Score { max, .. } if max.exclusive => score >= max.value,
+        ZRangeBy::Score { max, .. } => score > max.value,
+        ZRangeBy::Lex { max, .. } => match max {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(x) => member > x.as_ref(),
+            LexBound::Exclusive(x) => member >= x.as_ref(),
+        },
+    }
+}
+
+/// Same layout as redis' skiplist encoding, the map answers ZSCORE in O(1) and the skiplist keeps
+/// the members in order for the rank and range queries.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds the member or moves it to its new score, true if it wasn't there before.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(previous) if previous == score => false,
+            Some(previous) => {
+                self.list.remove(previous, &member);
+                self.list.insert(score, member);
+                false
+            }
+            None => {
+                self.list.insert(score, member);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.list.remove(score, member),
+            None => false,
+        }
+    }
+
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        self.list.rank(self.score(member)?, member)
+    }
+
+    /// The members within `by` in order, or in reverse order when `rev` is set.
+    fn range<'a>(&'a self, by: &'a ZRangeBy, rev: bool) -> Box<dyn Iterator<Item = (&'a Bytes, f64)> + 'a> {
+        if let ZRangeBy::Rank { start, stop } = by {
+            let Some((start, stop)) = normalize_range(*start, *stop, self.len()) else {
+                return Box::new(std::iter::empty());
+            };
+            // For REV the ranks count from the highest score down
+            let first = if rev { self.len() - 1 - start } else { start };
+            return Box::new(self.list.iter_from(self.list.node_at(first), rev).take(stop - start + 1));
+        }
+
+        if rev {
+            let last = self.list.last_matching(|node| !above_max(by, node.score, &node.member));
+            let last = if last == HEAD { NIL } else { last };
+            Box::new(self.list.iter_from(last, true).take_while(|(member, score)| !below_min(by, *score, member)))
+        } else {
+            let first = self.list.nodes[self.list.last_matching(|node| below_min(by, node.score, &node.member))].levels[0].forward;
+            Box::new(self.list.iter_from(first, false).take_while(|(member, score)| !above_max(by, *score, member)))
+        }
+    }
+
+    /// How many members are within `by`, from the ranks of the first and last one.
+    fn count(&self, by: &ZRangeBy) -> usize {
+        let Some((first, first_score)) = self.range(by, false).next() else {
+            return 0;
+        };
+        let Some((last, last_score)) = self.range(by, true).next() else {
+            return 0;
+        };
+        match (self.list.rank(first_score, first), self.list.rank(last_score, last)) {
+            (Some(first), Some(last)) if last >= first => last - first + 1,
+            _ => 0,
+        }
+    }
+
+    fn pop(&mut self, max: bool) -> Option<(Bytes, f64)> {
+        let idx = if max { self.list.tail } else { self.list.first() };
+        if idx == NIL {
+            return None;
+        }
+        let node = &self.list.nodes[idx];
+        let (member, score) = (node.member.clone(), node.score);
+        self.remove(&member);
+        Some((member, score))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.list.iter_from(self.list.first(), false)
+    }
+}
+
+impl FromIterator<(Bytes, f64)> for SortedSet {
+    fn from_iter<T: IntoIterator<Item = (Bytes, f64)>>(iter: T) -> Self {
+        let mut zset = SortedSet::default();
+        for (member, score) in iter {
+            zset.insert(member, score);
+        }
+        zset
+    }
+}
+
+/// The error side of these results is the reply to send back as is, which is always WRONGTYPE.
+type ZSetResult<T> = Result<T, DataType>;
+
+/// Returns the sorted set stored at `key`, None if there's nothing there.
+fn get_zset<'a>(map: &'a mut Keyspace, key: &[u8], now: u128) -> ZSetResult<Option<&'a mut SortedSet>> {
+    match map.get_mut(key, now) {
+        None => Ok(None),
+        Some(StorageValue::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+/// The new sorted set starts out empty, the caller has to remove it again if nothing gets added.
+fn get_or_insert_zset<'a>(map: &'a mut Keyspace, key: &Bytes, now: u128) -> ZSetResult<&'a mut SortedSet> {
+    if get_zset(map, key, now)?.is_none() {
+        map.insert(key.clone(), StorageRecord {
+            value: StorageValue::ZSet(SortedSet::default()),
+            ttl: None,
+        });
+    }
+    match map.get_mut(key, now) {
+        Some(StorageValue::ZSet(zset)) => Ok(zset),
+        _ => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+/// Members followed by their score when `with_scores` is set, flat like RESP2 has it.
+fn range_reply<'a>(items: impl Iterator<Item = (&'a Bytes, f64)>, with_scores: bool) -> DataType {
+    let mut reply = Vec::new();
+    for (member, score) in items {
+        reply.push(DataType::BulkString(member.clone()));
+        if with_scores {
+            reply.push(DataType::Double(score));
+        }
+    }
+    DataType::Array(reply)
+}
+
+fn add(map: &mut Keyspace, key: Bytes, options: ZAddOptions, members: Vec<(f64, Bytes)>, now: u128) -> ZSetResult<DataType> {
+    let zset = get_or_insert_zset(map, &key, now)?;
+    let (mut added, mut changed) = (0, 0);
+    let mut last_score = None;
+
+    for (score, member) in members {
+        let current = zset.score(&member);
+        let score = match current {
+            Some(current) if options.increment => current + score,
+            _ => score,
+        };
+        if score.is_nan() {
+            remove_if_empty(map, &key, now);
+            return Ok(DataType::Error("ERR resulting score is not a number (NaN)".into()));
+        }
+
+        let skip = match (current, &options.set_existing, options.comparison) {
+            (Some(_), Some(SetExistingOptions::OnlySetIfNotExists), _) => true,
+            (None, Some(SetExistingOptions::OnlySetIfExists), _) => true,
+            (Some(current), _, Some(ScoreComparison::GreaterThan)) => score <= current,
+            (Some(current), _, Some(ScoreComparison::LessThan)) => score >= current,
+            _ => false,
+        };
+        if skip {
+            continue;
+        }
+
+        match current {
+            None => added += 1,
+            Some(current) if current != score => changed += 1,
+            Some(_) => {}
+        }
+        zset.insert(member, score);
+        last_score = Some(score);
+    }
+    if added + changed > 0 {
+        map.notify(EventClass::ZSet, if options.increment { "zincr" } else { "zadd" }, &key);
+    }
+    remove_if_empty(map, &key, now);
+
+    if options.increment {
+        return Ok(last_score.map_or(DataType::Nil, DataType::Double));
+    }
+    Ok(DataType::Integer(if options.changed { added + changed } else { added }))
+}
+
+fn pop(map: &mut Keyspace, key: Bytes, max: bool, count: Option<usize>, now: u128) -> ZSetResult<DataType> {
+    let Some(zset) = get_zset(map, &key, now)? else {
+        return Ok(DataType::Array(vec![]));
+    };
+
+    let mut reply = Vec::new();
+    for _ in 0..count.unwrap_or(1) {
+        let Some((member, score)) = zset.pop(max) else {
+            break;
+        };
+        reply.push(DataType::BulkString(member));
+        reply.push(DataType::Double(score));
+    }
+    if !reply.is_empty() {
+        map.notify(EventClass::ZSet, if max { "zpopmax" } else { "zpopmin" }, &key);
+    }
+    remove_if_empty(map, &key, now);
+    Ok(DataType::Array(reply))
+}
+
+fn run_zset_command(map: &mut Keyspace, cmd: ZSetCommand, now: u128) -> ZSetResult<DataType> {
+    match cmd {
+        ZSetCommand::Add { key, options, members } => add(map, key, options, members, now),
+        ZSetCommand::Rem { key, members } => {
+            let Some(zset) = get_zset(map, &key, now)? else {
+                return Ok(DataType::Integer(0));
+            };
+            let removed = members.iter().filter(|member| zset.remove(member)).count();
+            if removed > 0 {
+                map.notify(EventClass::ZSet, "zrem", &key);
+            }
+            remove_if_empty(map, &key, now);
+            Ok(DataType::Integer(removed as i64))
+        }
+        ZSetCommand::Score { key, member } => {
+            let score = get_zset(map, &key, now)?.and_then(|zset| zset.score(&member));
+            Ok(score.map_or(DataType::Nil, DataType::Double))
+        }
+        ZSetCommand::MScore { key, members } => {
+            let zset = get_zset(map, &key, now)?;
+            let scores = members
+                .iter()
+                .map(|member| zset.as_ref().and_then(|zset| zset.score(member)).map_or(DataType::Nil, DataType::Double))
+                .collect();
+            Ok(DataType::Array(scores))
+        }
+        ZSetCommand::Card { key } => {
+            let len = get_zset(map, &key, now)?.map_or(0, |zset| zset.len());
+            Ok(DataType::Integer(len as i64))
+        }
+        ZSetCommand::Count { key, by } => {
+            let count = get_zset(map, &key, now)?.map_or(0, |zset| zset.count(&by));
+            Ok(DataType::Integer(count as i64))
+        }
+        ZSetCommand::Rank { key, member, rev, with_score } => {
+            let Some(zset) = get_zset(map, &key, now)? else {
+                return Ok(DataType::Nil);
+            };
+            let (Some(rank), Some(score)) = (zset.rank(&member), zset.score(&member)) else {
+                return Ok(DataType::Nil);
+            };
+            let rank = if rev { zset.len() - 1 - rank } else { rank };
+            let rank = DataType::Integer(rank as i64);
+            match with_score {
+                true => Ok(DataType::Array(vec![rank, DataType::Double(score)])),
+                false => Ok(rank),
+            }
+        }
+        ZSetCommand::Range { key, options } => {
+            let Some(zset) = get_zset(map, &key, now)? else {
+                return Ok(DataType::Array(vec![]));
+            };
+            let (offset, count) = match options.limit {
+                Some((offset, _)) if offset < 0 => return Ok(DataType::Array(vec![])),
+                Some((offset, count)) => (offset as usize, usize::try_from(count).unwrap_or(usize::MAX)),
+                None => (0, usize::MAX),
+            };
+            let items = zset.range(&options.by, options.rev).skip(offset).take(count);
+            Ok(range_reply(items, options.with_scores))
+        }
+        ZSetCommand::RemRange { key, by } => {
+            let Some(zset) = get_zset(map, &key, now)? else {
+                return Ok(DataType::Integer(0));
+            };
+            let members = zset.range(&by, false).map(|(member, _)| member.clone()).collect::<Vec<_>>();
+            for member in &members {
+                zset.remove(member);
+            }
+            if !members.is_empty() {
+                let event = match by {
+                    ZRangeBy::Rank { .. } => "zremrangebyrank",
+                    ZRangeBy::Score { .. } => "zremrangebyscore",
+                    ZRangeBy::Lex { .. } => "zremrangebylex",
+                };
+                map.notify(EventClass::ZSet, event, &key);
+            }
+            remove_if_empty(map, &key, now);
+            Ok(DataType::Integer(members.len() as i64))
+        }
+        ZSetCommand::Pop { key, max, count } => pop(map, key, max, count, now),
+    }
+}
+
+pub(crate) fn process_zset(map: &mut Keyspace, cmd: ZSetCommand, now: u128) -> Result<DataType, String> {
+    Ok(run_zset_command(map, cmd, now).unwrap_or_else(|err| err))
+}
+
+/// ZUNIONSTORE and friends also take plain sets, whose members all count as having a score of 1.
+enum Source<'a> {
+    Sorted(&'a SortedSet),
+    Set(&'a HashSet<Bytes>),
+}
+
+impl Source<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Source::Sorted(zset) => zset.len(),
+            Source::Set(set) => set.len(),
+        }
+    }
+
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Source::Sorted(zset) => zset.score(member),
+            Source::Set(set) => set.contains(member).then_some(1.0),
+        }
+    }
+
+    fn for_each(&self, mut f: impl FnMut(&Bytes, f64)) {
+        match self {
+            Source::Sorted(zset) => zset.iter().for_each(|(member, score)| f(member, score)),
+            Source::Set(set) => set.iter().for_each(|member| f(member, 1.0)),
+        }
+    }
+}
+
+fn get_source<'a>(map: &'a mut Keyspace, key: &[u8], now: u128) -> ZSetResult<Option<Source<'a>>> {
+    match map.get_mut(key, now) {
+        None => Ok(None),
+        Some(StorageValue::ZSet(zset)) => Ok(Some(Source::Sorted(zset))),
+        Some(StorageValue::Set(set)) => Ok(Some(Source::Set(set))),
+        Some(_) => Err(DataType::Error(WRONGTYPE.into())),
+    }
+}
+
+/// Like redis, `inf * 0` and `inf + -inf` come out as 0 rather than NaN.
+fn zero_if_nan(x: f64) -> f64 {
+    if x.is_nan() { 0.0 } else { x }
+}
+
+fn aggregate(aggregate: Aggregate, a: f64, b: f64) -> f64 {
+    match aggregate {
+        Aggregate::Sum => zero_if_nan(a + b),
+        Aggregate::Min => a.min(b),
+        Aggregate::Max => a.max(b),
+    }
+}
+
+/// Combines the sorted sets at the keys of `cmd` the same way `combine_sets` does for sets, one
+/// key borrowed at a time so it works across the shards of `Keyspaces`.
+fn combine_zsets(map: &mut impl Keyspaces, cmd: &ZStoreCommand, now: u128) -> ZSetResult<HashMap<Bytes, f64>> {
+    let mut sizes = Vec::with_capacity(cmd.keys.len());
+    for key in &cmd.keys {
+        sizes.push(get_source(map.keyspace_for(key), key, now)?.map_or(0, |source| source.len()));
+    }
+
+    let start = match cmd.operation {
+        SetOperation::Inter => (0..cmd.keys.len()).min_by_key(|idx| sizes[*idx]).unwrap_or(0),
+        SetOperation::Union | SetOperation::Diff => 0,
+    };
+    let Some(start_key) = cmd.keys.get(start) else {
+        return Ok(HashMap::new());
+    };
+    let weight = |idx: usize| cmd.weights.get(idx).copied().unwrap_or(1.0);
+
+    let mut result = HashMap::new();
+    if let Some(source) = get_source(map.keyspace_for(start_key), start_key, now)? {
+        source.for_each(|member, score| {
+            result.insert(member.clone(), zero_if_nan(score * weight(start)));
+        });
+    }
+
+    for (idx, key) in cmd.keys.iter().enumerate() {
+        if idx == start {
+            continue;
+        }
+        let source = get_source(map.keyspace_for(key), key, now)?;
+        match (cmd.operation, source) {
+            (SetOperation::Inter, None) => result.clear(),
+            (SetOperation::Inter, Some(source)) => result.retain(|member, score| match source.score(member) {
+                Some(other) => {
+                    *score = aggregate(cmd.aggregate, *score, zero_if_nan(other * weight(idx)));
+                    true
+                }
+                None => false,
+            }),
+            (SetOperation::Union, Some(source)) => source.for_each(|member, score| {
+                let score = zero_if_nan(score * weight(idx));
+                result
+                    .entry(member.clone())
+                    .and_modify(|x| *x = aggregate(cmd.aggregate, *x, score))
+                    .or_insert(score);
+            }),
+            (SetOperation::Diff, Some(source)) => result.retain(|member, _| source.score(member).is_none()),
+            (_, None) => {}
+        }
+    }
+    Ok(result)
+}
+
+fn zstore_event(operation: SetOperation) -> &'static str {
+    match operation {
+        SetOperation::Inter => "zinterstore",
+        SetOperation::Union => "zunionstore",
+        SetOperation::Diff => "zdiffstore",
+    }
+}
+
+pub(crate) fn process_zstore(map: &mut impl Keyspaces, cmd: ZStoreCommand, now: u128) -> Result<DataType, String> {
+    let result = match combine_zsets(map, &cmd, now) {
+        Ok(result) => result,
+        Err(err) => return Ok(err),
+    };
+
+    let len = result.len();
+    let destination = map.keyspace_for(&cmd.destination);
+    if result.is_empty() {
+        if destination.remove(&cmd.destination).is_some() {
+            destination.notify(EventClass::Generic, "del", &cmd.destination);
+        }
+    } else {
+        destination.insert(cmd.destination.clone(), StorageRecord {
+            value: StorageValue::ZSet(result.into_iter().collect()),
+            ttl: None,
+        });
+        destination.notify(EventClass::ZSet, zstore_event(cmd.operation), &cmd.destination);
+    }
+    Ok(DataType::Integer(len as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{ScoreBound, ZRangeOptions};
+
+    use super::*;
+
+    fn zadd(map: &mut Keyspace, key: &'static str, members: &[(f64, &'static str)]) {
+        let members = members.iter().map(|(score, member)| (*score, Bytes::from(*member))).collect();
+        let cmd = ZSetCommand::Add { key: key.into(), options: ZAddOptions::default(), members };
+        process_zset(map, cmd, 0).expect("Expected the add to succeed");
+    }
+
+    fn range(map: &mut Keyspace, key: &'static str, by: ZRangeBy, rev: bool, limit: Option<(i64, i64)>) -> DataType {
+        let options = ZRangeOptions { by, rev, limit, with_scores: false };
+        process_zset(map, ZSetCommand::Range { key: key.into(), options }, 0).unwrap()
+    }
+
+    fn array(values: &[&'static str]) -> DataType {
+        DataType::Array(values.iter().map(|x| DataType::BulkString((*x).into())).collect())
+    }
+
+    fn score_range(min: f64, max: f64, exclusive: bool) -> ZRangeBy {
+        ZRangeBy::Score {
+            min: ScoreBound { value: min, exclusive },
+            max: ScoreBound { value: max, exclusive },
+        }
+    }
+
+    #[test]
+    pub fn test_skiplist_ranks_follow_sorted_order() {
+        let mut zset = SortedSet::default();
+        let mut expected = Vec::new();
+        for idx in 0..500u32 {
+            let score = ((idx * 7919) % 101) as f64;
+            let member = Bytes::from(format!("m{}", idx));
+            zset.insert(member.clone(), score);
+            expected.push((score, member));
+        }
+        // Move some members around and drop others so the spans get updated both ways
+        for (idx, (score, member)) in expected.iter_mut().enumerate() {
+            match idx % 3 {
+                0 => {
+                    zset.remove(member);
+                }
+                1 => {
+                    *score = -*score;
+                    zset.insert(member.clone(), *score);
+                }
+                _ => {}
+            }
+        }
+        let mut expected = expected.into_iter().enumerate().filter(|(idx, _)| idx % 3 != 0).map(|(_, x)| x).collect::<Vec<_>>();
+        expected.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then_with(|| a.1.cmp(&b.1)));
+
+        assert_eq!(zset.len(), expected.len());
+        for (rank, (score, member)) in expected.iter().enumerate() {
+            assert_eq!(zset.rank(member), Some(rank));
+            assert_eq!(zset.list.nodes[zset.list.node_at(rank)].score, *score);
+        }
+        let in_order = zset.iter().map(|(member, _)| member.clone()).collect::<Vec<_>>();
+        assert_eq!(in_order, expected.into_iter().map(|(_, member)| member).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn test_zadd_flags() {
+        let mut map = Keyspace::new();
+        zadd(&mut map, "z", &[(1.0, "a"), (2.0, "b")]);
+
+        let options = ZAddOptions { comparison: Some(ScoreComparison::GreaterThan), changed: true, ..Default::default() };
+        let cmd = ZSetCommand::Add { key: "z".into(), options, members: vec![(0.5, "a".into()), (3.0, "b".into()), (1.0, "c".into())] };
+        assert_eq!(process_zset(&mut map, cmd, 0), Ok(DataType::Integer(2)));
+        assert_eq!(process_zset(&mut map, ZSetCommand::Score { key: "z".into(), member: "a".into() }, 0), Ok(DataType::Double(1.0)));
+
+        let options = ZAddOptions { set_existing: Some(SetExistingOptions::OnlySetIfNotExists), increment: true, ..Default::default() };
+        let cmd = ZSetCommand::Add { key: "z".into(), options, members: vec![(5.0, "b".into())] };
+        assert_eq!(process_zset(&mut map, cmd, 0), Ok(DataType::Nil));
+
+        let options = ZAddOptions { set_existing: Some(SetExistingOptions::OnlySetIfExists), ..Default::default() };
+        let cmd = ZSetCommand::Add { key: "missing".into(), options, members: vec![(1.0, "a".into())] };
+        assert_eq!(process_zset(&mut map, cmd, 0), Ok(DataType::Integer(0)));
+        assert!(map.get(b"missing", 0).is_none());
+    }
+
+    #[test]
+    pub fn test_ranges() {
+        let mut map = Keyspace::new();
+        zadd(&mut map, "z", &[(1.0, "a"), (2.0, "b"), (3.0, "c"), (4.0, "d")]);
+
+        assert_eq!(range(&mut map, "z", ZRangeBy::Rank { start: 0, stop: -2 }, true, None), array(&["d", "c", "b"]));
+        assert_eq!(range(&mut map, "z", score_range(1.0, 4.0, true), false, None), array(&["b", "c"]));
+        assert_eq!(range(&mut map, "z", score_range(1.0, 4.0, false), true, Some((1, 2))), array(&["c", "b"]));
+        assert_eq!(range(&mut map, "z", score_range(5.0, 9.0, false), true, None), array(&[]));
+
+        let by = ZRangeBy::Lex { min: LexBound::Exclusive("a".into()), max: LexBound::Max };
+        assert_eq!(range(&mut map, "z", by.clone(), false, Some((0, -1))), array(&["b", "c", "d"]));
+        assert_eq!(process_zset(&mut map, ZSetCommand::Count { key: "z".into(), by }, 0), Ok(DataType::Integer(3)));
+    }
+
+    #[test]
+    pub fn test_zunionstore_weights_and_aggregate() {
+        let mut map = Keyspace::new();
+        zadd(&mut map, "a", &[(1.0, "x"), (2.0, "y")]);
+        zadd(&mut map, "b", &[(10.0, "y"), (20.0, "z")]);
+
+        let cmd = ZStoreCommand {
+            destination: "dest".into(),
+            operation: SetOperation::Union,
+            keys: vec!["a".into(), "b".into()],
+            weights: vec![2.0, 1.0],
+            aggregate: Aggregate::Max,
+        };
+        assert_eq!(process_zstore(&mut map, cmd, 0), Ok(DataType::Integer(3)));
+        let options = ZRangeOptions { by: ZRangeBy::Rank { start: 0, stop: -1 }, rev: false, limit: None, with_scores: true };
+        assert_eq!(
+            process_zset(&mut map, ZSetCommand::Range { key: "dest".into(), options }, 0),
+            Ok(DataType::Array(vec![
+                DataType::BulkString("x".into()),
+                DataType::Double(2.0),
+                DataType::BulkString("y".into()),
+                DataType::Double(10.0),
+                DataType::BulkString("z".into()),
+                DataType::Double(20.0),
+            ]))
+        );
+    }
+}
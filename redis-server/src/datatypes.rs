@@ -1,15 +1,48 @@
+use std::collections::{HashSet, VecDeque};
+use bytes::Bytes;
+
+use crate::data::{hash::HashValue, stream::Stream, zset::SortedSet};
+
 #[derive(Debug, PartialEq)]
 pub enum DataType {
+    /// The null reply, `$-1` under RESP2 and `_` under RESP3.
     Nil,
+    /// The null array, `*-1` under RESP2 and `_` under RESP3. Blocking commands reply with it
+    /// when they time out.
+    NullArray,
     SimpleString(String),
-    BulkString(String),
+    BulkString(Bytes),
     Array(Vec<DataType>),
     Error(String),
+    Integer(i64),
+    // RESP3 types, these get downgraded to their closest RESP2 equivalent for RESP2 connections
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    Verbatim {
+        format: String,
+        text: Bytes,
+    },
+    Map(Vec<(DataType, DataType)>),
+    Set(Vec<DataType>),
+    Push(Vec<DataType>),
+    Attribute {
+        attributes: Vec<(DataType, DataType)>,
+        value: Box<DataType>,
+    },
+    /// Not a RESP type, replies written one after the other for the commands that answer once
+    /// per argument like SUBSCRIBE.
+    Sequence(Vec<DataType>),
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum StorageValue {
-    String(String),
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashValue),
+    Set(HashSet<Bytes>),
+    ZSet(SortedSet),
+    Stream(Stream),
 }
 
 #[derive(Debug, Clone)]
@@ -4,4 +4,8 @@ pub mod datatypes;
 pub mod commands;
 pub mod multi_server;
 pub mod single_server;
-pub mod data;
\ No newline at end of file
+pub mod data;
+pub mod session;
+pub mod pubsub;
+pub mod config;
+pub mod log;
@@ -0,0 +1,69 @@
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How important a log line is, from least to most. Same levels as redis.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl Level {
+    fn marker(self) -> char {
+        match self {
+            Level::Debug => '.',
+            Level::Verbose => '-',
+            Level::Notice => '*',
+            Level::Warning => '#',
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Notice as u8);
+static FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Sets the least important level that's still logged from the `loglevel` config, `nothing`
+/// turns the log off.
+pub fn set_level(name: &str) {
+    let level = match name {
+        "debug" => Level::Debug as u8,
+        "verbose" => Level::Verbose as u8,
+        "notice" => Level::Notice as u8,
+        "warning" => Level::Warning as u8,
+        _ => u8::MAX,
+    };
+    LEVEL.store(level, Ordering::Relaxed);
+}
+
+/// Sends the log to `path` instead of stderr, appending to what's there. Only the first call
+/// takes effect, `logfile` can't change while the server runs.
+pub fn log_to_file(path: &str) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Can't open the log file '{}': {}", path, err))?;
+    let _ = FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Writes a line to the server log if `level` is at least as important as the configured one.
+/// Lines look like redis' own, `pid:M seconds.millis marker message`.
+pub fn log(level: Level, message: impl Display) {
+    if (level as u8) < LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let line = format!("{}:M {}.{:03} {} {}\n", std::process::id(), now.as_secs(), now.subsec_millis(), level.marker(), message);
+    // The log going away isn't a reason to stop serving clients
+    let _ = match FILE.get().map(|file| file.lock()) {
+        Some(Ok(mut file)) => file.write_all(line.as_bytes()),
+        _ => std::io::stderr().lock().write_all(line.as_bytes()),
+    };
+}
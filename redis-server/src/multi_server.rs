@@ -1,31 +1,303 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::commands::{BlockingCommand, BlockingOperation, PubSubCommand};
+use crate::config::{Config, ConfigValue};
+use crate::data::blocking::{attempts, last_entry_command, pin_last_id, served, timeout_reply};
 use crate::data::memory_engine::InMemoryEngine;
+use crate::data::notify::Notifier;
 use crate::data::thread_engine::ThreadEngineManager;
+use crate::data::transaction::WatchedKey;
 use crate::data::typesd::StorageEngine;
+use crate::protocol::serializer::ProtocolVersion;
+use crate::pubsub::Broker;
+use crate::session::{Session, Transaction};
 use crate::{commands::Command, datatypes::DataType};
-// use crate::data::memory_engine::InMemoryEngine;
 
 pub struct Server {
-    // engine: Box<dyn StorageEngine>, Why doesn't this work? https://doc.rust-lang.org/reference/items/traits.html#object-safety
-    engine: InMemoryEngine,
-    // engine: ThreadEngineManager,
+    engine: Box<dyn StorageEngine>,
+    broker: Arc<Broker>,
+    notifier: Arc<Notifier>,
+    config: Config,
+    next_client_id: AtomicU64,
+    clients: AtomicUsize,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Server {
     pub fn new() -> Server {
-        Server {
-            engine: InMemoryEngine::new(),
-            // engine: ThreadEngineManager::new(),
+        Self::with_config(Config::new())
+    }
+
+    pub fn with_config(config: Config) -> Server {
+        let broker = Arc::new(Broker::default());
+        let notifier = Arc::new(Notifier::new(broker.clone()));
+        let engine: Box<dyn StorageEngine> = match config.get("engine") {
+            Some(ConfigValue::Enum("threaded")) => Box::new(ThreadEngineManager::with_notifier(notifier.clone())),
+            _ => Box::new(InMemoryEngine::with_notifier(notifier.clone())),
+        };
+        let server = Server {
+            engine,
+            broker,
+            notifier,
+            config,
+            next_client_id: AtomicU64::new(1),
+            clients: AtomicUsize::new(0),
+        };
+        server.apply_config();
+        server
+    }
+
+    pub fn create_session(&self) -> Session {
+        let mut session = Session::new(self.next_client_id.fetch_add(1, Ordering::Relaxed));
+        session.authenticated = self.config.requirepass().is_none();
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        session
+    }
+
+    /// Whether more clients are connected than `maxclients` allows, the one that went over is
+    /// told so and disconnected.
+    pub fn too_many_clients(&self) -> bool {
+        self.clients.load(Ordering::Relaxed) > self.config.maxclients()
+    }
+
+    /// How long a client can go without sending anything before it's disconnected. Subscribers
+    /// are left alone since they're only there to listen, same as redis.
+    pub fn idle_timeout(&self, session: &Session) -> Option<Duration> {
+        match self.config.get("timeout") {
+            Some(ConfigValue::Integer(seconds)) if seconds > 0 && session.inbox.is_none() => Some(Duration::from_secs(seconds as u64)),
+            _ => None,
+        }
+    }
+
+    /// Cleans up after a client that disconnected.
+    pub fn close_session(&self, session: &Session) {
+        self.clients.fetch_sub(1, Ordering::Relaxed);
+        if session.inbox.is_some() {
+            self.broker.remove(session.id);
+        }
+        if !session.watched.is_empty() {
+            self.engine.unwatch(&session.watched);
+        }
+    }
+
+    pub async fn process_command(&self, session: &mut Session, command: Command) -> Result<DataType, String> {
+        if !session.authenticated && !matches!(command, Command::Auth { .. } | Command::Hello(_)) {
+            return Ok(DataType::Error("NOAUTH Authentication required.".into()));
+        }
+
+        match command {
+            Command::Multi => Ok(session.multi()),
+            Command::Exec => self.process_exec(session).await,
+            Command::Discard => Ok(self.process_discard(session)),
+            command if session.transaction.is_some() => Ok(session.queue(command)),
+            Command::Watch { keys } => {
+                let keys = session.keys_to_watch(keys);
+                let watched = self.engine.process_watch(keys).await?;
+                session.watched.extend(watched);
+                Ok(DataType::SimpleString("OK".into()))
+            }
+            Command::Unwatch => {
+                self.engine.unwatch(&std::mem::take(&mut session.watched));
+                Ok(DataType::SimpleString("OK".into()))
+            }
+            Command::Blocking(command) => self.process_blocking(command).await,
+            command if command.works_on_keys() || matches!(command, Command::Dump) => self.execute(command).await,
+            command => Ok(self.process_unkeyed(session, command)),
         }
     }
 
-    pub async fn process_command(&self, command: Command) -> Result<DataType, String> {
+    /// The commands that belong to the server or the connection rather than to the keyspace.
+    /// Inside EXEC they run at their place in the queue, in between the commands on keys.
+    fn process_unkeyed(&self, session: &mut Session, command: Command) -> DataType {
         match command {
-            Command::Set(command) => self.engine.process_set(command).await,
-            Command::Get { key } => self.engine.process_get(key).await,
-            Command::ConfigGet { .. } => {
-                Ok(DataType::Array(vec![DataType::BulkString("save".into()), DataType::BulkString("3600 1 300 100 60 10000".into())]))
-            },
-            Command::Dump => self.engine.process_dump().await,
+            // Subscribed RESP2 clients can't tell a reply from a message, so PING answers like a message
+            Command::Ping { message } if session.subscriptions > 0 && session.protocol == ProtocolVersion::Resp2 => {
+                DataType::Array(vec![DataType::BulkString("pong".into()), DataType::BulkString(message.unwrap_or_default())])
+            }
+            Command::Ping { message: None } => DataType::SimpleString("PONG".into()),
+            Command::Ping { message: Some(message) } => DataType::BulkString(message),
+            Command::Hello(command) => session.hello(command, self.config.requirepass().as_deref()),
+            Command::Auth { username, password } => session.auth(self.config.requirepass().as_deref(), username.as_deref(), &password),
+            Command::Config(command) => {
+                let reply = self.config.process(command);
+                self.apply_config();
+                reply
+            }
+            Command::PubSub(command) => self.process_pubsub(session, command),
+            // Queued in a transaction, EXEC let go of the watched keys already
+            Command::Unwatch => DataType::SimpleString("OK".into()),
+            _ => DataType::Error("ERR Command not allowed inside a transaction".into()),
+        }
+    }
+
+    /// The watched keys are let go of whatever happens to the transaction.
+    async fn process_exec(&self, session: &mut Session) -> Result<DataType, String> {
+        let Some(transaction) = session.transaction.take() else {
+            return Ok(DataType::Error("ERR EXEC without MULTI".into()));
+        };
+        let watched = std::mem::take(&mut session.watched);
+        let res = match transaction.aborted {
+            true => Ok(DataType::Error("EXECABORT Transaction discarded because of previous errors.".into())),
+            false => self.run_transaction(session, transaction, &watched).await,
+        };
+        self.engine.unwatch(&watched);
+        res
+    }
+
+    /// The engine runs the whole queue as one, calling back here for the commands that don't
+    /// work on keys so a CONFIG SET affects the commands queued after it.
+    async fn run_transaction(&self, session: &mut Session, transaction: Transaction, watched: &[WatchedKey]) -> Result<DataType, String> {
+        let mut unkeyed = |command| self.process_unkeyed(session, command);
+        self.engine.process_transaction(transaction.commands, watched, &mut unkeyed).await
+    }
+
+    fn process_discard(&self, session: &mut Session) -> DataType {
+        if session.transaction.take().is_none() {
+            return DataType::Error("ERR DISCARD without MULTI".into());
         }
+        self.engine.unwatch(&std::mem::take(&mut session.watched));
+        DataType::SimpleString("OK".into())
+    }
+
+    /// Hands the settings that take effect right away to whatever uses them. The idle timeout and
+    /// maxclients are read from the config by every connection, so they don't need anything here.
+    fn apply_config(&self) {
+        self.config.apply_process_settings();
+        if let Some(ConfigValue::NotifyFlags(flags)) = self.config.get("notify-keyspace-events") {
+            self.notifier.set_flags(flags);
+        }
+    }
+
+    fn process_pubsub(&self, session: &mut Session, command: PubSubCommand) -> DataType {
+        match command {
+            PubSubCommand::Subscribe { kind, names } => self.broker.subscribe(session, kind, names),
+            PubSubCommand::Unsubscribe { kind, names } => self.broker.unsubscribe(session, kind, names),
+            PubSubCommand::Publish { channel, message } => DataType::Integer(self.broker.publish(&channel, &message)),
+            PubSubCommand::ShardPublish { channel, message } => DataType::Integer(self.broker.shard_publish(&channel, &message)),
+            PubSubCommand::Channels { pattern } => self.broker.channels(pattern.as_deref()),
+            PubSubCommand::ShardChannels { pattern } => self.broker.shard_channels(pattern.as_deref()),
+            PubSubCommand::NumSub { channels } => self.broker.numsub(channels),
+            PubSubCommand::ShardNumSub { channels } => self.broker.shard_numsub(channels),
+            PubSubCommand::NumPat => self.broker.numpat(),
+        }
+    }
+
+    /// Runs a command against the engine, it doesn't need anything from the session.
+    async fn execute(&self, command: Command) -> Result<DataType, String> {
+        self.engine.process(command).await
+    }
+
+    /// Tries the command and if there's nothing for it yet parks the client until a write to one
+    /// of its keys, then tries again. Dropping the future, as happens when the client
+    /// disconnects, takes it out of the queue.
+    async fn process_blocking(&self, command: BlockingCommand) -> Result<DataType, String> {
+        // A deadline further out than the clock goes waits forever
+        let deadline = command.timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut operation = command.operation;
+        if let BlockingOperation::XRead(cmd) = &mut operation {
+            for (key, id) in cmd.streams.iter_mut() {
+                let reply = self.execute(last_entry_command(key)).await?;
+                pin_last_id(id, &reply);
+            }
+        }
+
+        let keys = operation.keys();
+        let waiters = self.engine.waiters();
+        let mut woken = false;
+        loop {
+            // The wait is queued before looking so a write in between isn't missed
+            let wait = waiters.register(&keys, woken);
+            for (attempt, command) in attempts(&operation).into_iter().enumerate() {
+                let reply = self.execute(command).await?;
+                if let Some(reply) = served(&operation, attempt, reply) {
+                    drop(wait);
+                    // There may be enough left for the next client in line
+                    keys.iter().for_each(|key| waiters.signal(key));
+                    return Ok(reply);
+                }
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, wait.notified()).await.is_err() {
+                        return Ok(timeout_reply(&operation));
+                    }
+                }
+                None => wait.notified().await,
+            }
+            woken = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{ConfigCommand, ListCommand, ListEnd, SetCommand, SubscriptionKind};
+
+    use super::*;
+
+    fn set(key: &'static str, value: &'static str) -> Command {
+        Command::Set(SetCommand { key: key.into(), value: value.into(), ..Default::default() })
+    }
+
+    #[tokio::test]
+    pub async fn test_blocking_with_a_timeout_past_the_clock() {
+        let server = Server::new();
+        let mut session = server.create_session();
+        let push = Command::List(ListCommand::Push { key: "l".into(), end: ListEnd::Left, values: vec!["a".into()], only_if_exists: false });
+        server.process_command(&mut session, push).await.unwrap();
+        let pop = BlockingCommand { operation: BlockingOperation::Pop { keys: vec!["l".into()], end: ListEnd::Left }, timeout: Some(Duration::MAX) };
+        assert_eq!(
+            server.process_command(&mut session, Command::Blocking(pop)).await,
+            Ok(DataType::Array(vec![DataType::BulkString("l".into()), DataType::BulkString("a".into())]))
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_exec_runs_the_queue_in_order() {
+        let server = Server::new();
+        let mut subscriber = server.create_session();
+        let subscribe = PubSubCommand::Subscribe { kind: SubscriptionKind::Channel, names: vec!["__keyevent@0__:set".into()] };
+        server.process_command(&mut subscriber, Command::PubSub(subscribe)).await.unwrap();
+
+        let mut session = server.create_session();
+        let queue = vec![
+            Command::Multi,
+            set("before", "1"),
+            Command::Config(ConfigCommand::Set { parameters: vec![("notify-keyspace-events".into(), "E$".into())] }),
+            set("after", "1"),
+            Command::Ping { message: None },
+        ];
+        for command in queue {
+            server.process_command(&mut session, command).await.unwrap();
+        }
+        assert_eq!(
+            server.process_command(&mut session, Command::Exec).await,
+            Ok(DataType::Array(vec![
+                DataType::SimpleString("OK".into()),
+                DataType::SimpleString("OK".into()),
+                DataType::SimpleString("OK".into()),
+                DataType::SimpleString("PONG".into()),
+            ]))
+        );
+
+        // Only the SET queued after the CONFIG SET was published
+        let inbox = subscriber.inbox.as_mut().unwrap();
+        let message = inbox.recv().await.unwrap();
+        assert_eq!(
+            message,
+            DataType::Push(vec![
+                DataType::BulkString("message".into()),
+                DataType::BulkString("__keyevent@0__:set".into()),
+                DataType::BulkString("after".into()),
+            ])
+        );
     }
 }
@@ -1,20 +1,159 @@
-use crate::commands::{Command, SetCommand, SetExistingOptions};
+use crate::commands::{Aggregate, BlockingCommand, BlockingOperation, Command, ConfigCommand, CopyCommand, ExpireCommand, ExpireCondition, HashCommand, HelloCommand, IncrBy, IncrCommand, LMoveCommand, LexBound, ListCommand, ListEnd, RenameCommand, SMoveCommand, ScanOptions, ScoreBound, ScoreComparison, SetAlgebraCommand, SetAlgebraOutput, SetCommand, SetExistingOptions, SetOperation, SetTypeCommand, ClaimOptions, PendingRange, PubSubCommand, ReadGroup, StreamCommand, StreamId, StreamTrim, SubscriptionKind, TrimStrategy, TtlKind, XAddId, XReadCommand, XReadId, ZAddOptions, ZRangeBy, ZRangeOptions, ZSetCommand, ZStoreCommand};
 use crate::datatypes::DataType;
+use bytes::Bytes;
 use phf::phf_map;
+use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH, Duration};
 
 struct CommandParsingContext {
     now: Duration,
 }
 
-static COMMAND_PARSER: phf::Map<
-    &'static str,
-    fn(ctx: &CommandParsingContext, datatype: &[DataType]) -> Result<Command, String>,
-> = phf_map! {
+type CommandParserFn = fn(ctx: &CommandParsingContext, datatype: &[DataType]) -> Result<Command, String>;
+
+static COMMAND_PARSER: phf::Map<&'static str, CommandParserFn> = phf_map! {
     "set" => parse_set,
     "get" => parse_get,
     "dump" => parse_dump,
     "config" => parse_config,
+    "ping" => parse_ping,
+    "subscribe" => parse_subscribe,
+    "unsubscribe" => parse_unsubscribe,
+    "psubscribe" => parse_psubscribe,
+    "punsubscribe" => parse_punsubscribe,
+    "publish" => parse_publish,
+    "ssubscribe" => parse_ssubscribe,
+    "sunsubscribe" => parse_sunsubscribe,
+    "spublish" => parse_spublish,
+    "pubsub" => parse_pubsub,
+    "hello" => parse_hello,
+    "auth" => parse_auth,
+    "multi" => parse_multi,
+    "exec" => parse_exec,
+    "discard" => parse_discard,
+    "watch" => parse_watch,
+    "unwatch" => parse_unwatch,
+    "incr" => parse_incr,
+    "decr" => parse_decr,
+    "incrby" => parse_incrby,
+    "decrby" => parse_decrby,
+    "incrbyfloat" => parse_incrbyfloat,
+    "expire" => parse_expire,
+    "pexpire" => parse_pexpire,
+    "expireat" => parse_expireat,
+    "pexpireat" => parse_pexpireat,
+    "persist" => parse_persist,
+    "ttl" => parse_ttl,
+    "pttl" => parse_pttl,
+    "expiretime" => parse_expiretime,
+    "pexpiretime" => parse_pexpiretime,
+    "del" => parse_del,
+    "unlink" => parse_unlink,
+    "exists" => parse_exists,
+    "touch" => parse_touch,
+    "type" => parse_type,
+    "rename" => parse_rename,
+    "renamenx" => parse_renamenx,
+    "copy" => parse_copy,
+    "lpush" => parse_lpush,
+    "rpush" => parse_rpush,
+    "lpushx" => parse_lpushx,
+    "rpushx" => parse_rpushx,
+    "lpop" => parse_lpop,
+    "rpop" => parse_rpop,
+    "lrange" => parse_lrange,
+    "llen" => parse_llen,
+    "lindex" => parse_lindex,
+    "lset" => parse_lset,
+    "lrem" => parse_lrem,
+    "ltrim" => parse_ltrim,
+    "linsert" => parse_linsert,
+    "lmove" => parse_lmove,
+    "rpoplpush" => parse_rpoplpush,
+    "blpop" => parse_blpop,
+    "brpop" => parse_brpop,
+    "blmove" => parse_blmove,
+    "brpoplpush" => parse_brpoplpush,
+    "hset" => parse_hset,
+    "hsetnx" => parse_hsetnx,
+    "hget" => parse_hget,
+    "hmget" => parse_hmget,
+    "hdel" => parse_hdel,
+    "hgetall" => parse_hgetall,
+    "hkeys" => parse_hkeys,
+    "hvals" => parse_hvals,
+    "hlen" => parse_hlen,
+    "hexists" => parse_hexists,
+    "hincrby" => parse_hincrby,
+    "hincrbyfloat" => parse_hincrbyfloat,
+    "hscan" => parse_hscan,
+    "hrandfield" => parse_hrandfield,
+    "hexpire" => parse_hexpire,
+    "hpexpire" => parse_hpexpire,
+    "hexpireat" => parse_hexpireat,
+    "hpexpireat" => parse_hpexpireat,
+    "httl" => parse_httl,
+    "hpttl" => parse_hpttl,
+    "hexpiretime" => parse_hexpiretime,
+    "hpexpiretime" => parse_hpexpiretime,
+    "hpersist" => parse_hpersist,
+    "sadd" => parse_sadd,
+    "srem" => parse_srem,
+    "smembers" => parse_smembers,
+    "sismember" => parse_sismember,
+    "smismember" => parse_smismember,
+    "scard" => parse_scard,
+    "spop" => parse_spop,
+    "srandmember" => parse_srandmember,
+    "sscan" => parse_sscan,
+    "sinter" => parse_sinter,
+    "sunion" => parse_sunion,
+    "sdiff" => parse_sdiff,
+    "sinterstore" => parse_sinterstore,
+    "sunionstore" => parse_sunionstore,
+    "sdiffstore" => parse_sdiffstore,
+    "sintercard" => parse_sintercard,
+    "smove" => parse_smove,
+    "zadd" => parse_zadd,
+    "zincrby" => parse_zincrby,
+    "zrem" => parse_zrem,
+    "zscore" => parse_zscore,
+    "zmscore" => parse_zmscore,
+    "zcard" => parse_zcard,
+    "zcount" => parse_zcount,
+    "zlexcount" => parse_zlexcount,
+    "zrank" => parse_zrank,
+    "zrevrank" => parse_zrevrank,
+    "zrange" => parse_zrange,
+    "zrevrange" => parse_zrevrange,
+    "zrangebyscore" => parse_zrangebyscore,
+    "zrevrangebyscore" => parse_zrevrangebyscore,
+    "zrangebylex" => parse_zrangebylex,
+    "zrevrangebylex" => parse_zrevrangebylex,
+    "zremrangebyrank" => parse_zremrangebyrank,
+    "zremrangebyscore" => parse_zremrangebyscore,
+    "zremrangebylex" => parse_zremrangebylex,
+    "zpopmin" => parse_zpopmin,
+    "zpopmax" => parse_zpopmax,
+    "bzpopmin" => parse_bzpopmin,
+    "bzpopmax" => parse_bzpopmax,
+    "zunionstore" => parse_zunionstore,
+    "zinterstore" => parse_zinterstore,
+    "zdiffstore" => parse_zdiffstore,
+    "xadd" => parse_xadd,
+    "xrange" => parse_xrange,
+    "xrevrange" => parse_xrevrange,
+    "xlen" => parse_xlen,
+    "xtrim" => parse_xtrim,
+    "xdel" => parse_xdel,
+    "xread" => parse_xread,
+    "xreadgroup" => parse_xreadgroup,
+    "xgroup" => parse_xgroup,
+    "xack" => parse_xack,
+    "xpending" => parse_xpending,
+    "xclaim" => parse_xclaim,
+    "xautoclaim" => parse_xautoclaim,
+    "xinfo" => parse_xinfo,
 };
 
 fn current_unix_timestamp_millis() -> Duration {
@@ -22,12 +161,32 @@ fn current_unix_timestamp_millis() -> Duration {
     now.duration_since(UNIX_EPOCH).expect("SystemTime before UNIX EPOCH!")
 }
 
+fn parse_number<T: FromStr>(x: &[u8]) -> Result<T, String> {
+    std::str::from_utf8(x)
+        .ok()
+        .and_then(|str| str.parse::<T>().ok())
+        .ok_or("ERR value is not an integer or out of range".to_string())
+}
+
+/// Reads every argument as a key, for the commands that take one or more keys and nothing else.
+fn parse_keys(x: &[DataType]) -> Result<Vec<Bytes>, String> {
+    if x.is_empty() {
+        return Err("Invalid structure".into());
+    }
+    x.iter()
+        .map(|x| match x {
+            DataType::BulkString(key) => Ok(key.clone()),
+            _ => Err("Invalid datatype, expected BulkString".to_string()),
+        })
+        .collect()
+}
+
 fn parse_set(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
     match x {
         [DataType::BulkString(key), DataType::BulkString(value), rest@..] => {
             let mut command = SetCommand {
-                key: key.to_string(),
-                value: value.to_string(),
+                key: key.clone(),
+                value: value.clone(),
                 ..Default::default()
             };
 
@@ -38,46 +197,46 @@ fn parse_set(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, Str
                         return Err("Invalid datatype, expected BulkString".to_string());
                     };
                     idx += 1;
-                    return Ok(x);
+                    Ok(x)
                 };
 
                 let x = read_next()?;
 
-                match x.as_ref() {
-                    "NX" => {
+                match x.to_ascii_uppercase().as_slice() {
+                    b"NX" => {
                         command.set_existing = Some(SetExistingOptions::OnlySetIfNotExists);
                     },
-                    "XX" => {
+                    b"XX" => {
                         command.set_existing = Some(SetExistingOptions::OnlySetIfExists);
                     },
-                    "EX" => {
+                    b"EX" => {
                         let next_val = read_next()?;
-                        let seconds = next_val.parse::<u64>().map_err(|err| err.to_string())?;
+                        let seconds = parse_number::<u64>(next_val)?;
                         let duration = Duration::from_secs(seconds);
                         let result = ctx.now + duration;
                         let ms_since = result.as_millis();
                         command.expiration = Some(ms_since);
                     },
-                    "PX" => {
+                    b"PX" => {
                         let next_val = read_next()?;
-                        let milliseconds = next_val.parse::<u64>().map_err(|err| err.to_string())?;
+                        let milliseconds = parse_number::<u64>(next_val)?;
                         let duration = Duration::from_millis(milliseconds);
                         let result = ctx.now + duration;
                         let ms_since = result.as_millis();
                         command.expiration = Some(ms_since);
                     },
-                    "EXAT" => {
+                    b"EXAT" => {
                         let next_val = read_next()?;
-                        let seconds = next_val.parse::<u128>().map_err(|err| err.to_string())?;
+                        let seconds = parse_number::<u128>(next_val)?;
                         command.expiration = Some(seconds.saturating_mul(1000));
                     },
-                    "PXAT" => {
+                    b"PXAT" => {
                         let next_val = read_next()?;
-                        let milliseconds = next_val.parse::<u128>().map_err(|err| err.to_string())?;
+                        let milliseconds = parse_number::<u128>(next_val)?;
                         command.expiration = Some(milliseconds);
                     },
-                    x => {
-                        return Err(format!("Invalid command sequence: {}", x));
+                    _ => {
+                        return Err(format!("Invalid command sequence: {}", String::from_utf8_lossy(x)));
                     }
                 }
             }
@@ -88,35 +247,1939 @@ fn parse_set(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, Str
     }
 }
 
-fn parse_get(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+fn parse_get(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Get {
+            key: key.clone(),
+        }),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_incr(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Incr(IncrCommand {
+            key: key.clone(),
+            by: IncrBy::Integer(1),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_decr(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Incr(IncrCommand {
+            key: key.clone(),
+            by: IncrBy::Integer(-1),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_incrby(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(increment)] => Ok(Command::Incr(IncrCommand {
+            key: key.clone(),
+            by: IncrBy::Integer(parse_number::<i64>(increment)?),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_decrby(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(decrement)] => {
+            let decrement = parse_number::<i64>(decrement)?;
+            let increment = decrement
+                .checked_neg()
+                .ok_or("ERR decrement would overflow".to_string())?;
+            Ok(Command::Incr(IncrCommand {
+                key: key.clone(),
+                by: IncrBy::Integer(increment),
+            }))
+        }
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_incrbyfloat(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(increment)] => {
+            let increment = parse_number::<f64>(increment)
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or("ERR value is not a valid float".to_string())?;
+            Ok(Command::Incr(IncrCommand {
+                key: key.clone(),
+                by: IncrBy::Float(increment),
+            }))
+        }
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+/// Shared by the EXPIRE families, `to_millis` turns the given time into a unix timestamp in
+/// milliseconds or returns None if it overflows.
+fn parse_expire_time(name: &str, time: &[u8], to_millis: impl Fn(i64) -> Option<i128>) -> Result<u128, String> {
+    let invalid_time = || format!("ERR invalid expire time in '{}' command", name);
+    let expiration = to_millis(parse_number::<i64>(time)?)
+        .filter(|x| *x <= i64::MAX as i128)
+        .ok_or_else(invalid_time)?;
+    // Anything before the epoch is just as expired as the epoch itself
+    Ok(expiration.max(0) as u128)
+}
+
+fn parse_expire_conditions(options: &[DataType]) -> Result<Vec<ExpireCondition>, String> {
+    let mut conditions = Vec::new();
+    for option in options {
+        let DataType::BulkString(option) = option else {
+            return Err("Invalid datatype, expected BulkString".to_string());
+        };
+        let condition = match option.to_ascii_uppercase().as_slice() {
+            b"NX" => ExpireCondition::NoExpiry,
+            b"XX" => ExpireCondition::HasExpiry,
+            b"GT" => ExpireCondition::GreaterThan,
+            b"LT" => ExpireCondition::LessThan,
+            _ => return Err(format!("ERR Unsupported option {}", String::from_utf8_lossy(option))),
+        };
+        if !conditions.contains(&condition) {
+            conditions.push(condition);
+        }
+    }
+
+    if conditions.contains(&ExpireCondition::NoExpiry) && conditions.len() > 1 {
+        return Err("ERR NX and XX, GT or LT options at the same time are not compatible".into());
+    }
+    if conditions.contains(&ExpireCondition::GreaterThan) && conditions.contains(&ExpireCondition::LessThan) {
+        return Err("ERR GT and LT options at the same time are not compatible".into());
+    }
+    Ok(conditions)
+}
+
+fn parse_expire_command(
+    name: &str,
+    x: &[DataType],
+    to_millis: impl Fn(i64) -> Option<i128>,
+) -> Result<Command, String> {
+    let [DataType::BulkString(key), DataType::BulkString(time), options @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+
+    Ok(Command::Expire(ExpireCommand {
+        key: key.clone(),
+        expiration: parse_expire_time(name, time, to_millis)?,
+        conditions: parse_expire_conditions(options)?,
+    }))
+}
+
+fn parse_expire(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let now = ctx.now.as_millis() as i128;
+    parse_expire_command("expire", x, |seconds| (seconds as i128).checked_mul(1000).map(|x| x + now))
+}
+
+fn parse_pexpire(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let now = ctx.now.as_millis() as i128;
+    parse_expire_command("pexpire", x, |milliseconds| Some(milliseconds as i128 + now))
+}
+
+fn parse_expireat(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_expire_command("expireat", x, |seconds| (seconds as i128).checked_mul(1000))
+}
+
+fn parse_pexpireat(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_expire_command("pexpireat", x, |milliseconds| Some(milliseconds as i128))
+}
+
+fn parse_persist(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Persist { key: key.clone() }),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_ttl_command(x: &[DataType], kind: TtlKind) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Ttl { key: key.clone(), kind }),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_ttl(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_ttl_command(x, TtlKind::Ttl)
+}
+
+fn parse_pttl(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_ttl_command(x, TtlKind::Pttl)
+}
+
+fn parse_expiretime(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_ttl_command(x, TtlKind::ExpireTime)
+}
+
+fn parse_pexpiretime(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_ttl_command(x, TtlKind::PexpireTime)
+}
+
+fn parse_del(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    Ok(Command::Del { keys: parse_keys(x)?, unlink: false })
+}
+
+fn parse_unlink(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    Ok(Command::Del { keys: parse_keys(x)?, unlink: true })
+}
+
+fn parse_exists(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    Ok(Command::Exists { keys: parse_keys(x)? })
+}
+
+fn parse_touch(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    Ok(Command::Touch { keys: parse_keys(x)? })
+}
+
+fn parse_type(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Type { key: key.clone() }),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_rename_command(x: &[DataType], only_if_new: bool) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(source), DataType::BulkString(destination)] => Ok(Command::Rename(RenameCommand {
+            source: source.clone(),
+            destination: destination.clone(),
+            only_if_new,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_rename(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_rename_command(x, false)
+}
+
+fn parse_renamenx(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_rename_command(x, true)
+}
+
+fn parse_copy(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [DataType::BulkString(source), DataType::BulkString(destination), options @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+
+    let mut command = CopyCommand {
+        source: source.clone(),
+        destination: destination.clone(),
+        replace: false,
+    };
+    let mut idx = 0;
+    while let Some(option) = options.get(idx) {
+        let DataType::BulkString(option) = option else {
+            return Err("Invalid datatype, expected BulkString".to_string());
+        };
+        match option.to_ascii_uppercase().as_slice() {
+            b"REPLACE" => command.replace = true,
+            // There's only the one database, so it's the only one that can be named
+            b"DB" => match options.get(idx + 1) {
+                Some(DataType::BulkString(db)) if parse_number::<i64>(db)? == 0 => idx += 1,
+                Some(_) => return Err("ERR DB index is out of range".into()),
+                None => return Err("ERR syntax error".into()),
+            },
+            _ => return Err("ERR syntax error".into()),
+        }
+        idx += 1;
+    }
+
+    Ok(Command::Copy(command))
+}
+
+fn parse_push_command(x: &[DataType], end: ListEnd, only_if_exists: bool) -> Result<Command, String> {
+    let [DataType::BulkString(key), values @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    Ok(Command::List(ListCommand::Push {
+        key: key.clone(),
+        end,
+        values: parse_keys(values)?,
+        only_if_exists,
+    }))
+}
+
+fn parse_lpush(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_push_command(x, ListEnd::Left, false)
+}
+
+fn parse_rpush(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_push_command(x, ListEnd::Right, false)
+}
+
+fn parse_lpushx(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_push_command(x, ListEnd::Left, true)
+}
+
+fn parse_rpushx(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_push_command(x, ListEnd::Right, true)
+}
+
+/// The count of the pop commands, which can't be negative.
+fn parse_pop_count(x: &[u8]) -> Result<usize, String> {
+    usize::try_from(parse_number::<i64>(x)?).map_err(|_| "ERR value is out of range, must be positive".to_string())
+}
+
+/// The count of HRANDFIELD and SRANDMEMBER. Same limit as redis, a negative count repeats
+/// members so it could otherwise ask for anything.
+fn parse_random_count(x: &[u8]) -> Result<i64, String> {
+    let count = parse_number::<i64>(x)?;
+    if count < -(i64::MAX / 2) {
+        return Err("ERR value is out of range".into());
+    }
+    Ok(count)
+}
+
+/// The timeout of the blocking commands in seconds, None for 0 which waits forever.
+fn parse_timeout(x: &[u8]) -> Result<Option<Duration>, String> {
+    let seconds = std::str::from_utf8(x)
+        .ok()
+        .and_then(|str| str.parse::<f64>().ok())
+        .filter(|x| x.is_finite())
+        .ok_or("ERR timeout is not a float or out of range".to_string())?;
+    if seconds < 0.0 {
+        return Err("ERR timeout is negative".into());
+    }
+    let timeout = Duration::try_from_secs_f64(seconds).map_err(|_| "ERR timeout is out of range".to_string())?;
+    Ok(Some(timeout).filter(|timeout| !timeout.is_zero()))
+}
+
+/// Splits the keys of BLPOP, BRPOP, BZPOPMIN and BZPOPMAX from the timeout that comes last.
+fn parse_blocking_keys(x: &[DataType], name: &str) -> Result<(Vec<Bytes>, Option<Duration>), String> {
+    let mut keys = parse_keys(x)?;
+    let timeout = keys.pop().filter(|_| !keys.is_empty()).ok_or(format!("ERR wrong number of arguments for '{}' command", name))?;
+    Ok((keys, parse_timeout(&timeout)?))
+}
+
+fn parse_pop_command(x: &[DataType], end: ListEnd) -> Result<Command, String> {
+    let (key, count) = match x {
+        [DataType::BulkString(key)] => (key, None),
+        [DataType::BulkString(key), DataType::BulkString(count)] => (key, Some(parse_pop_count(count)?)),
+        _ => return Err("Invalid structure".into()),
+    };
+    Ok(Command::List(ListCommand::Pop { key: key.clone(), end, count }))
+}
+
+fn parse_lpop(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_pop_command(x, ListEnd::Left)
+}
+
+fn parse_rpop(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_pop_command(x, ListEnd::Right)
+}
+
+fn parse_lrange(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(start), DataType::BulkString(stop)] => Ok(Command::List(ListCommand::Range {
+            key: key.clone(),
+            start: parse_number::<i64>(start)?,
+            stop: parse_number::<i64>(stop)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_llen(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::List(ListCommand::Len { key: key.clone() })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_lindex(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(index)] => Ok(Command::List(ListCommand::Index {
+            key: key.clone(),
+            index: parse_number::<i64>(index)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_lset(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(index), DataType::BulkString(value)] => Ok(Command::List(ListCommand::Set {
+            key: key.clone(),
+            index: parse_number::<i64>(index)?,
+            value: value.clone(),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_lrem(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(count), DataType::BulkString(value)] => Ok(Command::List(ListCommand::Rem {
+            key: key.clone(),
+            count: parse_number::<i64>(count)?,
+            value: value.clone(),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_ltrim(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(start), DataType::BulkString(stop)] => Ok(Command::List(ListCommand::Trim {
+            key: key.clone(),
+            start: parse_number::<i64>(start)?,
+            stop: parse_number::<i64>(stop)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_linsert(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [DataType::BulkString(key), DataType::BulkString(position), DataType::BulkString(pivot), DataType::BulkString(value)] = x else {
+        return Err("Invalid structure".into());
+    };
+    let before = match position.to_ascii_uppercase().as_slice() {
+        b"BEFORE" => true,
+        b"AFTER" => false,
+        _ => return Err("ERR syntax error".into()),
+    };
+    Ok(Command::List(ListCommand::Insert {
+        key: key.clone(),
+        before,
+        pivot: pivot.clone(),
+        value: value.clone(),
+    }))
+}
+
+fn parse_list_end(x: &[u8]) -> Result<ListEnd, String> {
+    match x.to_ascii_uppercase().as_slice() {
+        b"LEFT" => Ok(ListEnd::Left),
+        b"RIGHT" => Ok(ListEnd::Right),
+        _ => Err("ERR syntax error".into()),
+    }
+}
+
+fn parse_lmove(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(source), DataType::BulkString(destination), DataType::BulkString(from), DataType::BulkString(to)] => Ok(Command::LMove(LMoveCommand {
+            source: source.clone(),
+            destination: destination.clone(),
+            from: parse_list_end(from)?,
+            to: parse_list_end(to)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_rpoplpush(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(source), DataType::BulkString(destination)] => Ok(Command::LMove(LMoveCommand {
+            source: source.clone(),
+            destination: destination.clone(),
+            from: ListEnd::Right,
+            to: ListEnd::Left,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_blpop(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let (keys, timeout) = parse_blocking_keys(x, "blpop")?;
+    Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::Pop { keys, end: ListEnd::Left }, timeout }))
+}
+
+fn parse_brpop(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let (keys, timeout) = parse_blocking_keys(x, "brpop")?;
+    Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::Pop { keys, end: ListEnd::Right }, timeout }))
+}
+
+fn parse_blmove(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [args @ .., DataType::BulkString(timeout)] = x else {
+        return Err("Invalid structure".into());
+    };
+    let Command::LMove(cmd) = parse_lmove(ctx, args)? else {
+        return Err("Invalid structure".into());
+    };
+    Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::Move(cmd), timeout: parse_timeout(timeout)? }))
+}
+
+fn parse_brpoplpush(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [args @ .., DataType::BulkString(timeout)] = x else {
+        return Err("Invalid structure".into());
+    };
+    let Command::LMove(cmd) = parse_rpoplpush(ctx, args)? else {
+        return Err("Invalid structure".into());
+    };
+    Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::Move(cmd), timeout: parse_timeout(timeout)? }))
+}
+
+/// Reads the cursor and the MATCH and COUNT options of the SCAN family. Any other option is
+/// handed to `flag`, which returns false if it doesn't know it either.
+fn parse_scan_options(cursor: &[u8], options: &[DataType], mut flag: impl FnMut(&[u8]) -> bool) -> Result<ScanOptions, String> {
+    let mut scan = ScanOptions {
+        cursor: parse_number::<u64>(cursor).map_err(|_| "ERR invalid cursor".to_string())?,
+        pattern: None,
+        count: 10,
+    };
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let DataType::BulkString(option) = option else {
+            return Err("Invalid datatype, expected BulkString".to_string());
+        };
+        match (option.to_ascii_uppercase().as_slice(), options.next()) {
+            (b"MATCH", Some(DataType::BulkString(pattern))) => scan.pattern = Some(pattern.clone()),
+            (b"COUNT", Some(DataType::BulkString(count))) => {
+                scan.count = parse_number::<usize>(count)?;
+                if scan.count < 1 {
+                    return Err("ERR syntax error".into());
+                }
+            }
+            _ if flag(option) => {}
+            _ => return Err("ERR syntax error".into()),
+        }
+    }
+    Ok(scan)
+}
+
+fn parse_hset_command(x: &[DataType], only_if_new: bool) -> Result<Command, String> {
+    let [DataType::BulkString(key), pairs @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    if pairs.is_empty() || pairs.len() % 2 != 0 || (only_if_new && pairs.len() != 2) {
+        return Err("Invalid structure".into());
+    }
+
+    let values = parse_keys(pairs)?;
+    Ok(Command::Hash(HashCommand::Set {
+        key: key.clone(),
+        fields: values.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect(),
+        only_if_new,
+    }))
+}
+
+fn parse_hset(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hset_command(x, false)
+}
+
+fn parse_hsetnx(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hset_command(x, true)
+}
+
+fn parse_hget(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(field)] => Ok(Command::Hash(HashCommand::Get {
+            key: key.clone(),
+            field: field.clone(),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hmget(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), fields @ ..] => Ok(Command::Hash(HashCommand::MGet {
+            key: key.clone(),
+            fields: parse_keys(fields)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hdel(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), fields @ ..] => Ok(Command::Hash(HashCommand::Del {
+            key: key.clone(),
+            fields: parse_keys(fields)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hash_key_command(x: &[DataType], command: fn(Bytes) -> HashCommand) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Hash(command(key.clone()))),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hgetall(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hash_key_command(x, |key| HashCommand::GetAll { key })
+}
+
+fn parse_hkeys(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hash_key_command(x, |key| HashCommand::Keys { key })
+}
+
+fn parse_hvals(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hash_key_command(x, |key| HashCommand::Vals { key })
+}
+
+fn parse_hlen(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hash_key_command(x, |key| HashCommand::Len { key })
+}
+
+fn parse_hexists(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(field)] => Ok(Command::Hash(HashCommand::Exists {
+            key: key.clone(),
+            field: field.clone(),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hincrby(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(field), DataType::BulkString(increment)] => Ok(Command::Hash(HashCommand::IncrBy {
+            key: key.clone(),
+            field: field.clone(),
+            by: IncrBy::Integer(parse_number::<i64>(increment)?),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hincrbyfloat(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(field), DataType::BulkString(increment)] => {
+            let increment = parse_number::<f64>(increment)
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or("ERR value is not a valid float".to_string())?;
+            Ok(Command::Hash(HashCommand::IncrBy {
+                key: key.clone(),
+                field: field.clone(),
+                by: IncrBy::Float(increment),
+            }))
+        }
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hscan(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [DataType::BulkString(key), DataType::BulkString(cursor), options @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+
+    let mut no_values = false;
+    let options = parse_scan_options(cursor, options, |flag| {
+        let known = flag.eq_ignore_ascii_case(b"NOVALUES");
+        no_values |= known;
+        known
+    })?;
+    Ok(Command::Hash(HashCommand::Scan { key: key.clone(), options, no_values }))
+}
+
+fn parse_hrandfield(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let (key, count, with_values) = match x {
+        [DataType::BulkString(key)] => (key, None, false),
+        [DataType::BulkString(key), DataType::BulkString(count)] => (key, Some(count), false),
+        [DataType::BulkString(key), DataType::BulkString(count), DataType::BulkString(option)]
+            if option.eq_ignore_ascii_case(b"WITHVALUES") => (key, Some(count), true),
+        _ => return Err("Invalid structure".into()),
+    };
+    let count = count.map(|count| parse_random_count(count)).transpose()?;
+    Ok(Command::Hash(HashCommand::RandField { key: key.clone(), count, with_values }))
+}
+
+/// Reads the `numfields field [field ...]` that the field expiration commands end with.
+fn parse_numbered_fields(x: &[DataType]) -> Result<Vec<Bytes>, String> {
+    let [DataType::BulkString(count), fields @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    let count = parse_number::<usize>(count)
+        .ok()
+        .filter(|x| *x > 0)
+        .ok_or("ERR Parameter `numFields` should be greater than 0".to_string())?;
+    if count != fields.len() {
+        return Err("ERR The `numfields` parameter must match the number of arguments".into());
+    }
+    parse_keys(fields)
+}
+
+fn parse_hexpire_command(
+    name: &str,
+    x: &[DataType],
+    to_millis: impl Fn(i64) -> Option<i128>,
+) -> Result<Command, String> {
+    let [DataType::BulkString(key), DataType::BulkString(time), rest @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    let fields_at = rest
+        .iter()
+        .position(|x| matches!(x, DataType::BulkString(x) if x.eq_ignore_ascii_case(b"FIELDS")))
+        .ok_or("ERR Mandatory argument FIELDS is missing or not at the right position".to_string())?;
+
+    Ok(Command::Hash(HashCommand::Expire {
+        key: key.clone(),
+        expiration: parse_expire_time(name, time, to_millis)?,
+        conditions: parse_expire_conditions(&rest[..fields_at])?,
+        fields: parse_numbered_fields(&rest[fields_at + 1..])?,
+    }))
+}
+
+fn parse_hexpire(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let now = ctx.now.as_millis() as i128;
+    parse_hexpire_command("hexpire", x, |seconds| (seconds as i128).checked_mul(1000).map(|x| x + now))
+}
+
+fn parse_hpexpire(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let now = ctx.now.as_millis() as i128;
+    parse_hexpire_command("hpexpire", x, |milliseconds| Some(milliseconds as i128 + now))
+}
+
+fn parse_hexpireat(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hexpire_command("hexpireat", x, |seconds| (seconds as i128).checked_mul(1000))
+}
+
+fn parse_hpexpireat(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_hexpire_command("hpexpireat", x, |milliseconds| Some(milliseconds as i128))
+}
+
+/// Splits off the `FIELDS numfields field [field ...]` shared by HTTL, HPERSIST and the rest.
+fn parse_hash_fields_command(x: &[DataType]) -> Result<(Bytes, Vec<Bytes>), String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(option), fields @ ..] if option.eq_ignore_ascii_case(b"FIELDS") => {
+            Ok((key.clone(), parse_numbered_fields(fields)?))
+        }
+        [_, _, ..] => Err("ERR Mandatory argument FIELDS is missing or not at the right position".into()),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_httl_command(x: &[DataType], kind: TtlKind) -> Result<Command, String> {
+    let (key, fields) = parse_hash_fields_command(x)?;
+    Ok(Command::Hash(HashCommand::Ttl { key, kind, fields }))
+}
+
+fn parse_httl(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_httl_command(x, TtlKind::Ttl)
+}
+
+fn parse_hpttl(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_httl_command(x, TtlKind::Pttl)
+}
+
+fn parse_hexpiretime(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_httl_command(x, TtlKind::ExpireTime)
+}
+
+fn parse_hpexpiretime(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_httl_command(x, TtlKind::PexpireTime)
+}
+
+fn parse_hpersist(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let (key, fields) = parse_hash_fields_command(x)?;
+    Ok(Command::Hash(HashCommand::Persist { key, fields }))
+}
+
+fn parse_set_members_command(x: &[DataType], command: fn(Bytes, Vec<Bytes>) -> SetTypeCommand) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), members @ ..] => Ok(Command::SetType(command(key.clone(), parse_keys(members)?))),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_sadd(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_members_command(x, |key, members| SetTypeCommand::Add { key, members })
+}
+
+fn parse_srem(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_members_command(x, |key, members| SetTypeCommand::Rem { key, members })
+}
+
+fn parse_smismember(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_members_command(x, |key, members| SetTypeCommand::MIsMember { key, members })
+}
+
+fn parse_smembers(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::SetType(SetTypeCommand::Members { key: key.clone() })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_scard(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::SetType(SetTypeCommand::Card { key: key.clone() })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_sismember(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(member)] => Ok(Command::SetType(SetTypeCommand::IsMember {
+            key: key.clone(),
+            member: member.clone(),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_spop(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::SetType(SetTypeCommand::Pop { key: key.clone(), count: None })),
+        [DataType::BulkString(key), DataType::BulkString(count)] => Ok(Command::SetType(SetTypeCommand::Pop {
+            key: key.clone(),
+            count: Some(parse_pop_count(count)?),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_srandmember(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::SetType(SetTypeCommand::RandMember { key: key.clone(), count: None })),
+        [DataType::BulkString(key), DataType::BulkString(count)] => Ok(Command::SetType(SetTypeCommand::RandMember {
+            key: key.clone(),
+            count: Some(parse_random_count(count)?),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_sscan(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [DataType::BulkString(key), DataType::BulkString(cursor), options @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    Ok(Command::SetType(SetTypeCommand::Scan {
+        key: key.clone(),
+        options: parse_scan_options(cursor, options, |_| false)?,
+    }))
+}
+
+fn parse_set_algebra_command(x: &[DataType], operation: SetOperation, store: bool) -> Result<Command, String> {
+    let (output, keys) = match (store, x) {
+        (true, [DataType::BulkString(destination), keys @ ..]) => (SetAlgebraOutput::Store(destination.clone()), keys),
+        (false, keys) => (SetAlgebraOutput::Members, keys),
+        _ => return Err("Invalid structure".into()),
+    };
+    Ok(Command::SetAlgebra(SetAlgebraCommand {
+        operation,
+        keys: parse_keys(keys)?,
+        output,
+    }))
+}
+
+fn parse_sinter(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_algebra_command(x, SetOperation::Inter, false)
+}
+
+fn parse_sunion(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_algebra_command(x, SetOperation::Union, false)
+}
+
+fn parse_sdiff(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_algebra_command(x, SetOperation::Diff, false)
+}
+
+fn parse_sinterstore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_algebra_command(x, SetOperation::Inter, true)
+}
+
+fn parse_sunionstore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_algebra_command(x, SetOperation::Union, true)
+}
+
+fn parse_sdiffstore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_set_algebra_command(x, SetOperation::Diff, true)
+}
+
+fn parse_sintercard(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [DataType::BulkString(numkeys), rest @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    let numkeys = parse_number::<usize>(numkeys)
+        .ok()
+        .filter(|x| *x > 0)
+        .ok_or("ERR numkeys should be greater than 0".to_string())?;
+    if numkeys > rest.len() {
+        return Err("ERR Number of keys can't be greater than number of args".into());
+    }
+
+    let (keys, options) = rest.split_at(numkeys);
+    let limit = match options {
+        [] => 0,
+        [DataType::BulkString(option), DataType::BulkString(limit)] if option.eq_ignore_ascii_case(b"LIMIT") => {
+            usize::try_from(parse_number::<i64>(limit)?).map_err(|_| "ERR LIMIT can't be negative".to_string())?
+        }
+        _ => return Err("ERR syntax error".into()),
+    };
+    Ok(Command::SetAlgebra(SetAlgebraCommand {
+        operation: SetOperation::Inter,
+        keys: parse_keys(keys)?,
+        output: SetAlgebraOutput::Cardinality { limit },
+    }))
+}
+
+fn parse_smove(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(source), DataType::BulkString(destination), DataType::BulkString(member)] => Ok(Command::SMove(SMoveCommand {
+            source: source.clone(),
+            destination: destination.clone(),
+            member: member.clone(),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+/// Scores can be anything a double can hold, including the infinities, but not NaN.
+fn parse_score(x: &[u8]) -> Result<f64, String> {
+    parse_number::<f64>(x)
+        .ok()
+        .filter(|x| !x.is_nan())
+        .ok_or("ERR value is not a valid float".to_string())
+}
+
+fn parse_zadd(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let [DataType::BulkString(key), rest @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+
+    let mut options = ZAddOptions::default();
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    let mut idx = 0;
+    while let Some(DataType::BulkString(option)) = rest.get(idx) {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"GT" => gt = true,
+            b"LT" => lt = true,
+            b"CH" => options.changed = true,
+            b"INCR" => options.increment = true,
+            _ => break,
+        }
+        idx += 1;
+    }
+
+    if nx && xx {
+        return Err("ERR XX and NX options at the same time are not compatible".into());
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return Err("ERR GT, LT, and/or NX options at the same time are not compatible".into());
+    }
+    let pairs = &rest[idx..];
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err("ERR syntax error".into());
+    }
+    if options.increment && pairs.len() > 2 {
+        return Err("ERR INCR option supports a single increment-element pair".into());
+    }
+
+    options.set_existing = match (nx, xx) {
+        (true, _) => Some(SetExistingOptions::OnlySetIfNotExists),
+        (_, true) => Some(SetExistingOptions::OnlySetIfExists),
+        _ => None,
+    };
+    options.comparison = match (gt, lt) {
+        (true, _) => Some(ScoreComparison::GreaterThan),
+        (_, true) => Some(ScoreComparison::LessThan),
+        _ => None,
+    };
+    let members = pairs
+        .chunks(2)
+        .map(|pair| match pair {
+            [DataType::BulkString(score), DataType::BulkString(member)] => Ok((parse_score(score)?, member.clone())),
+            _ => Err("Invalid datatype, expected BulkString".to_string()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Command::ZSet(ZSetCommand::Add { key: key.clone(), options, members }))
+}
+
+fn parse_zincrby(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(increment), DataType::BulkString(member)] => Ok(Command::ZSet(ZSetCommand::Add {
+            key: key.clone(),
+            options: ZAddOptions { increment: true, ..Default::default() },
+            members: vec![(parse_score(increment)?, member.clone())],
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_zrem(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), members @ ..] => Ok(Command::ZSet(ZSetCommand::Rem { key: key.clone(), members: parse_keys(members)? })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_zscore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(member)] => Ok(Command::ZSet(ZSetCommand::Score {
+            key: key.clone(),
+            member: member.clone(),
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_zmscore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), members @ ..] => Ok(Command::ZSet(ZSetCommand::MScore { key: key.clone(), members: parse_keys(members)? })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_zcard(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::ZSet(ZSetCommand::Card { key: key.clone() })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+/// A `(` in front makes the bound exclusive, `-inf` and `+inf` are the open ends.
+fn parse_score_bound(x: &[u8]) -> Result<ScoreBound, String> {
+    let (value, exclusive) = match x.strip_prefix(b"(") {
+        Some(value) => (value, true),
+        None => (x, false),
+    };
+    let value = parse_score(value).map_err(|_| "ERR min or max is not a float".to_string())?;
+    Ok(ScoreBound { value, exclusive })
+}
+
+fn parse_lex_bound(x: &[u8]) -> Result<LexBound, String> {
+    match x.split_first() {
+        Some((b'-', [])) => Ok(LexBound::Min),
+        Some((b'+', [])) => Ok(LexBound::Max),
+        Some((b'[', value)) => Ok(LexBound::Inclusive(Bytes::copy_from_slice(value))),
+        Some((b'(', value)) => Ok(LexBound::Exclusive(Bytes::copy_from_slice(value))),
+        _ => Err("ERR min or max not valid string range item".into()),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+fn parse_range_by(kind: RangeKind, min: &[u8], max: &[u8]) -> Result<ZRangeBy, String> {
+    match kind {
+        RangeKind::Rank => Ok(ZRangeBy::Rank { start: parse_number(min)?, stop: parse_number(max)? }),
+        RangeKind::Score => Ok(ZRangeBy::Score { min: parse_score_bound(min)?, max: parse_score_bound(max)? }),
+        RangeKind::Lex => Ok(ZRangeBy::Lex { min: parse_lex_bound(min)?, max: parse_lex_bound(max)? }),
+    }
+}
+
+fn parse_zcount_command(x: &[DataType], kind: RangeKind) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(min), DataType::BulkString(max)] => Ok(Command::ZSet(ZSetCommand::Count {
+            key: key.clone(),
+            by: parse_range_by(kind, min, max)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_zcount(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zcount_command(x, RangeKind::Score)
+}
+
+fn parse_zlexcount(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zcount_command(x, RangeKind::Lex)
+}
+
+fn parse_zrank_command(x: &[DataType], rev: bool) -> Result<Command, String> {
+    let (key, member, with_score) = match x {
+        [DataType::BulkString(key), DataType::BulkString(member)] => (key, member, false),
+        [DataType::BulkString(key), DataType::BulkString(member), DataType::BulkString(option)] if option.eq_ignore_ascii_case(b"WITHSCORE") => {
+            (key, member, true)
+        }
+        [_, _, _] => return Err("ERR syntax error".into()),
+        _ => return Err("Invalid structure".into()),
+    };
+    Ok(Command::ZSet(ZSetCommand::Rank { key: key.clone(), member: member.clone(), rev, with_score }))
+}
+
+fn parse_zrank(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrank_command(x, false)
+}
+
+fn parse_zrevrank(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrank_command(x, true)
+}
+
+/// Shared by ZRANGE and its older forms. Only ZRANGE itself, the `unified` one, takes BYSCORE,
+/// BYLEX and REV as options, the others have them baked into `kind` and `rev`.
+fn parse_zrange_command(x: &[DataType], kind: RangeKind, rev: bool, unified: bool) -> Result<Command, String> {
+    let [DataType::BulkString(key), DataType::BulkString(start), DataType::BulkString(stop), options @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+
+    let (mut kind, mut rev) = (kind, rev);
+    let mut limit = None;
+    let mut with_scores = false;
+    let mut idx = 0;
+    while let Some(option) = options.get(idx) {
+        let DataType::BulkString(option) = option else {
+            return Err("Invalid datatype, expected BulkString".to_string());
+        };
+        match (option.to_ascii_uppercase().as_slice(), unified) {
+            (b"WITHSCORES", _) => with_scores = true,
+            (b"BYSCORE", true) => kind = RangeKind::Score,
+            (b"BYLEX", true) => kind = RangeKind::Lex,
+            (b"REV", true) => rev = true,
+            (b"LIMIT", _) => {
+                let (Some(DataType::BulkString(offset)), Some(DataType::BulkString(count))) = (options.get(idx + 1), options.get(idx + 2)) else {
+                    return Err("ERR syntax error".into());
+                };
+                limit = Some((parse_number(offset)?, parse_number(count)?));
+                idx += 2;
+            }
+            _ => return Err("ERR syntax error".into()),
+        }
+        idx += 1;
+    }
+
+    if limit.is_some() && kind == RangeKind::Rank {
+        return Err("ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".into());
+    }
+    if with_scores && kind == RangeKind::Lex {
+        return Err("ERR syntax error, WITHSCORES not supported in combination with BYLEX".into());
+    }
+    // Reversed score and lex ranges are given highest first, reversed ranks still count up from the start
+    let (min, max) = if rev && kind != RangeKind::Rank { (stop, start) } else { (start, stop) };
+
+    Ok(Command::ZSet(ZSetCommand::Range {
+        key: key.clone(),
+        options: ZRangeOptions { by: parse_range_by(kind, min, max)?, rev, limit, with_scores },
+    }))
+}
+
+fn parse_zrange(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrange_command(x, RangeKind::Rank, false, true)
+}
+
+fn parse_zrevrange(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrange_command(x, RangeKind::Rank, true, false)
+}
+
+fn parse_zrangebyscore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrange_command(x, RangeKind::Score, false, false)
+}
+
+fn parse_zrevrangebyscore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrange_command(x, RangeKind::Score, true, false)
+}
+
+fn parse_zrangebylex(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrange_command(x, RangeKind::Lex, false, false)
+}
+
+fn parse_zrevrangebylex(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zrange_command(x, RangeKind::Lex, true, false)
+}
+
+fn parse_zremrange_command(x: &[DataType], kind: RangeKind) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key), DataType::BulkString(min), DataType::BulkString(max)] => Ok(Command::ZSet(ZSetCommand::RemRange {
+            key: key.clone(),
+            by: parse_range_by(kind, min, max)?,
+        })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_zremrangebyrank(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zremrange_command(x, RangeKind::Rank)
+}
+
+fn parse_zremrangebyscore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zremrange_command(x, RangeKind::Score)
+}
+
+fn parse_zremrangebylex(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zremrange_command(x, RangeKind::Lex)
+}
+
+fn parse_zpop_command(x: &[DataType], max: bool) -> Result<Command, String> {
+    let (key, count) = match x {
+        [DataType::BulkString(key)] => (key, None),
+        [DataType::BulkString(key), DataType::BulkString(count)] => (key, Some(parse_pop_count(count)?)),
+        _ => return Err("Invalid structure".into()),
+    };
+    Ok(Command::ZSet(ZSetCommand::Pop { key: key.clone(), max, count }))
+}
+
+fn parse_zpopmin(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zpop_command(x, false)
+}
+
+fn parse_zpopmax(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zpop_command(x, true)
+}
+
+fn parse_bzpopmin(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let (keys, timeout) = parse_blocking_keys(x, "bzpopmin")?;
+    Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::ZPop { keys, max: false }, timeout }))
+}
+
+fn parse_bzpopmax(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let (keys, timeout) = parse_blocking_keys(x, "bzpopmax")?;
+    Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::ZPop { keys, max: true }, timeout }))
+}
+
+fn parse_zstore_command(name: &str, x: &[DataType], operation: SetOperation) -> Result<Command, String> {
+    let [DataType::BulkString(destination), DataType::BulkString(numkeys), rest @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    let numkeys = parse_number::<usize>(numkeys)
+        .ok()
+        .filter(|x| *x > 0)
+        .ok_or_else(|| format!("ERR at least 1 input key is needed for '{}' command", name))?;
+    if numkeys > rest.len() {
+        return Err("ERR syntax error".into());
+    }
+
+    let (keys, options) = rest.split_at(numkeys);
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+    let mut idx = 0;
+    while let Some(option) = options.get(idx) {
+        let DataType::BulkString(option) = option else {
+            return Err("Invalid datatype, expected BulkString".to_string());
+        };
+        match option.to_ascii_uppercase().as_slice() {
+            b"WEIGHTS" if operation != SetOperation::Diff => {
+                let Some(values) = options.get(idx + 1..idx + 1 + numkeys) else {
+                    return Err("ERR syntax error".into());
+                };
+                for (weight, value) in weights.iter_mut().zip(values) {
+                    let DataType::BulkString(value) = value else {
+                        return Err("Invalid datatype, expected BulkString".to_string());
+                    };
+                    *weight = parse_score(value).map_err(|_| "ERR weight value is not a float".to_string())?;
+                }
+                idx += numkeys;
+            }
+            b"AGGREGATE" if operation != SetOperation::Diff => {
+                let Some(DataType::BulkString(value)) = options.get(idx + 1) else {
+                    return Err("ERR syntax error".into());
+                };
+                aggregate = match value.to_ascii_uppercase().as_slice() {
+                    b"SUM" => Aggregate::Sum,
+                    b"MIN" => Aggregate::Min,
+                    b"MAX" => Aggregate::Max,
+                    _ => return Err("ERR syntax error".into()),
+                };
+                idx += 1;
+            }
+            _ => return Err("ERR syntax error".into()),
+        }
+        idx += 1;
+    }
+
+    Ok(Command::ZStore(ZStoreCommand {
+        destination: destination.clone(),
+        operation,
+        keys: parse_keys(keys)?,
+        weights,
+        aggregate,
+    }))
+}
+
+fn parse_zunionstore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zstore_command("zunionstore", x, SetOperation::Union)
+}
+
+fn parse_zinterstore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zstore_command("zinterstore", x, SetOperation::Inter)
+}
+
+fn parse_zdiffstore(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_zstore_command("zdiffstore", x, SetOperation::Diff)
+}
+
+/// `ms-seq`, or just `ms` in which case the sequence number is `missing_seq`.
+fn parse_stream_id(x: &[u8], missing_seq: u64) -> Result<StreamId, String> {
+    let invalid = || "ERR Invalid stream ID specified as stream command argument".to_string();
+    let (ms, seq) = match x.iter().position(|x| *x == b'-') {
+        Some(idx) => (&x[..idx], Some(&x[idx + 1..])),
+        None => (x, None),
+    };
+    let ms = parse_number::<u64>(ms).map_err(|_| invalid())?;
+    let seq = match seq {
+        Some(seq) => parse_number::<u64>(seq).map_err(|_| invalid())?,
+        None => missing_seq,
+    };
+    Ok(StreamId { ms, seq })
+}
+
+/// The start or end of an XRANGE, `-` and `+` are the smallest and greatest IDs and a `(` in
+/// front makes the bound exclusive, which is resolved here by moving it one ID inwards.
+fn parse_stream_range_bound(x: &[u8], is_start: bool) -> Result<StreamId, String> {
+    match x {
+        b"-" => return Ok(StreamId::MIN),
+        b"+" => return Ok(StreamId::MAX),
+        _ => {}
+    }
+    let missing_seq = if is_start { 0 } else { u64::MAX };
+    let Some(id) = x.strip_prefix(b"(") else {
+        return parse_stream_id(x, missing_seq);
+    };
+    let id = parse_stream_id(id, missing_seq)?;
+    match is_start {
+        true => id.next().ok_or("ERR invalid start ID for the interval".into()),
+        false => id.prev().ok_or("ERR invalid end ID for the interval".into()),
+    }
+}
+
+/// Reads `MAXLEN|MINID [=|~] threshold [LIMIT count]` from the start of `args`, returns the
+/// options and how many arguments they took up.
+fn parse_stream_trim(args: &[Bytes]) -> Result<(StreamTrim, usize), String> {
+    let [strategy, rest @ ..] = args else {
+        return Err("ERR syntax error".into());
+    };
+    let (approximate, rest, mut used) = match rest.first().map(|x| x.as_ref()) {
+        Some(b"~") => (true, &rest[1..], 2),
+        Some(b"=") => (false, &rest[1..], 2),
+        _ => (false, rest, 1),
+    };
+    let Some(threshold) = rest.first() else {
+        return Err("ERR syntax error".into());
+    };
+    used += 1;
+
+    let strategy = match strategy.to_ascii_uppercase().as_slice() {
+        b"MAXLEN" => {
+            let max = u64::try_from(parse_number::<i64>(threshold)?).map_err(|_| "ERR The MAXLEN argument must be >= 0.".to_string())?;
+            TrimStrategy::MaxLen(max)
+        }
+        b"MINID" => TrimStrategy::MinId(parse_stream_id(threshold, 0)?),
+        _ => return Err("ERR syntax error".into()),
+    };
+
+    let limit = match rest.get(1..3) {
+        Some([option, count]) if option.eq_ignore_ascii_case(b"LIMIT") => {
+            if !approximate {
+                return Err("ERR syntax error, LIMIT cannot be used without the special ~ option".into());
+            }
+            used += 2;
+            // Same as redis, a limit of 0 means there's no limit
+            Some(parse_number::<usize>(count)?).filter(|x| *x > 0)
+        }
+        _ => None,
+    };
+    Ok((StreamTrim { strategy, limit }, used))
+}
+
+fn parse_xadd(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [key, rest @ ..] = args.as_slice() else {
+        return Err("Invalid structure".into());
+    };
+    let wrong_arguments = || "ERR wrong number of arguments for 'xadd' command".to_string();
+
+    let mut no_mkstream = false;
+    let mut trim = None;
+    let mut idx = 0;
+    let id = loop {
+        let arg = rest.get(idx).ok_or_else(wrong_arguments)?;
+        match arg.to_ascii_uppercase().as_slice() {
+            b"NOMKSTREAM" => {
+                no_mkstream = true;
+                idx += 1;
+            }
+            b"MAXLEN" | b"MINID" => {
+                let (options, used) = parse_stream_trim(&rest[idx..])?;
+                trim = Some(options);
+                idx += used;
+            }
+            b"*" => break XAddId::Auto { now: ctx.now.as_millis() as u64 },
+            _ => match arg.strip_suffix(b"-*") {
+                Some(ms) => break XAddId::AutoSequence { ms: parse_stream_id(ms, 0)?.ms },
+                None => break XAddId::Explicit(parse_stream_id(arg, 0)?),
+            },
+        }
+    };
+
+    let pairs = &rest[idx + 1..];
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(wrong_arguments());
+    }
+    Ok(Command::Stream(StreamCommand::Add {
+        key: key.clone(),
+        id,
+        fields: pairs.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect(),
+        no_mkstream,
+        trim,
+    }))
+}
+
+fn parse_xrange_command(x: &[DataType], rev: bool) -> Result<Command, String> {
+    let [DataType::BulkString(key), DataType::BulkString(first), DataType::BulkString(second), options @ ..] = x else {
+        return Err("Invalid structure".into());
+    };
+    let count = match options {
+        [] => None,
+        [DataType::BulkString(option), DataType::BulkString(count)] if option.eq_ignore_ascii_case(b"COUNT") => {
+            Some(parse_number::<i64>(count)?.max(0) as usize)
+        }
+        _ => return Err("ERR syntax error".into()),
+    };
+    // XREVRANGE takes the end first
+    let (start, end) = if rev { (second, first) } else { (first, second) };
+
+    Ok(Command::Stream(StreamCommand::Range {
+        key: key.clone(),
+        start: parse_stream_range_bound(start, true)?,
+        end: parse_stream_range_bound(end, false)?,
+        count,
+        rev,
+    }))
+}
+
+fn parse_xrange(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_xrange_command(x, false)
+}
+
+fn parse_xrevrange(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_xrange_command(x, true)
+}
+
+fn parse_xlen(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(key)] => Ok(Command::Stream(StreamCommand::Len { key: key.clone() })),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_xtrim(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [key, rest @ ..] = args.as_slice() else {
+        return Err("Invalid structure".into());
+    };
+    let (trim, used) = parse_stream_trim(rest)?;
+    if used != rest.len() {
+        return Err("ERR syntax error".into());
+    }
+    Ok(Command::Stream(StreamCommand::Trim { key: key.clone(), trim }))
+}
+
+fn parse_xdel(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [key, ids @ ..] = args.as_slice() else {
+        return Err("Invalid structure".into());
+    };
+    if ids.is_empty() {
+        return Err("ERR wrong number of arguments for 'xdel' command".into());
+    }
+    Ok(Command::Stream(StreamCommand::Del {
+        key: key.clone(),
+        ids: ids.iter().map(|id| parse_stream_id(id, 0)).collect::<Result<_, _>>()?,
+    }))
+}
+
+/// Everything after the command name of XREAD, or after the GROUP option of XREADGROUP.
+fn parse_xread_command(args: &[Bytes], mut group: Option<ReadGroup>) -> Result<Command, String> {
+    let name = if group.is_some() { "xreadgroup" } else { "xread" };
+    let mut count = None;
+    let mut block = None;
+    let mut idx = 0;
+    let streams = loop {
+        let Some(arg) = args.get(idx) else {
+            return Err("ERR syntax error".into());
+        };
+        match (arg.to_ascii_uppercase().as_slice(), group.as_mut()) {
+            (b"COUNT", _) => {
+                let value = args.get(idx + 1).ok_or("ERR syntax error".to_string())?;
+                // A count of 0 or less means there's no limit
+                count = usize::try_from(parse_number::<i64>(value)?).ok().filter(|x| *x > 0);
+                idx += 2;
+            }
+            (b"BLOCK", _) => {
+                let value = args.get(idx + 1).ok_or("ERR syntax error".to_string())?;
+                let millis = u64::try_from(parse_number::<i64>(value)?).map_err(|_| "ERR timeout is negative".to_string())?;
+                // Same as the other blocking commands, 0 waits forever
+                block = Some(Some(Duration::from_millis(millis)).filter(|timeout| !timeout.is_zero()));
+                idx += 2;
+            }
+            (b"NOACK", Some(group)) => {
+                group.no_ack = true;
+                idx += 1;
+            }
+            (b"STREAMS", _) => break &args[idx + 1..],
+            _ => return Err("ERR syntax error".into()),
+        }
+    };
+
+    if streams.is_empty() || streams.len() % 2 != 0 {
+        return Err(format!(
+            "ERR Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+            name
+        ));
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let streams = keys
+        .iter()
+        .zip(ids)
+        .map(|(key, id)| match (id.as_ref(), group.is_some()) {
+            (b"$", false) => Ok((key.clone(), XReadId::Last)),
+            (b"$", true) => Err("ERR The $ ID is meaningful only for XREAD".to_string()),
+            (b">", true) => Ok((key.clone(), XReadId::New)),
+            (b">", false) => {
+                Err("ERR The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option.".to_string())
+            }
+            (id, _) => Ok((key.clone(), XReadId::After(parse_stream_id(id, 0)?))),
+        })
+        .collect::<Result<_, String>>()?;
+    let cmd = XReadCommand { streams, count, group };
+    match block {
+        Some(timeout) => Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::XRead(cmd), timeout })),
+        None => Ok(Command::XRead(cmd)),
+    }
+}
+
+fn parse_xread(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_xread_command(&parse_keys(x)?, None)
+}
+
+fn parse_xreadgroup(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [option, group, consumer, rest @ ..] = args.as_slice() else {
+        return Err("ERR syntax error".into());
+    };
+    if !option.eq_ignore_ascii_case(b"GROUP") {
+        return Err("ERR Missing GROUP option for XREADGROUP".into());
+    }
+    let group = ReadGroup { group: group.clone(), consumer: consumer.clone(), no_ack: false };
+    parse_xread_command(rest, Some(group))
+}
+
+/// The ID of XGROUP CREATE and SETID, followed by an optional ENTRIESREAD.
+fn parse_group_start(id: &[u8], options: &[Bytes]) -> Result<(XReadId, Option<u64>), String> {
+    let id = match id {
+        b"$" => XReadId::Last,
+        id => XReadId::After(parse_stream_id(id, 0)?),
+    };
+    match options {
+        [] => Ok((id, None)),
+        [option, value] if option.eq_ignore_ascii_case(b"ENTRIESREAD") => {
+            let entries_read = u64::try_from(parse_number::<i64>(value)?).map_err(|_| "ERR value for ENTRIESREAD must be positive or -1".to_string())?;
+            Ok((id, Some(entries_read)))
+        }
+        _ => Err("ERR syntax error".into()),
+    }
+}
+
+fn parse_xgroup(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let Some((sub_command, rest)) = args.split_first() else {
+        return Err("ERR wrong number of arguments for 'xgroup' command".into());
+    };
+    let command = match (sub_command.to_ascii_uppercase().as_slice(), rest) {
+        (b"CREATE", [key, group, id, options @ ..]) => {
+            let (mkstream, options) = match options.split_first() {
+                Some((option, options)) if option.eq_ignore_ascii_case(b"MKSTREAM") => (true, options),
+                _ => (false, options),
+            };
+            let (id, entries_read) = parse_group_start(id, options)?;
+            StreamCommand::GroupCreate { key: key.clone(), group: group.clone(), id, mkstream, entries_read }
+        }
+        (b"SETID", [key, group, id, options @ ..]) => {
+            let (id, entries_read) = parse_group_start(id, options)?;
+            StreamCommand::GroupSetId { key: key.clone(), group: group.clone(), id, entries_read }
+        }
+        (b"DESTROY", [key, group]) => StreamCommand::GroupDestroy { key: key.clone(), group: group.clone() },
+        (b"CREATECONSUMER", [key, group, consumer]) => StreamCommand::GroupCreateConsumer {
+            key: key.clone(),
+            group: group.clone(),
+            consumer: consumer.clone(),
+        },
+        (b"DELCONSUMER", [key, group, consumer]) => StreamCommand::GroupDelConsumer {
+            key: key.clone(),
+            group: group.clone(),
+            consumer: consumer.clone(),
+        },
+        (sub_command, _) => {
+            return Err(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try XGROUP HELP.",
+                String::from_utf8_lossy(sub_command)
+            ))
+        }
+    };
+    Ok(Command::Stream(command))
+}
+
+fn parse_xack(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [key, group, ids @ ..] = args.as_slice() else {
+        return Err("ERR wrong number of arguments for 'xack' command".into());
+    };
+    if ids.is_empty() {
+        return Err("ERR wrong number of arguments for 'xack' command".into());
+    }
+    Ok(Command::Stream(StreamCommand::Ack {
+        key: key.clone(),
+        group: group.clone(),
+        ids: ids.iter().map(|id| parse_stream_id(id, 0)).collect::<Result<_, _>>()?,
+    }))
+}
+
+/// Idle times can't be negative, anything below 0 is the same as 0.
+fn parse_idle(x: &[u8]) -> Result<u128, String> {
+    Ok(parse_number::<i64>(x)?.max(0) as u128)
+}
+
+fn parse_xpending(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [key, group, rest @ ..] = args.as_slice() else {
+        return Err("ERR wrong number of arguments for 'xpending' command".into());
+    };
+    let (min_idle, rest) = match rest {
+        [option, idle, rest @ ..] if option.eq_ignore_ascii_case(b"IDLE") => (parse_idle(idle)?, rest),
+        _ => (0, rest),
+    };
+    let range = match rest {
+        [] if min_idle == 0 => None,
+        [start, end, count, consumer @ ..] if consumer.len() <= 1 => Some(PendingRange {
+            min_idle,
+            start: parse_stream_range_bound(start, true)?,
+            end: parse_stream_range_bound(end, false)?,
+            count: parse_number::<i64>(count)?.max(0) as usize,
+            consumer: consumer.first().cloned(),
+        }),
+        _ => return Err("ERR syntax error".into()),
+    };
+    Ok(Command::Stream(StreamCommand::Pending { key: key.clone(), group: group.clone(), range }))
+}
+
+fn parse_xclaim(ctx: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [key, group, consumer, min_idle, rest @ ..] = args.as_slice() else {
+        return Err("ERR wrong number of arguments for 'xclaim' command".into());
+    };
+    // The IDs go on until the first argument that isn't one
+    let id_count = rest.iter().take_while(|id| parse_stream_id(id, 0).is_ok()).count();
+    let (ids, mut options_args) = rest.split_at(id_count);
+    if ids.is_empty() {
+        return Err("ERR wrong number of arguments for 'xclaim' command".into());
+    }
+
+    let now = ctx.now.as_millis();
+    let mut options = ClaimOptions::default();
+    while let Some((option, rest)) = options_args.split_first() {
+        options_args = match (option.to_ascii_uppercase().as_slice(), rest) {
+            (b"FORCE", rest) => {
+                options.force = true;
+                rest
+            }
+            (b"JUSTID", rest) => {
+                options.just_id = true;
+                rest
+            }
+            (b"IDLE", [idle, rest @ ..]) => {
+                options.delivery_time = Some(now.saturating_sub(parse_idle(idle)?));
+                rest
+            }
+            (b"TIME", [time, rest @ ..]) => {
+                options.delivery_time = Some(parse_idle(time)?);
+                rest
+            }
+            (b"RETRYCOUNT", [count, rest @ ..]) => {
+                let count = u64::try_from(parse_number::<i64>(count)?).map_err(|_| "ERR Invalid RETRYCOUNT option argument for XCLAIM".to_string())?;
+                options.retry_count = Some(count);
+                rest
+            }
+            (b"LASTID", [id, rest @ ..]) => {
+                options.last_id = Some(parse_stream_id(id, 0)?);
+                rest
+            }
+            _ => return Err(format!("ERR Unrecognized XCLAIM option '{}'", String::from_utf8_lossy(option))),
+        };
+    }
+
+    Ok(Command::Stream(StreamCommand::Claim {
+        key: key.clone(),
+        group: group.clone(),
+        consumer: consumer.clone(),
+        min_idle: parse_idle(min_idle)?,
+        ids: ids.iter().map(|id| parse_stream_id(id, 0)).collect::<Result<_, _>>()?,
+        options,
+    }))
+}
+
+fn parse_xautoclaim(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let [key, group, consumer, min_idle, start, options @ ..] = args.as_slice() else {
+        return Err("ERR wrong number of arguments for 'xautoclaim' command".into());
+    };
+    let mut options = options;
+    let mut count = 100;
+    let mut just_id = false;
+    while let Some((option, rest)) = options.split_first() {
+        options = match (option.to_ascii_uppercase().as_slice(), rest) {
+            (b"JUSTID", rest) => {
+                just_id = true;
+                rest
+            }
+            (b"COUNT", [value, rest @ ..]) => {
+                count = usize::try_from(parse_number::<i64>(value)?)
+                    .ok()
+                    .filter(|x| *x > 0 && x.checked_mul(10).is_some())
+                    .ok_or("ERR COUNT must be > 0".to_string())?;
+                rest
+            }
+            _ => return Err("ERR syntax error".into()),
+        };
+    }
+
+    Ok(Command::Stream(StreamCommand::AutoClaim {
+        key: key.clone(),
+        group: group.clone(),
+        consumer: consumer.clone(),
+        min_idle: parse_idle(min_idle)?,
+        start: parse_stream_range_bound(start, true)?,
+        count,
+        just_id,
+    }))
+}
+
+fn parse_xinfo(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let Some((sub_command, rest)) = args.split_first() else {
+        return Err("ERR wrong number of arguments for 'xinfo' command".into());
+    };
+    let command = match (sub_command.to_ascii_uppercase().as_slice(), rest) {
+        (b"STREAM", [key]) => StreamCommand::InfoStream { key: key.clone() },
+        (b"GROUPS", [key]) => StreamCommand::InfoGroups { key: key.clone() },
+        (b"CONSUMERS", [key, group]) => StreamCommand::InfoConsumers { key: key.clone(), group: group.clone() },
+        (sub_command, _) => {
+            return Err(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try XINFO HELP.",
+                String::from_utf8_lossy(sub_command)
+            ))
+        }
+    };
+    Ok(Command::Stream(command))
+}
+
+fn parse_config(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let (sub_command, rest) = x.split_first().ok_or("ERR wrong number of arguments for 'config' command".to_string())?;
+
+    let DataType::BulkString(sub_command_string) = sub_command else {
+        return Err("Expected second command to be a string".to_string());
+    };
+    let sub_command = sub_command_string.to_ascii_lowercase();
+    let command = match (sub_command.as_slice(), rest) {
+        (b"get", patterns) if !patterns.is_empty() => {
+            let patterns = parse_keys(patterns)?.iter().map(|pattern| String::from_utf8_lossy(pattern).into_owned()).collect();
+            ConfigCommand::Get { patterns }
+        },
+        (b"set", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let parameters = pairs
+                .chunks(2)
+                .map(|pair| match pair {
+                    [DataType::BulkString(name), DataType::BulkString(value)] => {
+                        Ok((String::from_utf8_lossy(name).to_lowercase(), String::from_utf8_lossy(value).into_owned()))
+                    }
+                    _ => Err("Invalid structure".to_string()),
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            ConfigCommand::Set { parameters }
+        },
+        (b"resetstat", []) => ConfigCommand::ResetStat,
+        (b"rewrite", []) => ConfigCommand::Rewrite,
+        (b"get" | b"set" | b"resetstat" | b"rewrite", _) => {
+            return Err(format!("ERR wrong number of arguments for 'config|{}' command", String::from_utf8_lossy(&sub_command)))
+        },
+        _ => {
+            return Err(format!(
+                "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+                String::from_utf8_lossy(sub_command_string)
+            ))
+        },
+    };
+    Ok(Command::Config(command))
+}
+
+fn parse_subscribe_command(x: &[DataType], kind: SubscriptionKind, name: &str) -> Result<Command, String> {
+    if x.is_empty() {
+        return Err(format!("ERR wrong number of arguments for '{}' command", name));
+    }
+    Ok(Command::PubSub(PubSubCommand::Subscribe { kind, names: parse_keys(x)? }))
+}
+
+fn parse_unsubscribe_command(x: &[DataType], kind: SubscriptionKind) -> Result<Command, String> {
+    let names = if x.is_empty() { vec![] } else { parse_keys(x)? };
+    Ok(Command::PubSub(PubSubCommand::Unsubscribe { kind, names }))
+}
+
+fn parse_subscribe(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_subscribe_command(x, SubscriptionKind::Channel, "subscribe")
+}
+
+fn parse_unsubscribe(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_unsubscribe_command(x, SubscriptionKind::Channel)
+}
+
+fn parse_psubscribe(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_subscribe_command(x, SubscriptionKind::Pattern, "psubscribe")
+}
+
+fn parse_punsubscribe(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_unsubscribe_command(x, SubscriptionKind::Pattern)
+}
+
+fn parse_ssubscribe(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_subscribe_command(x, SubscriptionKind::Shard, "ssubscribe")
+}
+
+fn parse_sunsubscribe(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_unsubscribe_command(x, SubscriptionKind::Shard)
+}
+
+fn parse_publish(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(channel), DataType::BulkString(message)] => Ok(Command::PubSub(PubSubCommand::Publish {
+            channel: channel.clone(),
+            message: message.clone(),
+        })),
+        _ => Err("ERR wrong number of arguments for 'publish' command".into()),
+    }
+}
+
+fn parse_spublish(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(channel), DataType::BulkString(message)] => Ok(Command::PubSub(PubSubCommand::ShardPublish {
+            channel: channel.clone(),
+            message: message.clone(),
+        })),
+        _ => Err("ERR wrong number of arguments for 'spublish' command".into()),
+    }
+}
+
+fn parse_pubsub(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let args = parse_keys(x)?;
+    let Some((sub_command, rest)) = args.split_first() else {
+        return Err("ERR wrong number of arguments for 'pubsub' command".into());
+    };
+    let command = match (sub_command.to_ascii_uppercase().as_slice(), rest) {
+        (b"CHANNELS", [] | [_]) => PubSubCommand::Channels { pattern: rest.first().cloned() },
+        (b"SHARDCHANNELS", [] | [_]) => PubSubCommand::ShardChannels { pattern: rest.first().cloned() },
+        (b"NUMSUB", channels) => PubSubCommand::NumSub { channels: channels.to_vec() },
+        (b"SHARDNUMSUB", channels) => PubSubCommand::ShardNumSub { channels: channels.to_vec() },
+        (b"NUMPAT", []) => PubSubCommand::NumPat,
+        (sub_command, _) => {
+            return Err(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+                String::from_utf8_lossy(sub_command)
+            ))
+        }
+    };
+    Ok(Command::PubSub(command))
+}
+
+fn parse_ping(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [] => Ok(Command::Ping { message: None }),
+        [DataType::BulkString(message)] => Ok(Command::Ping {
+            message: Some(message.clone()),
+        }),
+        _ => Err("Invalid structure".into()),
+    }
+}
+
+fn parse_hello(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    let Some((DataType::BulkString(protocol), mut rest)) = x.split_first() else {
+        return match x {
+            [] => Ok(Command::Hello(HelloCommand::default())),
+            _ => Err("Invalid structure".into()),
+        };
+    };
+
+    let mut command = HelloCommand {
+        protocol: Some(parse_number::<i64>(protocol).map_err(|_| "Protocol version is not an integer or out of range".to_string())?),
+        ..Default::default()
+    };
+
+    loop {
+        match rest {
+            [] => return Ok(Command::Hello(command)),
+            [DataType::BulkString(option), DataType::BulkString(username), DataType::BulkString(password), tail @ ..]
+                if option.eq_ignore_ascii_case(b"AUTH") =>
+            {
+                command.auth = Some((username.clone(), password.clone()));
+                rest = tail;
+            }
+            [DataType::BulkString(option), DataType::BulkString(name), tail @ ..]
+                if option.eq_ignore_ascii_case(b"SETNAME") =>
+            {
+                command.client_name = Some(name.clone());
+                rest = tail;
+            }
+            [DataType::BulkString(option), ..] => {
+                return Err(format!("Syntax error in HELLO option '{}'", String::from_utf8_lossy(option)));
+            }
+            _ => return Err("Invalid structure".into()),
+        }
+    }
+}
+
+fn parse_auth(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    match x {
+        [DataType::BulkString(password)] => Ok(Command::Auth { username: None, password: password.clone() }),
+        [DataType::BulkString(username), DataType::BulkString(password)] => Ok(Command::Auth { username: Some(username.clone()), password: password.clone() }),
+        _ => Err("ERR wrong number of arguments for 'auth' command".into()),
+    }
+}
+
+fn parse_dump(_: &CommandParsingContext, _: &[DataType]) -> Result<Command, String> {
+    Ok(Command::Dump)
+}
+
+fn parse_no_arguments(x: &[DataType], command: Command, name: &str) -> Result<Command, String> {
     match x {
-        [DataType::BulkString(key)] => Ok(Command::Get {
-            key: key.to_string(),
-        }),
-        _ => Err("Invalid structure".into()),
+        [] => Ok(command),
+        _ => Err(format!("ERR wrong number of arguments for '{}' command", name)),
     }
 }
 
-fn parse_config(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
-    let (sub_command, rest) = x.split_first().ok_or("Unknown second command for CONFIG".to_string())?;
+fn parse_multi(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_no_arguments(x, Command::Multi, "multi")
+}
 
-    let DataType::BulkString(sub_command_string) = sub_command else {
-        return Err("Expected second command to be a string".to_string());
-    };
-    match (sub_command_string.to_lowercase().as_ref(), rest) {
-        ("get", [DataType::BulkString(get_key)]) => {
-            Ok(Command::ConfigGet { key: Some(get_key.to_string()) })
-        },
-        _ => Err("Invalid structure".into()),
+fn parse_exec(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_no_arguments(x, Command::Exec, "exec")
+}
+
+fn parse_discard(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_no_arguments(x, Command::Discard, "discard")
+}
+
+fn parse_watch(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    if x.is_empty() {
+        return Err("ERR wrong number of arguments for 'watch' command".into());
     }
+    Ok(Command::Watch { keys: parse_keys(x)? })
 }
 
-fn parse_dump(_: &CommandParsingContext, _: &[DataType]) -> Result<Command, String> {
-    Ok(Command::Dump)
+fn parse_unwatch(_: &CommandParsingContext, x: &[DataType]) -> Result<Command, String> {
+    parse_no_arguments(x, Command::Unwatch, "unwatch")
 }
 
 impl DataType {
+    /// The name of the command in a request frame, lowercase.
+    pub fn command_name(&self) -> Option<String> {
+        match self {
+            DataType::Array(arr) => match arr.first() {
+                Some(DataType::BulkString(x)) => Some(String::from_utf8_lossy(x).to_lowercase()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     pub fn to_command(&self) -> Result<Command, String> {
+        self.to_command_at(current_unix_timestamp_millis())
+    }
+
+    fn to_command_at(&self, now: Duration) -> Result<Command, String> {
         match self {
             DataType::Array(arr) => {
                 let (command, values) =
@@ -124,15 +2187,12 @@ impl DataType {
 
                 match command {
                     DataType::BulkString(x) => {
-                        let Some(handler) = COMMAND_PARSER.get(x.to_lowercase().as_ref()) else {
-                            return Err(format!("Unknown command: {}", x.to_lowercase()));
-                        };
-
-                        let context = CommandParsingContext {
-                            now: current_unix_timestamp_millis(),
+                        let name = String::from_utf8_lossy(x).to_lowercase();
+                        let Some(handler) = COMMAND_PARSER.get(name.as_str()) else {
+                            return Err(format!("Unknown command: {}", name));
                         };
 
-                        handler(&context, values)
+                        handler(&CommandParsingContext { now }, values)
                     }
                     _ => Err("Invalid array command type, expected bulk string".to_string()),
                 }
@@ -146,6 +2206,13 @@ impl DataType {
 mod tests {
     use super::*;
 
+    /// Parses `args` the way a client would send them. The clock is stopped at 10 seconds past the
+    /// epoch so expirations and stream IDs come out the same every run.
+    fn parse(args: &[&'static str]) -> Result<Command, String> {
+        let data = DataType::Array(args.iter().map(|x| DataType::BulkString((*x).into())).collect());
+        data.to_command_at(Duration::from_secs(10))
+    }
+
     #[test]
     pub fn test_set_kv() {
         let data = DataType::Array(vec![
@@ -168,6 +2235,28 @@ mod tests {
         );
     }
 
+    #[test]
+    pub fn test_set_binary_value() {
+        let data = DataType::Array(vec![
+            DataType::BulkString("SET".into()),
+            DataType::BulkString("X".into()),
+            DataType::BulkString(Bytes::from_static(b"\x00\xff\r\n")),
+        ]);
+
+        let output = data
+            .to_command()
+            .expect("Expected the command to parse successfully");
+
+        assert_eq!(
+            output,
+            Command::Set(SetCommand{
+                key: "X".into(),
+                value: Bytes::from_static(b"\x00\xff\r\n"),
+                ..Default::default()
+            })
+        );
+    }
+
     #[test]
     pub fn test_get_key() {
         let data = DataType::Array(vec![
@@ -209,6 +2298,63 @@ mod tests {
         assert!(output.is_err());
     }
 
+    #[test]
+    pub fn test_hello() {
+        let data = DataType::Array(vec![
+            DataType::BulkString("HELLO".into()),
+            DataType::BulkString("3".into()),
+            DataType::BulkString("setname".into()),
+            DataType::BulkString("worker".into()),
+        ]);
+
+        let output = data
+            .to_command()
+            .expect("Expected the command to parse successfully");
+
+        assert_eq!(
+            output,
+            Command::Hello(HelloCommand {
+                protocol: Some(3),
+                client_name: Some("worker".into()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_hello_invalid_option() {
+        let data = DataType::Array(vec![
+            DataType::BulkString("HELLO".into()),
+            DataType::BulkString("3".into()),
+            DataType::BulkString("AUTH".into()),
+            DataType::BulkString("default".into()),
+        ]);
+
+        assert!(data.to_command().is_err());
+    }
+
+    mod tests_incr {
+        use super::*;
+
+        #[test]
+        pub fn test_incr_family() {
+            let incr = |key: &'static str, by: IncrBy| Ok(Command::Incr(IncrCommand { key: key.into(), by }));
+
+            assert_eq!(parse(&["INCR", "a"]), incr("a", IncrBy::Integer(1)));
+            assert_eq!(parse(&["DECR", "a"]), incr("a", IncrBy::Integer(-1)));
+            assert_eq!(parse(&["INCRBY", "a", "-5"]), incr("a", IncrBy::Integer(-5)));
+            assert_eq!(parse(&["DECRBY", "a", "5"]), incr("a", IncrBy::Integer(-5)));
+            assert_eq!(parse(&["INCRBYFLOAT", "a", "1.5"]), incr("a", IncrBy::Float(1.5)));
+        }
+
+        #[test]
+        pub fn test_incr_invalid_values() {
+            assert_eq!(parse(&["INCRBY", "a", "1.5"]), Err("ERR value is not an integer or out of range".into()));
+            assert_eq!(parse(&["DECRBY", "a", "-9223372036854775808"]), Err("ERR decrement would overflow".into()));
+            assert_eq!(parse(&["INCRBYFLOAT", "a", "inf"]), Err("ERR value is not a valid float".into()));
+        }
+    }
+
     mod tests_set_expirations {
         use super::*;
     
@@ -309,6 +2455,432 @@ mod tests {
         }
     }
 
+    mod tests_keyspace {
+        use super::*;
+
+        #[test]
+        pub fn test_multi_key_commands() {
+            assert_eq!(
+                parse(&["DEL", "a", "b"]),
+                Ok(Command::Del { keys: vec!["a".into(), "b".into()], unlink: false })
+            );
+            assert_eq!(parse(&["UNLINK", "a"]), Ok(Command::Del { keys: vec!["a".into()], unlink: true }));
+            assert_eq!(parse(&["EXISTS", "a", "a"]), Ok(Command::Exists { keys: vec!["a".into(), "a".into()] }));
+            assert!(parse(&["DEL"]).is_err());
+        }
+
+        #[test]
+        pub fn test_rename_and_copy() {
+            assert_eq!(
+                parse(&["RENAMENX", "a", "b"]),
+                Ok(Command::Rename(RenameCommand { source: "a".into(), destination: "b".into(), only_if_new: true }))
+            );
+            assert_eq!(
+                parse(&["COPY", "a", "b", "DB", "0", "replace"]),
+                Ok(Command::Copy(CopyCommand { source: "a".into(), destination: "b".into(), replace: true }))
+            );
+            assert_eq!(parse(&["COPY", "a", "b", "DB", "1"]), Err("ERR DB index is out of range".into()));
+        }
+    }
+
+    mod tests_expire {
+        use super::*;
+
+        fn expire(expiration: u128, conditions: Vec<ExpireCondition>) -> Result<Command, String> {
+            Ok(Command::Expire(ExpireCommand {
+                key: "X".into(),
+                expiration,
+                conditions,
+            }))
+        }
+
+        #[test]
+        pub fn test_relative_and_absolute_times() {
+            assert_eq!(parse(&["EXPIRE", "X", "5"]), expire(15000, vec![]));
+            assert_eq!(parse(&["PEXPIRE", "X", "5"]), expire(10005, vec![]));
+            assert_eq!(parse(&["EXPIREAT", "X", "5"]), expire(5000, vec![]));
+            assert_eq!(parse(&["PEXPIREAT", "X", "5"]), expire(5, vec![]));
+            assert_eq!(parse(&["EXPIRE", "X", "-20"]), expire(0, vec![]));
+        }
+
+        #[test]
+        pub fn test_conditions() {
+            assert_eq!(parse(&["PEXPIREAT", "X", "5", "nx"]), expire(5, vec![ExpireCondition::NoExpiry]));
+            assert_eq!(
+                parse(&["PEXPIREAT", "X", "5", "XX", "GT"]),
+                expire(5, vec![ExpireCondition::HasExpiry, ExpireCondition::GreaterThan])
+            );
+            assert!(parse(&["PEXPIREAT", "X", "5", "NX", "GT"]).is_err());
+            assert!(parse(&["PEXPIREAT", "X", "5", "GT", "LT"]).is_err());
+            assert!(parse(&["PEXPIREAT", "X", "5", "KEEPTTL"]).is_err());
+        }
+
+        #[test]
+        pub fn test_invalid_expire_time() {
+            assert_eq!(
+                parse(&["EXPIRE", "X", "9223372036854775807"]),
+                Err("ERR invalid expire time in 'expire' command".into())
+            );
+        }
+    }
+
+    mod tests_list {
+        use super::*;
+
+        #[test]
+        pub fn test_push() {
+            assert_eq!(
+                parse(&["RPUSHX", "L", "a", "b"]),
+                Ok(Command::List(ListCommand::Push {
+                    key: "L".into(),
+                    end: ListEnd::Right,
+                    values: vec!["a".into(), "b".into()],
+                    only_if_exists: true,
+                }))
+            );
+            assert!(parse(&["LPUSH", "L"]).is_err());
+        }
+
+        #[test]
+        pub fn test_pop_count() {
+            assert_eq!(
+                parse(&["LPOP", "L", "3"]),
+                Ok(Command::List(ListCommand::Pop { key: "L".into(), end: ListEnd::Left, count: Some(3) }))
+            );
+            assert_eq!(parse(&["RPOP", "L", "-1"]), Err("ERR value is out of range, must be positive".into()));
+        }
+
+        #[test]
+        pub fn test_blocking_pop_timeout() {
+            assert_eq!(
+                parse(&["BLPOP", "a", "b", "0.5"]),
+                Ok(Command::Blocking(BlockingCommand {
+                    operation: BlockingOperation::Pop { keys: vec!["a".into(), "b".into()], end: ListEnd::Left },
+                    timeout: Some(Duration::from_millis(500)),
+                }))
+            );
+            assert_eq!(
+                parse(&["BRPOP", "a", "0"]),
+                Ok(Command::Blocking(BlockingCommand { operation: BlockingOperation::Pop { keys: vec!["a".into()], end: ListEnd::Right }, timeout: None }))
+            );
+            assert_eq!(parse(&["BLPOP", "a", "-1"]), Err("ERR timeout is negative".into()));
+            assert_eq!(parse(&["BLPOP", "a", "soon"]), Err("ERR timeout is not a float or out of range".into()));
+            assert_eq!(parse(&["BLPOP", "a", "1e300"]), Err("ERR timeout is out of range".into()));
+            assert_eq!(parse(&["BLPOP", "a"]), Err("ERR wrong number of arguments for 'blpop' command".into()));
+        }
+
+        #[test]
+        pub fn test_lmove() {
+            assert_eq!(
+                parse(&["LMOVE", "A", "B", "left", "RIGHT"]),
+                Ok(Command::LMove(LMoveCommand {
+                    source: "A".into(),
+                    destination: "B".into(),
+                    from: ListEnd::Left,
+                    to: ListEnd::Right,
+                }))
+            );
+            assert_eq!(parse(&["LMOVE", "A", "B", "UP", "RIGHT"]), Err("ERR syntax error".into()));
+        }
+    }
+
+    mod tests_hash {
+        use super::*;
+
+        #[test]
+        pub fn test_hset_pairs() {
+            assert_eq!(
+                parse(&["hset", "H", "a", "1", "b", "2"]),
+                Ok(Command::Hash(HashCommand::Set {
+                    key: "H".into(),
+                    fields: vec![("a".into(), "1".into()), ("b".into(), "2".into())],
+                    only_if_new: false,
+                }))
+            );
+            assert!(parse(&["hset", "H", "a", "1", "b"]).is_err());
+        }
+
+        #[test]
+        pub fn test_hscan_options() {
+            assert_eq!(
+                parse(&["hscan", "H", "42", "match", "user:*", "COUNT", "5", "NOVALUES"]),
+                Ok(Command::Hash(HashCommand::Scan {
+                    key: "H".into(),
+                    options: ScanOptions { cursor: 42, pattern: Some("user:*".into()), count: 5 },
+                    no_values: true,
+                }))
+            );
+            assert_eq!(parse(&["hscan", "H", "x"]), Err("ERR invalid cursor".into()));
+            assert_eq!(parse(&["hscan", "H", "0", "COUNT", "0"]), Err("ERR syntax error".into()));
+        }
+
+        #[test]
+        pub fn test_hexpire() {
+            assert_eq!(
+                parse(&["hexpire", "H", "5", "NX", "FIELDS", "2", "a", "b"]),
+                Ok(Command::Hash(HashCommand::Expire {
+                    key: "H".into(),
+                    expiration: 15000,
+                    conditions: vec![ExpireCondition::NoExpiry],
+                    fields: vec!["a".into(), "b".into()],
+                }))
+            );
+            assert_eq!(
+                parse(&["hexpire", "H", "5", "FIELDS", "3", "a", "b"]),
+                Err("ERR The `numfields` parameter must match the number of arguments".into())
+            );
+            assert!(parse(&["httl", "H", "2", "a", "b"]).is_err());
+        }
+    }
+
+    mod tests_set_type {
+        use super::*;
+
+        #[test]
+        pub fn test_store_forms() {
+            assert_eq!(
+                parse(&["SDIFFSTORE", "D", "A", "B"]),
+                Ok(Command::SetAlgebra(SetAlgebraCommand {
+                    operation: SetOperation::Diff,
+                    keys: vec!["A".into(), "B".into()],
+                    output: SetAlgebraOutput::Store("D".into()),
+                }))
+            );
+            assert!(parse(&["SINTERSTORE", "D"]).is_err());
+        }
+
+        #[test]
+        pub fn test_sintercard() {
+            assert_eq!(
+                parse(&["SINTERCARD", "2", "A", "B", "LIMIT", "5"]),
+                Ok(Command::SetAlgebra(SetAlgebraCommand {
+                    operation: SetOperation::Inter,
+                    keys: vec!["A".into(), "B".into()],
+                    output: SetAlgebraOutput::Cardinality { limit: 5 },
+                }))
+            );
+            assert_eq!(parse(&["SINTERCARD", "3", "A", "B"]), Err("ERR Number of keys can't be greater than number of args".into()));
+            assert_eq!(parse(&["SINTERCARD", "1", "A", "B"]), Err("ERR syntax error".into()));
+        }
+    }
+
+    mod tests_zset {
+        use super::*;
+
+        #[test]
+        pub fn test_zadd_options() {
+            assert_eq!(
+                parse(&["ZADD", "z", "XX", "GT", "CH", "1.5", "a", "-inf", "b"]),
+                Ok(Command::ZSet(ZSetCommand::Add {
+                    key: "z".into(),
+                    options: ZAddOptions {
+                        set_existing: Some(SetExistingOptions::OnlySetIfExists),
+                        comparison: Some(ScoreComparison::GreaterThan),
+                        changed: true,
+                        increment: false,
+                    },
+                    members: vec![(1.5, "a".into()), (f64::NEG_INFINITY, "b".into())],
+                }))
+            );
+            assert_eq!(parse(&["ZADD", "z", "NX", "LT", "1", "a"]), Err("ERR GT, LT, and/or NX options at the same time are not compatible".into()));
+            assert_eq!(parse(&["ZADD", "z", "INCR", "1", "a", "2", "b"]), Err("ERR INCR option supports a single increment-element pair".into()));
+            assert_eq!(parse(&["ZADD", "z", "1", "a", "2"]), Err("ERR syntax error".into()));
+            assert_eq!(parse(&["ZADD", "z", "nan", "a"]), Err("ERR value is not a valid float".into()));
+        }
+
+        #[test]
+        pub fn test_reversed_ranges_are_stored_lowest_first() {
+            let expected = Command::ZSet(ZSetCommand::Range {
+                key: "z".into(),
+                options: ZRangeOptions {
+                    by: ZRangeBy::Score {
+                        min: ScoreBound { value: 1.0, exclusive: true },
+                        max: ScoreBound { value: f64::INFINITY, exclusive: false },
+                    },
+                    rev: true,
+                    limit: Some((0, 2)),
+                    with_scores: true,
+                },
+            });
+            assert_eq!(parse(&["ZREVRANGEBYSCORE", "z", "+inf", "(1", "WITHSCORES", "LIMIT", "0", "2"]), Ok(expected));
+            assert_eq!(
+                parse(&["ZRANGE", "z", "[b", "-", "BYLEX", "REV"]),
+                Ok(Command::ZSet(ZSetCommand::Range {
+                    key: "z".into(),
+                    options: ZRangeOptions {
+                        by: ZRangeBy::Lex { min: LexBound::Min, max: LexBound::Inclusive("b".into()) },
+                        rev: true,
+                        limit: None,
+                        with_scores: false,
+                    },
+                }))
+            );
+            assert!(parse(&["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]).is_err());
+            assert!(parse(&["ZRANGEBYSCORE", "z", "0", "1", "REV"]).is_err());
+        }
+
+        #[test]
+        pub fn test_zunionstore() {
+            assert_eq!(
+                parse(&["ZUNIONSTORE", "d", "2", "a", "b", "WEIGHTS", "2", "0.5", "AGGREGATE", "MAX"]),
+                Ok(Command::ZStore(ZStoreCommand {
+                    destination: "d".into(),
+                    operation: SetOperation::Union,
+                    keys: vec!["a".into(), "b".into()],
+                    weights: vec![2.0, 0.5],
+                    aggregate: Aggregate::Max,
+                }))
+            );
+            assert_eq!(parse(&["ZINTERSTORE", "d", "2", "a", "b", "WEIGHTS", "1"]), Err("ERR syntax error".into()));
+            assert_eq!(parse(&["ZDIFFSTORE", "d", "1", "a", "AGGREGATE", "MIN"]), Err("ERR syntax error".into()));
+        }
+    }
+
+    mod tests_stream {
+        use super::*;
+
+        #[test]
+        pub fn test_xadd() {
+            assert_eq!(
+                parse(&["xadd", "s", "NOMKSTREAM", "MAXLEN", "~", "10", "LIMIT", "5", "*", "f", "v"]),
+                Ok(Command::Stream(StreamCommand::Add {
+                    key: "s".into(),
+                    id: XAddId::Auto { now: 10_000 },
+                    fields: vec![("f".into(), "v".into())],
+                    no_mkstream: true,
+                    trim: Some(StreamTrim { strategy: TrimStrategy::MaxLen(10), limit: Some(5) }),
+                }))
+            );
+            assert_eq!(
+                parse(&["xadd", "s", "5-*", "f", "v"]).map(|cmd| matches!(cmd, Command::Stream(StreamCommand::Add { id: XAddId::AutoSequence { ms: 5 }, .. }))),
+                Ok(true)
+            );
+            assert_eq!(parse(&["xadd", "s", "MINID", "5", "LIMIT", "5", "*", "f", "v"]), Err("ERR syntax error, LIMIT cannot be used without the special ~ option".into()));
+            assert_eq!(parse(&["xadd", "s", "*", "f"]), Err("ERR wrong number of arguments for 'xadd' command".into()));
+            assert_eq!(parse(&["xadd", "s", "1-x", "f", "v"]), Err("ERR Invalid stream ID specified as stream command argument".into()));
+        }
+
+        #[test]
+        pub fn test_xrange_bounds() {
+            assert_eq!(
+                parse(&["xrevrange", "s", "(5", "-", "COUNT", "2"]),
+                Ok(Command::Stream(StreamCommand::Range {
+                    key: "s".into(),
+                    start: StreamId::MIN,
+                    end: StreamId { ms: 5, seq: u64::MAX - 1 },
+                    count: Some(2),
+                    rev: true,
+                }))
+            );
+            assert_eq!(parse(&["xrange", "s", "(18446744073709551615-18446744073709551615", "+"]), Err("ERR invalid start ID for the interval".into()));
+        }
+
+        #[test]
+        pub fn test_xread() {
+            assert_eq!(
+                parse(&["xread", "COUNT", "2", "STREAMS", "a", "b", "0", "$"]),
+                Ok(Command::XRead(XReadCommand {
+                    streams: vec![("a".into(), XReadId::After(StreamId::MIN)), ("b".into(), XReadId::Last)],
+                    count: Some(2),
+                    group: None,
+                }))
+            );
+            assert!(parse(&["xread", "STREAMS", "a", "b", "0"]).is_err());
+            assert!(parse(&["xread", "STREAMS", "a", ">"]).is_err());
+        }
+
+        #[test]
+        pub fn test_xread_block() {
+            assert_eq!(
+                parse(&["xread", "BLOCK", "1500", "STREAMS", "a", "$"]),
+                Ok(Command::Blocking(BlockingCommand {
+                    operation: BlockingOperation::XRead(XReadCommand { streams: vec![("a".into(), XReadId::Last)], count: None, group: None }),
+                    timeout: Some(Duration::from_millis(1500)),
+                }))
+            );
+            assert_eq!(parse(&["xread", "BLOCK", "-1", "STREAMS", "a", "$"]), Err("ERR timeout is negative".into()));
+        }
+
+        #[test]
+        pub fn test_xreadgroup() {
+            assert_eq!(
+                parse(&["xreadgroup", "GROUP", "g", "c", "NOACK", "STREAMS", "a", "b", ">", "0"]),
+                Ok(Command::XRead(XReadCommand {
+                    streams: vec![("a".into(), XReadId::New), ("b".into(), XReadId::After(StreamId::MIN))],
+                    count: None,
+                    group: Some(ReadGroup { group: "g".into(), consumer: "c".into(), no_ack: true }),
+                }))
+            );
+            assert_eq!(parse(&["xreadgroup", "GROUP", "g", "c", "STREAMS", "a", "$"]), Err("ERR The $ ID is meaningful only for XREAD".into()));
+        }
+
+        #[test]
+        pub fn test_xclaim_options() {
+            assert_eq!(
+                parse(&["xclaim", "s", "g", "c", "-5", "1-0", "2", "IDLE", "1000", "RETRYCOUNT", "3", "JUSTID"]),
+                Ok(Command::Stream(StreamCommand::Claim {
+                    key: "s".into(),
+                    group: "g".into(),
+                    consumer: "c".into(),
+                    min_idle: 0,
+                    ids: vec![StreamId { ms: 1, seq: 0 }, StreamId { ms: 2, seq: 0 }],
+                    options: ClaimOptions { delivery_time: Some(9_000), retry_count: Some(3), just_id: true, ..Default::default() },
+                }))
+            );
+            assert_eq!(parse(&["xclaim", "s", "g", "c", "0", "1-0", "NOPE"]), Err("ERR Unrecognized XCLAIM option 'NOPE'".into()));
+        }
+    }
+
+    mod tests_pubsub {
+        use super::*;
+
+        #[test]
+        pub fn test_subscribe_forms() {
+            assert_eq!(
+                parse(&["psubscribe", "news.*", "b"]),
+                Ok(Command::PubSub(PubSubCommand::Subscribe { kind: SubscriptionKind::Pattern, names: vec!["news.*".into(), "b".into()] }))
+            );
+            assert_eq!(
+                parse(&["spublish", "orders", "m"]),
+                Ok(Command::PubSub(PubSubCommand::ShardPublish { channel: "orders".into(), message: "m".into() }))
+            );
+            assert_eq!(parse(&["unsubscribe"]), Ok(Command::PubSub(PubSubCommand::Unsubscribe { kind: SubscriptionKind::Channel, names: vec![] })));
+            assert_eq!(parse(&["subscribe"]), Err("ERR wrong number of arguments for 'subscribe' command".into()));
+        }
+
+        #[test]
+        pub fn test_pubsub_subcommands() {
+            assert_eq!(parse(&["pubsub", "channels", "a*"]), Ok(Command::PubSub(PubSubCommand::Channels { pattern: Some("a*".into()) })));
+            assert_eq!(parse(&["pubsub", "NUMSUB"]), Ok(Command::PubSub(PubSubCommand::NumSub { channels: vec![] })));
+            assert_eq!(parse(&["pubsub", "shardchannels"]), Ok(Command::PubSub(PubSubCommand::ShardChannels { pattern: None })));
+            assert!(parse(&["pubsub", "numpat", "x"]).is_err());
+        }
+
+        #[test]
+        pub fn test_transaction_commands() {
+            assert_eq!(parse(&["multi"]), Ok(Command::Multi));
+            assert_eq!(parse(&["watch", "a", "b"]), Ok(Command::Watch { keys: vec!["a".into(), "b".into()] }));
+            assert_eq!(parse(&["watch"]), Err("ERR wrong number of arguments for 'watch' command".into()));
+            assert_eq!(parse(&["exec", "now"]), Err("ERR wrong number of arguments for 'exec' command".into()));
+        }
+
+        #[test]
+        pub fn test_config_subcommands() {
+            assert_eq!(parse(&["config", "GET", "max*", "port"]), Ok(Command::Config(ConfigCommand::Get { patterns: vec!["max*".into(), "port".into()] })));
+            assert_eq!(parse(&["config", "rewrite"]), Ok(Command::Config(ConfigCommand::Rewrite)));
+            assert_eq!(parse(&["config", "get"]), Err("ERR wrong number of arguments for 'config|get' command".into()));
+            assert_eq!(parse(&["config", "set", "timeout"]), Err("ERR wrong number of arguments for 'config|set' command".into()));
+            assert!(parse(&["config", "reload"]).is_err());
+        }
+
+        #[test]
+        pub fn test_auth_forms() {
+            assert_eq!(parse(&["auth", "secret"]), Ok(Command::Auth { username: None, password: "secret".into() }));
+            assert_eq!(parse(&["auth", "default", "secret"]), Ok(Command::Auth { username: Some("default".into()), password: "secret".into() }));
+            assert_eq!(parse(&["auth"]), Err("ERR wrong number of arguments for 'auth' command".into()));
+        }
+    }
+
     mod tests_set_nx_xx {
         use super::*;
     
@@ -0,0 +1,147 @@
+use bytes::Bytes;
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Splits an inline command line into arguments the same way redis-cli does (`sdssplitargs`).
+///
+/// Arguments are separated by whitespace. Double quoted arguments understand the `\n`, `\r`,
+/// `\t`, `\b`, `\a` and `\xHH` escapes, single quoted arguments only understand `\'`. A closing
+/// quote has to be followed by whitespace or the end of the line.
+pub(crate) fn split_args(line: &[u8]) -> Result<Vec<Bytes>, String> {
+    let unbalanced = || "Protocol error: unbalanced quotes in request".to_string();
+    let mut args = Vec::new();
+    let mut idx = 0;
+
+    loop {
+        while idx < line.len() && line[idx].is_ascii_whitespace() {
+            idx += 1;
+        }
+        if idx == line.len() {
+            return Ok(args);
+        }
+
+        let mut current = Vec::new();
+        let mut in_double_quotes = false;
+        let mut in_single_quotes = false;
+
+        loop {
+            let byte = line.get(idx).copied();
+            if in_double_quotes {
+                match (byte, line.get(idx + 1).copied()) {
+                    (None, _) => return Err(unbalanced()),
+                    (Some(b'\\'), Some(b'x')) => {
+                        let high = line.get(idx + 2).copied().and_then(hex_digit);
+                        let low = line.get(idx + 3).copied().and_then(hex_digit);
+                        if let (Some(high), Some(low)) = (high, low) {
+                            current.push(high * 16 + low);
+                            idx += 3;
+                        } else {
+                            current.push(b'x');
+                            idx += 1;
+                        }
+                    }
+                    (Some(b'\\'), Some(escaped)) => {
+                        current.push(match escaped {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            x => x,
+                        });
+                        idx += 1;
+                    }
+                    (Some(b'"'), next) => {
+                        if next.is_some_and(|x| !x.is_ascii_whitespace()) {
+                            return Err(unbalanced());
+                        }
+                        idx += 1;
+                        break;
+                    }
+                    (Some(x), _) => current.push(x),
+                }
+            } else if in_single_quotes {
+                match (byte, line.get(idx + 1).copied()) {
+                    (None, _) => return Err(unbalanced()),
+                    (Some(b'\\'), Some(b'\'')) => {
+                        current.push(b'\'');
+                        idx += 1;
+                    }
+                    (Some(b'\''), next) => {
+                        if next.is_some_and(|x| !x.is_ascii_whitespace()) {
+                            return Err(unbalanced());
+                        }
+                        idx += 1;
+                        break;
+                    }
+                    (Some(x), _) => current.push(x),
+                }
+            } else {
+                match byte {
+                    None => break,
+                    Some(x) if x.is_ascii_whitespace() => break,
+                    Some(b'"') => in_double_quotes = true,
+                    Some(b'\'') => in_single_quotes = true,
+                    Some(x) => current.push(x),
+                }
+            }
+            idx += 1;
+        }
+
+        args.push(Bytes::from(current));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Result<Vec<Bytes>, String> {
+        split_args(line.as_bytes())
+    }
+
+    #[test]
+    pub fn test_split_plain() {
+        assert_eq!(split("SET  a\tb "), Ok(vec!["SET".into(), "a".into(), "b".into()]));
+    }
+
+    #[test]
+    pub fn test_split_empty() {
+        assert_eq!(split("   "), Ok(vec![]));
+    }
+
+    #[test]
+    pub fn test_split_double_quotes() {
+        assert_eq!(
+            split(r#"SET "hello world" "a\n\x41\"""#),
+            Ok(vec!["SET".into(), "hello world".into(), "a\nA\"".into()])
+        );
+    }
+
+    #[test]
+    pub fn test_split_single_quotes() {
+        assert_eq!(
+            split(r#"SET 'it\'s' '\n'"#),
+            Ok(vec!["SET".into(), "it's".into(), "\\n".into()])
+        );
+    }
+
+    #[test]
+    pub fn test_split_quotes_inside_argument() {
+        assert_eq!(split(r#"SET a"b c"d"#), Err("Protocol error: unbalanced quotes in request".to_string()));
+        assert_eq!(split(r#"SET ab"c d" e"#), Ok(vec!["SET".into(), "abc d".into(), "e".into()]));
+    }
+
+    #[test]
+    pub fn test_split_unbalanced() {
+        assert!(split(r#"SET "abc"#).is_err());
+        assert!(split("SET 'abc").is_err());
+    }
+}
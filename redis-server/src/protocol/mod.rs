@@ -1,4 +1,5 @@
 pub mod serializer;
 pub mod stream_parser_tokio;
 pub mod stream_parser_std;
-pub mod string_parser;
\ No newline at end of file
+pub mod resp_parser;
+pub mod inline_parser;
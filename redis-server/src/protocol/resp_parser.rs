@@ -0,0 +1,490 @@
+use bytes::{Buf, Bytes, BytesMut};
+use crate::datatypes::DataType;
+use crate::protocol::inline_parser::split_args;
+
+// Same limits redis uses by default (proto-max-bulk-len and the multibulk cap), so a bogus
+// header can't make us reserve gigabytes before any payload has arrived.
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+const MAX_ARRAY_LENGTH: usize = 1024 * 1024;
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+
+const RESP_TYPE_BYTES: &[u8] = b"+-:$*_,#(!=%~>|";
+
+/// Aggregates nested deeper than this are refused. Clients only ever send flat arrays, this just
+/// keeps a hostile one from growing the parser's stack without bound.
+const MAX_NESTING_DEPTH: usize = 32;
+
+/// A parsed value and the offset just past it, or `None` when the buffer ends before the value does.
+type Parsed<T> = Result<Option<(T, usize)>, String>;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseResult {
+    Complete(DataType),
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AggregateKind {
+    Array,
+    Set,
+    Push,
+    Map,
+    Attribute,
+}
+
+/// What a single header line, plus its payload for the length-prefixed types, decodes to.
+enum Token {
+    Value(DataType),
+    Aggregate(AggregateKind, usize),
+}
+
+/// An aggregate whose header has been read but not all of its elements yet.
+struct Pending {
+    kind: AggregateKind,
+    remaining: usize,
+    items: Vec<DataType>,
+}
+
+/// Decodes frames from a connection's read buffer.
+///
+/// Whatever has been decoded is taken off the buffer and kept here, so a frame that arrives over
+/// many reads is only parsed once rather than from the start on every read. Callers keep
+/// appending socket reads to the same buffer and call `parse` until it stops returning
+/// `Incomplete`.
+#[derive(Default)]
+pub struct FrameParser {
+    stack: Vec<Pending>,
+    /// How far into the buffer an inline command has already been searched for its newline.
+    inline_scanned: usize,
+}
+
+impl FrameParser {
+    /// Attempts to decode a single frame from the front of `buf`.
+    ///
+    /// Anything that doesn't start with a RESP type byte is treated as an inline command (what
+    /// you get typing into telnet or `nc`) and comes back as an array of bulk strings.
+    pub fn parse(&mut self, buf: &mut BytesMut) -> Result<ParseResult, String> {
+        loop {
+            let Some(prefix) = buf.first() else {
+                return Ok(ParseResult::Incomplete);
+            };
+
+            if self.stack.is_empty() && !RESP_TYPE_BYTES.contains(prefix) {
+                match self.parse_inline(buf)? {
+                    Some(args) if args.is_empty() => continue,
+                    Some(args) => return Ok(ParseResult::Complete(DataType::Array(args))),
+                    None => return Ok(ParseResult::Incomplete),
+                }
+            }
+
+            let Some((token, consumed)) = parse_token(buf)? else {
+                return Ok(ParseResult::Incomplete);
+            };
+            buf.advance(consumed);
+
+            let value = match token {
+                Token::Value(value) => value,
+                Token::Aggregate(kind, 0) => build_aggregate(kind, Vec::new()),
+                Token::Aggregate(kind, remaining) => {
+                    if self.stack.len() >= MAX_NESTING_DEPTH {
+                        return Err("Protocol error: too many nested aggregates".to_string());
+                    }
+                    // Don't trust the header for the allocation, the elements may never arrive
+                    let items = Vec::with_capacity(remaining.min(1024));
+                    self.stack.push(Pending { kind, remaining, items });
+                    continue;
+                }
+            };
+            if let Some(value) = self.complete(value) {
+                return Ok(ParseResult::Complete(value));
+            }
+        }
+    }
+
+    /// Whether part of a frame has been taken off the buffer and the rest is still to come.
+    pub fn in_frame(&self) -> bool {
+        !self.stack.is_empty()
+    }
+
+    /// Hands a finished value to the aggregate it belongs to, finishing that one in turn if it
+    /// was the last element. Returns the frame once the outermost value is done.
+    fn complete(&mut self, mut value: DataType) -> Option<DataType> {
+        loop {
+            let Some(pending) = self.stack.last_mut() else {
+                return Some(value);
+            };
+            pending.items.push(value);
+            pending.remaining -= 1;
+            if pending.remaining > 0 {
+                return None;
+            }
+            let pending = self.stack.pop()?;
+            value = build_aggregate(pending.kind, pending.items);
+        }
+    }
+
+    /// Takes an inline command line off the buffer, `None` until its newline has arrived.
+    fn parse_inline(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<DataType>>, String> {
+        let Some(end) = buf[self.inline_scanned..].iter().position(|x| *x == b'\n') else {
+            if buf.len() > MAX_INLINE_LENGTH {
+                return Err("Protocol error: too big inline request".to_string());
+            }
+            self.inline_scanned = buf.len();
+            return Ok(None);
+        };
+
+        let line = buf.split_to(self.inline_scanned + end + 1);
+        self.inline_scanned = 0;
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        // Blank lines come back empty and are ignored, same as redis
+        Ok(Some(split_args(line)?.into_iter().map(DataType::BulkString).collect()))
+    }
+}
+
+fn build_aggregate(kind: AggregateKind, items: Vec<DataType>) -> DataType {
+    match kind {
+        AggregateKind::Array => DataType::Array(items),
+        AggregateKind::Set => DataType::Set(items),
+        AggregateKind::Push => DataType::Push(items),
+        AggregateKind::Map => DataType::Map(into_pairs(items)),
+        AggregateKind::Attribute => {
+            // Attributes decorate whatever value comes right after them
+            let mut items = items;
+            let value = items.pop().unwrap_or(DataType::Nil);
+            DataType::Attribute { attributes: into_pairs(items), value: Box::new(value) }
+        }
+    }
+}
+
+fn into_pairs(items: Vec<DataType>) -> Vec<(DataType, DataType)> {
+    let mut items = items.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+fn find_crlf(buf: &[u8], start: usize) -> Option<usize> {
+    buf.get(start..)?
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .map(|idx| start + idx)
+}
+
+/// Reads the line starting at `start`, returning it (without the CRLF) and the offset just past it.
+fn read_line(buf: &[u8], start: usize) -> Parsed<&[u8]> {
+    let Some(end) = find_crlf(buf, start) else {
+        // A header line is a type byte and a number, anything this long is never going to end
+        if buf.len() > MAX_INLINE_LENGTH {
+            return Err("Protocol error: too big header line".to_string());
+        }
+        return Ok(None);
+    };
+    Ok(Some((&buf[start..end], end + 2)))
+}
+
+fn parse_length(line: &[u8], max: usize) -> Result<Option<usize>, String> {
+    let str = std::str::from_utf8(line).map_err(|_| "Protocol error: invalid length".to_string())?;
+    let length = str
+        .parse::<i64>()
+        .map_err(|_| format!("Protocol error: invalid length '{}'", str))?;
+
+    match length {
+        -1 => Ok(None),
+        x if x < 0 => Err(format!("Protocol error: invalid length '{}'", str)),
+        x if x as u64 > max as u64 => Err(format!("Protocol error: length {} exceeds the limit of {}", x, max)),
+        x => Ok(Some(x as usize)),
+    }
+}
+
+fn parse_utf8(line: &[u8]) -> Result<String, String> {
+    String::from_utf8(line.to_vec()).map_err(|_| "Protocol error: invalid UTF-8".to_string())
+}
+
+fn parse_line<T: std::str::FromStr>(line: &[u8], name: &str) -> Result<T, String> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|str| str.parse::<T>().ok())
+        .ok_or(format!("Protocol error: invalid {}", name))
+}
+
+/// Reads a length-prefixed payload whose header line has already been read. A length of -1 comes
+/// back as a `None` payload.
+fn parse_blob<'a>(
+    buf: &'a [u8],
+    line: &[u8],
+    after_line: usize,
+) -> Parsed<Option<&'a [u8]>> {
+    let Some(length) = parse_length(line, MAX_BULK_LENGTH)? else {
+        return Ok(Some((None, after_line)));
+    };
+    let end = after_line + length;
+    if buf.len() < end + 2 {
+        return Ok(None);
+    }
+    if &buf[end..end + 2] != b"\r\n" {
+        return Err("Protocol error: bulk string is not terminated by CRLF".to_string());
+    }
+    Ok(Some((Some(&buf[after_line..end]), end + 2)))
+}
+
+/// Parses the header line at the front of `buf`, along with the payload of a length-prefixed
+/// value. The elements of an aggregate are left for the caller to read one by one.
+fn parse_token(buf: &[u8]) -> Parsed<Token> {
+    let Some(prefix) = buf.first() else {
+        return Ok(None);
+    };
+    let Some((line, after_line)) = read_line(buf, 1)? else {
+        return Ok(None);
+    };
+
+    let value = match prefix {
+        b'+' => DataType::SimpleString(parse_utf8(line)?),
+        b'-' => DataType::Error(parse_utf8(line)?),
+        b':' => DataType::Integer(parse_line(line, "integer")?),
+        b'_' => DataType::Nil,
+        b',' => DataType::Double(match line {
+            b"inf" => f64::INFINITY,
+            b"-inf" => f64::NEG_INFINITY,
+            x => parse_line(x, "double")?,
+        }),
+        b'#' => match line {
+            b"t" => DataType::Boolean(true),
+            b"f" => DataType::Boolean(false),
+            _ => return Err("Protocol error: invalid boolean".to_string()),
+        },
+        b'(' => DataType::BigNumber(parse_utf8(line)?),
+        b'$' | b'!' | b'=' => {
+            let Some((blob, next)) = parse_blob(buf, line, after_line)? else {
+                return Ok(None);
+            };
+            let value = match (prefix, blob) {
+                (_, None) => DataType::Nil,
+                (b'$', Some(blob)) => DataType::BulkString(Bytes::copy_from_slice(blob)),
+                (b'!', Some(blob)) => DataType::Error(parse_utf8(blob)?),
+                (_, Some(blob)) => {
+                    let Some((b':', text)) = blob.get(3..).and_then(|x| x.split_first()) else {
+                        return Err("Protocol error: invalid verbatim string".to_string());
+                    };
+                    DataType::Verbatim {
+                        format: parse_utf8(&blob[..3])?,
+                        text: Bytes::copy_from_slice(text),
+                    }
+                }
+            };
+            return Ok(Some((Token::Value(value), next)));
+        }
+        b'*' | b'~' | b'>' => {
+            let Some(length) = parse_length(line, MAX_ARRAY_LENGTH)? else {
+                return Ok(Some((Token::Value(DataType::Nil), after_line)));
+            };
+            let kind = match prefix {
+                b'*' => AggregateKind::Array,
+                b'~' => AggregateKind::Set,
+                _ => AggregateKind::Push,
+            };
+            return Ok(Some((Token::Aggregate(kind, length), after_line)));
+        }
+        b'%' | b'|' => {
+            let count = parse_length(line, MAX_ARRAY_LENGTH)?.ok_or("Protocol error: invalid map length".to_string())?;
+            let token = match prefix {
+                b'%' => Token::Aggregate(AggregateKind::Map, count * 2),
+                _ => Token::Aggregate(AggregateKind::Attribute, count * 2 + 1),
+            };
+            return Ok(Some((token, after_line)));
+        }
+        x => return Err(format!("Protocol error: unexpected type byte '{}'", x.escape_ascii())),
+    };
+
+    Ok(Some((Token::Value(value), after_line)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::serializer::ProtocolVersion;
+
+    fn bulk(str: &'static str) -> DataType {
+        DataType::BulkString(str.into())
+    }
+
+    #[test]
+    pub fn test_parse_array() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("*3\r\n$3\r\nSET\r\n$1\r\nY\r\n$1\r\n1\r\n");
+
+        let output = parser.parse(&mut buf).expect("Expected the frame to parse successfully");
+
+        assert_eq!(
+            output,
+            ParseResult::Complete(DataType::Array(vec![bulk("SET"), bulk("Y"), bulk("1")]))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    pub fn test_bulk_string_with_line_breaks() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("*2\r\n$3\r\nGET\r\n$6\r\na\r\nb\nc\r\n");
+
+        let output = parser.parse(&mut buf).expect("Expected the frame to parse successfully");
+
+        assert_eq!(
+            output,
+            ParseResult::Complete(DataType::Array(vec![bulk("GET"), bulk("a\r\nb\nc")]))
+        );
+    }
+
+    #[test]
+    pub fn test_bulk_string_binary() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from(&b"$4\r\n\xff\x00\xfe\r\r\n"[..]);
+
+        let output = parser.parse(&mut buf).expect("Expected the frame to parse successfully");
+
+        assert_eq!(
+            output,
+            ParseResult::Complete(DataType::BulkString(Bytes::from_static(b"\xff\x00\xfe\r")))
+        );
+    }
+
+    #[test]
+    pub fn test_partial_reads() {
+        let mut parser = FrameParser::default();
+        let frame = b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n";
+        let mut buf = BytesMut::new();
+
+        for byte in &frame[..frame.len() - 1] {
+            buf.extend_from_slice(&[*byte]);
+            let output = parser.parse(&mut buf).expect("Expected partial frames to not error");
+            assert_eq!(output, ParseResult::Incomplete);
+        }
+
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        let output = parser.parse(&mut buf).expect("Expected the frame to parse successfully");
+        assert_eq!(
+            output,
+            ParseResult::Complete(DataType::Array(vec![bulk("GET"), bulk("hello")]))
+        );
+    }
+
+    #[test]
+    pub fn test_leaves_trailing_frames() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("$1\r\na\r\n$1\r\nb\r\n$1\r\n");
+
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Complete(bulk("a"))));
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Complete(bulk("b"))));
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Incomplete));
+        assert_eq!(&buf[..], b"$1\r\n");
+    }
+
+    #[test]
+    pub fn test_null_bulk_string() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("$-1\r\n");
+
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Complete(DataType::Nil)));
+    }
+
+    #[test]
+    pub fn test_bulk_length_mismatch() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("$3\r\nabcd\r\n");
+
+        assert!(parser.parse(&mut buf).is_err());
+    }
+
+    #[test]
+    pub fn test_inline_command() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("\r\nSET a \"b c\"\r\nPING\n");
+
+        assert_eq!(
+            parser.parse(&mut buf),
+            Ok(ParseResult::Complete(DataType::Array(vec![bulk("SET"), bulk("a"), bulk("b c")])))
+        );
+        assert_eq!(
+            parser.parse(&mut buf),
+            Ok(ParseResult::Complete(DataType::Array(vec![bulk("PING")])))
+        );
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Incomplete));
+    }
+
+    #[test]
+    pub fn test_inline_command_partial() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("GET a");
+
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Incomplete));
+        buf.extend_from_slice(b"bc\r\n");
+        assert_eq!(
+            parser.parse(&mut buf),
+            Ok(ParseResult::Complete(DataType::Array(vec![bulk("GET"), bulk("abc")])))
+        );
+    }
+
+    #[test]
+    pub fn test_resp3_roundtrip() {
+        let mut parser = FrameParser::default();
+        let data = DataType::Attribute {
+            attributes: vec![(DataType::SimpleString("key".into()), DataType::Boolean(true))],
+            value: Box::new(DataType::Map(vec![
+                (bulk("a"), DataType::Double(1.5)),
+                (bulk("b"), DataType::Set(vec![DataType::Integer(-3), DataType::Nil])),
+                (bulk("c"), DataType::Push(vec![DataType::BigNumber("123456789012345678901".into())])),
+                (bulk("d"), DataType::Verbatim { format: "txt".into(), text: "x\r\ny".into() }),
+            ])),
+        };
+        let mut buf = BytesMut::from(&data.to_wire_protocol(ProtocolVersion::Resp3)[..]);
+
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Complete(data)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    pub fn test_invalid_length() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("*abc\r\n");
+
+        assert!(parser.parse(&mut buf).is_err());
+    }
+
+    #[test]
+    pub fn test_nesting_limit() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from(&b"*1\r\n".repeat(200_000)[..]);
+
+        assert!(parser.parse(&mut buf).is_err());
+    }
+
+    #[test]
+    pub fn test_nested_within_limit() {
+        let mut parser = FrameParser::default();
+        let mut buf = BytesMut::from("*1\r\n*1\r\n*0\r\n");
+
+        assert_eq!(
+            parser.parse(&mut buf),
+            Ok(ParseResult::Complete(DataType::Array(vec![DataType::Array(vec![DataType::Array(vec![])])])))
+        );
+    }
+
+    #[test]
+    pub fn test_large_array_across_reads() {
+        let mut parser = FrameParser::default();
+        let count = 10_000;
+        let mut frame = format!("*{}\r\n", count).into_bytes();
+        (0..count).for_each(|_| frame.extend_from_slice(b"$1\r\na\r\n"));
+        let mut buf = BytesMut::new();
+
+        for chunk in frame.chunks(5) {
+            assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Incomplete));
+            buf.extend_from_slice(chunk);
+            // Elements are taken off the buffer as they complete rather than parsed again
+            assert!(buf.len() < 16);
+        }
+        assert_eq!(parser.parse(&mut buf), Ok(ParseResult::Complete(DataType::Array((0..count).map(|_| bulk("a")).collect()))));
+    }
+}
@@ -1,19 +1,174 @@
+use bytes::{BufMut, Bytes, BytesMut};
 use crate::datatypes::DataType;
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProtocolVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+fn write_line(buf: &mut BytesMut, prefix: u8, line: &[u8]) {
+    buf.put_u8(prefix);
+    buf.put_slice(line);
+    buf.put_slice(b"\r\n");
+}
+
+fn write_blob(buf: &mut BytesMut, prefix: u8, blob: &[u8]) {
+    write_line(buf, prefix, blob.len().to_string().as_bytes());
+    buf.put_slice(blob);
+    buf.put_slice(b"\r\n");
+}
+
+/// The shortest text that reads back as the same double. Like redis' `%.17g` it switches to an
+/// exponent for very large or small magnitudes instead of writing out hundreds of digits. Also
+/// how INCRBYFLOAT stores its result.
+pub(crate) fn format_double(value: f64) -> String {
+    match value {
+        x if x.is_nan() => "nan".to_string(),
+        x if x == f64::INFINITY => "inf".to_string(),
+        x if x == f64::NEG_INFINITY => "-inf".to_string(),
+        x => {
+            let scientific = format!("{:e}", x);
+            let (mantissa, exponent) = scientific.split_once('e').expect("Expected an exponent");
+            let exponent = exponent.parse::<i32>().expect("Expected the exponent to be a number");
+            match exponent {
+                -4..=16 => x.to_string(),
+                _ => format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs()),
+            }
+        }
+    }
+}
+
 impl DataType {
-    pub fn to_wire_protocol(&self) -> String {
+    pub fn to_wire_protocol(&self, protocol: ProtocolVersion) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.write_wire_protocol(protocol, &mut buf);
+        buf.freeze()
+    }
+
+    pub fn write_wire_protocol(&self, protocol: ProtocolVersion, buf: &mut BytesMut) {
+        let resp3 = protocol == ProtocolVersion::Resp3;
         match self {
-            DataType::SimpleString(str) => format!("+{}\r\n", str),
-            DataType::BulkString(str) => format!("${}\r\n{}\r\n", str.len(), str),
+            DataType::SimpleString(str) => write_line(buf, b'+', str.as_bytes()),
+            DataType::BulkString(bytes) => write_blob(buf, b'$', bytes),
             DataType::Array(data) => {
-                let output = data
-                    .iter()
-                    .map(|x| x.to_wire_protocol())
-                    .collect::<Vec<String>>();
-                format!("*{}\r\n{}", output.len(), output.join(""))
+                write_line(buf, b'*', data.len().to_string().as_bytes());
+                data.iter().for_each(|x| x.write_wire_protocol(protocol, buf));
+            },
+            DataType::Error(str) => write_line(buf, b'-', str.as_bytes()),
+            DataType::Nil if resp3 => buf.put_slice(b"_\r\n"),
+            DataType::Nil => buf.put_slice(b"$-1\r\n"),
+            DataType::NullArray if resp3 => buf.put_slice(b"_\r\n"),
+            DataType::NullArray => buf.put_slice(b"*-1\r\n"),
+            DataType::Integer(x) => write_line(buf, b':', x.to_string().as_bytes()),
+            DataType::Double(x) if resp3 => write_line(buf, b',', format_double(*x).as_bytes()),
+            DataType::Double(x) => write_blob(buf, b'$', format_double(*x).as_bytes()),
+            DataType::Boolean(x) if resp3 => write_line(buf, b'#', if *x { b"t" } else { b"f" }),
+            DataType::Boolean(x) => write_line(buf, b':', if *x { b"1" } else { b"0" }),
+            DataType::BigNumber(x) if resp3 => write_line(buf, b'(', x.as_bytes()),
+            DataType::BigNumber(x) => write_blob(buf, b'$', x.as_bytes()),
+            DataType::Verbatim { format, text } if resp3 => {
+                let mut blob = BytesMut::with_capacity(format.len() + 1 + text.len());
+                blob.put_slice(format.as_bytes());
+                blob.put_u8(b':');
+                blob.put_slice(text);
+                write_blob(buf, b'=', &blob);
             },
-            DataType::Error(str)  => format!("-{}\r\n", str),
-            DataType::Nil => "$-1\r\n".to_string(),
+            DataType::Verbatim { text, .. } => write_blob(buf, b'$', text),
+            DataType::Map(pairs) => {
+                if resp3 {
+                    write_line(buf, b'%', pairs.len().to_string().as_bytes());
+                } else {
+                    write_line(buf, b'*', (pairs.len() * 2).to_string().as_bytes());
+                }
+                pairs.iter().for_each(|(key, value)| {
+                    key.write_wire_protocol(protocol, buf);
+                    value.write_wire_protocol(protocol, buf);
+                });
+            },
+            DataType::Set(data) | DataType::Push(data) => {
+                let prefix = match (resp3, self) {
+                    (true, DataType::Set(_)) => b'~',
+                    (true, _) => b'>',
+                    (false, _) => b'*',
+                };
+                write_line(buf, prefix, data.len().to_string().as_bytes());
+                data.iter().for_each(|x| x.write_wire_protocol(protocol, buf));
+            },
+            DataType::Attribute { attributes, value } => {
+                // RESP2 has no way to represent attributes, so those clients only get the value
+                if resp3 {
+                    write_line(buf, b'|', attributes.len().to_string().as_bytes());
+                    attributes.iter().for_each(|(key, value)| {
+                        key.write_wire_protocol(protocol, buf);
+                        value.write_wire_protocol(protocol, buf);
+                    });
+                }
+                value.write_wire_protocol(protocol, buf);
+            },
+            DataType::Sequence(replies) => replies.iter().for_each(|x| x.write_wire_protocol(protocol, buf)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(data: &DataType, protocol: ProtocolVersion) -> Bytes {
+        data.to_wire_protocol(protocol)
+    }
+
+    #[test]
+    pub fn test_map_downgrades_to_flat_array() {
+        let data = DataType::Map(vec![(DataType::BulkString("a".into()), DataType::Integer(1))]);
+
+        assert_eq!(serialize(&data, ProtocolVersion::Resp2), Bytes::from("*2\r\n$1\r\na\r\n:1\r\n"));
+        assert_eq!(serialize(&data, ProtocolVersion::Resp3), Bytes::from("%1\r\n$1\r\na\r\n:1\r\n"));
+    }
+
+    #[test]
+    pub fn test_scalars() {
+        let cases = [
+            (DataType::Nil, "$-1\r\n", "_\r\n"),
+            (DataType::NullArray, "*-1\r\n", "_\r\n"),
+            (DataType::Double(1.5), "$3\r\n1.5\r\n", ",1.5\r\n"),
+            (DataType::Double(f64::NEG_INFINITY), "$4\r\n-inf\r\n", ",-inf\r\n"),
+            (DataType::Double(1e300), "$6\r\n1e+300\r\n", ",1e+300\r\n"),
+            (DataType::Double(-1e-300), "$7\r\n-1e-300\r\n", ",-1e-300\r\n"),
+            (DataType::Double(1.5e-7), "$7\r\n1.5e-07\r\n", ",1.5e-07\r\n"),
+            (DataType::Double(0.0001), "$6\r\n0.0001\r\n", ",0.0001\r\n"),
+            (DataType::Double(1e16), "$17\r\n10000000000000000\r\n", ",10000000000000000\r\n"),
+            (DataType::Double(1.2345e17), "$10\r\n1.2345e+17\r\n", ",1.2345e+17\r\n"),
+            (DataType::Boolean(true), ":1\r\n", "#t\r\n"),
+            (DataType::BigNumber("12345678901234567890".into()), "$20\r\n12345678901234567890\r\n", "(12345678901234567890\r\n"),
+            (DataType::Verbatim { format: "txt".into(), text: "hi".into() }, "$2\r\nhi\r\n", "=6\r\ntxt:hi\r\n"),
+        ];
+
+        for (data, resp2, resp3) in cases {
+            assert_eq!(serialize(&data, ProtocolVersion::Resp2), Bytes::from(resp2), "{:?}", data);
+            assert_eq!(serialize(&data, ProtocolVersion::Resp3), Bytes::from(resp3), "{:?}", data);
+        }
+    }
+
+    #[test]
+    pub fn test_aggregates() {
+        let items = || vec![DataType::SimpleString("a".into())];
+
+        assert_eq!(serialize(&DataType::Set(items()), ProtocolVersion::Resp3), Bytes::from("~1\r\n+a\r\n"));
+        assert_eq!(serialize(&DataType::Push(items()), ProtocolVersion::Resp3), Bytes::from(">1\r\n+a\r\n"));
+        assert_eq!(serialize(&DataType::Push(items()), ProtocolVersion::Resp2), Bytes::from("*1\r\n+a\r\n"));
+    }
+
+    #[test]
+    pub fn test_attribute() {
+        let data = DataType::Attribute {
+            attributes: vec![(DataType::SimpleString("ttl".into()), DataType::Integer(3))],
+            value: Box::new(DataType::Integer(1)),
+        };
+
+        assert_eq!(serialize(&data, ProtocolVersion::Resp2), Bytes::from(":1\r\n"));
+        assert_eq!(serialize(&data, ProtocolVersion::Resp3), Bytes::from("|1\r\n+ttl\r\n:3\r\n:1\r\n"));
+    }
+}
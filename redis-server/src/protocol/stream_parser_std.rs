@@ -1,56 +1,78 @@
-use std::io::{BufRead, BufReader, Write};
+use std::io::{Read, Write};
 use std::net::TcpStream;
+use bytes::BytesMut;
 use crate::datatypes::DataType;
-use crate::protocol::string_parser::{ParseResult, Parser};
+use crate::protocol::resp_parser::{FrameParser, ParseResult};
 use crate::single_server::Server;
 
 pub fn handle_connection(server: &mut Server, stream: &mut TcpStream) -> Result<(), String> {
-    // let (read_stream, mut write_stream) = stream.;
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    // let mut lines: io::Lines<BufReader<tokio::net::tcp::ReadHalf<'_>>> = reader.lines();
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut parser = FrameParser::default();
+    let mut output = BytesMut::with_capacity(4096);
+    let mut session = server.create_session();
+    let mut chunk = [0u8; 4096];
 
     loop {
-        let mut parser = Parser::new();
-        let mut parsed_lines = false;
         loop {
-            let mut str = String::new();
-            let res = reader.read_line(&mut str).map_err(|err| err.to_string())?;
-            if res == 0 || str.is_empty() {
-                break;
-            }
-            let str = str.trim();
-            parsed_lines = true;
-    
-            // println!("Parsing Line: {str}");
-            let parse_res = parser.next(&str).map_err(|err| err.to_string())?;
-            if let ParseResult::Complete = parse_res {
-                break;
-            }
+            let res = match parser.parse(&mut buffer) {
+                Ok(ParseResult::Complete(res)) => res,
+                Ok(ParseResult::Incomplete) => break,
+                Err(err) => {
+                    // The commands before the bad frame already ran, their replies go out first
+                    DataType::Error(format!("ERR {err}")).write_wire_protocol(session.protocol, &mut output);
+                    stream.write_all(&output).map_err(|err| err.to_string())?;
+                    return Err(err);
+                }
+            };
+            // println!("Parser output: {:?}", res);
+            let command = res.to_command();
+
+            let response = match command {
+                Ok(command) => server.process_command(&mut session, command)?,
+                Err(err) => session.reject(err),
+            };
+
+            response.write_wire_protocol(session.protocol, &mut output);
         }
 
-        if !parsed_lines {
-            println!("No parse!");
-            return Ok(());
+        if !output.is_empty() {
+            stream.write_all(&output).map_err(|err| err.to_string())?;
+            output.clear();
         }
-    
-        let res = parser.to_datatype()?;
-        // println!("Parser output: {:?}", res);
-        // println!("Parser wire representation: {:?}", res.to_wire_protocol().escape_debug());
-    
-        let command = res.to_command();
-    
-        let response = match command {
-            Ok(command) => {
-                let result = server.process_command(command)?;
-                result
-            },
-            Err(err) => {
-                DataType::Error(err)
+
+        let read = stream.read(&mut chunk).map_err(|err| err.to_string())?;
+        if read == 0 {
+            if buffer.is_empty() && !parser.in_frame() {
+                return Ok(());
             }
-        };
+            return Err("Connection closed in the middle of a frame".to_string());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    pub fn test_pipeline_with_protocol_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handle_connection(&mut Server::new(), &mut stream)
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$abc\r\n").unwrap();
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).unwrap();
 
-        let resp = format!("{}",response.to_wire_protocol());
-        stream.write_all(resp.as_bytes()).map_err(|err| err.to_string())?;
+        assert_eq!(replies, b"+OK\r\n$1\r\nv\r\n-ERR Protocol error: invalid length 'abc'\r\n");
+        assert!(handler.join().unwrap().is_err());
     }
-    // Ok(())
 }
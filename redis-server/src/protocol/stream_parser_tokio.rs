@@ -1,68 +1,179 @@
 use std::sync::Arc;
 use std::time::Duration;
 
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::ReadHalf;
 use tokio::net::TcpStream;
-use tokio::time::sleep;
+use crate::commands::Command;
 use crate::datatypes::DataType;
-use crate::protocol::string_parser::{ParseResult, Parser};
+use crate::protocol::resp_parser::{FrameParser, ParseResult};
 use crate::multi_server::Server;
+use crate::pubsub::Inbox;
+use crate::session::Session;
 
 pub async fn handle_connection(server: Arc<Server>, stream: &mut TcpStream) -> Result<(), String> {
-    let (read_stream, mut write_stream) = stream.split();
-    let reader = BufReader::new(read_stream);
-    let mut lines: io::Lines<BufReader<tokio::net::tcp::ReadHalf<'_>>> = reader.lines();
+    let mut session = server.create_session();
+    let result = match server.too_many_clients() {
+        true => {
+            let reply = DataType::Error("ERR max number of clients reached".into()).to_wire_protocol(session.protocol);
+            stream.write_all(&reply).await.map_err(|err| err.to_string())
+        }
+        false => serve(&server, stream, &mut session).await,
+    };
+    server.close_session(&session);
+    result
+}
+
+async fn serve(server: &Server, stream: &mut TcpStream, session: &mut Session) -> Result<(), String> {
+    let (mut read_stream, mut write_stream) = stream.split();
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut parser = FrameParser::default();
+    let mut output = BytesMut::with_capacity(4096);
 
     loop {
-        let mut parser = Parser::new();
-        let mut parsed_lines = false;
-        while let Some(line) = lines.next_line().await.map_err(|err| err.to_string())? {
-            if line.is_empty() {
-                break;
+        // Run every frame that's already buffered before going back to the socket, so a pipelined
+        // batch gets all of its replies back in a single write.
+        loop {
+            let res = match parser.parse(&mut buffer) {
+                Ok(ParseResult::Complete(res)) => res,
+                Ok(ParseResult::Incomplete) => break,
+                Err(err) => {
+                    // The commands before the bad frame already ran, their replies go out first
+                    DataType::Error(format!("ERR {err}")).write_wire_protocol(session.protocol, &mut output);
+                    write_stream.write_all(&output).await.map_err(|err| err.to_string())?;
+                    return Err(err);
+                }
+            };
+            // println!("Parser output: {:?}", res);
+            let command = res.to_command();
+
+            let response = match command {
+                Ok(command) if !session.allows(&command) => DataType::Error(format!(
+                    "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING are allowed in this context",
+                    res.command_name().unwrap_or_default()
+                )),
+                Ok(command @ Command::Blocking(_)) => {
+                    // The client could be waiting on these before it sees the blocked reply
+                    if !output.is_empty() {
+                        write_stream.write_all(&output).await.map_err(|err| err.to_string())?;
+                        output.clear();
+                    }
+                    tokio::select! {
+                        response = server.process_command(session, command) => response?,
+                        closed = wait_for_close(&mut read_stream, &mut buffer) => return closed,
+                    }
+                }
+                Ok(command) => server.process_command(session, command).await?,
+                Err(err) => session.reject(err),
+            };
+
+            response.write_wire_protocol(session.protocol, &mut output);
+        }
+
+        if !output.is_empty() {
+            // println!("Writing response: {:?}", output);
+            write_stream
+                .write_all(&output)
+                .await
+                .map_err(|err| err.to_string())?;
+            output.clear();
+        }
+
+        // A subscribed client gets its messages pushed while the connection would otherwise sit idle
+        let idle_timeout = server.idle_timeout(session);
+        let read = tokio::select! {
+            read = read_stream.read_buf(&mut buffer) => read.map_err(|err| err.to_string())?,
+            _ = sleep_for(idle_timeout) => return Ok(()),
+            message = next_message(&mut session.inbox) => {
+                let message = message.ok_or("Subscriber went over the pub/sub output buffer limit".to_string())?;
+                message.write_wire_protocol(session.protocol, &mut output);
+                continue;
             }
-            parsed_lines = true;
-    
-            // println!("Parsing Line: {line}");
-            let parse_res = parser.next(&line).map_err(|err| err.to_string())?;
-            if let ParseResult::Complete = parse_res {
-                break;
+        };
+        if read == 0 {
+            if buffer.is_empty() && !parser.in_frame() {
+                return Ok(());
             }
+            return Err("Connection closed in the middle of a frame".to_string());
         }
+    }
+}
 
-        if !parsed_lines {
-            println!("No parse!");
-            sleep(Duration::from_millis(10)).await;
+async fn next_message(inbox: &mut Option<Inbox>) -> Option<DataType> {
+    match inbox {
+        Some(inbox) => inbox.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_for(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Keeps reading while a command is blocked, only to notice the client going away. Whatever it
+/// sends in the meantime stays in the buffer for after the blocked command.
+async fn wait_for_close(read_stream: &mut ReadHalf<'_>, buffer: &mut BytesMut) -> Result<(), String> {
+    loop {
+        let read = read_stream.read_buf(buffer).await.map_err(|err| err.to_string())?;
+        if read == 0 {
             return Ok(());
         }
-    
-        let res = parser.to_datatype()?;
-        // println!("Parser output: {:?}", res);
-        // println!("Parser wire representation: {:?}", res.to_wire_protocol().escape_debug());
-    
-        let command = res.to_command();
-    
-        let response = match command {
-            Ok(command) => {
-                let result = server.process_command(command).await?;
-                result
-            },
-            Err(err) => {
-                DataType::Error(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use crate::config::Config;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_pipeline_with_protocol_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new());
+        let handler = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            handle_connection(server, &mut stream).await
+        });
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$abc\r\n").await.unwrap();
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+
+        assert_eq!(replies, b"+OK\r\n$1\r\nv\r\n-ERR Protocol error: invalid length 'abc'\r\n");
+        assert!(handler.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_maxclients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config::from_args(["--maxclients", "1"].map(String::from)).unwrap();
+        let server = Arc::new(Server::with_config(config));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let server = server.clone();
+                tokio::spawn(async move { handle_connection(server, &mut stream).await });
             }
-        };
-    
-        write_stream
-            .writable()
-            .await
-            .map_err(|err| err.to_string())?;
-        let resp = format!("{}",response.to_wire_protocol());
-        // println!("Writing response: {}", resp.escape_debug());
-        write_stream
-            .write_all(resp.as_bytes())
-            .await
-            .map_err(|err| err.to_string())?;
-        // write_stream.flush().await.map_err(|err| err.to_string())?;
-        // println!("Done");
+        });
+
+        let mut first = TcpStream::connect(address).await.unwrap();
+        first.write_all(b"PING\r\n").await.unwrap();
+        let mut reply = [0; 7];
+        first.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+
+        let mut second = TcpStream::connect(address).await.unwrap();
+        let mut replies = Vec::new();
+        second.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, b"-ERR max number of clients reached\r\n");
     }
-    // Ok(())
 }
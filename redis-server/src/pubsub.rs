@@ -0,0 +1,494 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use crate::commands::SubscriptionKind;
+use crate::data::memory_engine::{shard_index, SHARD_COUNT};
+use crate::data::shared::glob_match;
+use crate::datatypes::DataType;
+use crate::log::{log, Level};
+use crate::session::Session;
+
+/// How many bytes of messages a subscriber can have waiting to be written before it gets
+/// disconnected, the same as the hard limit of redis' `client-output-buffer-limit pubsub`.
+pub const OUTPUT_BUFFER_LIMIT: usize = 32 * 1024 * 1024;
+
+/// Where a subscribed connection picks up its messages.
+#[derive(Debug)]
+pub struct Inbox {
+    receiver: UnboundedReceiver<(DataType, usize)>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Inbox {
+    /// The next message, None once the broker dropped the subscriber for falling behind. What
+    /// was still queued is thrown away, like redis closing the connection straight away.
+    pub async fn recv(&mut self) -> Option<DataType> {
+        // A rejected message is never taken off the count, so this stays over the limit
+        if self.queued.load(Ordering::Relaxed) > OUTPUT_BUFFER_LIMIT {
+            return None;
+        }
+        let (message, size) = self.receiver.recv().await?;
+        self.queued.fetch_sub(size, Ordering::Relaxed);
+        Some(message)
+    }
+
+    /// Takes the messages that are already queued without waiting for more.
+    fn drain(&mut self) -> Vec<DataType> {
+        let mut messages = Vec::new();
+        while let Ok((message, _)) = self.receiver.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+}
+
+/// The sending half of an inbox.
+#[derive(Clone)]
+struct Outbox {
+    sender: UnboundedSender<(DataType, usize)>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Outbox {
+    /// Queues the message, false if that would take the subscriber over the limit.
+    fn send(&self, message: DataType, size: usize) -> bool {
+        if self.queued.fetch_add(size, Ordering::Relaxed) + size > OUTPUT_BUFFER_LIMIT {
+            return false;
+        }
+        // The connection may have gone already, it cleans up after itself
+        self.sender.send((message, size)).is_ok()
+    }
+}
+
+struct Subscriber {
+    outbox: Outbox,
+    subscriptions: HashSet<(SubscriptionKind, Bytes)>,
+}
+
+impl Subscriber {
+    /// Shard subscriptions are counted apart from the rest, like redis does.
+    fn count(&self, kind: SubscriptionKind) -> usize {
+        let shard = kind == SubscriptionKind::Shard;
+        self.subscriptions.iter().filter(|(other, _)| (*other == SubscriptionKind::Shard) == shard).count()
+    }
+}
+
+/// The subscribers of the shard channels that hash to one shard.
+type ShardChannels = HashMap<Bytes, HashMap<u64, Outbox>>;
+
+#[derive(Default)]
+struct BrokerState {
+    subscribers: HashMap<u64, Subscriber>,
+    /// Client IDs by channel and by pattern.
+    channels: HashMap<Bytes, HashSet<u64>>,
+    patterns: HashMap<Bytes, HashSet<u64>>,
+}
+
+impl BrokerState {
+    fn targets(&mut self, kind: SubscriptionKind) -> &mut HashMap<Bytes, HashSet<u64>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::Shard => unreachable!("Shard channels are kept by the shards"),
+        }
+    }
+
+    /// Shard channels need their shard locked, always after this state.
+    fn unsubscribe(&mut self, shards: &[Mutex<ShardChannels>], client_id: u64, kind: SubscriptionKind, name: &Bytes) -> bool {
+        let Some(subscriber) = self.subscribers.get_mut(&client_id) else {
+            return false;
+        };
+        if !subscriber.subscriptions.remove(&(kind, name.clone())) {
+            return false;
+        }
+        if kind == SubscriptionKind::Shard {
+            let mut shard = shards[shard_index(name)].lock().unwrap();
+            if let Some(clients) = shard.get_mut(name) {
+                clients.remove(&client_id);
+                if clients.is_empty() {
+                    shard.remove(name);
+                }
+            }
+            return true;
+        }
+        let targets = self.targets(kind);
+        if let Some(clients) = targets.get_mut(name) {
+            clients.remove(&client_id);
+            if clients.is_empty() {
+                targets.remove(name);
+            }
+        }
+        true
+    }
+
+    fn remove(&mut self, shards: &[Mutex<ShardChannels>], client_id: u64) {
+        let Some(subscriber) = self.subscribers.get(&client_id) else {
+            return;
+        };
+        for (kind, name) in subscriber.subscriptions.clone() {
+            self.unsubscribe(shards, client_id, kind, &name);
+        }
+        self.subscribers.remove(&client_id);
+    }
+}
+
+/// Messages are a few small frames around the payload, this is only used to enforce the limit.
+fn message_size(parts: &[&Bytes]) -> usize {
+    parts.iter().map(|part| part.len() + 16).sum::<usize>() + 16
+}
+
+/// Keeps track of who's subscribed to what and hands published messages to them. Shard
+/// channels hash like keys do in the engine, so SPUBLISH only locks the one shard.
+#[derive(Default)]
+pub struct Broker {
+    state: Mutex<BrokerState>,
+    shards: [Mutex<ShardChannels>; SHARD_COUNT],
+}
+
+impl Broker {
+    /// Replies once per name with the number of subscriptions the client has after it.
+    pub fn subscribe(&self, session: &mut Session, kind: SubscriptionKind, names: Vec<Bytes>) -> DataType {
+        let mut state = self.state.lock().unwrap();
+        if session.inbox.is_none() {
+            let (sender, receiver) = unbounded_channel();
+            let queued = Arc::new(AtomicUsize::new(0));
+            session.inbox = Some(Inbox { receiver, queued: queued.clone() });
+            state.subscribers.insert(session.id, Subscriber { outbox: Outbox { sender, queued }, subscriptions: HashSet::new() });
+        }
+
+        let mut replies = Vec::new();
+        for name in names {
+            let subscriber = state.subscribers.get_mut(&session.id).expect("Expected the subscriber to be registered");
+            if subscriber.subscriptions.insert((kind, name.clone())) {
+                match kind {
+                    SubscriptionKind::Shard => {
+                        let outbox = subscriber.outbox.clone();
+                        self.shards[shard_index(&name)].lock().unwrap().entry(name.clone()).or_default().insert(session.id, outbox);
+                    }
+                    _ => {
+                        state.targets(kind).entry(name.clone()).or_default().insert(session.id);
+                    }
+                }
+            }
+            let subscriber = &state.subscribers[&session.id];
+            session.subscriptions = subscriber.subscriptions.len();
+            replies.push(subscription_reply(kind, true, Some(name), subscriber.count(kind)));
+        }
+        DataType::Sequence(replies)
+    }
+
+    /// Without names every subscription of the kind is dropped.
+    pub fn unsubscribe(&self, session: &mut Session, kind: SubscriptionKind, names: Vec<Bytes>) -> DataType {
+        let mut state = self.state.lock().unwrap();
+        let names = match names.is_empty() {
+            true => state.subscribers.get(&session.id).map_or(vec![], |subscriber| {
+                subscriber.subscriptions.iter().filter(|(other, _)| *other == kind).map(|(_, name)| name.clone()).collect()
+            }),
+            false => names,
+        };
+        let count = |state: &BrokerState| state.subscribers.get(&session.id).map_or(0, |subscriber| subscriber.count(kind));
+        if names.is_empty() {
+            return DataType::Sequence(vec![subscription_reply(kind, false, None, count(&state))]);
+        }
+
+        let mut replies = Vec::new();
+        for name in names {
+            state.unsubscribe(&self.shards, session.id, kind, &name);
+            session.subscriptions = state.subscribers.get(&session.id).map_or(0, |subscriber| subscriber.subscriptions.len());
+            replies.push(subscription_reply(kind, false, Some(name), count(&state)));
+        }
+        // Out of subscribed mode, the connection goes back to plain requests and replies. The
+        // messages PUBLISH already counted as delivered still go out, ahead of the replies.
+        if session.subscriptions == 0 {
+            state.subscribers.remove(&session.id);
+            if let Some(mut inbox) = session.inbox.take() {
+                let mut messages = inbox.drain();
+                messages.append(&mut replies);
+                replies = messages;
+            }
+        }
+        DataType::Sequence(replies)
+    }
+
+    /// Returns how many clients got the message. Subscribers that fall too far behind are
+    /// dropped and their connections closed.
+    pub fn publish(&self, channel: &Bytes, message: &Bytes) -> i64 {
+        let mut state = self.state.lock().unwrap();
+        let mut received = 0;
+        let mut overflowed = Vec::new();
+
+        if let Some(clients) = state.channels.get(channel) {
+            let size = message_size(&[channel, message]);
+            for client_id in clients {
+                let push = DataType::Push(vec![
+                    DataType::BulkString("message".into()),
+                    DataType::BulkString(channel.clone()),
+                    DataType::BulkString(message.clone()),
+                ]);
+                match state.subscribers[client_id].outbox.send(push, size) {
+                    true => received += 1,
+                    false => overflowed.push(*client_id),
+                }
+            }
+        }
+        for (pattern, clients) in state.patterns.iter().filter(|(pattern, _)| glob_match(pattern, channel)) {
+            let size = message_size(&[pattern, channel, message]);
+            for client_id in clients {
+                let push = DataType::Push(vec![
+                    DataType::BulkString("pmessage".into()),
+                    DataType::BulkString(pattern.clone()),
+                    DataType::BulkString(channel.clone()),
+                    DataType::BulkString(message.clone()),
+                ]);
+                match state.subscribers[client_id].outbox.send(push, size) {
+                    true => received += 1,
+                    false => overflowed.push(*client_id),
+                }
+            }
+        }
+
+        for client_id in overflowed {
+            log(Level::Notice, format_args!("Dropping subscriber {client_id}, it went over the output buffer limit"));
+            state.remove(&self.shards, client_id);
+        }
+        received
+    }
+
+    /// SPUBLISH, which only needs the shard of the channel.
+    pub fn shard_publish(&self, channel: &Bytes, message: &Bytes) -> i64 {
+        let mut received = 0;
+        let mut overflowed = Vec::new();
+        {
+            let shard = self.shards[shard_index(channel)].lock().unwrap();
+            if let Some(clients) = shard.get(channel) {
+                let size = message_size(&[channel, message]);
+                for (client_id, outbox) in clients {
+                    let push = DataType::Push(vec![
+                        DataType::BulkString("smessage".into()),
+                        DataType::BulkString(channel.clone()),
+                        DataType::BulkString(message.clone()),
+                    ]);
+                    match outbox.send(push, size) {
+                        true => received += 1,
+                        false => overflowed.push(*client_id),
+                    }
+                }
+            }
+        }
+
+        // The shard is unlocked by now, removing takes the broker state first
+        for client_id in overflowed {
+            log(Level::Notice, format_args!("Dropping subscriber {client_id}, it went over the output buffer limit"));
+            self.remove(client_id);
+        }
+        received
+    }
+
+    /// The channels with at least one subscriber, matching `pattern` if there is one.
+    pub fn channels(&self, pattern: Option<&[u8]>) -> DataType {
+        let state = self.state.lock().unwrap();
+        let channels = state
+            .channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .map(|channel| DataType::BulkString(channel.clone()))
+            .collect();
+        DataType::Array(channels)
+    }
+
+    pub fn shard_channels(&self, pattern: Option<&[u8]>) -> DataType {
+        let channels = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .keys()
+                    .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+                    .map(|channel| DataType::BulkString(channel.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        DataType::Array(channels)
+    }
+
+    pub fn numsub(&self, channels: Vec<Bytes>) -> DataType {
+        let state = self.state.lock().unwrap();
+        let counts = channels
+            .into_iter()
+            .flat_map(|channel| {
+                let count = state.channels.get(&channel).map_or(0, |clients| clients.len());
+                [DataType::BulkString(channel), DataType::Integer(count as i64)]
+            })
+            .collect();
+        DataType::Array(counts)
+    }
+
+    pub fn shard_numsub(&self, channels: Vec<Bytes>) -> DataType {
+        let counts = channels
+            .into_iter()
+            .flat_map(|channel| {
+                let shard = self.shards[shard_index(&channel)].lock().unwrap();
+                let count = shard.get(&channel).map_or(0, |clients| clients.len());
+                [DataType::BulkString(channel), DataType::Integer(count as i64)]
+            })
+            .collect();
+        DataType::Array(counts)
+    }
+
+    pub fn numpat(&self) -> DataType {
+        DataType::Integer(self.state.lock().unwrap().patterns.len() as i64)
+    }
+
+    /// Forgets every subscription of a client that disconnected.
+    pub fn remove(&self, client_id: u64) {
+        self.state.lock().unwrap().remove(&self.shards, client_id);
+    }
+}
+
+fn subscription_reply(kind: SubscriptionKind, subscribe: bool, name: Option<Bytes>, count: usize) -> DataType {
+    let kind = match (kind, subscribe) {
+        (SubscriptionKind::Channel, true) => "subscribe",
+        (SubscriptionKind::Channel, false) => "unsubscribe",
+        (SubscriptionKind::Pattern, true) => "psubscribe",
+        (SubscriptionKind::Pattern, false) => "punsubscribe",
+        (SubscriptionKind::Shard, true) => "ssubscribe",
+        (SubscriptionKind::Shard, false) => "sunsubscribe",
+    };
+    DataType::Push(vec![
+        DataType::BulkString(kind.into()),
+        name.map_or(DataType::Nil, DataType::BulkString),
+        DataType::Integer(count as i64),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{keyspace::Keyspace, notify::{parse_notify_flags, Notifier}};
+    use crate::datatypes::{StorageRecord, StorageValue};
+
+    use super::*;
+
+    #[test]
+    pub fn test_publish_reaches_channels_and_patterns() {
+        let broker = Broker::default();
+        let mut first = Session::new(1);
+        let mut second = Session::new(2);
+        broker.subscribe(&mut first, SubscriptionKind::Channel, vec!["news.tech".into(), "other".into()]);
+        broker.subscribe(&mut second, SubscriptionKind::Pattern, vec!["news.*".into()]);
+        assert_eq!(first.subscriptions, 2);
+
+        assert_eq!(broker.publish(&"news.tech".into(), &"hi".into()), 2);
+        assert_eq!(broker.publish(&"sports".into(), &"hi".into()), 0);
+
+        let pmessage = second.inbox.as_mut().unwrap().receiver.try_recv().unwrap().0;
+        assert_eq!(
+            pmessage,
+            DataType::Push(vec![
+                DataType::BulkString("pmessage".into()),
+                DataType::BulkString("news.*".into()),
+                DataType::BulkString("news.tech".into()),
+                DataType::BulkString("hi".into()),
+            ])
+        );
+    }
+
+    #[test]
+    pub fn test_expired_keys_are_published() {
+        let broker = Arc::new(Broker::default());
+        let notifier = Arc::new(Notifier::new(broker.clone()));
+        let mut session = Session::new(1);
+        broker.subscribe(&mut session, SubscriptionKind::Channel, vec!["__keyevent@0__:expired".into(), "__keyspace@0__:a".into()]);
+
+        let mut keyspace = Keyspace::with_notifier(notifier.clone());
+        keyspace.insert("a".into(), StorageRecord { value: StorageValue::String("1".into()), ttl: Some(10) });
+        keyspace.get(b"a", 11);
+        let receiver = &mut session.inbox.as_mut().unwrap().receiver;
+        assert!(receiver.try_recv().is_err());
+
+        notifier.set_flags(parse_notify_flags("Ex").unwrap());
+        keyspace.insert("a".into(), StorageRecord { value: StorageValue::String("1".into()), ttl: Some(10) });
+        keyspace.get(b"a", 11);
+        assert_eq!(
+            receiver.try_recv().unwrap().0,
+            DataType::Push(vec![
+                DataType::BulkString("message".into()),
+                DataType::BulkString("__keyevent@0__:expired".into()),
+                DataType::BulkString("a".into()),
+            ])
+        );
+        // Only keyevent notifications were asked for
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    pub fn test_unsubscribing_from_everything_leaves_subscribed_mode() {
+        let broker = Broker::default();
+        let mut session = Session::new(1);
+        broker.subscribe(&mut session, SubscriptionKind::Channel, vec!["a".into(), "b".into()]);
+
+        let DataType::Sequence(replies) = broker.unsubscribe(&mut session, SubscriptionKind::Channel, vec![]) else {
+            panic!("Expected one reply per channel");
+        };
+        assert_eq!(replies.len(), 2);
+        assert!(session.inbox.is_none());
+        assert_eq!(broker.numsub(vec!["a".into()]), DataType::Array(vec![DataType::BulkString("a".into()), DataType::Integer(0)]));
+    }
+
+    #[test]
+    pub fn test_queued_messages_go_out_before_leaving_subscribed_mode() {
+        let broker = Broker::default();
+        let mut session = Session::new(1);
+        broker.subscribe(&mut session, SubscriptionKind::Channel, vec!["a".into()]);
+        assert_eq!(broker.publish(&"a".into(), &"hi".into()), 1);
+
+        assert_eq!(
+            broker.unsubscribe(&mut session, SubscriptionKind::Channel, vec![]),
+            DataType::Sequence(vec![
+                DataType::Push(vec![DataType::BulkString("message".into()), DataType::BulkString("a".into()), DataType::BulkString("hi".into())]),
+                subscription_reply(SubscriptionKind::Channel, false, Some("a".into()), 0),
+            ])
+        );
+        assert!(session.inbox.is_none());
+    }
+
+    #[test]
+    pub fn test_shard_channels_are_counted_apart() {
+        let broker = Broker::default();
+        let mut session = Session::new(1);
+        broker.subscribe(&mut session, SubscriptionKind::Channel, vec!["a".into()]);
+        let reply = broker.subscribe(&mut session, SubscriptionKind::Shard, vec!["orders".into()]);
+        assert_eq!(
+            reply,
+            DataType::Sequence(vec![DataType::Push(vec![
+                DataType::BulkString("ssubscribe".into()),
+                DataType::BulkString("orders".into()),
+                DataType::Integer(1),
+            ])])
+        );
+        assert_eq!(session.subscriptions, 2);
+
+        // Shard channels only get SPUBLISH and the other way around
+        assert_eq!(broker.publish(&"orders".into(), &"m".into()), 0);
+        assert_eq!(broker.shard_publish(&"orders".into(), &"m".into()), 1);
+        assert_eq!(broker.shard_channels(None), DataType::Array(vec![DataType::BulkString("orders".into())]));
+
+        broker.unsubscribe(&mut session, SubscriptionKind::Shard, vec![]);
+        assert_eq!(broker.shard_numsub(vec!["orders".into()]), DataType::Array(vec![DataType::BulkString("orders".into()), DataType::Integer(0)]));
+        assert_eq!(session.subscriptions, 1);
+    }
+
+    #[test]
+    pub fn test_slow_subscriber_is_dropped() {
+        let broker = Broker::default();
+        let mut session = Session::new(1);
+        broker.subscribe(&mut session, SubscriptionKind::Channel, vec!["a".into()]);
+
+        let message = Bytes::from(vec![0u8; OUTPUT_BUFFER_LIMIT / 2]);
+        assert_eq!(broker.publish(&"a".into(), &message), 1);
+        assert_eq!(broker.publish(&"a".into(), &message), 0);
+        assert_eq!(broker.channels(None), DataType::Array(vec![]));
+    }
+}
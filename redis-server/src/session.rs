@@ -0,0 +1,149 @@
+use bytes::Bytes;
+use crate::commands::{Command, HelloCommand, PubSubCommand};
+use crate::data::transaction::WatchedKey;
+use crate::datatypes::DataType;
+use crate::protocol::serializer::ProtocolVersion;
+use crate::pubsub::Inbox;
+
+/// Version reported to clients by HELLO, clients use it to decide which commands they can send.
+pub const REDIS_VERSION: &str = "7.2.0";
+
+/// The commands sent after MULTI, waiting for EXEC.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    pub commands: Vec<Command>,
+    /// Set once a command couldn't be queued, EXEC then discards all of them.
+    pub aborted: bool,
+}
+
+/// State that belongs to a single client connection rather than to the keyspace.
+#[derive(Debug, Default)]
+pub struct Session {
+    pub id: u64,
+    pub protocol: ProtocolVersion,
+    pub name: Option<Bytes>,
+    /// Set while the client is subscribed to anything, the messages for it wait here.
+    pub inbox: Option<Inbox>,
+    pub subscriptions: usize,
+    /// Whether the client can run commands, set up front when the server has no password.
+    pub authenticated: bool,
+    /// Set between MULTI and EXEC or DISCARD.
+    pub transaction: Option<Transaction>,
+    /// The keys WATCHed for the next EXEC.
+    pub watched: Vec<WatchedKey>,
+}
+
+impl Session {
+    pub fn new(id: u64) -> Session {
+        Session {
+            id,
+            ..Default::default()
+        }
+    }
+
+    /// A subscribed RESP2 connection only takes the commands that manage its subscriptions,
+    /// RESP3 can tell pushes apart from replies so it can keep sending anything.
+    pub fn allows(&self, command: &Command) -> bool {
+        self.subscriptions == 0 || self.protocol == ProtocolVersion::Resp3 || command.allowed_while_subscribed()
+    }
+
+    pub fn multi(&mut self) -> DataType {
+        if self.transaction.is_some() {
+            return DataType::Error("ERR MULTI calls can not be nested".into());
+        }
+        self.transaction = Some(Transaction::default());
+        DataType::SimpleString("OK".into())
+    }
+
+    /// Adds a command to the transaction in progress.
+    pub fn queue(&mut self, command: Command) -> DataType {
+        let transaction = self.transaction.as_mut().expect("Expected a transaction in progress");
+        // Subscribing would leave the connection in subscribed mode halfway through EXEC, and DUMP
+        // needs every key while the engine only holds the transaction's
+        if matches!(command, Command::Watch { .. } | Command::Dump | Command::PubSub(PubSubCommand::Subscribe { .. } | PubSubCommand::Unsubscribe { .. })) {
+            transaction.aborted = true;
+            return DataType::Error("ERR Command not allowed inside a transaction".into());
+        }
+        transaction.commands.push(command);
+        DataType::SimpleString("QUEUED".into())
+    }
+
+    /// The reply to a command that couldn't be parsed. Inside a transaction it also makes EXEC
+    /// fail, the client can't know which of its commands were queued.
+    pub fn reject(&mut self, err: String) -> DataType {
+        if let Some(transaction) = &mut self.transaction {
+            transaction.aborted = true;
+        }
+        DataType::Error(err)
+    }
+
+    /// Leaves out the keys that are already watched, a key is watched once however many
+    /// times it's passed to WATCH.
+    pub fn keys_to_watch(&self, keys: Vec<Bytes>) -> Vec<Bytes> {
+        let mut new_keys: Vec<Bytes> = Vec::new();
+        for key in keys {
+            if !self.watched.iter().any(|watched| watched.key == key) && !new_keys.contains(&key) {
+                new_keys.push(key);
+            }
+        }
+        new_keys
+    }
+
+    /// AUTH, and the AUTH option of HELLO. There's only the default user, `requirepass` is its
+    /// password and without one any password goes as long as the user is named.
+    pub fn auth(&mut self, requirepass: Option<&str>, username: Option<&[u8]>, password: &[u8]) -> DataType {
+        let matches = match (requirepass, username) {
+            (None, None) => {
+                return DataType::Error(
+                    "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".into(),
+                )
+            }
+            (_, Some(username)) if username != b"default" => false,
+            (None, Some(_)) => true,
+            (Some(requirepass), _) => requirepass.as_bytes() == password,
+        };
+        if !matches {
+            return DataType::Error("WRONGPASS invalid username-password pair or user is disabled.".into());
+        }
+        self.authenticated = true;
+        DataType::SimpleString("OK".into())
+    }
+
+    pub fn hello(&mut self, cmd: HelloCommand, requirepass: Option<&str>) -> DataType {
+        if let Some((username, password)) = cmd.auth {
+            if let reply @ DataType::Error(_) = self.auth(requirepass, Some(&username), &password) {
+                return reply;
+            }
+        }
+        if !self.authenticated {
+            return DataType::Error(
+                "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".into(),
+            );
+        }
+
+        match cmd.protocol {
+            None => {}
+            Some(2) => self.protocol = ProtocolVersion::Resp2,
+            Some(3) => self.protocol = ProtocolVersion::Resp3,
+            Some(_) => return DataType::Error("NOPROTO unsupported protocol version".into()),
+        }
+        if let Some(name) = cmd.client_name {
+            self.name = Some(name);
+        }
+
+        let proto = match self.protocol {
+            ProtocolVersion::Resp2 => 2,
+            ProtocolVersion::Resp3 => 3,
+        };
+        let field = |name: &'static str| DataType::BulkString(name.into());
+        DataType::Map(vec![
+            (field("server"), field("redis")),
+            (field("version"), field(REDIS_VERSION)),
+            (field("proto"), DataType::Integer(proto)),
+            (field("id"), DataType::Integer(self.id as i64)),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), DataType::Array(vec![])),
+        ])
+    }
+}
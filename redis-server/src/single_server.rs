@@ -1,30 +1,125 @@
-use std::collections::HashMap;
-use crate::data::shared::{process_get, process_set};
-use crate::datatypes::StorageRecord;
+use std::time::Instant;
+use crate::config::Config;
+use crate::data::keyspace::{active_expire_interval, Keyspace};
+use crate::data::shared::{current_unix_timestamp_millis, dump_reply};
+use crate::data::dispatch::execute;
+use crate::data::transaction::{execute_transaction, unwatch_keys, watch_keys};
+use crate::session::Session;
 use crate::{commands::Command, datatypes::DataType};
 
 pub struct Server {
-    map: HashMap<String, StorageRecord>,
+    map: Keyspace,
+    config: Config,
+    next_client_id: u64,
+    last_expire_cycle: Instant,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Server {
     pub fn new() -> Server {
+        Self::with_config(Config::new())
+    }
+
+    pub fn with_config(config: Config) -> Server {
+        config.apply_process_settings();
         Server {
-            map: HashMap::new(),
+            map: Keyspace::new(),
+            config,
+            next_client_id: 1,
+            last_expire_cycle: Instant::now(),
         }
     }
 
-    pub fn process_command(&mut self, command: Command) -> Result<DataType, String> {
+    pub fn create_session(&mut self) -> Session {
+        let mut session = Session::new(self.next_client_id);
+        session.authenticated = self.config.requirepass().is_none();
+        self.next_client_id += 1;
+        session
+    }
+
+    pub fn process_command(&mut self, session: &mut Session, command: Command) -> Result<DataType, String> {
+        let now = current_unix_timestamp_millis();
+        // There's no background thread here, so the expiry cycle piggybacks on incoming commands
+        if self.last_expire_cycle.elapsed() >= active_expire_interval() {
+            self.map.active_expire_cycle(now);
+            self.last_expire_cycle = Instant::now();
+        }
+
+        if !session.authenticated && !matches!(command, Command::Auth { .. } | Command::Hello(_)) {
+            return Ok(DataType::Error("NOAUTH Authentication required.".into()));
+        }
+
         match command {
-            Command::Set(command) => process_set(&mut self.map, command),
-            Command::Get { key } => process_get(&mut self.map, key),
-            Command::ConfigGet { .. } => {
-                Ok(DataType::Array(vec![DataType::BulkString("save".into()), DataType::BulkString("3600 1 300 100 60 10000".into())]))
-            },
-            Command::Dump => {
-                println!("{:#?}", self.map);
-                Ok(DataType::Nil)
-            },
+            Command::Multi => Ok(session.multi()),
+            Command::Exec => self.process_exec(session, now),
+            Command::Discard => {
+                if session.transaction.take().is_none() {
+                    return Ok(DataType::Error("ERR DISCARD without MULTI".into()));
+                }
+                unwatch_keys(&mut self.map, &std::mem::take(&mut session.watched));
+                Ok(DataType::SimpleString("OK".into()))
+            }
+            command if session.transaction.is_some() => Ok(session.queue(command)),
+            Command::Watch { keys } => {
+                let keys = session.keys_to_watch(keys);
+                session.watched.extend(watch_keys(&mut self.map, keys, now));
+                Ok(DataType::SimpleString("OK".into()))
+            }
+            Command::Unwatch => {
+                unwatch_keys(&mut self.map, &std::mem::take(&mut session.watched));
+                Ok(DataType::SimpleString("OK".into()))
+            }
+            Command::Dump => Ok(dump_reply(&self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect())),
+            // Waiting would hold up every other client, so blocking commands act like their
+            // non blocking forms, same as redis does inside MULTI
+            command if command.works_on_keys() => execute(&mut self.map, command, now),
+            command => Ok(process_unkeyed(&self.config, session, command)),
+        }
+    }
+
+    /// Clients are served one at a time, so nothing can get in between the commands anyway.
+    fn process_exec(&mut self, session: &mut Session, now: u128) -> Result<DataType, String> {
+        let Some(transaction) = session.transaction.take() else {
+            return Ok(DataType::Error("ERR EXEC without MULTI".into()));
+        };
+        let watched = std::mem::take(&mut session.watched);
+        let res = match transaction.aborted {
+            true => Ok(DataType::Error("EXECABORT Transaction discarded because of previous errors.".into())),
+            false => {
+                let config = &self.config;
+                let mut unkeyed = |command| process_unkeyed(config, session, command);
+                execute_transaction(&mut self.map, transaction.commands, &watched, now, &mut unkeyed)
+            }
+        };
+        unwatch_keys(&mut self.map, &watched);
+        res
+    }
+}
+
+/// The commands that belong to the server or the connection rather than to the keyspace.
+/// Inside EXEC they run at their place in the queue, in between the commands on keys.
+fn process_unkeyed(config: &Config, session: &mut Session, command: Command) -> DataType {
+    match command {
+        // Keyspace notifications go out over pub/sub, which this server doesn't have, so
+        // notify-keyspace-events is only kept for CONFIG GET
+        Command::Config(command) => {
+            let reply = config.process(command);
+            config.apply_process_settings();
+            reply
         }
+        Command::Ping { message: None } => DataType::SimpleString("PONG".into()),
+        Command::Ping { message: Some(message) } => DataType::BulkString(message),
+        Command::Hello(command) => session.hello(command, config.requirepass().as_deref()),
+        Command::Auth { username, password } => session.auth(config.requirepass().as_deref(), username.as_deref(), &password),
+        // Connections are served one at a time here, a subscriber would never get a message
+        Command::PubSub(_) => DataType::Error("ERR pub/sub is only supported by the multi threaded server".into()),
+        // Queued in a transaction, EXEC let go of the watched keys already
+        Command::Unwatch => DataType::SimpleString("OK".into()),
+        _ => DataType::Error("ERR Command not allowed inside a transaction".into()),
     }
 }